@@ -1,7 +1,9 @@
 MEMORY {
     BOOT2 : ORIGIN = 0x10000000, LENGTH = 0x100
-    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100
-    RAM   : ORIGIN = 0x20000000, LENGTH = 256K
+    /* The last 64K is reserved for flash_store */
+    FLASH : ORIGIN = 0x10000100, LENGTH = 2048K - 0x100 - 64K
+    /* The last 256 bytes hold the panic message across a reset, see src/panic_info.rs */
+    RAM   : ORIGIN = 0x20000000, LENGTH = 256K - 256
 }
 
 EXTERN(BOOT2_FIRMWARE)
@@ -1,12 +1,19 @@
-use defmt::Format;
+use defmt::{debug, Format};
 use embassy_rp::adc::{Adc, Async, Channel, Error};
-use embassy_time::{with_timeout, Duration, TimeoutError};
+use embassy_time::{with_timeout, Duration, Instant, TimeoutError};
+use heapless::Vec;
+use picoserve::response::chunked::{ChunkWriter, Chunks, ChunksWritten};
+
+use crate::events::{self, SensorEvent};
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{describe, MetricDescription, MetricSink, MetricWriter};
 
 pub struct Sensor<'a> {
     pub adc: Adc<'a, Async>,
     pub temp_sensor: Channel<'a>,
 }
 
+#[derive(Clone, Copy, Format)]
 pub struct Value {
     pub temp_celsius: f32,
     pub volt: f32,
@@ -30,22 +37,161 @@ impl From<TimeoutError> for AdcError {
         AdcError::Timeout(value)
     }
 }
+
+/// Most samples `GET /sensor/adc/raw-samples` takes in one request.
+pub const MAX_RAW_SAMPLES: usize = 1000;
+
 impl<'a> Sensor<'a> {
     pub async fn read(&mut self) -> Result<Value, AdcError> {
-        with_timeout(Duration::from_secs(1), async {
-            let raw = self.adc.read(&mut self.temp_sensor).await?;
-
-            // Convert to temperature in Celsius
-            // RP2040 datasheet formula: T = 27 - (ADC_voltage - 0.706)/0.001721
-            let volt = (raw as f32 * 3.29) / 4096.0; // 12-bit ADC, 3.3V reference
-            let temp_celsius = 27. - (volt - 0.706) / 0.001721;
-
-            Ok(Value {
-                temp_celsius,
-                volt,
-                raw,
-            })
+        let raw = self.read_raw().await?;
+
+        // Convert to temperature in Celsius
+        // RP2040 datasheet formula: T = 27 - (ADC_voltage - 0.706)/0.001721
+        let volt = (raw as f32 * 3.29) / 4096.0; // 12-bit ADC, 3.3V reference
+        let temp_celsius = 27. - (volt - 0.706) / 0.001721;
+
+        Ok(Value {
+            temp_celsius,
+            volt,
+            raw,
         })
-        .await?
+    }
+
+    /// The 12 bit ADC count, unconverted.
+    pub async fn read_raw(&mut self) -> Result<u16, AdcError> {
+        Ok(with_timeout(Duration::from_secs(1), self.adc.read(&mut self.temp_sensor)).await??)
+    }
+}
+
+/// The onboard temperature sensor, read directly from the ADC on every poll.
+pub struct AdcTempSensor {
+    sensor: &'static mut Sensor<'static>,
+    last: Option<Value>,
+    last_success: Option<Instant>,
+}
+
+impl AdcTempSensor {
+    pub fn new(sensor: &'static mut Sensor<'static>) -> Self {
+        Self {
+            sensor,
+            last: None,
+            last_success: None,
+        }
+    }
+
+    /// Reading from the most recent poll, `None` if it failed.
+    pub fn last(&self) -> Option<&Value> {
+        self.last.as_ref()
+    }
+
+    /// When a poll last succeeded.
+    pub fn last_success(&self) -> Option<Instant> {
+        self.last_success
+    }
+
+    /// Read the ADC count without touching the cached reading.
+    pub async fn read_raw(&mut self) -> Result<u16, AdcError> {
+        self.sensor.read_raw().await
+    }
+}
+
+impl crate::Sensor for AdcTempSensor {
+    type Reading = Option<Value>;
+
+    fn name(&self) -> &'static str {
+        "adc_temp_sensor"
+    }
+
+    fn error_source(&self) -> Option<crate::ErrorSource> {
+        Some(crate::ErrorSource::Adc)
+    }
+
+    async fn poll(&mut self) -> Result<(), crate::SensorError> {
+        self.last = None;
+        let value = self.sensor.read().await?;
+        debug!("adc_temp_sensor: {}", value);
+        events::publish(SensorEvent::Adc(value));
+        self.last = Some(value);
+        self.last_success = Some(Instant::now());
+        Ok(())
+    }
+
+    fn reading(&self) -> Option<Value> {
+        self.last
+    }
+}
+
+/// The `unit` label says which of the three values a sample is.
+pub(crate) const ADC_TEMP_SENSOR: MetricDescription = describe(
+    "adc_temp_sensor",
+    "RP2040 onboard temperature sensor.  raw is the 12 bit ADC count (0 to 4095), volts is \
+     raw * 3.29 / 4096, and C is degrees Celsius from T = 27 - (volts - 0.706) / 0.001721, \
+     accurate to a few degrees",
+    "",
+);
+
+impl crate::WriteMetrics for Value {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer
+            .write(
+                ADC_TEMP_SENSOR.gauge(
+                    ["unit"],
+                    [
+                        Sample::new(["C"], self.temp_celsius),
+                        Sample::new(["volts"], self.volt),
+                        Sample::new(["raw"], self.raw as f32),
+                    ]
+                    .iter(),
+                ),
+            )
+            .await?;
+        Ok(())
+    }
+}
+
+/// Raw ADC counts for `GET /sensor/adc/raw-samples`, one per line after a JSON line with
+/// their statistics.
+pub struct RawSamplesResponse {
+    samples: Vec<u16, 1024>,
+}
+
+impl RawSamplesResponse {
+    /// `samples` must not be empty.
+    pub fn new(samples: Vec<u16, 1024>) -> Self {
+        Self { samples }
+    }
+}
+
+impl Chunks for RawSamplesResponse {
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        let count = self.samples.len() as f32;
+        let mean = self.samples.iter().map(|&s| s as f32).sum::<f32>() / count;
+        let variance = self
+            .samples
+            .iter()
+            .map(|&s| (s as f32 - mean) * (s as f32 - mean))
+            .sum::<f32>()
+            / count;
+        writeln!(
+            chunk_writer,
+            "{{\"count\":{},\"min\":{},\"max\":{},\"mean\":{},\"stddev\":{}}}",
+            self.samples.len(),
+            self.samples.iter().min().copied().unwrap_or(0),
+            self.samples.iter().max().copied().unwrap_or(0),
+            mean,
+            libm::sqrtf(variance)
+        )
+        .await?;
+        for sample in &self.samples {
+            writeln!(chunk_writer, "{}", sample).await?;
+        }
+        chunk_writer.finalize().await
     }
 }
@@ -0,0 +1,353 @@
+//! Alarm on the INA237 bus voltage, the supply to the monitored circuit, leaving its
+//! normal range, e.g. below 4.5 V on a 5 V supply that is failing.
+//!
+//! Each change of state is POSTed as JSON to a webhook:
+//!
+//! ```text
+//! {"device":"pico-climate-1a2b3c4d","alarm":"low_voltage","voltage_v":4.2,"threshold_v":4.5}
+//! ```
+//!
+//! `alarm` is `normal` when the voltage comes back, with the threshold it recovered from.
+//! A change the webhook doesn't take is retried with backoff until it does, or until the
+//! next change replaces it.
+
+use core::fmt::Write as _;
+
+use defmt::{error, info, Format};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_time::{with_deadline, with_timeout, Duration, Instant};
+use embedded_io_async::Write;
+use heapless::String;
+
+use crate::events::{self, SensorEvent};
+use crate::ina237;
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{counter, gauge, MetricSink, MetricWriter};
+use crate::push::PushError;
+use crate::task_registry::{self, TaskStatus};
+use crate::Mutex;
+
+/// Out of range readings in a row before the alarm goes off, and in range readings in a
+/// row before it clears.
+pub const DEFAULT_DEBOUNCE_COUNT: u8 = 3;
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+/// First wait before retrying a failed webhook, doubled on each failure up to
+/// `MAX_WEBHOOK_RETRY`.
+const WEBHOOK_RETRY: Duration = Duration::from_secs(5);
+const MAX_WEBHOOK_RETRY: Duration = Duration::from_secs(300);
+
+/// Set by main when `VOLTAGE_ALARM_WEBHOOK` is configured and an INA237 is fitted.
+pub static VOLTAGE_ALARM: Mutex<Option<VoltageAlarm>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum AlarmState {
+    Normal,
+    LowVoltage,
+    HighVoltage,
+}
+
+impl AlarmState {
+    fn name(self) -> &'static str {
+        match self {
+            AlarmState::Normal => "normal",
+            AlarmState::LowVoltage => "low_voltage",
+            AlarmState::HighVoltage => "high_voltage",
+        }
+    }
+}
+
+pub struct VoltageAlarm {
+    low_threshold_v: f32,
+    high_threshold_v: f32,
+    state: AlarmState,
+    /// Readings in a row needed to change `state`.
+    debounce_count: u8,
+    /// The state the last readings were in, when it isn't `state`, and how many in a row.
+    pending: Option<(AlarmState, u8)>,
+    transitions: u32,
+}
+
+#[derive(Clone, Copy)]
+pub struct Status {
+    pub state: AlarmState,
+    pub transitions: u32,
+}
+
+impl VoltageAlarm {
+    pub const fn new(low_threshold_v: f32, high_threshold_v: f32, debounce_count: u8) -> Self {
+        Self {
+            low_threshold_v,
+            high_threshold_v,
+            state: AlarmState::Normal,
+            debounce_count,
+            pending: None,
+            transitions: 0,
+        }
+    }
+
+    /// Take a reading, returning the new state if this one changed it.
+    pub fn update(&mut self, voltage: f32) -> Option<AlarmState> {
+        let reading_state = if voltage < self.low_threshold_v {
+            AlarmState::LowVoltage
+        } else if voltage > self.high_threshold_v {
+            AlarmState::HighVoltage
+        } else {
+            AlarmState::Normal
+        };
+        if reading_state == self.state {
+            self.pending = None;
+            return None;
+        }
+
+        let count = match self.pending {
+            Some((state, count)) if state == reading_state => count.saturating_add(1),
+            _ => 1,
+        };
+        if count < self.debounce_count {
+            self.pending = Some((reading_state, count));
+            return None;
+        }
+        self.pending = None;
+        self.state = reading_state;
+        self.transitions = self.transitions.wrapping_add(1);
+        Some(reading_state)
+    }
+
+    /// The threshold that `state` crossed, or for `Normal` the one crossed back from
+    /// `previous`.
+    fn threshold_v(&self, state: AlarmState, previous: AlarmState) -> f32 {
+        match (state, previous) {
+            (AlarmState::HighVoltage, _) | (AlarmState::Normal, AlarmState::HighVoltage) => {
+                self.high_threshold_v
+            }
+            _ => self.low_threshold_v,
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            state: self.state,
+            transitions: self.transitions,
+        }
+    }
+}
+
+/// The alarm's state, `None` if it isn't configured.
+pub async fn status() -> Option<Status> {
+    VOLTAGE_ALARM
+        .lock()
+        .await
+        .as_ref()
+        .map(VoltageAlarm::status)
+}
+
+/// Split `http://host[:port]/path` into its parts.  `None` for anything else, including
+/// https, which the device can't speak.
+pub fn parse_url(url: &str) -> Option<(&str, u16, &str)> {
+    let rest = url.strip_prefix("http://")?;
+    let (authority, path) = match rest.find('/') {
+        Some(i) => rest.split_at(i),
+        None => (rest, "/"),
+    };
+    let (host, port) = match authority.split_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (authority, 80),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    Some((host, port, path))
+}
+
+/// POST `payload` as JSON to `url` and check for a 2xx response, giving up after
+/// 10 seconds.
+pub async fn send_webhook(
+    stack: Stack<'static>,
+    url: &str,
+    payload: &str,
+) -> Result<(), PushError> {
+    // main only spawns `voltage_alarm_task` with a URL that parses
+    let (host, port, path) = parse_url(url).ok_or(PushError::Dns)?;
+    with_timeout(WEBHOOK_TIMEOUT, post(stack, host, port, path, payload))
+        .await
+        .map_err(|_| PushError::Timeout)?
+}
+
+async fn post(
+    stack: Stack<'static>,
+    host: &str,
+    port: u16,
+    path: &str,
+    payload: &str,
+) -> Result<(), PushError> {
+    let addr = match stack
+        .dns_query(host, embassy_net::dns::DnsQueryType::A)
+        .await
+    {
+        Ok(addresses) => *addresses.first().ok_or(PushError::Dns)?,
+        Err(_) => return Err(PushError::Dns),
+    };
+
+    let mut rx_buffer = [0; 256];
+    let mut tx_buffer = [0; 512];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(WEBHOOK_TIMEOUT));
+    socket
+        .connect(embassy_net::IpEndpoint::new(addr, port))
+        .await
+        .map_err(PushError::Connect)?;
+
+    let mut head = String::<256>::new();
+    write!(
+        &mut head,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        port,
+        payload.len()
+    )
+    .map_err(|_| PushError::BufferFull)?;
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(payload.as_bytes()).await?;
+    socket.flush().await?;
+
+    // Only the status line matters: "HTTP/1.1 200 OK"
+    let mut status = [0u8; 12];
+    let mut len = 0;
+    while len < status.len() {
+        let n = socket.read(&mut status[len..]).await?;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    socket.close();
+
+    match status.get(9) {
+        Some(b'2') if status.starts_with(b"HTTP/") => Ok(()),
+        Some(digit) if status.starts_with(b"HTTP/") => Err(PushError::Status(digit - b'0')),
+        _ => Err(PushError::Status(0)),
+    }
+}
+
+/// Feed the INA237 bus voltage to `VOLTAGE_ALARM`, POSTing each change of state to
+/// `url`.  A change that fails to send is retried with backoff until it gets a 2xx or a
+/// newer change replaces it, and the task shows as errored meanwhile.
+#[embassy_executor::task]
+pub async fn voltage_alarm_task(
+    stack: &'static Stack<'static>,
+    url: &'static str,
+    device: &'static str,
+) -> ! {
+    let task = task_registry::VOLTAGE_ALARM;
+    let mut subscriber = events::subscribe();
+    // The payload of the last change while the webhook hasn't taken it
+    let mut undelivered: Option<String<160>> = None;
+    let mut retry_at = Instant::now();
+    let mut retry_delay = WEBHOOK_RETRY;
+    loop {
+        let status = if undelivered.is_some() {
+            TaskStatus::Error
+        } else {
+            TaskStatus::Waiting
+        };
+        task_registry::set_status(task, status);
+        let event = if undelivered.is_some() {
+            with_deadline(retry_at, events::next(&mut subscriber))
+                .await
+                .ok()
+        } else {
+            Some(events::next(&mut subscriber).await)
+        };
+        if let Some(event) = event {
+            let SensorEvent::Ina237(output) = event else {
+                continue;
+            };
+            task_registry::iteration(task);
+            let Some(payload) = alarm_payload(&output, device).await else {
+                continue;
+            };
+            undelivered = Some(payload);
+            retry_delay = WEBHOOK_RETRY;
+        }
+        let Some(payload) = &undelivered else {
+            continue;
+        };
+
+        // Readings past the channel's capacity while this waits are dropped as lagged
+        match send_webhook(*stack, url, payload).await {
+            Ok(()) => undelivered = None,
+            Err(e) => {
+                error!(
+                    "alarm: Webhook failed, retrying in {} s: {:?}",
+                    retry_delay.as_secs(),
+                    e
+                );
+                retry_at = Instant::now() + retry_delay;
+                retry_delay = (retry_delay * 2).min(MAX_WEBHOOK_RETRY);
+            }
+        }
+    }
+}
+
+/// Feed `output` to `VOLTAGE_ALARM`, returning the webhook payload if it changed the
+/// state.
+async fn alarm_payload(output: &ina237::TickOutput, device: &str) -> Option<String<160>> {
+    if output.math_overflow {
+        return None;
+    }
+
+    let voltage = output.bus_voltage;
+    let (state, threshold_v) = {
+        let mut alarm = VOLTAGE_ALARM.lock().await;
+        let alarm = alarm.as_mut()?;
+        let previous = alarm.state;
+        let state = alarm.update(voltage)?;
+        (state, alarm.threshold_v(state, previous))
+    };
+    info!("alarm: Bus voltage {} V, now {}", voltage, state.name());
+
+    let mut payload = String::<160>::new();
+    // Can't fail, the hostname is at most 32 bytes
+    let _ = write!(
+        &mut payload,
+        "{{\"device\":\"{}\",\"alarm\":\"{}\",\"voltage_v\":{},\"threshold_v\":{}}}",
+        device,
+        state.name(),
+        voltage,
+        threshold_v
+    );
+    Some(payload)
+}
+
+impl crate::WriteMetrics for Status {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer
+            .write(gauge(
+                "voltage_alarm_state",
+                "Whether the INA237 bus voltage is past the threshold, 1 if alarming",
+                ["threshold"],
+                [
+                    Sample::new(["low"], (self.state == AlarmState::LowVoltage) as u8 as f32),
+                    Sample::new(
+                        ["high"],
+                        (self.state == AlarmState::HighVoltage) as u8 as f32,
+                    ),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "voltage_alarm_transitions_total",
+                "Changes of the bus voltage alarm state since boot",
+                [],
+                [Sample::new([], self.transitions as f32)].iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
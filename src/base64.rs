@@ -0,0 +1,29 @@
+//! Standard base64 decoding, for PEM certificates and HTTP Basic credentials.
+
+/// Decode `encoded` into `out`, returning the decoded length.  Padding ends the input and
+/// line breaks and spaces are skipped.  `None` if it is invalid or `out` is too small.
+pub fn decode(encoded: &[u8], out: &mut [u8]) -> Option<usize> {
+    let mut acc: u32 = 0;
+    let mut bits = 0;
+    let mut len = 0;
+    for &c in encoded {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            b'\r' | b'\n' | b' ' => continue,
+            _ => return None,
+        };
+        acc = (acc << 6) | value as u32;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            *out.get_mut(len)? = (acc >> bits) as u8;
+            len += 1;
+        }
+    }
+    Some(len)
+}
@@ -0,0 +1,248 @@
+//! State of charge of a LiPo battery powering the device, from the INA237.
+//!
+//! Counting the charge that flows through the shunt is accurate over hours but drifts,
+//! and needs a starting point.  The bus voltage gives a rough but absolute estimate, used
+//! to start from when nothing was saved and exported alongside so drift can be spotted.
+//! Current drawn from the battery reads positive, as with the shunt on the battery's
+//! high side.
+
+use defmt::{error, info};
+use embassy_time::{Duration, Instant, Timer};
+
+use crate::events::{self, SensorEvent};
+use crate::flash_log;
+use crate::flash_store::{FlashStore, Slot};
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{gauge, MetricSink, MetricWriter};
+use crate::task_registry::{self, TaskStatus};
+use crate::Mutex;
+
+/// Resting LiPo cell voltage against state of charge in percent, in increasing order.
+const VOLTAGE_SOC: [(f32, f32); 11] = [
+    (3.0, 0.),
+    (3.7, 10.),
+    (3.75, 20.),
+    (3.79, 30.),
+    (3.83, 40.),
+    (3.87, 50.),
+    (3.92, 60.),
+    (3.97, 70.),
+    (4.02, 80.),
+    (4.08, 90.),
+    (4.2, 100.),
+];
+
+/// How often `persist_task` writes the remaining charge to flash.
+const PERSIST_INTERVAL: Duration = Duration::from_secs(5 * 60);
+/// Time constant of the average current used for the runtime estimate.
+const AVERAGE_CURRENT_WINDOW_HOURS: f32 = 1.;
+
+/// Set by main when `BATTERY_CAPACITY_MAH` is configured and an INA237 is fitted.
+pub static MONITOR: Mutex<Option<BatteryMonitor>> = Mutex::new(None);
+
+/// State of charge from `bus_voltage_v`, interpolating linearly within `VOLTAGE_SOC`.
+pub fn voltage_soc(bus_voltage_v: f32) -> f32 {
+    let (first_v, first_soc) = VOLTAGE_SOC[0];
+    if bus_voltage_v <= first_v {
+        return first_soc;
+    }
+    for pair in VOLTAGE_SOC.windows(2) {
+        let ((low_v, low_soc), (high_v, high_soc)) = (pair[0], pair[1]);
+        if bus_voltage_v <= high_v {
+            return low_soc + (high_soc - low_soc) * (bus_voltage_v - low_v) / (high_v - low_v);
+        }
+    }
+    VOLTAGE_SOC[VOLTAGE_SOC.len() - 1].1
+}
+
+pub struct BatteryMonitor {
+    capacity_mah: f32,
+    soc_percent: f32,
+    /// `None` until the first reading when nothing was saved, then seeded from the voltage.
+    charge_mah: Option<f32>,
+    voltage_soc_percent: f32,
+    /// Moving average over about `AVERAGE_CURRENT_WINDOW_HOURS`, positive when discharging.
+    average_current_ma: f32,
+    /// `None` until the first reading, so time before the sensor started isn't counted.
+    last_update: Option<Instant>,
+}
+
+#[derive(Clone, Copy)]
+pub struct Status {
+    pub soc_coulomb_percent: f32,
+    pub soc_voltage_percent: f32,
+    pub charge_mah_remaining: f32,
+    /// NaN unless the battery is discharging.
+    pub runtime_hours_estimate: f32,
+}
+
+impl BatteryMonitor {
+    /// `charge_mah` is what `load` found, if anything.
+    pub const fn new(capacity_mah: f32, charge_mah: Option<f32>) -> Self {
+        Self {
+            capacity_mah,
+            soc_percent: 0.,
+            charge_mah,
+            voltage_soc_percent: 0.,
+            average_current_ma: 0.,
+            last_update: None,
+        }
+    }
+
+    /// Take the charge used since the previous update, assuming `current_ma` held for the
+    /// whole interval.
+    pub fn update(&mut self, current_ma: f32, bus_voltage_v: f32) {
+        let now = Instant::now();
+        self.voltage_soc_percent = voltage_soc(bus_voltage_v);
+
+        let capacity_mah = self.capacity_mah;
+        let voltage_soc_percent = self.voltage_soc_percent;
+        let charge_mah = self.charge_mah.get_or_insert_with(|| {
+            info!("battery: Starting from {}% by voltage", voltage_soc_percent);
+            capacity_mah * voltage_soc_percent / 100.
+        });
+        if let Some(last_update) = self.last_update {
+            let elapsed_hours = (now - last_update).as_millis() as f32 / 3_600_000.;
+            *charge_mah = (*charge_mah - current_ma * elapsed_hours).clamp(0., capacity_mah);
+
+            let weight = (elapsed_hours / AVERAGE_CURRENT_WINDOW_HOURS).min(1.);
+            self.average_current_ma += (current_ma - self.average_current_ma) * weight;
+        } else {
+            self.average_current_ma = current_ma;
+        }
+        self.soc_percent = (*charge_mah / capacity_mah * 100.).clamp(0., 100.);
+        self.last_update = Some(now);
+    }
+
+    pub fn charge_mah(&self) -> Option<f32> {
+        self.charge_mah
+    }
+
+    /// `None` before the first reading.
+    pub fn status(&self) -> Option<Status> {
+        let charge_mah = self.charge_mah?;
+        self.last_update?;
+        Some(Status {
+            soc_coulomb_percent: self.soc_percent,
+            soc_voltage_percent: self.voltage_soc_percent,
+            charge_mah_remaining: charge_mah,
+            runtime_hours_estimate: if self.average_current_ma > 0. {
+                charge_mah / self.average_current_ma
+            } else {
+                f32::NAN
+            },
+        })
+    }
+
+    /// Charge saved by `save`, if there is any.
+    pub async fn load(store: &FlashStore) -> Option<f32> {
+        flash_log::load(store, Slot::BatteryCharge)
+            .await
+            .map(|micro_amp_hours| micro_amp_hours as f32 / 1000.)
+    }
+
+    /// Persist `charge_mah`, rounded to the nearest microamp-hour.  Appended to a log, so
+    /// the sector is only erased every few hundred saves.
+    pub async fn save(charge_mah: f32, store: &FlashStore) -> Result<(), embassy_rp::flash::Error> {
+        let micro_amp_hours = libm::roundf(charge_mah * 1000.) as u32;
+        flash_log::append(store, Slot::BatteryCharge, micro_amp_hours).await
+    }
+}
+
+/// The monitor's state, `None` if it isn't configured or hasn't had a reading.
+pub async fn status() -> Option<Status> {
+    MONITOR
+        .lock()
+        .await
+        .as_ref()
+        .and_then(BatteryMonitor::status)
+}
+
+/// Feed the INA237 readings to the monitor.
+#[embassy_executor::task]
+pub async fn record_task() -> ! {
+    let task = task_registry::BATTERY;
+    let mut subscriber = events::subscribe();
+    loop {
+        task_registry::set_status(task, TaskStatus::Waiting);
+        if let SensorEvent::Ina237(output) = events::next(&mut subscriber).await {
+            task_registry::iteration(task);
+            // An overflowed current is garbage, leave the count alone
+            if output.math_overflow {
+                continue;
+            }
+            if let Some(monitor) = MONITOR.lock().await.as_mut() {
+                monitor.update(output.current * 1000., output.bus_voltage);
+            }
+        }
+    }
+}
+
+/// Write the remaining charge to flash every 5 minutes so it survives a reboot.
+#[embassy_executor::task]
+pub async fn persist_task(store: &'static FlashStore) -> ! {
+    let mut last_saved = None;
+    let task = task_registry::BATTERY_PERSIST;
+    loop {
+        Timer::after(PERSIST_INTERVAL).await;
+        task_registry::iteration(task);
+        let charge_mah = MONITOR.lock().await.as_ref().and_then(|m| m.charge_mah());
+        // Skip unchanged values to save flash erase cycles
+        let Some(charge_mah) = charge_mah.filter(|&c| last_saved != Some(c)) else {
+            task_registry::set_status(task, TaskStatus::Waiting);
+            continue;
+        };
+        match BatteryMonitor::save(charge_mah, store).await {
+            Ok(()) => {
+                last_saved = Some(charge_mah);
+                task_registry::set_status(task, TaskStatus::Waiting);
+            }
+            Err(e) => {
+                error!("Unable to save battery charge: {:?}", e);
+                task_registry::set_status(task, TaskStatus::Error);
+            }
+        }
+    }
+}
+
+impl crate::WriteMetrics for Status {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer
+            .write(gauge(
+                "battery_soc_coulomb_percent",
+                "Battery state of charge from counting the INA237 current",
+                [],
+                [Sample::new([], self.soc_coulomb_percent)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "battery_soc_voltage_percent",
+                "Battery state of charge estimated from the INA237 bus voltage",
+                [],
+                [Sample::new([], self.soc_voltage_percent)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "battery_charge_mah_remaining",
+                "Charge left in the battery by counting the INA237 current, in mAh",
+                [],
+                [Sample::new([], self.charge_mah_remaining)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "battery_runtime_hours_estimate",
+                "Hours until the battery is empty at the average current of the last hour",
+                [],
+                [Sample::new([], self.runtime_hours_estimate)].iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,180 @@
+//! BH1750 ambient light sensor, read every few seconds by `ambient_light_task`.
+
+use defmt::{error, info};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_hal::i2c::ErrorType;
+
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{counter, gauge, MetricSink, MetricWriter};
+use crate::task_registry::{self, TaskStatus};
+use crate::{DualI2cDevice, Mutex};
+
+// BH1750 I2C Addresses, selected by the ADDR pin
+pub const BH1750_ADDR_LOW: u8 = 0x23;
+pub const BH1750_ADDR_HIGH: u8 = 0x5C;
+
+// BH1750 Instructions
+const POWER_ON: [u8; 1] = [0x01];
+const RESET: [u8; 1] = [0x07];
+/// 1 lx resolution, 120 ms typical measurement time.
+const CONT_H_RES_MODE: [u8; 1] = [0x10];
+
+/// Worst case H-resolution measurement time, from the datasheet.
+const MEASUREMENT_TIME: Duration = Duration::from_millis(180);
+const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+const READ_INTERVAL: Duration = Duration::from_secs(5);
+
+pub type Bh1750 = Bh1750Device<DualI2cDevice>;
+
+pub struct Bh1750Device<I> {
+    addr: u8,
+    i2c: I,
+}
+
+impl<I: embedded_hal_async::i2c::I2c> Bh1750Device<I> {
+    pub fn new(i2c: I, addr: u8) -> Self {
+        Self { addr, i2c }
+    }
+
+    /// Power on and clear the data register.  Fails if nothing answers at the address.
+    pub async fn init(&mut self) -> Result<(), <I as ErrorType>::Error> {
+        self.i2c.write(self.addr, &POWER_ON).await?;
+        // Reset is ignored while powered down
+        self.i2c.write(self.addr, &RESET).await?;
+        info!("bh1750: Initialized at {:x}", self.addr);
+        Ok(())
+    }
+
+    /// Start a high resolution measurement and read it back, in lux.
+    pub async fn read_lux(&mut self) -> Result<f32, <I as ErrorType>::Error> {
+        self.i2c.write(self.addr, &CONT_H_RES_MODE).await?;
+        Timer::after(MEASUREMENT_TIME).await;
+
+        let mut raw = [0u8; 2];
+        self.i2c.read(self.addr, &mut raw).await?;
+        Ok(u16::from_be_bytes(raw) as f32 / 1.2)
+    }
+}
+
+pub struct SharedState {
+    /// `None` until the first reading, and after a failed one.
+    lux: Option<f32>,
+    errors: u32,
+}
+
+impl SharedState {
+    pub const fn new() -> Self {
+        Self {
+            lux: None,
+            errors: 0,
+        }
+    }
+
+    pub fn snapshot(&self) -> Reading {
+        Reading {
+            lux: self.lux,
+            errors: self.errors,
+        }
+    }
+}
+
+impl Default for SharedState {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Copy)]
+pub struct Reading {
+    pub lux: Option<f32>,
+    pub errors: u32,
+}
+
+/// Read the light level every 5 seconds into `state`.
+#[embassy_executor::task]
+pub async fn ambient_light_task(mut device: Bh1750, state: &'static Mutex<SharedState>) -> ! {
+    let task = task_registry::AMBIENT_LIGHT;
+    loop {
+        task_registry::iteration(task);
+        let result = match with_timeout(READ_TIMEOUT, device.read_lux()).await {
+            Ok(result) => result.map_err(crate::SensorError::from),
+            Err(e) => Err(e.into()),
+        };
+
+        let mut state = state.lock().await;
+        match result {
+            Ok(lux) => {
+                state.lux = Some(lux);
+                task_registry::set_status(task, TaskStatus::Waiting);
+            }
+            Err(e) => {
+                error!("bh1750: Unable to read: {}", e);
+                state.lux = None;
+                state.errors = state.errors.wrapping_add(1);
+                task_registry::set_status(task, TaskStatus::Error);
+            }
+        }
+        drop(state);
+
+        Timer::after(READ_INTERVAL).await;
+    }
+}
+
+/// The BH1750 as seen by the metrics endpoint, a copy of what `ambient_light_task` read
+/// last.
+pub struct Bh1750Sensor {
+    state: &'static Mutex<SharedState>,
+    last: Option<Reading>,
+}
+
+impl Bh1750Sensor {
+    pub fn new(state: &'static Mutex<SharedState>) -> Self {
+        Self { state, last: None }
+    }
+}
+
+impl crate::Sensor for Bh1750Sensor {
+    type Reading = Option<Reading>;
+
+    fn name(&self) -> &'static str {
+        "bh1750"
+    }
+
+    async fn poll(&mut self) -> Result<(), crate::SensorError> {
+        self.last = Some(self.state.lock().await.snapshot());
+        Ok(())
+    }
+
+    fn reading(&self) -> Option<Reading> {
+        self.last
+    }
+}
+
+impl crate::WriteMetrics for Reading {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        if let Some(lux) = self.lux {
+            writer
+                .write(
+                    gauge(
+                        "bh1750_lux",
+                        "Ambient light from the BH1750, in lux",
+                        [],
+                        [Sample::new([], lux)].iter(),
+                    )
+                    .with_unit("lux"),
+                )
+                .await?;
+        }
+
+        writer
+            .write(counter(
+                "bh1750_errors_total",
+                "Failed BH1750 reads since boot",
+                [],
+                [Sample::new([], self.errors as f32)].iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+use defmt::{info, Format};
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_time::{with_timeout, Duration};
+use embedded_hal::i2c::ErrorType;
+
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{describe, gauge, MetricDescription, MetricSink, MetricWriter};
+use crate::{climate_math, I2c0, Mutex, SensorError};
+
+const READ_TIMEOUT: Duration = Duration::from_millis(1000);
+
+// BMP280 I2C Addresses, selected by the SDO pin
+pub const BMP280_ADDR_SDO_LOW: u8 = 0x76;
+pub const BMP280_ADDR_SDO_HIGH: u8 = 0x77;
+
+// BMP280 Register Addresses
+const BMP280_REG_CALIB: u8 = 0x88;
+const BMP280_REG_CHIP_ID: u8 = 0xD0;
+const BMP280_REG_CTRL_MEAS: u8 = 0xF4;
+const BMP280_REG_CONFIG: u8 = 0xF5;
+const BMP280_REG_PRESS_MSB: u8 = 0xF7;
+
+const BMP280_CHIP_ID: u8 = 0x58;
+
+// CTRL_MEAS: osrs_t (bits 7-5), osrs_p (bits 4-2), mode (bits 1-0)
+const BMP280_OSRS_T_X4: u8 = 0b011 << 5;
+const BMP280_OSRS_P_X4: u8 = 0b011 << 2;
+const BMP280_MODE_NORMAL: u8 = 0b11;
+
+// CONFIG: t_sb (bits 7-5) standby 125ms, filter (bits 4-2) off
+const BMP280_STANDBY_125MS: u8 = 0b010 << 5;
+
+pub type Bmp280 = Bmp280Device<I2cDevice<'static, CriticalSectionRawMutex, I2c0>>;
+
+#[derive(Debug, Format)]
+pub enum Bmp280Error<E> {
+    I2c(E),
+    InvalidChipId(u8),
+}
+
+#[derive(Clone, Copy, Format)]
+pub struct Reading {
+    pub pressure_pa: f32,
+    pub temperature_c: f32,
+}
+
+/// Factory trimming parameters, datasheet table 17.
+#[derive(Default)]
+struct Calibration {
+    t1: u16,
+    t2: i16,
+    t3: i16,
+    p1: u16,
+    p2: i16,
+    p3: i16,
+    p4: i16,
+    p5: i16,
+    p6: i16,
+    p7: i16,
+    p8: i16,
+    p9: i16,
+}
+
+impl Calibration {
+    fn from_registers(raw: &[u8; 24]) -> Self {
+        let u = |i: usize| u16::from_le_bytes([raw[i], raw[i + 1]]);
+        let s = |i: usize| i16::from_le_bytes([raw[i], raw[i + 1]]);
+        Self {
+            t1: u(0),
+            t2: s(2),
+            t3: s(4),
+            p1: u(6),
+            p2: s(8),
+            p3: s(10),
+            p4: s(12),
+            p5: s(14),
+            p6: s(16),
+            p7: s(18),
+            p8: s(20),
+            p9: s(22),
+        }
+    }
+
+    /// Returns `t_fine`, which the pressure compensation also needs, and the temperature
+    /// in 0.01 °C.  Datasheet section 3.11.3.
+    fn compensate_temperature(&self, adc_t: i32) -> (i32, i32) {
+        let t1 = self.t1 as i32;
+        let var1 = (((adc_t >> 3) - (t1 << 1)) * self.t2 as i32) >> 11;
+        let var2 = (((((adc_t >> 4) - t1) * ((adc_t >> 4) - t1)) >> 12) * self.t3 as i32) >> 14;
+        let t_fine = var1 + var2;
+        (t_fine, (t_fine * 5 + 128) >> 8)
+    }
+
+    /// Pressure in Pa as unsigned Q24.8, using the 64 bit integer formula.
+    fn compensate_pressure(&self, adc_p: i32, t_fine: i32) -> u32 {
+        let mut var1 = t_fine as i64 - 128000;
+        let mut var2 = var1 * var1 * self.p6 as i64;
+        var2 += (var1 * self.p5 as i64) << 17;
+        var2 += (self.p4 as i64) << 35;
+        var1 = ((var1 * var1 * self.p3 as i64) >> 8) + ((var1 * self.p2 as i64) << 12);
+        var1 = (((1i64 << 47) + var1) * self.p1 as i64) >> 33;
+        if var1 == 0 {
+            // Avoid dividing by zero
+            return 0;
+        }
+
+        let mut p = 1048576 - adc_p as i64;
+        p = (((p << 31) - var2) * 3125) / var1;
+        let var1 = (self.p9 as i64 * (p >> 13) * (p >> 13)) >> 25;
+        let var2 = (self.p8 as i64 * p) >> 19;
+        (((p + var1 + var2) >> 8) + ((self.p7 as i64) << 4)) as u32
+    }
+}
+
+pub struct Bmp280Device<I> {
+    addr: u8,
+    i2c: I,
+    calibration: Calibration,
+}
+
+impl<I: embedded_hal_async::i2c::I2c> Bmp280Device<I> {
+    pub fn new(i2c: I, addr: u8) -> Self {
+        Self {
+            addr,
+            i2c,
+            calibration: Calibration::default(),
+        }
+    }
+
+    /// Check the chip id, load the trimming parameters and start normal mode with x4
+    /// oversampling on both pressure and temperature.
+    pub async fn init(&mut self) -> Result<(), Bmp280Error<<I as ErrorType>::Error>> {
+        let mut chip_id = [0u8; 1];
+        self.i2c
+            .write_read(self.addr, &[BMP280_REG_CHIP_ID], &mut chip_id)
+            .await
+            .map_err(Bmp280Error::I2c)?;
+        if chip_id[0] != BMP280_CHIP_ID {
+            return Err(Bmp280Error::InvalidChipId(chip_id[0]));
+        }
+
+        let mut calib = [0u8; 24];
+        self.i2c
+            .write_read(self.addr, &[BMP280_REG_CALIB], &mut calib)
+            .await
+            .map_err(Bmp280Error::I2c)?;
+        self.calibration = Calibration::from_registers(&calib);
+
+        self.i2c
+            .write(self.addr, &[BMP280_REG_CONFIG, BMP280_STANDBY_125MS])
+            .await
+            .map_err(Bmp280Error::I2c)?;
+        self.i2c
+            .write(
+                self.addr,
+                &[
+                    BMP280_REG_CTRL_MEAS,
+                    BMP280_OSRS_T_X4 | BMP280_OSRS_P_X4 | BMP280_MODE_NORMAL,
+                ],
+            )
+            .await
+            .map_err(Bmp280Error::I2c)?;
+
+        info!("bmp280: Initialized at {:x}", self.addr);
+        Ok(())
+    }
+
+    /// Read the latest result of the continuous normal mode measurement.
+    pub async fn read(&mut self) -> Result<Reading, Bmp280Error<<I as ErrorType>::Error>> {
+        // Burst read press_msb..temp_xlsb so both values come from the same measurement
+        let mut raw = [0u8; 6];
+        self.i2c
+            .write_read(self.addr, &[BMP280_REG_PRESS_MSB], &mut raw)
+            .await
+            .map_err(Bmp280Error::I2c)?;
+
+        let adc_p = ((raw[0] as i32) << 12) | ((raw[1] as i32) << 4) | ((raw[2] as i32) >> 4);
+        let adc_t = ((raw[3] as i32) << 12) | ((raw[4] as i32) << 4) | ((raw[5] as i32) >> 4);
+
+        let (t_fine, temperature) = self.calibration.compensate_temperature(adc_t);
+        let pressure = self.calibration.compensate_pressure(adc_p, t_fine);
+
+        Ok(Reading {
+            pressure_pa: pressure as f32 / 256.,
+            temperature_c: temperature as f32 / 100.,
+        })
+    }
+}
+
+/// The BMP280 as seen by the metrics endpoint.  It runs in normal mode, so polling just
+/// reads the latest measurement.
+pub struct Bmp280Sensor {
+    device: &'static Mutex<Bmp280>,
+    last: Option<Reading>,
+}
+
+impl Bmp280Sensor {
+    pub fn new(device: &'static Mutex<Bmp280>) -> Self {
+        Self { device, last: None }
+    }
+}
+
+impl crate::Sensor for Bmp280Sensor {
+    type Reading = Option<Reading>;
+
+    fn name(&self) -> &'static str {
+        "bmp280"
+    }
+
+    async fn poll(&mut self) -> Result<(), SensorError> {
+        self.last = None;
+        let reading = with_timeout(READ_TIMEOUT, async {
+            self.device.lock().await.read().await
+        })
+        .await??;
+        self.last = Some(reading);
+        Ok(())
+    }
+
+    fn reading(&self) -> Option<Reading> {
+        self.last
+    }
+}
+
+pub(crate) const BMP280_READING: MetricDescription =
+    describe("bmp280_reading", "Reading from BMP280 Sensor", "");
+
+impl crate::WriteMetrics for Reading {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        let reading = self;
+
+        writer
+            .write(
+                BMP280_READING.gauge(
+                    ["sensor"],
+                    [
+                        Sample::new(["pressure_pa"], reading.pressure_pa),
+                        Sample::new(["temperature_c"], reading.temperature_c),
+                    ]
+                    .iter(),
+                ),
+            )
+            .await?;
+
+        writer
+            .write(gauge(
+                "bmp280_derived",
+                "Values derived from the BMP280 pressure",
+                ["sensor"],
+                [Sample::new(
+                    ["altitude_m"],
+                    climate_math::altitude_m(reading.pressure_pa),
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+//! Quantities derived from temperature, relative humidity and pressure.
+
+/// Saturation vapour pressure over water in hPa (Magnus formula).
+fn saturation_vapor_pressure_hpa(temp_c: f32) -> f32 {
+    6.112 * libm::expf(17.67 * temp_c / (temp_c + 243.5))
+}
+
+/// Mass of water vapour per cubic metre of air.  Unlike relative humidity this can be
+/// compared between readings taken at different temperatures.
+pub fn absolute_humidity_g_m3(temp_c: f32, rh_percent: f32) -> f32 {
+    (saturation_vapor_pressure_hpa(temp_c) * rh_percent * 2.1674) / (273.15 + temp_c)
+}
+
+/// Vapour pressure deficit: how much more moisture the air could hold before saturating.
+pub fn vapor_pressure_deficit_kpa(temp_c: f32, rh_percent: f32) -> f32 {
+    let svp_kpa = 0.6108 * libm::expf(17.27 * temp_c / (temp_c + 237.3));
+    svp_kpa * (1. - rh_percent / 100.)
+}
+
+/// Altitude above sea level from the international barometric formula, assuming standard
+/// sea level pressure.  Weather moves this by tens of metres, so treat it as relative.
+pub fn altitude_m(pressure_pa: f32) -> f32 {
+    44330. * (1. - libm::powf(pressure_pa / 101325., 1. / 5.255))
+}
+
+/// How a temperature and humidity feel to someone indoors, see `comfort_index`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ComfortCategory {
+    Cold,
+    Cool,
+    Comfortable,
+    Warm,
+    Hot,
+    VeryHot,
+}
+
+impl ComfortCategory {
+    pub const ALL: [ComfortCategory; 6] = [
+        ComfortCategory::Cold,
+        ComfortCategory::Cool,
+        ComfortCategory::Comfortable,
+        ComfortCategory::Warm,
+        ComfortCategory::Hot,
+        ComfortCategory::VeryHot,
+    ];
+
+    pub fn label(self) -> &'static str {
+        match self {
+            ComfortCategory::Cold => "cold",
+            ComfortCategory::Cool => "cool",
+            ComfortCategory::Comfortable => "comfortable",
+            ComfortCategory::Warm => "warm",
+            ComfortCategory::Hot => "hot",
+            ComfortCategory::VeryHot => "very_hot",
+        }
+    }
+
+    /// 1 for `Cold` through 6 for `VeryHot`.
+    pub fn score(self) -> u8 {
+        self as u8 + 1
+    }
+}
+
+/// Temperature each category ends below, at moderate humidity.  ASHRAE 55 puts
+/// the comfort zone at about 20 to 24 °C for indoor clothing and light activity, the
+/// other bands are a step of a few degrees either side.
+const COMFORT_BANDS: [(f32, ComfortCategory); 5] = [
+    (16., ComfortCategory::Cold),
+    (20., ComfortCategory::Cool),
+    (24., ComfortCategory::Comfortable),
+    (27., ComfortCategory::Warm),
+    (32., ComfortCategory::Hot),
+];
+/// ASHRAE 55 limits the comfort zone to a humidity ratio of 0.012, about 65 %RH at 24 °C.
+const COMFORT_MAX_RH: f32 = 65.;
+
+/// Comfort category of a temperature and relative humidity.  Above the comfort zone's
+/// humidity limit, warm air feels about 1 °C warmer for every 10 %RH.
+pub fn comfort_index(temp_c: f32, humidity_rh: f32) -> ComfortCategory {
+    let felt_c = if temp_c >= 20. && humidity_rh > COMFORT_MAX_RH {
+        temp_c + (humidity_rh - COMFORT_MAX_RH) / 10.
+    } else {
+        temp_c
+    };
+    COMFORT_BANDS
+        .iter()
+        .find(|&&(upper_c, _)| felt_c < upper_c)
+        .map_or(ComfortCategory::VeryHot, |&(_, category)| category)
+}
@@ -0,0 +1,42 @@
+//! Settings fixed at build time that aren't worth an environment variable.
+
+use crate::http::security_headers::SecurityHeaders;
+
+/// 2.4 GHz channels that get `wifi_signal_strength` histograms.  Each channel costs three
+/// histograms of RAM and series, so only the non-overlapping channels are tracked by
+/// default.  Scan results on other channels are dropped.
+pub const WIFI_CHANNEL_COUNT: usize = 3;
+pub const WIFI_CHANNELS: [u8; WIFI_CHANNEL_COUNT] = [1, 6, 11];
+
+const _: () = {
+    let mut i = 0;
+    while i < WIFI_CHANNEL_COUNT {
+        assert!(
+            WIFI_CHANNELS[i] >= 1 && WIFI_CHANNELS[i] <= 14,
+            "WIFI_CHANNELS must be 2.4 GHz channels 1 to 14"
+        );
+        i += 1;
+    }
+};
+
+/// `web_task`s spawned, which is also how many HTTP connections can be served at once.
+/// Each task keeps its buffers on its own stack for good, so the HTTP buffers take
+/// `HTTP_TASK_COUNT * (HTTP_RX_BUFFER_SIZE + HTTP_TX_BUFFER_SIZE + HTTP_BUFFER_SIZE +
+/// prometheus::CHUNK_SIZE)` bytes of RAM, the last for `/metrics` responses, about
+/// 30 KiB with the defaults.  At most 16.
+pub const HTTP_TASK_COUNT: usize = 4;
+/// TCP receive buffer per connection.
+pub const HTTP_RX_BUFFER_SIZE: usize = 1024;
+/// TCP send buffer per connection.  `/metrics` streams, so this only limits throughput.
+pub const HTTP_TX_BUFFER_SIZE: usize = 4096;
+/// Request line and headers, plus whatever part of the body arrives with them.
+pub const HTTP_BUFFER_SIZE: usize = 1024;
+
+const _: () = assert!(
+    HTTP_TASK_COUNT >= 1 && HTTP_TASK_COUNT <= 16,
+    "HTTP_TASK_COUNT must be 1 to 16"
+);
+
+/// Hardening headers added to every HTTP response.  `SecurityHeaders::NONE` saves the
+/// ~100 bytes each.
+pub static SECURITY_HEADERS: SecurityHeaders = SecurityHeaders::DEFAULT;
@@ -0,0 +1,251 @@
+//! `/metrics` as CSV, for pasting into a spreadsheet rather than scraping.
+//!
+//! The metric families only know how to write exposition lines, so `CsvSink` sits
+//! between them and the response and rewrites each finished line as a row:
+//!
+//! ```text
+//! timestamp,metric_name,label1_name,label1_value,...,value
+//! 2024-01-15T10:30:00.123Z,sht30_reading,sensor,temperature,21.5
+//! ```
+//!
+//! Comment lines are dropped.  Rows have as many label columns as the metric has labels,
+//! the header is sized for the first row.  Lines too long to buffer, or that don't parse,
+//! are dropped too and counted in `DROPPED_LINES`.
+
+use defmt::warn;
+use embassy_time::Instant;
+use heapless::{String, Vec};
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::prometheus::{MetricSink, METRIC_PREFIX};
+use crate::remote_write;
+
+/// Longest exposition line kept, longer ones are dropped.
+const MAX_LINE_LEN: usize = 256;
+const MAX_LABELS: usize = 8;
+
+/// `(name, value)` pairs, value still escaped.
+type Labels<'a> = Vec<(&'a str, &'a str), MAX_LABELS>;
+
+/// Exposition lines left out of CSV responses for being over `MAX_LINE_LEN` or not
+/// parsing, shown as `csv_dropped_lines_total`.
+pub static DROPPED_LINES: AtomicU32 = AtomicU32::new(0);
+
+/// Write one row, from label values escaped as in the exposition format.  `ts` is Unix
+/// milliseconds, or `None` to use the time since boot as an ISO 8601 duration, e.g.
+/// `PT3600.250S`, when the device doesn't know the time.
+pub async fn write_csv_row<W: MetricSink>(
+    writer: &mut W,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: f32,
+    ts: Option<u64>,
+) -> Result<(), W::Error> {
+    match ts {
+        Some(unix_ms) => {
+            let seconds = unix_ms / 1000;
+            let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+            let time = seconds % 86400;
+            write!(
+                writer,
+                "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z,",
+                year,
+                month,
+                day,
+                time / 3600,
+                time % 3600 / 60,
+                time % 60,
+                unix_ms % 1000
+            )
+            .await?;
+        }
+        None => {
+            let uptime_ms = Instant::now().as_millis();
+            write!(writer, "PT{}.{:03}S,", uptime_ms / 1000, uptime_ms % 1000).await?;
+        }
+    }
+
+    write_field(writer, name).await?;
+    for (label_name, label_value) in labels {
+        write!(writer, ",").await?;
+        write_field(writer, label_name).await?;
+        write!(writer, ",").await?;
+        if label_value.contains('\\') {
+            write_field(writer, &unescape(label_value)).await?;
+        } else {
+            write_field(writer, label_value).await?;
+        }
+    }
+    writeln!(writer, ",{}", value).await
+}
+
+/// A field, quoted if it holds anything CSV treats specially.
+async fn write_field<W: MetricSink>(writer: &mut W, field: &str) -> Result<(), W::Error> {
+    if !field.contains([',', '"', '\n', '\r']) {
+        return write!(writer, "{}", field).await;
+    }
+    write!(writer, "\"").await?;
+    for (i, part) in field.split('"').enumerate() {
+        if i > 0 {
+            write!(writer, "\"\"").await?;
+        }
+        write!(writer, "{}", part).await?;
+    }
+    write!(writer, "\"").await
+}
+
+/// Year, month and day of `days` since 1970-01-01, the inverse of
+/// `remote_write::days_from_civil`.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let day_of_era = z - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = year_of_era + era * 400 + (month <= 2) as i64;
+    (year, month, day)
+}
+
+/// The index of the quote closing a label value, skipping escaped ones.
+fn closing_quote(value: &str) -> Option<usize> {
+    let mut escaped = false;
+    for (i, b) in value.bytes().enumerate() {
+        match b {
+            _ if escaped => escaped = false,
+            b'\\' => escaped = true,
+            b'"' => return Some(i),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// A label value with the exposition format's `\\`, `\"` and `\n` escapes undone.
+fn unescape(value: &str) -> String<MAX_LINE_LEN> {
+    let mut unescaped = String::new();
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let c = match c {
+            '\\' => match chars.next() {
+                Some('n') => '\n',
+                Some(c) => c,
+                None => break,
+            },
+            c => c,
+        };
+        // Can't fail, unescaping only makes the value shorter than its line
+        let _ = unescaped.push(c);
+    }
+    unescaped
+}
+
+/// Splits an exposition line, `name{a="b",c="d"} value`, into its parts.  Label values
+/// are left escaped.  `None` for anything else.
+fn parse_line(line: &str) -> Option<(&str, Labels<'_>, f32)> {
+    let (series, value) = line.rsplit_once(' ')?;
+    let value = value.parse().ok()?;
+    let (name, labels) = match series.split_once('{') {
+        Some((name, labels)) => (name, labels.strip_suffix('}')?),
+        None => (series, ""),
+    };
+
+    let mut parsed = Vec::new();
+    let mut rest = labels;
+    while !rest.is_empty() {
+        let (label_name, after) = rest.split_once("=\"")?;
+        let end = closing_quote(after)?;
+        let (label_value, after) = (&after[..end], &after[end + 1..]);
+        parsed.push((label_name, label_value)).ok()?;
+        rest = after.strip_prefix(',').unwrap_or(after);
+    }
+    Some((name, parsed, value))
+}
+
+/// A `MetricSink` that turns the exposition lines written to it into CSV rows on
+/// `inner`.
+pub struct CsvSink<'a, W: MetricSink> {
+    inner: &'a mut W,
+    /// The line being written, until its newline arrives.
+    line: String<MAX_LINE_LEN>,
+    /// The line is a comment or didn't fit, so nothing more of it is kept.
+    skipping: bool,
+    /// Lines finished by the last `write_fmt`, waiting to be written to `inner`.
+    finished: Vec<String<MAX_LINE_LEN>, 2>,
+    header_written: bool,
+    ts: Option<u64>,
+}
+
+impl<'a, W: MetricSink> CsvSink<'a, W> {
+    /// Rows are all stamped with the time now, from `remote_write` if it has learned it.
+    pub fn new(inner: &'a mut W) -> Self {
+        Self {
+            inner,
+            line: String::new(),
+            skipping: false,
+            finished: Vec::new(),
+            header_written: false,
+            ts: remote_write::unix_time_ms(),
+        }
+    }
+
+    async fn write_row(&mut self, line: &str) -> Result<(), W::Error> {
+        let Some((name, labels, value)) = parse_line(line) else {
+            warn!("csv: Dropped a line that didn't parse");
+            DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+            return Ok(());
+        };
+        if !self.header_written {
+            self.header_written = true;
+            write!(self.inner, "timestamp,metric_name").await?;
+            for i in 1..=labels.len() {
+                write!(self.inner, ",label{}_name,label{}_value", i, i).await?;
+            }
+            writeln!(self.inner, ",value").await?;
+        }
+        let name = name.strip_prefix(METRIC_PREFIX).unwrap_or(name);
+        write_csv_row(self.inner, name, &labels, value, self.ts).await
+    }
+}
+
+impl<W: MetricSink> core::fmt::Write for CsvSink<'_, W> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if c == '\n' {
+                if !self.skipping && !self.line.is_empty() {
+                    // More than two lines in one write only happens to comments
+                    let _ = self.finished.push(self.line.clone());
+                }
+                self.line.clear();
+                self.skipping = false;
+            } else if !self.skipping {
+                if self.line.is_empty() && c == '#' {
+                    self.skipping = true;
+                } else if self.line.push(c).is_err() {
+                    warn!("csv: Dropped a line over {} bytes", MAX_LINE_LEN);
+                    DROPPED_LINES.fetch_add(1, Ordering::Relaxed);
+                    self.skipping = true;
+                    self.line.clear();
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl<W: MetricSink> MetricSink for CsvSink<'_, W> {
+    type Error = W::Error;
+
+    async fn write_fmt(&mut self, args: core::fmt::Arguments<'_>) -> Result<(), W::Error> {
+        // Can't fail, overlong lines are counted and skipped rather than returned as errors
+        let _ = core::fmt::Write::write_fmt(self, args);
+        let finished = core::mem::take(&mut self.finished);
+        for line in &finished {
+            self.write_row(line).await?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,217 @@
+//! DHT22 (AM2302) temperature and humidity sensor on a PIO state machine.
+//!
+//! The single-wire protocol is timed by the PIO at 1 µs per cycle, so reading the 40 bits
+//! doesn't tie up the CPU.
+
+use defmt::{debug, Format};
+use embassy_rp::clocks::clk_sys_freq;
+use embassy_rp::gpio::{Level, Pull};
+use embassy_rp::peripherals::PIO1;
+use embassy_rp::pio::program::pio_asm;
+use embassy_rp::pio::{Common, Config, Direction, Instance, PioPin, ShiftDirection, StateMachine};
+use embassy_rp::Peri;
+use embassy_time::{with_timeout, Duration, Instant};
+use fixed::types::U24F8;
+
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{describe, MetricDescription, MetricSink, MetricWriter};
+use crate::{Mutex, SensorError};
+
+/// A whole read takes about 5 ms, anything longer means no sensor answered.
+const READ_TIMEOUT: Duration = Duration::from_millis(20);
+/// The sensor needs 2 seconds between measurements.
+const MIN_READ_INTERVAL: Duration = Duration::from_secs(2);
+/// Host start signal, in 32 µs loops of the PIO program: just over 1 ms low.
+const START_LOOPS: u32 = 32;
+
+pub type Dht22 = Dht22Device<'static, PIO1, 0>;
+
+#[derive(Debug, Format)]
+pub enum Dht22Error {
+    Timeout,
+    Checksum,
+}
+
+#[derive(Clone, Copy, Format)]
+pub struct Dht22Reading {
+    pub temperature_c: f32,
+    pub humidity_rh: f32,
+}
+
+impl Dht22Reading {
+    /// Decode the 5 bytes sent by the sensor: humidity and temperature in tenths, the
+    /// temperature in sign and magnitude, then a checksum of the first 4 bytes.
+    fn from_bytes(data: &[u8; 5]) -> Result<Self, Dht22Error> {
+        let checksum = data[..4].iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if checksum != data[4] {
+            return Err(Dht22Error::Checksum);
+        }
+
+        let humidity = u16::from_be_bytes([data[0], data[1]]);
+        let temperature = u16::from_be_bytes([data[2] & 0x7F, data[3]]) as f32 / 10.;
+        Ok(Dht22Reading {
+            temperature_c: if data[2] & 0x80 != 0 {
+                -temperature
+            } else {
+                temperature
+            },
+            humidity_rh: humidity as f32 / 10.,
+        })
+    }
+}
+
+pub struct Dht22Device<'d, P: Instance, const SM: usize> {
+    sm: StateMachine<'d, P, SM>,
+    start: u8,
+}
+
+impl<'d, P: Instance, const SM: usize> Dht22Device<'d, P, SM> {
+    pub fn new(
+        common: &mut Common<'d, P>,
+        mut sm: StateMachine<'d, P, SM>,
+        pin: Peri<'d, impl PioPin>,
+    ) -> Self {
+        let program = pio_asm!(
+            // Start signal: drive the line low for OSR * 32 µs, then release it
+            "pull block"
+            "set pindirs, 1"
+            "mov x, osr"
+            "start:"
+            "jmp x-- start [31]"
+            "set pindirs, 0"
+            // Response: wait for the pull-up, then 80 µs low and 80 µs high
+            "wait 1 pin 0"
+            "wait 0 pin 0"
+            "wait 1 pin 0"
+            "wait 0 pin 0"
+            // Each bit is 50 µs low then 26-28 µs high for 0, 70 µs high for 1.
+            // Sample 48 µs after the rising edge.
+            ".wrap_target"
+            "wait 1 pin 0 [31]"
+            "nop [15]"
+            "in pins, 1"
+            "wait 0 pin 0"
+            ".wrap"
+        );
+        let loaded = common.load_program(&program.program);
+
+        let mut pin = common.make_pio_pin(pin);
+        // Most breakout boards have their own pull-up, this covers a bare sensor
+        pin.set_pull(Pull::Up);
+
+        let mut cfg = Config::default();
+        cfg.use_program(&loaded, &[]);
+        cfg.set_set_pins(&[&pin]);
+        cfg.set_in_pins(&[&pin]);
+        // One byte per RX word, most significant bit first
+        cfg.shift_in.direction = ShiftDirection::Left;
+        cfg.shift_in.auto_fill = true;
+        cfg.shift_in.threshold = 8;
+        cfg.clock_divider = U24F8::from_num(clk_sys_freq() / 1_000_000);
+        sm.set_config(&cfg);
+
+        // `set pindirs` switches between driving low and letting the line float
+        sm.set_pins(Level::Low, &[&pin]);
+        sm.set_pin_dirs(Direction::In, &[&pin]);
+
+        Self {
+            sm,
+            start: loaded.origin,
+        }
+    }
+
+    /// Run one measurement.  Calls less than 2 seconds apart return stale data or fail.
+    pub async fn read(&mut self) -> Result<Dht22Reading, Dht22Error> {
+        let mut data = [0u8; 5];
+        let result = with_timeout(READ_TIMEOUT, async {
+            self.sm.set_enable(false);
+            self.sm.clear_fifos();
+            self.sm.restart();
+            unsafe {
+                self.sm.exec_jmp(self.start);
+            }
+            self.sm.set_enable(true);
+
+            self.sm.tx().wait_push(START_LOOPS).await;
+            for byte in data.iter_mut() {
+                *byte = self.sm.rx().wait_pull().await as u8;
+            }
+        })
+        .await;
+
+        // The program waits forever for a 41st bit, stop it until the next read
+        self.sm.set_enable(false);
+        result.map_err(|_| Dht22Error::Timeout)?;
+        Dht22Reading::from_bytes(&data)
+    }
+}
+
+/// The DHT22 as seen by the metrics endpoint.  Scrapes closer together than the sensor
+/// allows reuse the previous reading.
+pub struct Dht22Sensor {
+    device: &'static Mutex<Dht22>,
+    last: Option<Dht22Reading>,
+    last_read: Option<Instant>,
+}
+
+impl Dht22Sensor {
+    pub fn new(device: &'static Mutex<Dht22>) -> Self {
+        Self {
+            device,
+            last: None,
+            last_read: None,
+        }
+    }
+}
+
+impl crate::Sensor for Dht22Sensor {
+    type Reading = Option<Dht22Reading>;
+
+    fn name(&self) -> &'static str {
+        "dht22"
+    }
+
+    async fn poll(&mut self) -> Result<(), SensorError> {
+        if self
+            .last_read
+            .is_some_and(|at| at.elapsed() < MIN_READ_INTERVAL)
+        {
+            return Ok(());
+        }
+
+        self.last_read = Some(Instant::now());
+        self.last = None;
+        let reading = self.device.lock().await.read().await?;
+        debug!("dht22: {}", reading);
+        self.last = Some(reading);
+        Ok(())
+    }
+
+    fn reading(&self) -> Option<Dht22Reading> {
+        self.last
+    }
+}
+
+pub(crate) const DHT22_READING: MetricDescription =
+    describe("dht22_reading", "Reading from DHT22 Sensor", "");
+
+impl crate::WriteMetrics for Dht22Reading {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        let reading = self;
+
+        writer
+            .write(
+                DHT22_READING.gauge(
+                    ["sensor"],
+                    [
+                        Sample::new(["temperature"], reading.temperature_c),
+                        Sample::new(["humidity"], reading.humidity_rh),
+                    ]
+                    .iter(),
+                ),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
@@ -0,0 +1,65 @@
+//! Sensor readings broadcast from the reading tasks to whoever wants them.
+//!
+//! The reading tasks publish without waiting, so a subscriber that falls more than
+//! `CAPACITY` readings behind loses the oldest ones rather than holding up the sensors.
+//! Lost readings are counted in `LAGGED_EVENTS`.
+
+use defmt::warn;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::pubsub::{PubSubChannel, Subscriber, WaitResult};
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::{adc_temp_sensor, ina237, sht30};
+
+const CAPACITY: usize = 4;
+/// The SHT30 and INA237 state recorders, `temperature_alert_task`,
+/// `battery::record_task` and `alarm::voltage_alarm_task`, plus one spare.
+const SUBSCRIBERS: usize = 6;
+/// Unused, `publish` doesn't take a publisher slot.
+const PUBLISHERS: usize = 1;
+
+#[derive(Clone)]
+pub enum SensorEvent {
+    Sht30(sht30::Reading),
+    Ina237(ina237::TickOutput),
+    Adc(adc_temp_sensor::Value),
+}
+
+pub type EventSubscriber =
+    Subscriber<'static, CriticalSectionRawMutex, SensorEvent, CAPACITY, SUBSCRIBERS, PUBLISHERS>;
+
+pub static SENSOR_EVENTS: PubSubChannel<
+    CriticalSectionRawMutex,
+    SensorEvent,
+    CAPACITY,
+    SUBSCRIBERS,
+    PUBLISHERS,
+> = PubSubChannel::new();
+
+/// Readings dropped because a subscriber fell behind.
+pub static LAGGED_EVENTS: AtomicU32 = AtomicU32::new(0);
+
+/// Send a reading to every subscriber without waiting for any of them.
+pub fn publish(event: SensorEvent) {
+    SENSOR_EVENTS.immediate_publisher().publish_immediate(event);
+}
+
+/// Panics if there are more subscribers than `SUBSCRIBERS`, which is a bug.
+pub fn subscribe() -> EventSubscriber {
+    SENSOR_EVENTS
+        .subscriber()
+        .expect("more sensor event subscribers than SUBSCRIBERS")
+}
+
+/// Wait for the next reading, counting any that were missed.
+pub async fn next(subscriber: &mut EventSubscriber) -> SensorEvent {
+    loop {
+        match subscriber.next_message().await {
+            WaitResult::Message(event) => return event,
+            WaitResult::Lagged(missed) => {
+                warn!("events: Subscriber missed {} readings", missed);
+                LAGGED_EVENTS.fetch_add(missed as u32, Ordering::Relaxed);
+            }
+        }
+    }
+}
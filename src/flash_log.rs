@@ -0,0 +1,69 @@
+//! A value saved every few minutes, appended to a flash sector rather than stored as a
+//! `FlashStore` record, which would erase the sector on every save.
+//!
+//! Each save appends an entry of the value and its bitwise complement, as little endian
+//! `u32`s, so an entry torn by a reset is told apart from a good one.  Erased flash reads
+//! as `0xFF`, which marks the end of the log.  When the sector is full it is erased and
+//! the value written as its first entry, so the sector is erased once every `ENTRIES`
+//! saves rather than on every one.
+
+use embassy_rp::flash::{Error, ERASE_SIZE, PAGE_SIZE};
+
+use crate::flash_store::{FlashStore, Slot};
+
+const ENTRY_LEN: usize = 8;
+/// Entries that fit in one sector.
+const ENTRIES: usize = ERASE_SIZE / ENTRY_LEN;
+const ERASED: [u8; ENTRY_LEN] = [0xFF; ENTRY_LEN];
+
+/// The value in a good entry, `None` for a torn one.
+fn decode(entry: &[u8]) -> Option<u32> {
+    let value = u32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]);
+    let complement = u32::from_le_bytes([entry[4], entry[5], entry[6], entry[7]]);
+    (complement == !value).then_some(value)
+}
+
+/// The newest value in the log, and the number of entries before its end.
+async fn scan(store: &FlashStore, slot: Slot) -> Result<(Option<u32>, usize), Error> {
+    let mut page = [0u8; PAGE_SIZE];
+    let mut latest = None;
+    let mut written = 0;
+    for offset in (0..ERASE_SIZE).step_by(PAGE_SIZE) {
+        store.read_raw(slot, offset, &mut page).await?;
+        for entry in page.chunks_exact(ENTRY_LEN) {
+            if entry == ERASED {
+                return Ok((latest, written));
+            }
+            latest = decode(entry).or(latest);
+            written += 1;
+        }
+    }
+    Ok((latest, written))
+}
+
+/// The value last appended to `slot`.  Until the first append a slot that held a
+/// `FlashStore` record of a `u32`, from before it was a log, is read as that record.
+pub async fn load(store: &FlashStore, slot: Slot) -> Option<u32> {
+    if let Ok((Some(value), _)) = scan(store, slot).await {
+        return Some(value);
+    }
+    let mut buf = [0u8; 4];
+    match store.load(slot, &mut buf).await {
+        Some(4) => Some(u32::from_le_bytes(buf)),
+        _ => None,
+    }
+}
+
+/// Append `value` to the log in `slot`, erasing the sector first if it is full.
+pub async fn append(store: &FlashStore, slot: Slot, value: u32) -> Result<(), Error> {
+    let (_, mut written) = scan(store, slot).await?;
+    if written == ENTRIES {
+        store.erase(slot).await?;
+        written = 0;
+    }
+
+    let mut entry = [0u8; ENTRY_LEN];
+    entry[..4].copy_from_slice(&value.to_le_bytes());
+    entry[4..].copy_from_slice(&(!value).to_le_bytes());
+    store.write_raw(slot, written * ENTRY_LEN, &entry).await
+}
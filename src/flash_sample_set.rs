@@ -0,0 +1,114 @@
+//! A `SampleSet` that survives reboots by logging every sample to a flash sector.
+//!
+//! Samples are appended to the sector as little endian `f32`s.  Erased flash reads as
+//! `0xFFFFFFFF`, which marks the end of the log.  When the sector is full it is erased and
+//! the samples still in the set are written back, so the sector is erased once every
+//! `ENTRIES - N` samples rather than on every write.
+
+use defmt::{error, info};
+use embassy_rp::flash::{Error, ERASE_SIZE, PAGE_SIZE};
+
+use crate::flash_store::{FlashStore, Slot};
+use crate::SampleSet;
+
+const ENTRY_LEN: usize = 4;
+/// Samples that fit in one sector.
+const ENTRIES: usize = ERASE_SIZE / ENTRY_LEN;
+const ERASED: [u8; ENTRY_LEN] = [0xFF; ENTRY_LEN];
+
+pub struct PersistentSampleSet<const N: usize> {
+    samples: SampleSet<N>,
+    slot: Slot,
+    /// Entries used in the sector, the next sample is written after them.
+    written: usize,
+}
+
+impl<const N: usize> PersistentSampleSet<N> {
+    /// An empty set, call `load` once the flash is available.
+    pub const fn new(slot: Slot) -> Self {
+        // Leave room to append after rewriting the set into a fresh sector
+        assert!(N < ENTRIES);
+        Self {
+            samples: SampleSet::new(),
+            slot,
+            written: 0,
+        }
+    }
+
+    /// Replay the log in flash, keeping the newest `N` samples.
+    pub async fn load(&mut self, store: &FlashStore) {
+        let mut page = [0u8; PAGE_SIZE];
+        'pages: for offset in (0..ERASE_SIZE).step_by(PAGE_SIZE) {
+            if let Err(e) = store.read_raw(self.slot, offset, &mut page).await {
+                error!("flash_sample_set: Error reading log: {:?}", e);
+                break;
+            }
+            for entry in page.chunks_exact(ENTRY_LEN) {
+                if entry == ERASED {
+                    break 'pages;
+                }
+                self.samples
+                    .record(f32::from_le_bytes([entry[0], entry[1], entry[2], entry[3]]));
+                self.written += 1;
+            }
+        }
+        info!("flash_sample_set: Loaded {} samples", self.samples.len());
+    }
+
+    /// Add a sample and append it to the log.  NaN can't be told apart from erased flash,
+    /// so it is dropped.
+    pub async fn record(&mut self, store: &FlashStore, sample: f32) -> Result<(), Error> {
+        if sample.is_nan() {
+            return Ok(());
+        }
+        self.samples.record(sample);
+
+        if self.written < ENTRIES {
+            store
+                .write_raw(self.slot, self.written * ENTRY_LEN, &sample.to_le_bytes())
+                .await?;
+            self.written += 1;
+            return Ok(());
+        }
+
+        // A reset before the rewrite finishes loses the older samples, not the log
+        store.erase(self.slot).await?;
+        self.written = 0;
+        let mut page = [0xFFu8; PAGE_SIZE];
+        let mut len = 0;
+        for value in self.samples.oldest_first() {
+            page[len..len + ENTRY_LEN].copy_from_slice(&value.to_le_bytes());
+            len += ENTRY_LEN;
+            if len == PAGE_SIZE {
+                store
+                    .write_raw(self.slot, self.written * ENTRY_LEN, &page)
+                    .await?;
+                self.written += len / ENTRY_LEN;
+                len = 0;
+            }
+        }
+        if len > 0 {
+            store
+                .write_raw(self.slot, self.written * ENTRY_LEN, &page[..len])
+                .await?;
+            self.written += len / ENTRY_LEN;
+        }
+        Ok(())
+    }
+
+    pub fn median(&self) -> f32 {
+        self.samples.median()
+    }
+
+    pub fn percentile(&self, p: f32) -> f32 {
+        self.samples.percentile(p)
+    }
+
+    pub fn len(&self) -> usize {
+        self.samples.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.samples.is_empty()
+    }
+}
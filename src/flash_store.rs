@@ -0,0 +1,129 @@
+use embassy_rp::flash::{Async, Error, Flash, ERASE_SIZE, PAGE_SIZE};
+use embassy_rp::peripherals::FLASH;
+
+use crate::{Mutex, FLASH_SIZE};
+
+pub type FlashDevice = Flash<'static, FLASH, Async, FLASH_SIZE>;
+
+/// Space at the end of flash reserved for persisted data.  `memory.x` keeps the firmware
+/// out of this region.
+pub const STORE_SIZE: usize = 64 * 1024;
+const STORE_START: u32 = (FLASH_SIZE - STORE_SIZE) as u32;
+
+const MAGIC: [u8; 4] = *b"PCS1";
+const HEADER_LEN: usize = MAGIC.len() + 2;
+
+/// Largest record that fits in the single page written by `store`.
+pub const MAX_RECORD_LEN: usize = PAGE_SIZE - HEADER_LEN;
+
+/// Each slot is one erase sector within the store.
+#[derive(Clone, Copy)]
+pub enum Slot {
+    Ina237Calibration = 0,
+    /// Log written by `flash_log`, not a record.
+    Ina237Energy = 1,
+    PanicInfo = 2,
+    /// Raw log written by `PersistentSampleSet`, not a record.
+    Sht30TemperatureLog = 3,
+    /// Log written by `flash_log`, not a record.
+    BatteryCharge = 4,
+    Sht30Calibration = 5,
+}
+
+impl Slot {
+    pub const ALL: [Slot; 6] = [
+        Slot::Ina237Calibration,
+        Slot::Ina237Energy,
+        Slot::PanicInfo,
+        Slot::Sht30TemperatureLog,
+        Slot::BatteryCharge,
+        Slot::Sht30Calibration,
+    ];
+
+    fn offset(self) -> u32 {
+        STORE_START + (self as u32) * ERASE_SIZE as u32
+    }
+}
+
+/// Small records kept in flash across reboots, one record per `Slot`.
+///
+/// A record is a magic number and length followed by the data.  Erased flash reads as
+/// `0xFF`, so an empty slot fails the magic check and loads as `None`.
+pub struct FlashStore {
+    flash: Mutex<FlashDevice>,
+}
+
+impl FlashStore {
+    pub fn new(flash: FlashDevice) -> Self {
+        Self {
+            flash: Mutex::new(flash),
+        }
+    }
+
+    /// Copy the record in `slot` into `buf`, returning its length.
+    pub async fn load(&self, slot: Slot, buf: &mut [u8]) -> Option<usize> {
+        let mut page = [0u8; PAGE_SIZE];
+        self.flash
+            .lock()
+            .await
+            .blocking_read(slot.offset(), &mut page)
+            .ok()?;
+
+        if page[..MAGIC.len()] != MAGIC {
+            return None;
+        }
+        let len = u16::from_le_bytes([page[4], page[5]]) as usize;
+        if len > MAX_RECORD_LEN || len > buf.len() {
+            return None;
+        }
+
+        buf[..len].copy_from_slice(&page[HEADER_LEN..HEADER_LEN + len]);
+        Some(len)
+    }
+
+    /// Replace the record in `slot`.  Panics if `data` is longer than `MAX_RECORD_LEN`.
+    pub async fn store(&self, slot: Slot, data: &[u8]) -> Result<(), Error> {
+        let mut page = [0xFFu8; PAGE_SIZE];
+        page[..MAGIC.len()].copy_from_slice(&MAGIC);
+        page[4..HEADER_LEN].copy_from_slice(&(data.len() as u16).to_le_bytes());
+        page[HEADER_LEN..HEADER_LEN + data.len()].copy_from_slice(data);
+
+        let mut flash = self.flash.lock().await;
+        flash.blocking_erase(slot.offset(), slot.offset() + ERASE_SIZE as u32)?;
+        flash.blocking_write(slot.offset(), &page)
+    }
+
+    /// Read `buf.len()` bytes at `offset` into a slot that isn't used for records.
+    pub async fn read_raw(&self, slot: Slot, offset: usize, buf: &mut [u8]) -> Result<(), Error> {
+        assert!(offset + buf.len() <= ERASE_SIZE);
+        self.flash
+            .lock()
+            .await
+            .blocking_read(slot.offset() + offset as u32, buf)
+    }
+
+    /// Program `data` at `offset` without erasing.  Flash bits can only be cleared, so the
+    /// bytes must still be erased.
+    pub async fn write_raw(&self, slot: Slot, offset: usize, data: &[u8]) -> Result<(), Error> {
+        assert!(offset + data.len() <= ERASE_SIZE);
+        self.flash
+            .lock()
+            .await
+            .blocking_write(slot.offset() + offset as u32, data)
+    }
+
+    pub async fn erase(&self, slot: Slot) -> Result<(), Error> {
+        self.flash
+            .lock()
+            .await
+            .blocking_erase(slot.offset(), slot.offset() + ERASE_SIZE as u32)
+    }
+
+    /// Erase every slot, losing everything persisted.
+    pub async fn erase_all(&self) -> Result<(), Error> {
+        for slot in Slot::ALL {
+            self.erase(slot).await?;
+        }
+        Ok(())
+    }
+}
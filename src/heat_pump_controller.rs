@@ -0,0 +1,202 @@
+//! Drive an external heat pump from the SHT30 temperature with a PWM signal.
+//!
+//! The duty cycle is 100% for full heating and 0% for full cooling.  Within `hysteresis`
+//! of the setpoint it moves linearly between the two, so 50% is the setpoint itself.
+//!
+//! Without a temperature for `STALE_AFTER`, from a failed sensor or a long
+//! dehumidification, the signal drops to 0% and the mode to idle rather than holding a
+//! duty cycle from an old reading.
+
+use defmt::{info, Format};
+use embassy_rp::pwm::{Config, Pwm};
+use embassy_time::Duration;
+use serde::Serialize;
+
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{gauge, MetricSink, MetricWriter};
+use crate::Mutex;
+
+/// PWM counter wrap, 125 MHz / 10000 is a 12.5 kHz signal with 0.01% steps.
+const PWM_TOP: u16 = 9_999;
+pub const DEFAULT_HYSTERESIS_C: f32 = 1.0;
+/// Setpoints accepted from `POST /controller/setpoint`.
+pub const SETPOINT_RANGE: core::ops::RangeInclusive<f32> = 5.0..=35.0;
+/// How long without a usable SHT30 temperature before the controller stops driving.
+pub const STALE_AFTER: Duration = Duration::from_secs(60);
+
+/// Set by main when `HEAT_PUMP_SETPOINT` is configured.
+pub static CONTROLLER: Mutex<Option<Controller>> = Mutex::new(None);
+
+#[derive(Clone, Copy, PartialEq, Eq, Format, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Below the hysteresis band, full heat.
+    Heating,
+    /// Above the hysteresis band, full cooling.
+    Cooling,
+    /// Within the band, before the first reading, or without a recent one.
+    Idle,
+}
+
+impl Mode {
+    const ALL: [Mode; 3] = [Mode::Heating, Mode::Cooling, Mode::Idle];
+
+    fn label(self) -> &'static str {
+        match self {
+            Mode::Heating => "heating",
+            Mode::Cooling => "cooling",
+            Mode::Idle => "idle",
+        }
+    }
+}
+
+pub struct Controller {
+    setpoint_celsius: f32,
+    hysteresis: f32,
+    pwm: Pwm<'static>,
+    duty_percent: f32,
+    mode: Mode,
+    last_temperature: Option<f32>,
+}
+
+#[derive(Clone, Copy, Serialize)]
+pub struct Status {
+    pub setpoint_celsius: f32,
+    pub hysteresis_celsius: f32,
+    pub duty_percent: f32,
+    pub mode: Mode,
+    pub temperature_celsius: Option<f32>,
+}
+
+impl Controller {
+    /// Starts idle at 50% until the first temperature arrives.
+    pub fn new(pwm: Pwm<'static>, setpoint_celsius: f32, hysteresis: f32) -> Self {
+        let mut controller = Self {
+            setpoint_celsius,
+            hysteresis,
+            pwm,
+            duty_percent: 50.,
+            mode: Mode::Idle,
+            last_temperature: None,
+        };
+        controller.set_duty(50.);
+        controller
+    }
+
+    fn set_duty(&mut self, duty_percent: f32) {
+        self.duty_percent = duty_percent;
+        let mut config = Config::default();
+        config.top = PWM_TOP;
+        config.compare_a = ((PWM_TOP as f32 + 1.) * duty_percent / 100.) as u16;
+        self.pwm.set_config(&config);
+    }
+
+    pub fn update(&mut self, current_temp: f32) {
+        let low = self.setpoint_celsius - self.hysteresis;
+        let high = self.setpoint_celsius + self.hysteresis;
+        let (mode, duty) = if current_temp < low {
+            (Mode::Heating, 100.)
+        } else if current_temp > high {
+            (Mode::Cooling, 0.)
+        } else if high > low {
+            (Mode::Idle, 100. * (high - current_temp) / (high - low))
+        } else {
+            // No hysteresis and exactly on the setpoint
+            (Mode::Idle, 50.)
+        };
+
+        if mode != self.mode {
+            info!("heat_pump: {} -> {} at {} C", self.mode, mode, current_temp);
+        }
+        self.mode = mode;
+        self.last_temperature = Some(current_temp);
+        self.set_duty(duty);
+    }
+
+    /// Stop driving the heat pump until the next reading, for when the temperature is
+    /// stale.
+    pub fn stop(&mut self) {
+        if self.mode != Mode::Idle || self.duty_percent != 0. {
+            info!(
+                "heat_pump: No temperature for {} s, stopping",
+                STALE_AFTER.as_secs()
+            );
+        }
+        self.mode = Mode::Idle;
+        self.last_temperature = None;
+        self.set_duty(0.);
+    }
+
+    /// Change the setpoint, taking effect from the next reading.
+    pub fn set_setpoint(&mut self, setpoint_celsius: f32) {
+        info!(
+            "heat_pump: Setpoint {} -> {} C",
+            self.setpoint_celsius, setpoint_celsius
+        );
+        self.setpoint_celsius = setpoint_celsius;
+    }
+
+    pub fn status(&self) -> Status {
+        Status {
+            setpoint_celsius: self.setpoint_celsius,
+            hysteresis_celsius: self.hysteresis,
+            duty_percent: self.duty_percent,
+            mode: self.mode,
+            temperature_celsius: self.last_temperature,
+        }
+    }
+}
+
+/// Feed a new SHT30 temperature to the controller, if there is one.
+pub async fn update(current_temp: f32) {
+    if let Some(controller) = CONTROLLER.lock().await.as_mut() {
+        controller.update(current_temp);
+    }
+}
+
+/// Stop the controller, if there is one, when no temperature has arrived for
+/// `STALE_AFTER`.
+pub async fn stop() {
+    if let Some(controller) = CONTROLLER.lock().await.as_mut() {
+        controller.stop();
+    }
+}
+
+/// The controller's state, `None` if it isn't configured.
+pub async fn status() -> Option<Status> {
+    CONTROLLER.lock().await.as_ref().map(Controller::status)
+}
+
+impl crate::WriteMetrics for Status {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer
+            .write(gauge(
+                "heat_pump_duty_percent",
+                "PWM duty cycle sent to the heat pump, 100 is full heat and 0 full cooling",
+                [],
+                [Sample::new([], self.duty_percent)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "heat_pump_setpoint_celsius",
+                "Temperature the heat pump controller is aiming for",
+                [],
+                [Sample::new([], self.setpoint_celsius)].iter(),
+            ))
+            .await?;
+
+        let samples = Mode::ALL.map(|m| Sample::new([m.label()], (m == self.mode) as u8 as f32));
+        writer
+            .write(gauge(
+                "heat_pump_mode",
+                "1 for the heat pump controller's current mode, 0 for the others",
+                ["mode"],
+                samples.iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
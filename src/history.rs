@@ -0,0 +1,116 @@
+use defmt::{error, info};
+use embassy_time::{Duration, Instant, Timer};
+use heapless::{HistoryBuffer, Vec};
+use picoserve::response::chunked::{ChunkWriter, Chunks, ChunksWritten};
+
+use crate::flash_sample_set::PersistentSampleSet;
+use crate::flash_store::{FlashStore, Slot};
+use crate::sht30;
+use crate::task_registry::{self, TaskStatus};
+use crate::Mutex;
+
+/// Minutes of readings kept in RAM.
+pub const HISTORY_LEN: usize = 60;
+
+const HISTORY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// One reading per minute, 12 bytes each.
+#[derive(Clone, Copy)]
+pub struct HistoryEntry {
+    /// Seconds since boot, the device has no wall clock.
+    pub ts: u32,
+    pub temperature: f32,
+    pub humidity: f32,
+}
+
+pub static SHT30_HISTORY: Mutex<HistoryBuffer<HistoryEntry, HISTORY_LEN>> =
+    Mutex::new(HistoryBuffer::new());
+
+/// Minutes of SHT30 temperatures kept in flash, 8 hours.
+pub const PERSISTENT_LEN: usize = 480;
+
+/// The same per minute temperatures as `SHT30_HISTORY`, but kept across reboots.
+pub static SHT30_TEMPERATURE_LOG: Mutex<PersistentSampleSet<PERSISTENT_LEN>> =
+    Mutex::new(PersistentSampleSet::new(Slot::Sht30TemperatureLog));
+
+/// Record the SHT30 median once a minute, whether or not anyone is scraping.
+#[embassy_executor::task]
+pub async fn history_task(
+    sht30_state: &'static Mutex<sht30::SharedState>,
+    flash_store: &'static FlashStore,
+) -> ! {
+    info!(
+        "history: Recording every {} seconds",
+        HISTORY_INTERVAL.as_secs()
+    );
+    SHT30_TEMPERATURE_LOG.lock().await.load(flash_store).await;
+    loop {
+        Timer::after(HISTORY_INTERVAL).await;
+        task_registry::iteration(task_registry::HISTORY);
+
+        let output = sht30_state.lock().await.snapshot();
+        // Nothing worth keeping until the sensor has produced a reading
+        if output.last_read_age_seconds.is_none() {
+            task_registry::set_status(task_registry::HISTORY, TaskStatus::Waiting);
+            continue;
+        }
+
+        SHT30_HISTORY.lock().await.write(HistoryEntry {
+            ts: Instant::now().as_secs() as u32,
+            temperature: output.temperature,
+            humidity: output.humidity,
+        });
+
+        let mut log = SHT30_TEMPERATURE_LOG.lock().await;
+        match log.record(flash_store, output.temperature).await {
+            Ok(()) => task_registry::set_status(task_registry::HISTORY, TaskStatus::Waiting),
+            Err(e) => {
+                error!("history: Error saving temperature to flash: {:?}", e);
+                task_registry::set_status(task_registry::HISTORY, TaskStatus::Error);
+            }
+        }
+    }
+}
+
+/// The newest `minutes` entries, oldest first, copied out so the lock isn't held while
+/// the response is written.
+pub async fn recent(minutes: usize) -> Vec<HistoryEntry, HISTORY_LEN> {
+    let history = SHT30_HISTORY.lock().await;
+    let skip = history.len().saturating_sub(minutes);
+    history.oldest_ordered().skip(skip).copied().collect()
+}
+
+/// Streams entries as a JSON array, which can be larger than the TX buffer.
+pub struct HistoryResponse {
+    entries: Vec<HistoryEntry, HISTORY_LEN>,
+}
+
+impl HistoryResponse {
+    pub fn new(entries: Vec<HistoryEntry, HISTORY_LEN>) -> Self {
+        HistoryResponse { entries }
+    }
+}
+
+impl Chunks for HistoryResponse {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        write!(chunk_writer, "[").await?;
+        for (i, entry) in self.entries.iter().enumerate() {
+            let separator = if i == 0 { "" } else { "," };
+            write!(
+                chunk_writer,
+                "{}{{\"ts\":{},\"temperature\":{},\"humidity\":{}}}",
+                separator, entry.ts, entry.temperature, entry.humidity
+            )
+            .await?;
+        }
+        write!(chunk_writer, "]").await?;
+        chunk_writer.finalize().await
+    }
+}
@@ -0,0 +1,388 @@
+use core::convert::Infallible;
+use core::ops::Deref;
+
+use embassy_time::{Duration, Instant};
+use heapless::Vec;
+use picoserve::extract::{FromRequest, FromRequestParts};
+use picoserve::io::Read;
+use picoserve::request::{RequestBody, RequestParts};
+use picoserve::response::{Connection, IntoResponse, Response, ResponseWriter, StatusCode};
+use picoserve::ResponseSent;
+use portable_atomic::{AtomicU32, Ordering};
+use serde::de::DeserializeOwned;
+
+use crate::Mutex;
+
+/// Why a request body couldn't be extracted.
+pub enum BodyRejection {
+    /// `Transfer-Encoding: chunked` bodies are not supported, send a `Content-Length`.
+    LengthRequired,
+    TooLarge,
+    Read,
+    InvalidJson,
+}
+
+impl IntoResponse for BodyRejection {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let (status, message) = match self {
+            BodyRejection::LengthRequired => (
+                StatusCode::LENGTH_REQUIRED,
+                "Chunked request bodies are not supported\n",
+            ),
+            BodyRejection::TooLarge => (StatusCode::BAD_REQUEST, "Request body too large\n"),
+            BodyRejection::Read => (StatusCode::BAD_REQUEST, "Error reading request body\n"),
+            BodyRejection::InvalidJson => (StatusCode::BAD_REQUEST, "Invalid JSON\n"),
+        };
+        (status, message)
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+/// The raw request body, read into a fixed `N` byte buffer.
+///
+/// Requests with a `Content-Length` larger than `N` are rejected with 400, and chunked
+/// requests with 411.
+pub struct BodyBytes<const N: usize> {
+    buf: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> BodyBytes<N> {
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf[..self.len]
+    }
+}
+
+impl<const N: usize> Deref for BodyBytes<N> {
+    type Target = [u8];
+    fn deref(&self) -> &Self::Target {
+        self.as_slice()
+    }
+}
+
+impl<'r, State, const N: usize> FromRequest<'r, State> for BodyBytes<N> {
+    type Rejection = BodyRejection;
+
+    async fn from_request<R: Read>(
+        _state: &'r State,
+        request_parts: RequestParts<'r>,
+        request_body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        if request_parts.headers().get("Transfer-Encoding").is_some() {
+            return Err(BodyRejection::LengthRequired);
+        }
+
+        let len = request_body.content_length();
+        if len > N {
+            return Err(BodyRejection::TooLarge);
+        }
+
+        let mut buf = [0u8; N];
+        let mut reader = request_body.reader();
+        let mut read = 0;
+        while read < len {
+            match reader.read(&mut buf[read..len]).await {
+                Ok(0) | Err(_) => return Err(BodyRejection::Read),
+                Ok(n) => read += n,
+            }
+        }
+
+        Ok(BodyBytes { buf, len })
+    }
+}
+
+/// A JSON request body of at most `N` bytes, parsed without allocating.
+pub struct JsonBody<T, const N: usize>(pub T);
+
+impl<'r, State, T: DeserializeOwned, const N: usize> FromRequest<'r, State> for JsonBody<T, N> {
+    type Rejection = BodyRejection;
+
+    async fn from_request<R: Read>(
+        state: &'r State,
+        request_parts: RequestParts<'r>,
+        request_body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let body = BodyBytes::<N>::from_request(state, request_parts, request_body).await?;
+        serde_json_core::from_slice::<T>(&body)
+            .map(|(value, _)| JsonBody(value))
+            .map_err(|_| BodyRejection::InvalidJson)
+    }
+}
+
+/// Whether the `Accept` header asks for `text/csv`.  Quality values are ignored, a client
+/// that lists CSV at all is taken to want it.
+pub struct AcceptsCsv(pub bool);
+
+impl<'r, State> FromRequestParts<'r, State> for AcceptsCsv {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        let accepts_csv = request_parts
+            .headers()
+            .get("Accept")
+            .and_then(|value| value.as_str().ok())
+            .is_some_and(|value| {
+                value.split(',').any(|media_range| {
+                    let media_type = media_range.split(';').next().unwrap_or("").trim();
+                    media_type.eq_ignore_ascii_case("text/csv")
+                })
+            });
+        Ok(AcceptsCsv(accepts_csv))
+    }
+}
+
+/// The entity tags from an `If-None-Match` header, parsed as the hex tags `/metrics`
+/// hands out.  Tags in any other format are ignored, so they never match.
+pub struct IfNoneMatch {
+    any: bool,
+    tags: Vec<u32, 4>,
+}
+
+impl IfNoneMatch {
+    /// Whether the client already has the representation tagged `etag`.  Weak tags
+    /// compare the same as strong ones, as RFC 9110 requires for `If-None-Match`.
+    pub fn matches(&self, etag: u32) -> bool {
+        self.any || self.tags.contains(&etag)
+    }
+}
+
+impl<'r, State> FromRequestParts<'r, State> for IfNoneMatch {
+    type Rejection = Infallible;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        let mut header = IfNoneMatch {
+            any: false,
+            tags: Vec::new(),
+        };
+        let Some(value) = request_parts
+            .headers()
+            .get("If-None-Match")
+            .and_then(|value| value.as_str().ok())
+        else {
+            return Ok(header);
+        };
+
+        for tag in value.split(',').map(str::trim) {
+            if tag == "*" {
+                header.any = true;
+                continue;
+            }
+            let tag = tag.strip_prefix("W/").unwrap_or(tag);
+            let Some(tag) = tag.strip_prefix('"').and_then(|t| t.strip_suffix('"')) else {
+                continue;
+            };
+            if let Ok(tag) = u32::from_str_radix(tag, 16) {
+                // Past the first few the client is unlikely to be sending ours
+                let _ = header.tags.push(tag);
+            }
+        }
+
+        Ok(header)
+    }
+}
+
+const METRICS_USERNAME: &str = match option_env!("METRICS_USERNAME") {
+    Some(username) => username,
+    None => "",
+};
+const METRICS_PASSWORD: &str = match option_env!("METRICS_PASSWORD") {
+    Some(password) => password,
+    None => "",
+};
+
+/// Whether `BasicAuth` checks anything, it is disabled when `METRICS_USERNAME` is empty.
+pub const AUTH_ENABLED: bool = !METRICS_USERNAME.is_empty();
+
+// An empty password would let anyone who knows the username in
+const _: () = assert!(
+    !AUTH_ENABLED || !METRICS_PASSWORD.is_empty(),
+    "METRICS_PASSWORD must be set along with METRICS_USERNAME"
+);
+
+/// Requests rejected by `BasicAuth`.
+pub static AUTH_FAILURES: AtomicU32 = AtomicU32::new(0);
+
+/// Sent when `BasicAuth` rejects a request.
+pub struct Unauthorized;
+
+impl IntoResponse for Unauthorized {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        Response::new(StatusCode::UNAUTHORIZED, "Unauthorized\n")
+            .with_header("WWW-Authenticate", "Basic realm=\"pico-climate\"")
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+/// Requires HTTP Basic credentials matching `METRICS_USERNAME` and `METRICS_PASSWORD`
+/// from the build environment.
+pub struct BasicAuth;
+
+impl<'r, State> FromRequestParts<'r, State> for BasicAuth {
+    type Rejection = Unauthorized;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        if !AUTH_ENABLED {
+            return Ok(BasicAuth);
+        }
+
+        let mut decoded = [0u8; 128];
+        let authorized = request_parts
+            .headers()
+            .get("Authorization")
+            .and_then(|value| value.as_str().ok())
+            .and_then(|value| value.strip_prefix("Basic "))
+            .and_then(|encoded| crate::base64::decode(encoded.trim().as_bytes(), &mut decoded))
+            .map(|len| &decoded[..len])
+            .is_some_and(|credentials| {
+                // Split at the first ':', passwords may contain more
+                let split = credentials
+                    .iter()
+                    .position(|b| *b == b':')
+                    .unwrap_or(credentials.len());
+                let (username, password) = credentials.split_at(split);
+                let password = password.get(1..).unwrap_or_default();
+                // `&` rather than `&&` so both are always compared
+                ct_compare(username, METRICS_USERNAME.as_bytes())
+                    & ct_compare(password, METRICS_PASSWORD.as_bytes())
+            });
+
+        if authorized {
+            Ok(BasicAuth)
+        } else {
+            AUTH_FAILURES.fetch_add(1, Ordering::Relaxed);
+            Err(Unauthorized)
+        }
+    }
+}
+
+const ADMIN_TOKEN: &str = match option_env!("ADMIN_TOKEN") {
+    Some(token) => token,
+    None => "",
+};
+
+/// Sent when `AdminToken` rejects a request.
+pub struct Forbidden;
+
+impl IntoResponse for Forbidden {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        (StatusCode::FORBIDDEN, "Invalid admin token\n")
+            .write_to(connection, response_writer)
+            .await
+    }
+}
+
+/// Requires an `X-Admin-Token` header matching `ADMIN_TOKEN` from the build environment.
+/// Without `ADMIN_TOKEN` every request is rejected.
+pub struct AdminToken;
+
+impl<'r, State> FromRequestParts<'r, State> for AdminToken {
+    type Rejection = Forbidden;
+
+    async fn from_request_parts(
+        _state: &'r State,
+        request_parts: &RequestParts<'r>,
+    ) -> Result<Self, Self::Rejection> {
+        let authorized = !ADMIN_TOKEN.is_empty()
+            && request_parts
+                .headers()
+                .get("X-Admin-Token")
+                .and_then(|value| value.as_str().ok())
+                .is_some_and(|token| ct_compare(token.trim().as_bytes(), ADMIN_TOKEN.as_bytes()));
+
+        if authorized {
+            Ok(AdminToken)
+        } else {
+            Err(Forbidden)
+        }
+    }
+}
+
+/// Compare without returning early, so the time taken doesn't reveal how much of a guess
+/// was right.
+fn ct_compare(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = (a.len() != b.len()) as u8;
+    for i in 0..a.len().max(b.len()) {
+        diff |= a.get(i).copied().unwrap_or(0) ^ b.get(i).copied().unwrap_or(0);
+    }
+    diff == 0
+}
+
+/// Requests rejected with `TooManyRequests`.
+pub static RATE_LIMITED: AtomicU32 = AtomicU32::new(0);
+
+/// Sent when a request comes too soon after the last, with the whole seconds to wait.
+pub struct TooManyRequests(u64);
+
+impl TooManyRequests {
+    /// Reject a request that is allowed after `wait`, counting it in `RATE_LIMITED`.
+    pub fn after(wait: Duration) -> Self {
+        RATE_LIMITED.fetch_add(1, Ordering::Relaxed);
+        // Round up, a client retrying after 0 seconds would be rejected again
+        Self(wait.as_millis().div_ceil(1000))
+    }
+}
+
+/// Allows one request per `min_interval`.  Each limit keeps its own time of the last
+/// request, so traffic to other endpoints doesn't use it up.
+pub struct RateLimit {
+    min_interval: Duration,
+    last_request: Mutex<Option<Instant>>,
+}
+
+impl RateLimit {
+    pub const fn new(min_interval: Duration) -> Self {
+        Self {
+            min_interval,
+            last_request: Mutex::new(None),
+        }
+    }
+
+    /// Record a request, or reject it with how long until the next one is allowed.
+    pub async fn check(&self) -> Result<(), TooManyRequests> {
+        let mut last_request = self.last_request.lock().await;
+        if let Some(at) = *last_request {
+            let elapsed = at.elapsed();
+            if elapsed < self.min_interval {
+                return Err(TooManyRequests::after(self.min_interval - elapsed));
+            }
+        }
+        *last_request = Some(Instant::now());
+        Ok(())
+    }
+}
+
+impl IntoResponse for TooManyRequests {
+    async fn write_to<R: Read, W: ResponseWriter<Error = R::Error>>(
+        self,
+        connection: Connection<'_, R>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        Response::new(StatusCode::TOO_MANY_REQUESTS, "Too many requests\n")
+            .with_header("Retry-After", self.0)
+            .write_to(connection, response_writer)
+            .await
+    }
+}
@@ -0,0 +1,148 @@
+//! `application/x-www-form-urlencoded` request bodies, for POST handlers that take a form
+//! rather than a query string or JSON.
+
+use heapless::{FnvIndexMap, String, Vec};
+use picoserve::extract::FromRequest;
+use picoserve::io::Read;
+use picoserve::request::{RequestBody, RequestParts};
+
+use super::extractors::{BodyBytes, BodyRejection};
+
+/// Largest form body `FormBody` reads.
+const MAX_FORM_LEN: usize = 512;
+
+pub type Form<const N: usize> = FnvIndexMap<String<32>, String<64>, N>;
+
+/// Decode `+` to a space and `%XX` to its byte into `out`.  False if an escape is
+/// malformed, the result isn't UTF-8 or it doesn't fit in `N` bytes.
+pub fn url_decode<const N: usize>(input: &str, out: &mut String<N>) -> bool {
+    let mut bytes = Vec::<u8, N>::new();
+    let mut rest = input.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let decoded = match byte {
+            b'+' => b' ',
+            b'%' => {
+                // `from_str_radix` alone would take a sign, as in `%+1`
+                let Some(hex) = tail
+                    .get(..2)
+                    .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+                else {
+                    return false;
+                };
+                let Some(value) = core::str::from_utf8(hex)
+                    .ok()
+                    .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                else {
+                    return false;
+                };
+                rest = &tail[2..];
+                if bytes.push(value).is_err() {
+                    return false;
+                }
+                continue;
+            }
+            byte => byte,
+        };
+        if bytes.push(decoded).is_err() {
+            return false;
+        }
+        rest = tail;
+    }
+
+    match String::from_utf8(bytes) {
+        Ok(decoded) => {
+            *out = decoded;
+            true
+        }
+        Err(_) => false,
+    }
+}
+
+/// Split `key=value&...` into at most `N` decoded pairs.  Only the first `=` of a pair
+/// separates the key, a value may contain more.  Pairs that don't decode or fit, and any
+/// past the `N`th, are dropped, and a later duplicate key replaces the earlier value.
+pub fn parse_form<const N: usize>(body: &[u8]) -> Form<N> {
+    let mut form = Form::new();
+    let Ok(body) = core::str::from_utf8(body) else {
+        return form;
+    };
+
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let mut decoded_key = String::new();
+        let mut decoded_value = String::new();
+        if !url_decode(key, &mut decoded_key) || !url_decode(value, &mut decoded_value) {
+            continue;
+        }
+        if form.insert(decoded_key, decoded_value).is_err() {
+            break;
+        }
+    }
+    form
+}
+
+/// A form body of at most 512 bytes, decoded with `parse_form` into at most `N` pairs.
+/// `N` must be a power of two, e.g. `FormBody<8>`.
+pub struct FormBody<const N: usize>(pub Form<N>);
+
+impl<'r, State, const N: usize> FromRequest<'r, State> for FormBody<N> {
+    type Rejection = BodyRejection;
+
+    async fn from_request<R: Read>(
+        state: &'r State,
+        request_parts: RequestParts<'r>,
+        request_body: RequestBody<'r, R>,
+    ) -> Result<Self, Self::Rejection> {
+        let body =
+            BodyBytes::<MAX_FORM_LEN>::from_request(state, request_parts, request_body).await?;
+        Ok(FormBody(parse_form(&body)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode(input: &str) -> Option<String<64>> {
+        let mut out = String::new();
+        url_decode(input, &mut out).then_some(out)
+    }
+
+    fn value<'a>(form: &'a Form<4>, key: &str) -> Option<&'a str> {
+        form.iter()
+            .find(|(k, _)| k.as_str() == key)
+            .map(|(_, v)| v.as_str())
+    }
+
+    #[test]
+    fn decodes_spaces_and_plus() {
+        assert_eq!(decode("a%20b").as_deref(), Some("a b"));
+        assert_eq!(decode("a+b").as_deref(), Some("a b"));
+        assert_eq!(decode("a%2Bb").as_deref(), Some("a+b"));
+        assert_eq!(decode("a%2bb").as_deref(), Some("a+b"));
+    }
+
+    #[test]
+    fn rejects_malformed_escapes() {
+        assert_eq!(decode("%+1"), None);
+        assert_eq!(decode("%-1"), None);
+        assert_eq!(decode("%2"), None);
+        assert_eq!(decode("%zz"), None);
+        // Decodes to a lone continuation byte
+        assert_eq!(decode("%80"), None);
+    }
+
+    #[test]
+    fn value_keeps_later_equals_signs() {
+        let form = parse_form::<4>(b"key=a=b%3Dc&other=1");
+        assert_eq!(value(&form, "key"), Some("a=b=c"));
+        assert_eq!(value(&form, "other"), Some("1"));
+    }
+
+    #[test]
+    fn malformed_pair_is_dropped() {
+        let form = parse_form::<4>(b"bad=%+1&good=ok");
+        assert_eq!(value(&form, "bad"), None);
+        assert_eq!(value(&form, "good"), Some("ok"));
+    }
+}
@@ -0,0 +1,36 @@
+//! Keeps the watchdog fed while the web server is answering, see `crate::LAST_DELIVERY`.
+
+use picoserve::io::Read;
+use picoserve::request::RequestParts;
+use picoserve::response::ResponseWriter;
+use picoserve::routing::{Layer, Next};
+use picoserve::ResponseSent;
+
+use crate::record_delivery;
+
+/// Router layer that counts every response sent as a delivery, whatever its status.  A
+/// 304, 401 or 429 shows the network and web tasks are working as well as a 200 does.
+pub struct LivenessLayer;
+
+impl<State, PathParameters> Layer<State, PathParameters> for LivenessLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        _request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let sent = next.run(state, path_parameters, response_writer).await?;
+        record_delivery().await;
+        Ok(sent)
+    }
+}
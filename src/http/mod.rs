@@ -0,0 +1,2327 @@
+pub mod extractors;
+pub mod form;
+pub mod liveness;
+pub mod request_id;
+pub mod security_headers;
+
+use core::fmt::Write as _;
+use core::ops::Deref;
+
+use defmt::{error, info};
+use embassy_embedded_hal::SetConfig;
+use embassy_net::tcp::TcpSocket;
+use embassy_net::Stack;
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::mutex::MutexGuard;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use picoserve::response::chunked::ChunkedResponse;
+use picoserve::response::{IntoResponse, Json, Response, StatusCode};
+use picoserve::routing::{get, post};
+use portable_atomic::{AtomicI32, AtomicU32, AtomicU64, AtomicU8, Ordering};
+use serde::Serialize;
+
+use static_cell::StaticCell;
+
+use self::extractors::{AcceptsCsv, AdminToken, BasicAuth, IfNoneMatch, JsonBody, RateLimit};
+use crate::alarm;
+use crate::battery;
+use crate::bh1750;
+use crate::bmp280;
+use crate::config::{
+    HTTP_BUFFER_SIZE, HTTP_RX_BUFFER_SIZE, HTTP_TASK_COUNT, HTTP_TX_BUFFER_SIZE, SECURITY_HEADERS,
+    WIFI_CHANNELS, WIFI_CHANNEL_COUNT,
+};
+use crate::dht22;
+use crate::events;
+use crate::flash_store::FlashStore;
+use crate::heat_pump_controller;
+use crate::history::{self, HistoryResponse, HISTORY_LEN};
+use crate::i2c_health;
+use crate::ina237;
+use crate::influx::{self, InfluxResponse, InfluxWriter};
+use crate::json::JsonEncoder;
+use crate::mqtt;
+use crate::panic_info;
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{
+    chunks_written, counter, describe, gauge, histogram, verify_buckets, HistogramSamples,
+    MetricDescription, MetricSink, MetricWriter, MetricsFormat, MetricsRender, MetricsResponse,
+};
+use crate::push;
+use crate::remote_write;
+use crate::sht30;
+use crate::task_registry::{self, TaskStatus};
+use crate::vpd;
+use crate::{
+    adc_temp_sensor, crc32, heartbeat_skips, log_error, mem_info, sensor_error_samples, wifi,
+    ErrorEvent, ErrorSource, I2c0Bus, LedState, Mutex, MutexExt, Sensor, SensorList, WriteMetrics,
+    ERROR_LOG, ERROR_LOG_LEN, FLASH_SIZE, HEARTBEAT, I2C0_DEFAULT_FREQUENCY, LED_STATE,
+    LOCK_TIMEOUT, REBOOT,
+};
+
+/// Each `/metrics` render reads every sensor, so renders closer together than a typical
+/// Prometheus scrape interval only load the I2C bus.
+const METRICS_MIN_INTERVAL: Duration = Duration::from_secs(5);
+static METRICS_RATE_LIMIT: RateLimit = RateLimit::new(METRICS_MIN_INTERVAL);
+
+pub(crate) struct PicoClimateMetrics {
+    app_state: AppState,
+    /// False when rendering for a push rather than `GET /metrics`.  Pushes don't count as
+    /// requests and leave out the per-channel WiFi histograms, which are most of the
+    /// output.
+    scrape: bool,
+}
+
+impl PicoClimateMetrics {
+    pub(crate) fn for_push(app_state: AppState) -> Self {
+        Self {
+            app_state,
+            scrape: false,
+        }
+    }
+}
+
+const HTTP_REQUEST_COUNT: MetricDescription = describe(
+    "http_request_count",
+    "Requests to /metrics served since boot, counted before the response is rendered",
+    "",
+);
+
+impl MetricsRender for PicoClimateMetrics {
+    async fn write_chunks<W>(&self, chunk_writer: &mut W) -> Result<(), W::Error>
+    where
+        W: MetricSink,
+    {
+        let start = Instant::now();
+        let result = self.render(chunk_writer, start).await;
+        if self.scrape {
+            // A failed render is nearly always the client going away mid response
+            let outcome = if result.is_ok() { 0 } else { 1 };
+            self.app_state.state.lock().await.render_duration[outcome]
+                .sample(start.elapsed().as_micros() as f32 / 1_000_000.);
+        }
+        result
+    }
+}
+
+impl PicoClimateMetrics {
+    async fn render<W: MetricSink>(
+        &self,
+        chunk_writer: &mut W,
+        start: Instant,
+    ) -> Result<(), W::Error> {
+        // Tasks run on the core 0 stack, so this is the depth while rendering
+        mem_info::sample_stack_free();
+        // Only held while the sensors are read, so writing a slow response doesn't block
+        // other requests or the tasks that update the state.
+        let snapshot = {
+            let mut app_state_lock = self.app_state.state.lock().await;
+            if self.scrape {
+                app_state_lock.count[0].incr(1.);
+            }
+            app_state_lock.collect_snapshot(self.scrape).await
+        };
+
+        chunk_writer
+            .write(HTTP_REQUEST_COUNT.counter([], [Sample::new([], snapshot.request_count)].iter()))
+            .await?;
+
+        // Counted as chunks go out, so this scrape's own chunks are mostly missing
+        chunk_writer
+            .write(counter(
+                "http_chunks_written_total",
+                "HTTP chunks sent for /metrics responses, each up to one TCP segment",
+                [],
+                [Sample::new([], chunks_written() as f32)].iter(),
+            ))
+            .await?;
+
+        // Sampled at the end of the render, so this shows up one scrape late.
+        chunk_writer
+            .write(
+                histogram(
+                    "http_request_duration_seconds",
+                    "Time taken to render the response, including waiting for the state lock",
+                    ["endpoint"],
+                    core::iter::once(&snapshot.request_latency),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        // Also sampled after the render, including renders cut short by a write error
+        chunk_writer
+            .write(
+                histogram(
+                    "metrics_render_duration_seconds",
+                    "Time taken to poll the sensors and write the /metrics response",
+                    ["outcome"],
+                    snapshot.render_duration.iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "web_task_connections_total",
+                "Connections accepted by each web task",
+                ["task_id"],
+                TaskStats::samples(&TASK_STATS.connections).iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "web_task_errors_total",
+                "Connections on each web task that ended with an error",
+                ["task_id"],
+                TaskStats::samples(&TASK_STATS.errors).iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "sensor_events_lagged_total",
+                "Sensor readings a subscriber missed because it fell behind",
+                [],
+                [Sample::new(
+                    [],
+                    events::LAGGED_EVENTS.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "http_request_ids_generated_total",
+                "Requests without a usable X-Request-ID header, which were given one",
+                [],
+                [Sample::new(
+                    [],
+                    request_id::REQUEST_IDS_GENERATED.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(gauge(
+                "device_firmware_info",
+                "Always 1, the labels describe the running firmware",
+                ["version", "git_hash", "build_date"],
+                [Sample::new(
+                    [
+                        env!("CARGO_PKG_VERSION"),
+                        env!("GIT_HASH"),
+                        // YYYY-MM-DD
+                        &env!("BUILD_TIMESTAMP")[..10],
+                    ],
+                    1.,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        if let Some(network) = &snapshot.network_info {
+            chunk_writer
+                .write(gauge(
+                    "device_network_info",
+                    "Always 1, the labels are the device's address as of the last DHCP lease",
+                    ["ip", "hostname", "mac"],
+                    [Sample::new(
+                        [
+                            network.ip.as_str(),
+                            self.app_state.hostname,
+                            network.mac.as_str(),
+                        ],
+                        1.,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        chunk_writer
+            .write(counter(
+                "device_heartbeat_total",
+                "Seconds counted by the heartbeat task",
+                [],
+                [Sample::new([], HEARTBEAT.load(Ordering::Relaxed) as f32)].iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "device_heartbeat_skips_total",
+                "Seconds the heartbeat has fallen behind uptime, a sign of a stalled executor",
+                [],
+                [Sample::new([], heartbeat_skips() as f32)].iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "mutex_timeout_total",
+                "Locks given up on after waiting 5 seconds, a sign of a deadlock",
+                ["mutex"],
+                [Sample::new(
+                    ["app_state"],
+                    APP_STATE_LOCK_TIMEOUTS.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(
+                gauge(
+                    "device_memory_bytes",
+                    "Memory layout of the device in bytes",
+                    ["region"],
+                    [
+                        Sample::new(["static_used"], mem_info::static_used_bytes() as f32),
+                        Sample::new(
+                            ["stack_allocated"],
+                            mem_info::stack_allocated_bytes() as f32,
+                        ),
+                        Sample::new(["sram_total"], mem_info::SRAM_TOTAL_BYTES as f32),
+                        Sample::new(["flash_total"], FLASH_SIZE as f32),
+                    ]
+                    .iter(),
+                )
+                .with_unit("bytes"),
+            )
+            .await?;
+
+        chunk_writer
+            .write(
+                gauge(
+                    "device_stack_free_bytes",
+                    "Stack left below the stack pointer while rendering the metrics, not the high-water mark",
+                    ["task"],
+                    [Sample::new(["main"], mem_info::stack_free_bytes() as f32)].iter(),
+                )
+                .with_unit("bytes"),
+            )
+            .await?;
+
+        if self.scrape {
+            chunk_writer
+                .write(histogram(
+                    "wifi_signal_strength",
+                    "Wifi signal strength",
+                    ["ssid", "channel", "metric"],
+                    snapshot.wifi_signal.iter(),
+                ))
+                .await?;
+        }
+
+        let wifi_rssi = snapshot.wifi_rssi;
+        if wifi_rssi != 0 {
+            chunk_writer
+                .write(gauge(
+                    "wifi_rssi_dbm",
+                    "RSSI of the current WiFi association, read every 30 seconds",
+                    ["ssid"],
+                    [Sample::new([env!("WIFI_SSID")], wifi_rssi as f32)].iter(),
+                ))
+                .await?;
+
+            chunk_writer
+                .write(gauge(
+                    "wifi_rssi_quality_percent",
+                    "Wifi signal quality, 0% at -100dBm to 100% at -50dBm",
+                    ["ssid"],
+                    [Sample::new(
+                        [env!("WIFI_SSID")],
+                        wifi::rssi_quality_percent(wifi_rssi),
+                    )]
+                    .iter(),
+                ))
+                .await?;
+
+            chunk_writer
+                .write(gauge(
+                    "wifi_rssi_ewma_dbm",
+                    "Exponential moving average of wifi_rssi_dbm, alpha 0.2",
+                    ["ssid"],
+                    [Sample::new([env!("WIFI_SSID")], snapshot.rssi_ewma)].iter(),
+                ))
+                .await?;
+
+            chunk_writer
+                .write(gauge(
+                    "wifi_rssi_trend_dbm_per_5min",
+                    "Change in wifi_rssi_ewma_dbm over the last 5 minutes, negative is degrading",
+                    ["ssid"],
+                    [Sample::new(
+                        [env!("WIFI_SSID")],
+                        snapshot.rssi_ewma - snapshot.rssi_ewma_prev,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        if let Some(bssid) = snapshot.wifi_bssid {
+            chunk_writer
+                .write(gauge(
+                    "wifi_channel",
+                    "Channel of the strongest access point for the configured SSID",
+                    ["ssid"],
+                    [Sample::new(
+                        [env!("WIFI_SSID")],
+                        snapshot.wifi_channel as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+
+            // Cut to 24 bits so the f32 sample holds it exactly
+            chunk_writer
+                .write(gauge(
+                    "wifi_bssid_hash",
+                    "Low 24 bits of the CRC-32 of the strongest access point's BSSID, changes on roaming",
+                    ["ssid"],
+                    [Sample::new(
+                        [env!("WIFI_SSID")],
+                        (crc32(&bssid) & 0xFF_FFFF) as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        chunk_writer
+            .write(counter(
+                "wifi_associations_total",
+                "Times the strongest access point for the configured SSID changed, including the first",
+                [],
+                [Sample::new([], snapshot.wifi_associations as f32)].iter(),
+            ))
+            .await?;
+
+        let wifi_stats = *wifi::WIFI_STATS.lock().await;
+        chunk_writer
+            .write(counter(
+                "wifi_reconnects_total",
+                "Times the WiFi link came back up after going down",
+                [],
+                [Sample::new([], wifi_stats.reconnects as f32)].iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "wifi_histogram_resets_total",
+                "Times wifi_signal_strength was cleared after a WiFi reconnect",
+                [],
+                [Sample::new(
+                    [],
+                    wifi::HISTOGRAM_RESETS.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        if let Some(associated) = wifi_stats.associated_seconds() {
+            chunk_writer
+                .write(
+                    gauge(
+                        "wifi_associated_since_seconds",
+                        "Time since the WiFi link last came up",
+                        [],
+                        [Sample::new([], associated)].iter(),
+                    )
+                    .with_unit("seconds"),
+                )
+                .await?;
+        }
+
+        chunk_writer
+            .write(
+                counter(
+                    "wifi_connected_seconds_total",
+                    "Time the WiFi link has been up since boot",
+                    [],
+                    [Sample::new([], wifi_stats.connected_seconds())].iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        chunk_writer
+            .write(
+                counter(
+                    "wifi_disconnected_seconds_total",
+                    "Time the WiFi link has been down since boot",
+                    [],
+                    [Sample::new([], wifi_stats.disconnected_seconds())].iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        chunk_writer
+            .write(gauge(
+                "wifi_uptime_ratio",
+                "Fraction of the time since boot the WiFi link has been up",
+                [],
+                [Sample::new([], wifi_stats.uptime_ratio())].iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "wifi_scan_count_total",
+                "Scans requested with POST /wifi/scan",
+                [],
+                [Sample::new(
+                    [],
+                    wifi::SCAN_COUNT.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(
+                gauge(
+                    "wifi_scan_duration_seconds",
+                    "Duration of the last POST /wifi/scan",
+                    [],
+                    [Sample::new(
+                        [],
+                        wifi::SCAN_DURATION_MS.load(Ordering::Relaxed) as f32 / 1000.,
+                    )]
+                    .iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        snapshot.readings.write_metrics(chunk_writer).await?;
+        heat_pump_controller::status()
+            .await
+            .write_metrics(chunk_writer)
+            .await?;
+        battery::status().await.write_metrics(chunk_writer).await?;
+        alarm::status().await.write_metrics(chunk_writer).await?;
+
+        chunk_writer
+            .write(gauge(
+                "sht30_present",
+                "Whether an SHT30 answered at 0x44 or 0x45 during boot",
+                [],
+                [Sample::new([], if snapshot.has_sht30 { 1. } else { 0. })].iter(),
+            ))
+            .await?;
+
+        // The SHT30's age is `sht30_last_read_age_seconds`, written with its readings
+        if let Some(ina237_last_success) = snapshot.ina237_last_success {
+            chunk_writer
+                .write(
+                    gauge(
+                        "ina237_reading_age_seconds",
+                        "Time since the last successful INA237 reading",
+                        [],
+                        [Sample::new(
+                            [],
+                            snapshot.reading_age_seconds(ina237_last_success),
+                        )]
+                        .iter(),
+                    )
+                    .with_unit("seconds"),
+                )
+                .await?;
+        }
+
+        chunk_writer
+            .write(
+                gauge(
+                    "adc_reading_age_seconds",
+                    "Time since the last successful onboard temperature sensor reading",
+                    [],
+                    [Sample::new(
+                        [],
+                        snapshot.reading_age_seconds(snapshot.adc_last_success),
+                    )]
+                    .iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        chunk_writer
+            .write(gauge(
+                "i2c_frequency_hz",
+                "Clock frequency of the sensor I2C bus",
+                [],
+                [Sample::new([], snapshot.i2c_frequency as f32)].iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(gauge(
+                "i2c_bus_healthy",
+                "0 if the last check found the sensor I2C bus held low or busy",
+                [],
+                [Sample::new(
+                    [],
+                    i2c_health::I2C_BUS_HEALTHY.load(Ordering::Relaxed) as u8 as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "i2c_bus_resets_total",
+                "Times the sensor I2C bus was clocked free and set up again",
+                [],
+                [Sample::new(
+                    [],
+                    i2c_health::I2C_BUS_RESETS.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        let i2c_transactions = i2c_health::transaction_histograms();
+        chunk_writer
+            .write(
+                histogram(
+                    "i2c_transaction_duration_seconds",
+                    "Time taken by sensor transactions on the I2C bus, by operation and result",
+                    ["operation", "result"],
+                    i2c_transactions.iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        if let Some(alert_latency) = ina237::alert_latency_histogram() {
+            chunk_writer
+                .write(histogram(
+                    "ina237_alert_latency_ms",
+                    "Time from triggering an INA237 one-shot conversion to ALERT reporting it \
+                     ready, in milliseconds",
+                    [],
+                    [alert_latency].iter(),
+                ))
+                .await?;
+        }
+
+        chunk_writer
+            .write(counter(
+                "sensor_error_total",
+                "Sensor errors by sensor and kind",
+                ["sensor", "kind"],
+                sensor_error_samples().iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "device_panics_total",
+                "Panics recorded in flash since it was last erased",
+                [],
+                [Sample::new(
+                    [],
+                    panic_info::PANIC_COUNT.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(counter(
+                "http_rate_limited_total",
+                "Requests to /metrics rejected for coming less than 5 seconds after the last",
+                [],
+                [Sample::new(
+                    [],
+                    extractors::RATE_LIMITED.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        if extractors::AUTH_ENABLED {
+            chunk_writer
+                .write(counter(
+                    "metrics_auth_failures_total",
+                    "Requests to /metrics rejected for missing or wrong credentials",
+                    [],
+                    [Sample::new(
+                        [],
+                        extractors::AUTH_FAILURES.load(Ordering::Relaxed) as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        if option_env!("PUSHGATEWAY_HOST").is_some() {
+            chunk_writer
+                .write(counter(
+                    "pushgateway_push_total",
+                    "Attempts to push metrics to the Pushgateway",
+                    [],
+                    [Sample::new(
+                        [],
+                        push::PUSH_COUNT.load(Ordering::Relaxed) as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+
+            chunk_writer
+                .write(counter(
+                    "pushgateway_push_errors_total",
+                    "Pushes to the Pushgateway that failed",
+                    [],
+                    [Sample::new(
+                        [],
+                        push::PUSH_ERRORS.load(Ordering::Relaxed) as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        if option_env!("REMOTE_WRITE_HOST").is_some() {
+            chunk_writer
+                .write(counter(
+                    "remote_write_samples_total",
+                    "Samples accepted by the remote-write endpoint",
+                    [],
+                    [Sample::new(
+                        [],
+                        remote_write::SAMPLES.load(Ordering::Relaxed) as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+
+            chunk_writer
+                .write(
+                    counter(
+                        "remote_write_bytes_total",
+                        "Compressed request bytes accepted by the remote-write endpoint",
+                        [],
+                        [Sample::new(
+                            [],
+                            remote_write::BYTES.load(Ordering::Relaxed) as f32,
+                        )]
+                        .iter(),
+                    )
+                    .with_unit("bytes"),
+                )
+                .await?;
+        }
+
+        chunk_writer
+            .write(counter(
+                "csv_dropped_lines_total",
+                "Lines left out of CSV responses for being over 256 bytes or not parsing",
+                [],
+                [Sample::new(
+                    [],
+                    crate::csv::DROPPED_LINES.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        #[cfg(feature = "tcp-logger")]
+        chunk_writer
+            .write(counter(
+                "tcp_logger_dropped_frames_total",
+                "defmt frames dropped for being over 256 bytes or finding the send buffer full",
+                [],
+                [Sample::new(
+                    [],
+                    crate::tcp_logger::DROPPED_FRAMES.load(Ordering::Relaxed) as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        if option_env!("MQTT_BROKER").is_some() {
+            chunk_writer
+                .write(gauge(
+                    "mqtt_connected",
+                    "1 while connected to the MQTT broker",
+                    ["transport"],
+                    [Sample::new(
+                        [mqtt::MQTT_TRANSPORT],
+                        mqtt::MQTT_CONNECTED.load(Ordering::Relaxed) as u8 as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+
+            #[cfg(feature = "mqtt-tls")]
+            chunk_writer
+                .write(counter(
+                    "mqtt_tls_errors_total",
+                    "Failed TLS handshakes with the MQTT broker",
+                    [],
+                    [Sample::new(
+                        [],
+                        mqtt::tls::TLS_ERRORS.load(Ordering::Relaxed) as f32,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        let led_state = *LED_STATE.lock().await;
+        chunk_writer
+            .write(gauge(
+                "temperature_alarm_active",
+                "1 while the SHT30 temperature is outside the alarm thresholds",
+                ["kind"],
+                [
+                    Sample::new(["high"], (led_state == LedState::AlarmHigh) as u8 as f32),
+                    Sample::new(["low"], (led_state == LedState::AlarmLow) as u8 as f32),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        chunk_writer
+            .write(gauge(
+                "wifi_signal_weak",
+                "1 while the 5th percentile of wifi_signal_strength RSSI on some channel is below -80 dBm",
+                [],
+                [Sample::new(
+                    [],
+                    wifi::WIFI_SIGNAL_WEAK.load(Ordering::Relaxed) as u8 as f32,
+                )]
+                .iter(),
+            ))
+            .await?;
+
+        let (temperature_log_len, temperature_quantiles) = {
+            let log = history::SHT30_TEMPERATURE_LOG.lock().await;
+            (
+                log.len(),
+                [0.1, 0.5, 0.9].map(|quantile| log.percentile(quantile)),
+            )
+        };
+        if temperature_log_len > 0 {
+            chunk_writer
+                .write(gauge(
+                    "sht30_temperature_persistent_celsius",
+                    "SHT30 temperature over the last 8 hours of per minute readings, kept in flash across reboots",
+                    ["quantile"],
+                    [
+                        Sample::new(["0.1"], temperature_quantiles[0]),
+                        Sample::new(["0.5"], temperature_quantiles[1]),
+                        Sample::new(["0.9"], temperature_quantiles[2]),
+                    ]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        if snapshot.has_sht30 {
+            chunk_writer
+                .write(counter(
+                    "sht30_error",
+                    "Errors reading from SHT30 Sensor",
+                    [],
+                    [Sample::new([], snapshot.sht30_errors as f32)].iter(),
+                ))
+                .await?;
+        }
+
+        if let Some(serial) = snapshot.sht30_serial {
+            // f32 rounds serials above 2^24, still enough to tell sensors apart
+            chunk_writer
+                .write(gauge(
+                    "sht30_serial_number",
+                    "Serial number read from the SHT30 at boot",
+                    [],
+                    [Sample::new([], serial as f32)].iter(),
+                ))
+                .await?;
+        }
+
+        if let Some(calibration) = snapshot.ina237_calibration {
+            if calibration.timestamp != 0 {
+                chunk_writer
+                    .write(gauge(
+                        "ina237_calibration_timestamp",
+                        "Unix time of the last shunt calibration",
+                        [],
+                        [Sample::new([], calibration.timestamp as f32)].iter(),
+                    ))
+                    .await?;
+            }
+        }
+
+        if self.scrape {
+            self.app_state
+                .state
+                .lock()
+                .await
+                .request_latency
+                .sample(start.elapsed().as_micros() as f32 / 1_000_000.);
+        }
+
+        Ok(())
+    }
+}
+
+/// Quoted hex form of a `/metrics` ETag.
+struct ETag(u32);
+
+impl core::fmt::Display for ETag {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "\"{:08x}\"", self.0)
+    }
+}
+
+/// Changes every `METRICS_MIN_INTERVAL` of uptime.  Uptime, counters and WiFi are on
+/// `/metrics` too, so nothing short of a render says whether the body changed.  Instead a
+/// body is treated as current for as long as a new render would get a 429, and a cache
+/// revalidating it never keeps one older than that.  Each format has its own, so a
+/// cache can't validate a CSV body for a Prometheus request.
+fn metrics_etag(format: MetricsFormat) -> u32 {
+    let window = Instant::now().as_ticks() / METRICS_MIN_INTERVAL.as_ticks();
+    let mut data = [0u8; 9];
+    data[..8].copy_from_slice(&window.to_le_bytes());
+    data[8] = format as u8;
+    crc32(&data)
+}
+
+#[derive(serde::Deserialize)]
+struct MetricsQuery {
+    format: Option<MetricsFormat>,
+}
+
+/// Prometheus metrics.  Clients that send back the `ETag` in `If-None-Match` get a 304
+/// for up to 5 seconds, see `metrics_etag`.  Otherwise a render within 5 seconds of the
+/// last one gets a 429, see `METRICS_RATE_LIMIT`.
+///
+/// `?format=openmetrics` ends the response with `# EOF`.  It isn't chosen from `Accept`,
+/// since Prometheus asks for OpenMetrics by default and would then parse every scrape
+/// with its stricter OpenMetrics parser.  CSV is, as nothing asks for it by accident;
+/// `?format=` wins over `Accept`.
+async fn metrics(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    _auth: BasicAuth,
+    if_none_match: IfNoneMatch,
+    AcceptsCsv(accepts_csv): AcceptsCsv,
+    picoserve::extract::Query(query): picoserve::extract::Query<MetricsQuery>,
+) -> impl IntoResponse {
+    info!("GET /metrics");
+
+    let format = query
+        .format
+        .or(accepts_csv.then_some(MetricsFormat::Csv))
+        .unwrap_or_default();
+    let etag = metrics_etag(format);
+    // The body depends on `Accept`, so caches must key on it too
+    if if_none_match.matches(etag) {
+        return Err(Response::new(StatusCode::NOT_MODIFIED, "")
+            .with_header("ETag", ETag(etag))
+            .with_header("Vary", "Accept"));
+    }
+
+    // A 304 reads no sensors, so only a full render counts towards the limit
+    if let Err(rejected) = METRICS_RATE_LIMIT.check().await {
+        return Ok(Err(rejected));
+    }
+
+    Ok(Ok(ChunkedResponse::new(
+        MetricsResponse::new(PicoClimateMetrics {
+            app_state,
+            scrape: true,
+        })
+        .with_format(format),
+    )
+    .into_response()
+    .with_header("ETag", ETag(etag))
+    .with_header("Vary", "Accept")))
+}
+
+struct PicoClimateInflux {
+    app_state: AppState,
+}
+
+impl MetricsRender for PicoClimateInflux {
+    async fn write_chunks<W>(&self, chunk_writer: &mut W) -> Result<(), W::Error>
+    where
+        W: MetricSink,
+    {
+        let mut app_state_lock = self.app_state.state.lock().await;
+        let tags = [("device", self.app_state.hostname)];
+        let timestamp_ns = remote_write::unix_time_ms().map(|ms| ms * 1_000_000);
+
+        let adc = &mut app_state_lock.sensors.0;
+        // A failed poll clears the last reading, so there is nothing to write.
+        let _ = adc.poll().await;
+        if let Some(adc_sample) = adc.last() {
+            chunk_writer
+                .write_point(
+                    influx::point(
+                        "adc_temp_sensor",
+                        &tags,
+                        [
+                            ("temperature", adc_sample.temp_celsius),
+                            ("volts", adc_sample.volt),
+                            ("raw", adc_sample.raw as f32),
+                        ],
+                    )
+                    .with_timestamp(timestamp_ns),
+                )
+                .await?;
+        }
+
+        if app_state_lock.has_sht30 {
+            let sht30_output = app_state_lock.sht30_state.lock().await.snapshot();
+            chunk_writer
+                .write_point(
+                    influx::point(
+                        "sht30",
+                        &tags,
+                        [
+                            ("temperature", sht30_output.temperature),
+                            ("humidity", sht30_output.humidity),
+                        ],
+                    )
+                    .with_timestamp(timestamp_ns),
+                )
+                .await?;
+        }
+
+        if let Some(ina237_state) = app_state_lock.ina237_state {
+            let ina237_output = ina237_state.lock().await.peek();
+            chunk_writer
+                .write_point(
+                    influx::point(
+                        "ina237",
+                        &tags,
+                        [
+                            ("bus_voltage", ina237_output.bus_voltage),
+                            ("shunt_voltage_mv", ina237_output.shunt_voltage_mv),
+                            ("current", ina237_output.current),
+                        ],
+                    )
+                    .with_timestamp(timestamp_ns),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Current readings in InfluxDB line protocol.  Points are stamped with the time
+/// remote-write learned from its server, or sent without a timestamp for InfluxDB to
+/// stamp on arrival until then.
+async fn influx_metrics(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+) -> impl IntoResponse {
+    info!("GET /influx");
+
+    ChunkedResponse::new(InfluxResponse::new(PicoClimateInflux { app_state }))
+}
+
+#[derive(Serialize)]
+struct Sht30Raw {
+    measurement: [u8; 6],
+    status: [u8; 2],
+    temp_raw: u16,
+    hum_raw: u16,
+    crc_ok_temp: bool,
+    crc_ok_hum: bool,
+}
+
+#[derive(serde::Deserialize)]
+struct RawSamplesQuery {
+    count: Option<usize>,
+}
+
+/// Read the onboard temperature sensor's ADC `count` times, 10 ms apart, for measuring its
+/// noise.  The state lock is only held for each read, but 1000 samples still take 10 s.
+async fn adc_raw_samples(
+    _admin: AdminToken,
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    picoserve::extract::Query(query): picoserve::extract::Query<RawSamplesQuery>,
+) -> impl IntoResponse {
+    let count = query.count.unwrap_or(adc_temp_sensor::MAX_RAW_SAMPLES);
+    info!("GET /sensor/adc/raw-samples count={}", count);
+    if count == 0 || count > adc_temp_sensor::MAX_RAW_SAMPLES {
+        return Err((StatusCode::BAD_REQUEST, "count must be 1 to 1000\n"));
+    }
+
+    let mut samples = heapless::Vec::new();
+    for i in 0..count {
+        if i > 0 {
+            Timer::after_millis(10).await;
+        }
+        let result = app_state
+            .lock_or_timeout("GET /sensor/adc/raw-samples")
+            .await?
+            .sensors
+            .0
+            .read_raw()
+            .await;
+        match result {
+            // Can't fail, count is below the capacity
+            Ok(raw) => {
+                let _ = samples.push(raw);
+            }
+            Err(e) => {
+                error!("Error reading ADC: {}", e);
+                return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading ADC\n"));
+            }
+        }
+    }
+
+    Ok(ChunkedResponse::new(
+        adc_temp_sensor::RawSamplesResponse::new(samples),
+    ))
+}
+
+/// Diagnostic dump of the raw SHT30 measurement and status bytes.
+///
+/// This triggers a real I2C read, which is recorded in the same success/error counters
+/// as the background reader, so it will show up in `sht30_successes` and friends.
+async fn sht30_raw(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+) -> impl IntoResponse {
+    info!("GET /sensor/sht30/raw");
+    let (device, shared, has_sht30) = {
+        let state = app_state.lock_or_timeout("GET /sensor/sht30/raw").await?;
+        (state.sht30_device, state.sht30_state, state.has_sht30)
+    };
+    if !has_sht30 {
+        return Err((StatusCode::NOT_FOUND, "No SHT30 detected\n"));
+    }
+
+    let result = with_timeout(Duration::from_secs(1), async {
+        device.lock().await.read_raw().await
+    })
+    .await;
+
+    match result {
+        Ok(Ok(raw)) => {
+            shared.lock().await.record(&sht30::Reading::from_raw(&raw));
+
+            let mut measurement = [0u8; 6];
+            measurement.copy_from_slice(&raw[..6]);
+            Ok(Json(Sht30Raw {
+                measurement,
+                status: [raw[6], raw[7]],
+                temp_raw: u16::from_be_bytes([raw[0], raw[1]]),
+                hum_raw: u16::from_be_bytes([raw[3], raw[4]]),
+                crc_ok_temp: sht30::crc8(&raw[0..2]) == raw[2],
+                crc_ok_hum: sht30::crc8(&raw[3..5]) == raw[5],
+            }))
+        }
+        Ok(Err(e)) => {
+            error!("Error reading sht30: {}", e);
+            shared.lock().await.record_error();
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading SHT30\n"))
+        }
+        Err(_) => {
+            error!("Timeout reading sht30");
+            shared.lock().await.record_timeout();
+            Err((StatusCode::GATEWAY_TIMEOUT, "Timeout reading SHT30\n"))
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Sht30Status {
+    heater_on: bool,
+    alert_pending: bool,
+    hum_alert: bool,
+    temp_alert: bool,
+    last_reset_detected: bool,
+    command_status: &'static str,
+    checksum_status: &'static str,
+    /// Bit 4 as read, the same flag as `last_reset_detected`.
+    reset_bit: bool,
+}
+
+fn ok_or_failed(failed: bool) -> &'static str {
+    if failed {
+        "failed"
+    } else {
+        "ok"
+    }
+}
+
+/// Every flag in the SHT30 status register.  The background reader clears the register
+/// after reading it with each measurement, so alerts and resets are the ones since then.
+async fn sht30_status(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+) -> impl IntoResponse {
+    info!("GET /sensor/sht30/status");
+    let (device, shared, has_sht30) = {
+        let state = app_state
+            .lock_or_timeout("GET /sensor/sht30/status")
+            .await?;
+        (state.sht30_device, state.sht30_state, state.has_sht30)
+    };
+    if !has_sht30 {
+        return Err((StatusCode::NOT_FOUND, "No SHT30 detected\n"));
+    }
+
+    let result = with_timeout(Duration::from_secs(1), async {
+        device.lock().await.read_status().await
+    })
+    .await;
+
+    match result {
+        Ok(Ok(status)) => Ok(Json(Sht30Status {
+            heater_on: status.heater_status,
+            alert_pending: status.alert_pending,
+            hum_alert: status.humidity_tracking_alert,
+            temp_alert: status.temperature_tracking_alert,
+            last_reset_detected: status.reset_detected,
+            command_status: ok_or_failed(status.command_status),
+            checksum_status: ok_or_failed(status.write_data_checksum_status),
+            reset_bit: status.reset_detected,
+        })),
+        Ok(Err(e)) => {
+            error!("Error reading sht30 status: {}", e);
+            shared.lock().await.record_error();
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading SHT30\n"))
+        }
+        Err(_) => {
+            error!("Timeout reading sht30 status");
+            shared.lock().await.record_timeout();
+            Err((StatusCode::GATEWAY_TIMEOUT, "Timeout reading SHT30\n"))
+        }
+    }
+}
+
+const WIFI_SCAN_TIMEOUT: Duration = Duration::from_secs(20);
+
+/// Clears `SCAN_IN_PROGRESS` however the handler exits, including the client going away.
+struct ScanGuard;
+
+impl Drop for ScanGuard {
+    fn drop(&mut self) {
+        wifi::SCAN_IN_PROGRESS.store(false, Ordering::Relaxed);
+    }
+}
+
+/// Scan for every visible access point.  The scan itself runs in `wifi_monitor`, which
+/// owns the radio, so this only works while WiFi is connected.
+async fn wifi_scan() -> impl IntoResponse {
+    info!("POST /wifi/scan");
+    if wifi::SCAN_IN_PROGRESS.swap(true, Ordering::Relaxed) {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            "A scan is already in progress\n",
+        ));
+    }
+    let _guard = ScanGuard;
+
+    // Drop anything left over from a request that timed out
+    while wifi::SCAN_RESPONSES.try_receive().is_ok() {}
+    let _ = wifi::SCAN_REQUESTS.try_send(wifi::ScanRequest);
+
+    match with_timeout(WIFI_SCAN_TIMEOUT, wifi::SCAN_RESPONSES.receive()).await {
+        Ok(response) => Ok(Json(response.results)),
+        Err(_) => {
+            let _ = wifi::SCAN_REQUESTS.try_receive();
+            Err((StatusCode::GATEWAY_TIMEOUT, "Timeout waiting for scan\n"))
+        }
+    }
+}
+
+#[derive(serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum HistorySensor {
+    Sht30,
+}
+
+#[derive(serde::Deserialize)]
+struct HistoryQuery {
+    sensor: HistorySensor,
+    minutes: Option<usize>,
+}
+
+/// Per-minute readings from the last hour, for looking into spikes and drops.
+async fn sensor_history(
+    picoserve::extract::Query(query): picoserve::extract::Query<HistoryQuery>,
+) -> impl IntoResponse {
+    let minutes = query.minutes.unwrap_or(HISTORY_LEN).min(HISTORY_LEN);
+    info!("GET /sensor/history minutes={}", minutes);
+    let entries = match query.sensor {
+        HistorySensor::Sht30 => history::recent(minutes).await,
+    };
+    ChunkedResponse::new(HistoryResponse::new(entries))
+}
+
+#[derive(serde::Deserialize)]
+struct RepeatabilityQuery {
+    level: sht30::Repeatability,
+}
+
+#[derive(Serialize)]
+struct RepeatabilityResponse {
+    level: sht30::Repeatability,
+}
+
+async fn set_sht30_repeatability(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    picoserve::extract::Query(query): picoserve::extract::Query<RepeatabilityQuery>,
+) -> Result<Json<RepeatabilityResponse>, (StatusCode, &'static str)> {
+    info!("POST /sht30/repeatability {}", query.level);
+    let device = {
+        let state = app_state
+            .lock_or_timeout("POST /sht30/repeatability")
+            .await?;
+        state
+            .sht30_repeatability
+            .store(query.level as u8, Ordering::Relaxed);
+        state.sht30_device
+    };
+    device.lock().await.set_repeatability(query.level);
+
+    Ok(Json(RepeatabilityResponse { level: query.level }))
+}
+
+#[derive(serde::Deserialize)]
+struct AcquisitionModeQuery {
+    mode: sht30::AcquisitionMode,
+}
+
+#[derive(Serialize)]
+struct AcquisitionModeResponse {
+    mode: sht30::AcquisitionMode,
+}
+
+async fn set_sht30_acquisition_mode(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    picoserve::extract::Query(query): picoserve::extract::Query<AcquisitionModeQuery>,
+) -> Result<Json<AcquisitionModeResponse>, (StatusCode, &'static str)> {
+    info!("POST /sht30/acquisition-mode {}", query.mode);
+    let device = app_state
+        .lock_or_timeout("POST /sht30/acquisition-mode")
+        .await?
+        .sht30_device;
+    device.lock().await.set_acquisition_mode(query.mode);
+
+    Ok(Json(AcquisitionModeResponse { mode: query.mode }))
+}
+
+#[derive(serde::Deserialize)]
+struct DehumidifyQuery {
+    seconds: u64,
+}
+
+#[derive(Serialize)]
+struct DehumidifyResponse {
+    seconds: u64,
+}
+
+/// Run the SHT30 heater for up to 60 seconds to drive off condensation.  Readings taken
+/// meanwhile are labelled `dehumidify="true"`.
+async fn sht30_dehumidify(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    picoserve::extract::Query(query): picoserve::extract::Query<DehumidifyQuery>,
+) -> impl IntoResponse {
+    info!("POST /sht30/dehumidify seconds={}", query.seconds);
+    if query.seconds == 0 || query.seconds > sht30::MAX_DEHUMIDIFY.as_secs() {
+        return Err((StatusCode::BAD_REQUEST, "seconds must be 1 to 60\n"));
+    }
+
+    let (device, shared, has_sht30) = {
+        let state = app_state.lock_or_timeout("POST /sht30/dehumidify").await?;
+        (state.sht30_device, state.sht30_state, state.has_sht30)
+    };
+    if !has_sht30 {
+        return Err((StatusCode::NOT_FOUND, "No SHT30 detected\n"));
+    }
+
+    // Hold the shared state so two requests can't both start a session
+    let mut shared = shared.lock().await;
+    if shared
+        .dehumidification()
+        .is_some_and(|session| session.is_active())
+    {
+        return Err((
+            StatusCode::CONFLICT,
+            "Dehumidification is already running\n",
+        ));
+    }
+
+    let result = with_timeout(Duration::from_secs(1), async {
+        device.lock().await.heater_on().await
+    })
+    .await;
+    match result {
+        Ok(Ok(())) => {}
+        Ok(Err(e)) => {
+            error!("Error turning sht30 heater on: {}", e);
+            return Err((
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Error turning SHT30 heater on\n",
+            ));
+        }
+        Err(_) => {
+            return Err((
+                StatusCode::GATEWAY_TIMEOUT,
+                "Timeout turning SHT30 heater on\n",
+            ))
+        }
+    }
+
+    let duration = Duration::from_secs(query.seconds);
+    shared.start_dehumidification(duration);
+    sht30::DEHUMIDIFY.signal(duration);
+
+    Ok(Json(DehumidifyResponse {
+        seconds: query.seconds,
+    }))
+}
+
+/// Standard mode minimum to fast mode.  The SHT30 can go faster but the INA237 tops out at
+/// 400kHz.
+const I2C_FREQUENCY_RANGE: core::ops::RangeInclusive<u32> = 10_000..=400_000;
+
+#[derive(serde::Deserialize)]
+struct I2cFrequencyQuery {
+    hz: u32,
+}
+
+#[derive(Serialize)]
+struct I2cFrequencyResponse {
+    frequency_hz: u32,
+}
+
+/// Change the sensor I2C bus clock.  The new clock applies from the next transaction, the
+/// bus lock is held while reconfiguring so no transfer is cut short.
+async fn set_i2c_frequency(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    picoserve::extract::Query(query): picoserve::extract::Query<I2cFrequencyQuery>,
+) -> impl IntoResponse {
+    info!("POST /i2c/frequency {}", query.hz);
+    if !I2C_FREQUENCY_RANGE.contains(&query.hz) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "hz must be between 10000 and 400000\n",
+        ));
+    }
+
+    let i2c_bus0 = app_state
+        .lock_or_timeout("POST /i2c/frequency")
+        .await?
+        .i2c_bus0;
+    let mut config = embassy_rp::i2c::Config::default();
+    config.frequency = query.hz;
+    if i2c_bus0.lock().await.set_config(&config).is_err() {
+        error!("Unable to set i2c frequency to {}", query.hz);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Unable to set I2C frequency\n",
+        ));
+    }
+    app_state
+        .lock()
+        .await
+        .i2c_frequency
+        .store(query.hz, Ordering::Relaxed);
+
+    Ok(Json(I2cFrequencyResponse {
+        frequency_hz: query.hz,
+    }))
+}
+
+#[derive(serde::Deserialize)]
+struct Sht30CalibrateRequest {
+    reference_temp_c: f32,
+    reference_humidity_rh: f32,
+}
+
+/// Offset the SHT30 so its current medians match a reference instrument, and persist the
+/// offsets to flash.
+async fn calibrate_sht30(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    JsonBody(request): JsonBody<Sht30CalibrateRequest, 128>,
+) -> impl IntoResponse {
+    info!(
+        "POST /sht30/calibrate reference_temp_c={} reference_humidity_rh={}",
+        request.reference_temp_c, request.reference_humidity_rh
+    );
+    if !request.reference_temp_c.is_finite()
+        || !(0. ..=100.).contains(&request.reference_humidity_rh)
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "reference_temp_c must be finite and reference_humidity_rh 0 to 100\n",
+        ));
+    }
+
+    let (has_sht30, sht30_state, flash_store) = {
+        let state = app_state.lock_or_timeout("POST /sht30/calibrate").await?;
+        (state.has_sht30, state.sht30_state, state.flash_store)
+    };
+    if !has_sht30 {
+        return Err((StatusCode::NOT_FOUND, "No SHT30 detected\n"));
+    }
+
+    // No clock, but remote-write may have learned the time
+    let calibrated_at_unix = remote_write::unix_time_ms().map_or(0, |ms| (ms / 1000) as u32);
+    let calibration = sht30_state.lock().await.calibrate(
+        request.reference_temp_c,
+        request.reference_humidity_rh,
+        calibrated_at_unix,
+    );
+    let Some(calibration) = calibration else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "No SHT30 reading yet\n"));
+    };
+
+    if let Err(e) = calibration.save(flash_store).await {
+        error!("Error saving sht30 calibration: {}", e);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error saving calibration\n",
+        ));
+    }
+    sht30_state.lock().await.set_calibration(calibration);
+
+    Ok(Json(calibration))
+}
+
+#[derive(serde::Deserialize)]
+struct Ina237CalibrateRequest {
+    known_current_a: f32,
+    /// Unix time to record with the calibration, the device has no clock of its own.
+    timestamp: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct CalibrateResponse {
+    shunt_resistance_ohms: f32,
+    calibrated: bool,
+}
+
+/// Measure the INA237 shunt resistance with a known current flowing through it, program
+/// the new calibration and persist it to flash.
+async fn calibrate_ina237(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+    JsonBody(request): JsonBody<Ina237CalibrateRequest, 128>,
+) -> impl IntoResponse {
+    info!(
+        "POST /sensor/ina237/calibrate known_current_a={}",
+        request.known_current_a
+    );
+    if !request.known_current_a.is_finite()
+        || request.known_current_a < ina237::MIN_CURRENT_FOR_SHUNT_OHMS
+    {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "known_current_a must be at least 0.01\n",
+        ));
+    }
+
+    let (device, flash_store) = {
+        let state = app_state
+            .lock_or_timeout("POST /sensor/ina237/calibrate")
+            .await?;
+        (state.ina237_device, state.flash_store)
+    };
+    let Some(device) = device else {
+        return Err((StatusCode::NOT_FOUND, "No INA237 detected\n"));
+    };
+
+    let result = with_timeout(Duration::from_secs(2), async {
+        device.lock().await.calibrate(request.known_current_a).await
+    })
+    .await;
+
+    let shunt_ohms = match result {
+        Ok(Ok(shunt_ohms)) => shunt_ohms,
+        Ok(Err(ina237::Ina237Error::ShuntOutOfRange)) => {
+            return Err((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "Measured shunt resistance is out of range, check known_current_a and the load\n",
+            ));
+        }
+        Ok(Err(e)) => {
+            error!("Error calibrating ina237: {}", e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading INA237\n"));
+        }
+        Err(_) => {
+            error!("Timeout calibrating ina237");
+            return Err((StatusCode::GATEWAY_TIMEOUT, "Timeout reading INA237\n"));
+        }
+    };
+
+    let calibration = ina237::Calibration {
+        shunt_ohms,
+        timestamp: request.timestamp.unwrap_or(0),
+    };
+    if let Err(e) = calibration.save(flash_store).await {
+        error!("Error saving ina237 calibration: {}", e);
+        return Err((
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "Error saving calibration\n",
+        ));
+    }
+    app_state
+        .lock_or_timeout("POST /sensor/ina237/calibrate")
+        .await?
+        .ina237_calibration = Some(calibration);
+
+    Ok(Json(CalibrateResponse {
+        shunt_resistance_ohms: shunt_ohms,
+        calibrated: true,
+    }))
+}
+
+/// Every INA237 register as hex, keyed by address, for checking the configuration on the
+/// device against what the firmware meant to program.
+async fn ina237_registers(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+) -> impl IntoResponse {
+    info!("GET /sensor/ina237/registers");
+    let Some(device) = app_state
+        .lock_or_timeout("GET /sensor/ina237/registers")
+        .await?
+        .ina237_device
+    else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "No INA237 detected\n"));
+    };
+
+    let result = with_timeout(Duration::from_secs(1), async {
+        let mut device = device.lock().await;
+        let registers = device.read_all_registers().await?;
+        Ok::<_, ina237::Ina237Error<_>>((
+            registers,
+            device.shunt_ohms(),
+            device.expected_shunt_cal(),
+        ))
+    })
+    .await;
+    let (registers, shunt_ohms, expected_shunt_cal) = match result {
+        Ok(Ok(dump)) => dump,
+        Ok(Err(e)) => {
+            error!("Error reading ina237 registers: {}", e);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error reading INA237\n"));
+        }
+        Err(_) => {
+            error!("Timeout reading ina237 registers");
+            return Err((StatusCode::GATEWAY_TIMEOUT, "Timeout reading INA237\n"));
+        }
+    };
+    let register = |address: u8| {
+        registers
+            .iter()
+            .find(|(a, _)| *a == address)
+            .map_or(0, |(_, value)| *value)
+    };
+
+    let mut json = JsonEncoder::<512>::new();
+    json.object(|json| {
+        for (address, value) in &registers {
+            json.field(&hex(*address as u16, 2), hex(*value, 4).as_str());
+        }
+        json.field("shunt_ohms", shunt_ohms)
+            .field("current_lsb_a", ina237::CURRENT_LSB)
+            .field_object("shunt_cal", |json| {
+                json.field("expected", hex(expected_shunt_cal, 4).as_str())
+                    .field(
+                        "actual",
+                        hex(register(ina237::INA237_REG_SHUNT_CAL), 4).as_str(),
+                    );
+            })
+            .field_object("manufacturer_id", |json| {
+                json.field("expected", hex(ina237::INA237_MANUFACTURER_ID, 4).as_str())
+                    .field(
+                        "actual",
+                        hex(register(ina237::INA237_REG_MANUFACTURER_ID), 4).as_str(),
+                    );
+            });
+    });
+    json.into_response()
+}
+
+/// `value` as `0x` and `digits` upper case hex digits.
+fn hex(value: u16, digits: usize) -> heapless::String<6> {
+    let mut text = heapless::String::new();
+    let _ = write!(text, "0x{:0digits$X}", value, digits = digits);
+    text
+}
+
+#[derive(Serialize)]
+struct EnergyResponse {
+    watt_hours: f32,
+}
+
+/// Zero the INA237 energy total, e.g. after replacing a battery, and persist it right away.
+async fn reset_ina237_energy(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+) -> impl IntoResponse {
+    info!("POST /ina237/energy-reset");
+    let (ina237_state, flash_store) = {
+        let state = app_state
+            .lock_or_timeout("POST /ina237/energy-reset")
+            .await?;
+        (state.ina237_state, state.flash_store)
+    };
+    let Some(ina237_state) = ina237_state else {
+        return Err((StatusCode::NOT_FOUND, "No INA237 detected\n"));
+    };
+
+    ina237_state.lock().await.energy_mut().reset();
+    let energy = ina237::EnergyAccumulator::new(0.);
+    if let Err(e) = energy.save(flash_store).await {
+        error!("Error saving ina237 energy: {}", e);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error saving energy\n"));
+    }
+
+    Ok(Json(EnergyResponse {
+        watt_hours: energy.watt_hours(),
+    }))
+}
+
+#[derive(Serialize)]
+struct FactoryResetResponse {
+    rebooting: bool,
+}
+
+/// Erase everything kept in flash and reboot, returning the device to how it was first
+/// flashed.  The reboot clears every counter and accumulator held in RAM.
+async fn factory_reset(
+    _admin: AdminToken,
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+) -> impl IntoResponse {
+    info!("POST /factory-reset");
+    let (ina237_state, flash_store) = {
+        let state = app_state.lock_or_timeout("POST /factory-reset").await?;
+        (state.ina237_state, state.flash_store)
+    };
+
+    // Otherwise `energy_persist_task` could write the total back before the reboot
+    if let Some(ina237_state) = ina237_state {
+        ina237_state.lock().await.energy_mut().reset();
+    }
+    if let Err(e) = flash_store.erase_all().await {
+        error!("Error erasing flash: {}", e);
+        return Err((StatusCode::INTERNAL_SERVER_ERROR, "Error erasing flash\n"));
+    }
+
+    info!("Factory reset requested, rebooting...");
+    REBOOT.signal(Duration::from_millis(500));
+    Ok(Json(FactoryResetResponse { rebooting: true }))
+}
+
+#[derive(Serialize)]
+struct NoPanic {
+    message: Option<&'static str>,
+}
+
+/// The message of the last panic, which survives the reboot that follows it.
+async fn debug_panic_info(
+    picoserve::extract::State(app_state): picoserve::extract::State<AppState>,
+) -> impl IntoResponse {
+    info!("GET /debug/panic-info");
+    let flash_store = match app_state.lock_or_timeout("GET /debug/panic-info").await {
+        Ok(state) => state.flash_store,
+        Err(e) => return Err(e),
+    };
+    Ok(match panic_info::load(flash_store).await {
+        Some(record) => Ok(ChunkedResponse::new(panic_info::PanicMessage::new(
+            record.message,
+        ))),
+        None => Err(Json(NoPanic { message: None })),
+    })
+}
+
+/// The last `ERROR_LOG_LEN` errors, oldest first, with repeats merged.
+async fn last_errors() -> impl IntoResponse {
+    info!("GET /log/last-errors");
+    let events: heapless::Vec<ErrorEvent, ERROR_LOG_LEN> =
+        ERROR_LOG.lock(|log| log.borrow().events().copied().collect());
+    Json(events)
+}
+
+/// The last reported status and loop count of each task, see `task_registry`.
+async fn debug_tasks() -> impl IntoResponse {
+    info!("GET /debug/tasks");
+    let tasks: heapless::Vec<task_registry::TaskInfo, { task_registry::TASK_COUNT }> = (0
+        ..task_registry::TASK_COUNT)
+        .map(task_registry::task_info)
+        .collect();
+    Json(tasks)
+}
+
+#[derive(serde::Deserialize)]
+struct SetpointQuery {
+    celsius: f32,
+}
+
+/// Change the temperature the heat pump controller aims for.
+async fn set_controller_setpoint(
+    picoserve::extract::Query(query): picoserve::extract::Query<SetpointQuery>,
+) -> impl IntoResponse {
+    info!("POST /controller/setpoint {}", query.celsius);
+    if !heat_pump_controller::SETPOINT_RANGE.contains(&query.celsius) {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "celsius must be between 5 and 35\n",
+        ));
+    }
+
+    let mut controller = heat_pump_controller::CONTROLLER.lock().await;
+    let Some(controller) = controller.as_mut() else {
+        return Err((StatusCode::NOT_FOUND, "Heat pump controller not enabled\n"));
+    };
+    controller.set_setpoint(query.celsius);
+    Ok(Json(controller.status()))
+}
+
+async fn controller_status() -> impl IntoResponse {
+    info!("GET /controller/status");
+    match heat_pump_controller::status().await {
+        Some(status) => Ok(Json(status)),
+        None => Err((StatusCode::NOT_FOUND, "Heat pump controller not enabled\n")),
+    }
+}
+
+/// Set by `build.rs`.
+const FIRMWARE_VERSION: &str = concat!(
+    "pico-climate ",
+    env!("CARGO_PKG_VERSION"),
+    " (git: ",
+    env!("GIT_HASH"),
+    ", built: ",
+    env!("BUILD_TIMESTAMP"),
+    ")\n"
+);
+
+async fn firmware_version() -> &'static str {
+    info!("GET /firmware-version");
+    FIRMWARE_VERSION
+}
+
+#[derive(Serialize)]
+struct TargetLabels {
+    #[serde(rename = "__metrics_path__")]
+    metrics_path: &'static str,
+    device: &'static str,
+    firmware_version: &'static str,
+    sensor_sht30: &'static str,
+    sensor_ina237: &'static str,
+}
+
+#[derive(Serialize)]
+struct TargetGroup {
+    /// `255.255.255.255:80` at most.
+    targets: [heapless::String<21>; 1],
+    labels: TargetLabels,
+}
+
+/// This device as a Prometheus HTTP service discovery target group.
+async fn prometheus_targets(
+    app_state: AppState,
+    stack: &'static Stack<'static>,
+) -> Result<Json<[TargetGroup; 1]>, (StatusCode, &'static str)> {
+    info!("GET /prometheus/targets");
+    let Some(config) = stack.config_v4() else {
+        return Err((StatusCode::SERVICE_UNAVAILABLE, "No IP address yet\n"));
+    };
+    let mut target = heapless::String::new();
+    // Can't fail, the buffer fits the longest address
+    let _ = write!(target, "{}:80", config.address.address());
+
+    let (has_sht30, has_ina237) = {
+        let state = app_state.lock_or_timeout("GET /prometheus/targets").await?;
+        (state.has_sht30, state.ina237_state.is_some())
+    };
+    Ok(Json([TargetGroup {
+        targets: [target],
+        labels: TargetLabels {
+            metrics_path: "/metrics",
+            device: app_state.hostname,
+            firmware_version: env!("CARGO_PKG_VERSION"),
+            sensor_sht30: if has_sht30 { "true" } else { "false" },
+            sensor_ina237: if has_ina237 { "true" } else { "false" },
+        },
+    }]))
+}
+
+const WIFI_SIGNAL_BUCKETS: [f32; 11] = [
+    10.,
+    20.,
+    30.,
+    40.,
+    50.,
+    60.,
+    70.,
+    80.,
+    90.,
+    100.,
+    f32::INFINITY,
+];
+const _: () = assert!(verify_buckets(&WIFI_SIGNAL_BUCKETS));
+
+/// `metric` label values of the `wifi_signal_strength` histograms.
+pub const WIFI_SIGNAL_METRICS: [&str; 3] = ["rssi", "phy_noise", "snr"];
+const WIFI_SIGNAL_COUNT: usize = WIFI_CHANNEL_COUNT * WIFI_SIGNAL_METRICS.len();
+
+/// `channel` label values, in the order of `WIFI_CHANNELS`.
+const WIFI_CHANNEL_LABELS: [&str; WIFI_CHANNEL_COUNT] = channel_labels();
+
+const fn channel_labels() -> [&'static str; WIFI_CHANNEL_COUNT] {
+    const LABELS: [&str; 15] = [
+        "", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14",
+    ];
+    let mut labels = [""; WIFI_CHANNEL_COUNT];
+    let mut i = 0;
+    while i < WIFI_CHANNEL_COUNT {
+        labels[i] = LABELS[WIFI_CHANNELS[i] as usize];
+        i += 1;
+    }
+    labels
+}
+
+/// Where the histograms for `channel` are in `State::wifi_signal`, in the order of
+/// `WIFI_SIGNAL_METRICS`.  `None` for channels left out of `WIFI_CHANNELS`.
+pub fn wifi_signal_indices(channel: u8) -> Option<[usize; WIFI_SIGNAL_METRICS.len()]> {
+    let position = WIFI_CHANNELS.iter().position(|c| *c == channel)?;
+    Some(core::array::from_fn(|metric| {
+        position + WIFI_CHANNEL_COUNT * metric
+    }))
+}
+
+const REQUEST_LATENCY_BUCKETS: [f32; 10] = [
+    0.01,
+    0.05,
+    0.1,
+    0.25,
+    0.5,
+    1.0,
+    2.0,
+    5.0,
+    10.0,
+    f32::INFINITY,
+];
+const _: () = assert!(verify_buckets(&REQUEST_LATENCY_BUCKETS));
+
+const RENDER_DURATION_BUCKETS: [f32; 8] = [0.05, 0.1, 0.2, 0.5, 1.0, 2.0, 5.0, f32::INFINITY];
+const _: () = assert!(verify_buckets(&RENDER_DURATION_BUCKETS));
+
+static STATE: StaticCell<Mutex<State>> = StaticCell::new();
+
+/// App state locks that timed out since boot.  Atomics, as the lock is what's stuck.
+static APP_STATE_LOCK_TIMEOUTS: AtomicU32 = AtomicU32::new(0);
+static APP_STATE_LOCK_TIMEOUTS_IN_A_ROW: AtomicU32 = AtomicU32::new(0);
+/// Timeouts in a row after which the state is assumed deadlocked and the device reboots.
+const MAX_APP_STATE_LOCK_TIMEOUTS: u32 = 3;
+
+#[derive(Clone, Copy)]
+pub struct AppState {
+    state: &'static Mutex<State>,
+    pub hostname: &'static str,
+}
+
+impl AppState {
+    pub async fn new(
+        adc_temp_sensor: &'static mut adc_temp_sensor::Sensor<'static>,
+        ina237_state: Option<&'static Mutex<ina237::SharedState>>,
+        ina237_device: Option<&'static Mutex<ina237::Ina237Device>>,
+        ina237_calibration: Option<ina237::Calibration>,
+        sht30_device: &'static Mutex<sht30::Sht30>,
+        sht30_state: &'static Mutex<sht30::SharedState>,
+        has_sht30: bool,
+        bmp280_device: Option<&'static Mutex<bmp280::Bmp280>>,
+        bh1750_state: Option<&'static Mutex<bh1750::SharedState>>,
+        dht22_device: Option<&'static Mutex<dht22::Dht22>>,
+        flash_store: &'static FlashStore,
+        i2c_bus0: &'static I2c0Bus,
+        hostname: &'static str,
+    ) -> Result<Self, embassy_rp::i2c::Error> {
+        let repeatability = sht30::Repeatability::High;
+        sht30_device.lock().await.set_repeatability(repeatability);
+
+        let sht30_serial = if has_sht30 {
+            match sht30_device.lock().await.read_serial_number().await {
+                Ok(serial) => {
+                    info!("SHT30 serial: 0x{:08X}", serial);
+                    Some(serial)
+                }
+                Err(e) => {
+                    error!("sht30: Unable to read serial number: {:?}", e);
+                    None
+                }
+            }
+        } else {
+            None
+        };
+
+        let state = STATE.init(Mutex::new(State {
+            count: [Sample::new([], 0.)],
+            sensors: (
+                adc_temp_sensor::AdcTempSensor::new(adc_temp_sensor),
+                (
+                    has_sht30.then(|| sht30::Sht30Sensor::new(sht30_state)),
+                    (
+                        ina237_state.map(ina237::Ina237Sensor::new),
+                        (
+                            bmp280_device.map(bmp280::Bmp280Sensor::new),
+                            (
+                                dht22_device.map(dht22::Dht22Sensor::new),
+                                (
+                                    has_sht30.then(|| vpd::VpdSensor::new(sht30_state)),
+                                    (bh1750_state.map(bh1750::Bh1750Sensor::new), ()),
+                                ),
+                            ),
+                        ),
+                    ),
+                ),
+            ),
+            sht30_errors: 0,
+            ina237_errors: 0,
+            // i2c: I2cDevice::new(&i2c_bus),
+            ina237_state,
+            ina237_device,
+            ina237_calibration,
+            sht30_device,
+            sht30_state,
+            has_sht30,
+            sht30_serial,
+            flash_store,
+            i2c_bus0,
+            i2c_frequency: AtomicU32::new(I2C0_DEFAULT_FREQUENCY),
+            sht30_repeatability: AtomicU8::new(repeatability as u8),
+            wifi_rssi: AtomicI32::new(0),
+            rssi_ewma: 0.,
+            rssi_ewma_prev: 0.,
+            rssi_ewma_prev_at: Instant::now(),
+            wifi_channel: 0,
+            wifi_bssid: None,
+            wifi_associations: 0,
+            network_info: None,
+            request_latency: HistogramSamples::new(["/metrics"], REQUEST_LATENCY_BUCKETS),
+            render_duration: [
+                HistogramSamples::new(["ok"], RENDER_DURATION_BUCKETS),
+                HistogramSamples::new(["error"], RENDER_DURATION_BUCKETS),
+            ],
+            wifi_signal: core::array::from_fn(|i| {
+                HistogramSamples::new(
+                    [
+                        env!("WIFI_SSID"),
+                        WIFI_CHANNEL_LABELS[i % WIFI_CHANNEL_COUNT],
+                        WIFI_SIGNAL_METRICS[i / WIFI_CHANNEL_COUNT],
+                    ],
+                    WIFI_SIGNAL_BUCKETS,
+                )
+            }),
+        }));
+
+        Ok(AppState { state, hostname })
+    }
+}
+
+impl State {
+    /// Total `/metrics` requests served.
+    pub fn request_count(&self) -> f32 {
+        self.count[0].get()
+    }
+
+    /// Poll every sensor and copy out what `/metrics` renders, so the caller can release
+    /// the lock before writing the response.  Anything but a scrape only peeks, leaving
+    /// the INA237 current averaging for the next scrape.
+    pub async fn collect_snapshot(&mut self, scrape: bool) -> SensorSnapshot {
+        if scrape {
+            self.sensors.poll_all().await;
+        } else {
+            self.sensors.peek_all().await;
+        }
+        let ina237_last_success = match self.ina237_state {
+            Some(ina237_state) => Some(ina237_state.lock().await.last_success()),
+            None => None,
+        };
+        SensorSnapshot {
+            readings: self.sensors.readings(),
+            adc_last_success: self.sensors.0.last_success(),
+            has_sht30: self.has_sht30,
+            ina237_last_success,
+            request_count: self.request_count(),
+            request_latency: self.request_latency.clone(),
+            render_duration: self.render_duration.clone(),
+            wifi_signal: self.wifi_signal.clone(),
+            sht30_errors: self.sht30_errors,
+            sht30_serial: self.sht30_serial,
+            ina237_calibration: self.ina237_calibration,
+            i2c_frequency: self.i2c_frequency.load(Ordering::Relaxed),
+            wifi_rssi: self.wifi_rssi.load(Ordering::Relaxed),
+            rssi_ewma: self.rssi_ewma,
+            rssi_ewma_prev: self.rssi_ewma_prev,
+            wifi_channel: self.wifi_channel,
+            wifi_bssid: self.wifi_bssid,
+            wifi_associations: self.wifi_associations,
+            network_info: self.network_info.clone(),
+            timestamp: Instant::now(),
+        }
+    }
+}
+
+/// Everything `/metrics` renders from `State`, taken under a single lock by
+/// `State::collect_snapshot`, so nothing is written to the response under the lock.
+pub struct SensorSnapshot {
+    pub readings: <Sensors as SensorList>::Readings,
+    pub adc_last_success: Option<Instant>,
+    pub has_sht30: bool,
+    /// `None` without an INA237.
+    pub ina237_last_success: Option<Option<Instant>>,
+    pub request_count: f32,
+    pub request_latency: HistogramSamples<'static, 1, 10>,
+    pub render_duration: [HistogramSamples<'static, 1, 8>; 2],
+    pub wifi_signal: [HistogramSamples<'static, 3, 11>; WIFI_SIGNAL_COUNT],
+    pub sht30_errors: usize,
+    pub sht30_serial: Option<u32>,
+    pub ina237_calibration: Option<ina237::Calibration>,
+    pub i2c_frequency: u32,
+    pub wifi_rssi: i32,
+    pub rssi_ewma: f32,
+    pub rssi_ewma_prev: f32,
+    pub wifi_channel: u8,
+    pub wifi_bssid: Option<[u8; 6]>,
+    pub wifi_associations: u32,
+    pub network_info: Option<wifi::NetworkInfo>,
+    /// When the sensors were polled.
+    pub timestamp: Instant,
+}
+
+impl SensorSnapshot {
+    /// Seconds between a sensor's last good reading and the snapshot, `f32::MAX` if it has
+    /// never read successfully so staleness alerts fire.
+    fn reading_age_seconds(&self, last_success: Option<Instant>) -> f32 {
+        match last_success {
+            Some(at) => self.timestamp.saturating_duration_since(at).as_millis() as f32 / 1000.,
+            None => f32::MAX,
+        }
+    }
+}
+
+impl AppState {
+    /// Lock the state for the handler at `location`, or 503 after `LOCK_TIMEOUT`.  More
+    /// than 3 timeouts in a row reboot the device.
+    async fn lock_or_timeout(
+        &self,
+        location: &'static str,
+    ) -> Result<MutexGuard<'_, CriticalSectionRawMutex, State>, (StatusCode, &'static str)> {
+        match self.state.lock_with_timeout(LOCK_TIMEOUT).await {
+            Ok(state) => {
+                APP_STATE_LOCK_TIMEOUTS_IN_A_ROW.store(0, Ordering::Relaxed);
+                Ok(state)
+            }
+            Err(_) => {
+                log_error!(
+                    ErrorSource::Http,
+                    "Timeout getting the app state lock in {}",
+                    location
+                );
+                APP_STATE_LOCK_TIMEOUTS.fetch_add(1, Ordering::Relaxed);
+                let in_a_row = APP_STATE_LOCK_TIMEOUTS_IN_A_ROW.fetch_add(1, Ordering::Relaxed) + 1;
+                if in_a_row > MAX_APP_STATE_LOCK_TIMEOUTS {
+                    error!(
+                        "App state lock timed out {} times in a row, rebooting",
+                        in_a_row
+                    );
+                    REBOOT.signal(Duration::from_millis(500));
+                }
+                Err((
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "Timed out waiting for the state lock\n",
+                ))
+            }
+        }
+    }
+}
+
+impl Deref for AppState {
+    type Target = Mutex<State>;
+    fn deref(&self) -> &Self::Target {
+        self.state
+    }
+}
+
+/// Every sensor rendered on `/metrics`.  Add new sensors here and in `AppState::new`.
+pub type Sensors = (
+    adc_temp_sensor::AdcTempSensor,
+    (
+        Option<sht30::Sht30Sensor>,
+        (
+            Option<ina237::Ina237Sensor>,
+            (
+                Option<bmp280::Bmp280Sensor>,
+                (
+                    Option<dht22::Dht22Sensor>,
+                    (Option<vpd::VpdSensor>, (Option<bh1750::Bh1750Sensor>, ())),
+                ),
+            ),
+        ),
+    ),
+);
+
+pub struct State {
+    pub sensors: Sensors,
+    count: [Sample<'static, 0>; 1],
+    pub sht30_errors: usize,
+    pub ina237_errors: usize,
+    // pub i2c: I,
+    // pub sht30: Sht30Device<I>,
+    pub ina237_state: Option<&'static Mutex<ina237::SharedState>>,
+    pub ina237_device: Option<&'static Mutex<ina237::Ina237Device>>,
+    pub ina237_calibration: Option<ina237::Calibration>,
+    pub flash_store: &'static FlashStore,
+    pub i2c_bus0: &'static I2c0Bus,
+    pub i2c_frequency: AtomicU32,
+    pub sht30_device: &'static Mutex<sht30::Sht30>,
+    pub sht30_state: &'static Mutex<sht30::SharedState>,
+    /// False if no SHT30 answered at 0x44 or 0x45 during boot.
+    pub has_sht30: bool,
+    /// Read once at boot, `None` if that failed.
+    pub sht30_serial: Option<u32>,
+    pub sht30_repeatability: AtomicU8,
+    /// One histogram per `WIFI_SIGNAL_METRICS` entry and channel in `WIFI_CHANNELS`,
+    /// indexed by `wifi_signal_indices`.
+    pub wifi_signal: [HistogramSamples<'static, 3, 11>; WIFI_SIGNAL_COUNT],
+    /// RSSI in dBm of the current association, read every 30 seconds by `rssi_task`.  0
+    /// until the first read.
+    pub wifi_rssi: AtomicI32,
+    /// Moving average of `wifi_rssi`, alpha 0.2.
+    pub rssi_ewma: f32,
+    /// `rssi_ewma` as of `rssi_ewma_prev_at`, refreshed every 5 minutes.
+    pub rssi_ewma_prev: f32,
+    pub rssi_ewma_prev_at: Instant,
+    /// Channel of the strongest AP for the configured SSID, 0 until the first scan.
+    pub wifi_channel: u8,
+    /// BSSID of that AP, kept to notice roaming.
+    pub wifi_bssid: Option<[u8; 6]>,
+    /// Times `wifi_bssid` changed, including the first AP found.
+    pub wifi_associations: u32,
+    /// `None` until DHCP first completes.
+    pub network_info: Option<wifi::NetworkInfo>,
+    pub request_latency: HistogramSamples<'static, 1, 10>,
+    /// `/metrics` render times, `outcome="ok"` then `outcome="error"`.
+    pub render_duration: [HistogramSamples<'static, 1, 8>; 2],
+}
+
+/// `task_id` label values for the per task counters.
+const fn task_ids() -> [&'static str; HTTP_TASK_COUNT] {
+    const IDS: [&str; 16] = [
+        "0", "1", "2", "3", "4", "5", "6", "7", "8", "9", "10", "11", "12", "13", "14", "15",
+    ];
+    let mut ids = [""; HTTP_TASK_COUNT];
+    let mut i = 0;
+    while i < HTTP_TASK_COUNT {
+        ids[i] = IDS[i];
+        i += 1;
+    }
+    ids
+}
+const TASK_IDS: [&str; HTTP_TASK_COUNT] = task_ids();
+
+struct TaskStats {
+    connections: [AtomicU64; HTTP_TASK_COUNT],
+    errors: [AtomicU64; HTTP_TASK_COUNT],
+}
+
+impl TaskStats {
+    fn samples(counters: &[AtomicU64; HTTP_TASK_COUNT]) -> [Sample<'static, 1>; HTTP_TASK_COUNT] {
+        core::array::from_fn(|i| {
+            Sample::new([TASK_IDS[i]], counters[i].load(Ordering::Relaxed) as f32)
+        })
+    }
+}
+
+static TASK_STATS: TaskStats = TaskStats {
+    connections: [const { AtomicU64::new(0) }; HTTP_TASK_COUNT],
+    errors: [const { AtomicU64::new(0) }; HTTP_TASK_COUNT],
+};
+
+#[embassy_executor::task(pool_size = HTTP_TASK_COUNT)]
+pub async fn web_task(id: usize, stack: &'static Stack<'static>, app_state: &'static AppState) {
+    let app = picoserve::Router::new()
+        .route("/metrics", get(metrics))
+        .route("/influx", get(influx_metrics))
+        .route("/sensor/sht30/raw", get(sht30_raw))
+        .route("/sensor/sht30/status", get(sht30_status))
+        .route("/sensor/adc/raw-samples", get(adc_raw_samples))
+        .route("/debug/panic-info", get(debug_panic_info))
+        .route("/debug/tasks", get(debug_tasks))
+        .route("/log/last-errors", get(last_errors))
+        .route("/firmware-version", get(firmware_version))
+        .route("/sensor/history", get(sensor_history))
+        .route("/sht30/dehumidify", post(sht30_dehumidify))
+        .route("/sht30/repeatability", post(set_sht30_repeatability))
+        .route("/sht30/acquisition-mode", post(set_sht30_acquisition_mode))
+        .route("/sht30/calibrate", post(calibrate_sht30))
+        .route("/sensor/ina237/calibrate", post(calibrate_ina237))
+        .route("/sensor/ina237/registers", get(ina237_registers))
+        .route("/i2c/frequency", post(set_i2c_frequency))
+        .route("/wifi/scan", post(wifi_scan))
+        .route("/ina237/energy-reset", post(reset_ina237_energy))
+        .route("/factory-reset", post(factory_reset))
+        .route("/controller/setpoint", post(set_controller_setpoint))
+        .route("/controller/status", get(controller_status))
+        .route(
+            "/prometheus/targets",
+            get(
+                move |picoserve::extract::State(app_state): picoserve::extract::State<AppState>| {
+                    prometheus_targets(app_state, stack)
+                },
+            ),
+        )
+        .layer(liveness::LivenessLayer)
+        .layer(request_id::RequestIdLayer { task_id: id })
+        .layer(security_headers::SecurityHeadersLayer {
+            headers: &SECURITY_HEADERS,
+        })
+        .with_state(app_state);
+
+    task_registry::set_status(task_registry::WEB + id, TaskStatus::Waiting);
+    loop {
+        let config = picoserve::Config::new(picoserve::Timeouts {
+            start_read_request: Some(Duration::from_secs(5)),
+            persistent_start_read_request: Some(Duration::from_secs(1)),
+            read_request: Some(Duration::from_secs(1)),
+            write: Some(Duration::from_secs(10)),
+        });
+
+        let mut rx_buffer = [0; HTTP_RX_BUFFER_SIZE];
+        let mut tx_buffer = [0; HTTP_TX_BUFFER_SIZE];
+        let mut http_buffer = [0; HTTP_BUFFER_SIZE];
+
+        // Accept here rather than with `listen_and_serve` so each connection can be counted.
+        let mut socket = TcpSocket::new(*stack, &mut rx_buffer, &mut tx_buffer);
+        if let Err(e) = socket.accept(80).await {
+            log_error!(ErrorSource::Http, "web_task {}: accept failed: {:?}", id, e);
+            task_registry::set_status(task_registry::WEB + id, TaskStatus::Error);
+            continue;
+        }
+
+        task_registry::iteration(task_registry::WEB + id);
+        TASK_STATS.connections[id].fetch_add(1, Ordering::Relaxed);
+        if picoserve::Server::new(&app, &config, &mut http_buffer)
+            .serve(socket)
+            .await
+            .is_err()
+        {
+            TASK_STATS.errors[id].fetch_add(1, Ordering::Relaxed);
+            task_registry::set_status(task_registry::WEB + id, TaskStatus::Error);
+        } else {
+            task_registry::set_status(task_registry::WEB + id, TaskStatus::Waiting);
+        }
+    }
+}
@@ -0,0 +1,104 @@
+//! `X-Request-ID` on every response, so a request can be found in the defmt logs.
+
+use core::fmt::Write as _;
+
+use defmt::info;
+use embassy_time::Instant;
+use heapless::String;
+use picoserve::io::Read;
+use picoserve::request::RequestParts;
+use picoserve::response::{Body, Connection, HeadersIter, Response, ResponseWriter};
+use picoserve::routing::{Layer, Next};
+use picoserve::ResponseSent;
+use portable_atomic::{AtomicU32, Ordering};
+
+pub const MAX_REQUEST_ID_LEN: usize = 32;
+
+/// IDs made up because the request didn't bring a usable one.
+pub static REQUEST_IDS_GENERATED: AtomicU32 = AtomicU32::new(0);
+
+/// The client's `X-Request-ID` if it sent one of up to 32 printable characters,
+/// otherwise 8 hex digits from the time and `task_id`.
+pub fn extract_request_id(
+    request: &RequestParts<'_>,
+    task_id: usize,
+) -> String<MAX_REQUEST_ID_LEN> {
+    let supplied = request
+        .headers()
+        .get("X-Request-ID")
+        .and_then(|value| value.as_str().ok())
+        .filter(|id| !id.is_empty() && id.bytes().all(|b| b.is_ascii_graphic()))
+        .and_then(|id| String::try_from(id).ok());
+    if let Some(id) = supplied {
+        return id;
+    }
+
+    REQUEST_IDS_GENERATED.fetch_add(1, Ordering::Relaxed);
+    let mut id = String::new();
+    let _ = write!(
+        id,
+        "{:08x}",
+        Instant::now().as_micros() as u32 ^ task_id as u32
+    );
+    id
+}
+
+/// Adds the request ID to whatever response the handler writes.
+struct RequestIdWriter<W> {
+    inner: W,
+    id: String<MAX_REQUEST_ID_LEN>,
+}
+
+impl<W: ResponseWriter> ResponseWriter for RequestIdWriter<W> {
+    type Error = W::Error;
+
+    async fn write_response<R: Read<Error = Self::Error>, H: HeadersIter, B: Body>(
+        self,
+        connection: Connection<'_, R>,
+        response: Response<H, B>,
+    ) -> Result<ResponseSent, Self::Error> {
+        let Self { inner, id } = self;
+        inner
+            .write_response(
+                connection,
+                response.with_header("X-Request-ID", id.as_str()),
+            )
+            .await
+    }
+}
+
+/// Router layer for `web_task` number `task_id`.
+pub struct RequestIdLayer {
+    pub task_id: usize,
+}
+
+impl<State, PathParameters> Layer<State, PathParameters> for RequestIdLayer {
+    type NextState = State;
+    type NextPathParameters = PathParameters;
+
+    async fn call_layer<
+        'a,
+        R: Read + 'a,
+        NextLayer: Next<'a, R, Self::NextState, Self::NextPathParameters>,
+        W: ResponseWriter<Error = R::Error>,
+    >(
+        &self,
+        next: NextLayer,
+        state: &State,
+        path_parameters: PathParameters,
+        request_parts: RequestParts<'_>,
+        response_writer: W,
+    ) -> Result<ResponseSent, W::Error> {
+        let id = extract_request_id(&request_parts, self.task_id);
+        info!("request_id={} task={}", id.as_str(), self.task_id);
+        next.run(
+            state,
+            path_parameters,
+            RequestIdWriter {
+                inner: response_writer,
+                id,
+            },
+        )
+        .await
+    }
+}
@@ -0,0 +1,257 @@
+//! Notice a sensor holding the I2C0 bus and clock it free, and time the sensors'
+//! transactions on it.
+//!
+//! A sensor that browns out or is reset part way through sending a byte can keep SDA low,
+//! waiting for clocks the controller will never send.  Every transaction then fails until
+//! power is cycled.  The standard recovery is to clock SCL until the sensor lets go of SDA,
+//! send a STOP and start the I2C peripheral again.
+
+use core::cell::RefCell;
+use core::future::Future;
+
+use defmt::{info, warn};
+use embassy_rp::gpio::{Flex, Pull};
+use embassy_rp::i2c::{Config, I2c};
+use embassy_rp::peripherals::{I2C0, PIN_4, PIN_5};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::http::AppState;
+use crate::prometheus::HistogramSamples;
+use crate::task_registry::{self, TaskStatus};
+use crate::{I2c0, I2c0Irqs};
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+/// Longest a transaction may hold the bus before it counts as stuck.
+const LOCK_TIMEOUT: Duration = Duration::from_millis(100);
+/// Half an SCL period at 100 kHz.
+const HALF_CLOCK: Duration = Duration::from_micros(5);
+/// Enough for a sensor to finish any byte it was part way through, plus its ACK.
+const RECOVERY_CLOCKS: usize = 9;
+
+/// GPIO numbers of `PIN_4` and `PIN_5`, read through SIO while I2C0 owns them.
+const SDA_GPIO: u32 = 4;
+const SCL_GPIO: u32 = 5;
+
+pub static I2C_BUS_RESETS: AtomicU32 = AtomicU32::new(0);
+pub static I2C_BUS_HEALTHY: AtomicBool = AtomicBool::new(true);
+
+/// The `operation` label on `i2c_transaction_duration_seconds`.
+#[derive(Clone, Copy)]
+pub enum I2cOperation {
+    Sht30Read = 0,
+    Sht30Reset = 1,
+    Ina237Init = 2,
+    Ina237Read = 3,
+}
+
+const I2C_OPERATION_COUNT: usize = 4;
+const I2C_TRANSACTION_BUCKETS: [f32; 8] =
+    [0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, f32::INFINITY];
+
+/// `operation` and `result` label values of each histogram in `I2C_TRANSACTIONS`.
+const fn transaction_histogram(
+    operation: &'static str,
+    result: &'static str,
+) -> HistogramSamples<'static, 2, 8> {
+    HistogramSamples::new([operation, result], I2C_TRANSACTION_BUCKETS)
+}
+
+pub type I2cTransactionHistograms = [HistogramSamples<'static, 2, 8>; I2C_OPERATION_COUNT * 2];
+
+// Blocking and outside `AppState` so the core1 readers can sample into it.  Indexed by
+// `I2cOperation` times two, plus one for errors.
+static I2C_TRANSACTIONS: BlockingMutex<CriticalSectionRawMutex, RefCell<I2cTransactionHistograms>> =
+    BlockingMutex::new(RefCell::new([
+        transaction_histogram("sht30_read", "ok"),
+        transaction_histogram("sht30_read", "error"),
+        transaction_histogram("sht30_reset", "ok"),
+        transaction_histogram("sht30_reset", "error"),
+        transaction_histogram("ina237_init", "ok"),
+        transaction_histogram("ina237_init", "error"),
+        transaction_histogram("ina237_read", "ok"),
+        transaction_histogram("ina237_read", "error"),
+    ]));
+
+/// Times a transaction into `i2c_transaction_duration_seconds`.  It is sampled as ok by
+/// `finish`, and as an error if it is dropped first, by an early return on an error or by
+/// a timeout dropping the future it lives in.
+pub struct Transaction {
+    operation: I2cOperation,
+    busy: Duration,
+    /// When the clock last started, `None` while paused.
+    since: Option<Instant>,
+}
+
+impl Transaction {
+    pub fn start(operation: I2cOperation) -> Self {
+        Self {
+            operation,
+            busy: Duration::from_ticks(0),
+            since: Some(Instant::now()),
+        }
+    }
+
+    /// Stop the clock, e.g. while waiting for a sensor to finish measuring.
+    pub fn pause(&mut self) {
+        if let Some(since) = self.since.take() {
+            self.busy += since.elapsed();
+        }
+    }
+
+    pub fn resume(&mut self) {
+        if self.since.is_none() {
+            self.since = Some(Instant::now());
+        }
+    }
+
+    pub fn finish(mut self) {
+        self.sample(false);
+        core::mem::forget(self);
+    }
+
+    fn sample(&mut self, error: bool) {
+        self.pause();
+        let elapsed = self.busy.as_micros() as f32 / 1_000_000.;
+        let index = self.operation as usize * 2 + error as usize;
+        I2C_TRANSACTIONS.lock(|histograms| histograms.borrow_mut()[index].sample(elapsed));
+    }
+}
+
+impl Drop for Transaction {
+    fn drop(&mut self) {
+        self.sample(true);
+    }
+}
+
+/// Run `transaction`, sampling how long it took into `i2c_transaction_duration_seconds`,
+/// as an error if it fails or is dropped part way.
+pub async fn timed<T, E>(
+    operation: I2cOperation,
+    transaction: impl Future<Output = Result<T, E>>,
+) -> Result<T, E> {
+    let timer = Transaction::start(operation);
+    let result = transaction.await;
+    if result.is_ok() {
+        timer.finish();
+    }
+    result
+}
+
+/// A copy of the transaction timings, for rendering without holding the lock.
+pub fn transaction_histograms() -> I2cTransactionHistograms {
+    I2C_TRANSACTIONS.lock(|histograms| histograms.borrow().clone())
+}
+
+/// Both lines should be pulled high whenever no transaction is running.
+fn lines_idle() -> bool {
+    let levels = embassy_rp::pac::SIO.gpio_in(0).read();
+    levels & (1 << SDA_GPIO) != 0 && levels & (1 << SCL_GPIO) != 0
+}
+
+/// Clock SCL by hand until SDA is released, then send a STOP.  Returns whether both lines
+/// are high afterwards.
+///
+/// The caller must hold the bus lock, the pins are taken away from I2C0 meanwhile.
+async fn clock_out_bus() -> bool {
+    // Safety: the bus lock is held, so no transaction is using the pins
+    let mut scl = Flex::new(unsafe { PIN_5::steal() });
+    let mut sda = Flex::new(unsafe { PIN_4::steal() });
+
+    // Open drain by hand: low is an output driving low, high is an input left to the pull up
+    for pin in [&mut scl, &mut sda] {
+        pin.set_pull(Pull::Up);
+        pin.set_low();
+        pin.set_as_input();
+    }
+    Timer::after(HALF_CLOCK).await;
+
+    for _ in 0..RECOVERY_CLOCKS {
+        if sda.is_high() {
+            break;
+        }
+        scl.set_as_output();
+        Timer::after(HALF_CLOCK).await;
+        scl.set_as_input();
+        Timer::after(HALF_CLOCK).await;
+    }
+
+    // STOP: SDA rises while SCL is high
+    sda.set_as_output();
+    Timer::after(HALF_CLOCK).await;
+    scl.set_as_input();
+    Timer::after(HALF_CLOCK).await;
+    sda.set_as_input();
+    Timer::after(HALF_CLOCK).await;
+
+    sda.is_high() && scl.is_high()
+}
+
+/// Hand the pins back to a freshly set up I2C0.
+fn reinit(bus: &mut I2c0, frequency: u32) {
+    let mut config = Config::default();
+    config.frequency = frequency;
+    // Safety: the bus lock is held, and the old driver has no Drop to touch the peripheral
+    // or pins once it is replaced
+    let i2c = unsafe {
+        I2c::new_async(
+            I2C0::steal(),
+            PIN_5::steal(),
+            PIN_4::steal(),
+            I2c0Irqs,
+            config,
+        )
+    };
+    *bus = i2c;
+}
+
+/// Check the sensor bus every minute and recover it if a sensor is holding it low.
+#[embassy_executor::task]
+pub async fn i2c_health_task(app_state: &'static AppState) -> ! {
+    let task = task_registry::I2C_HEALTH;
+    task_registry::set_status(task, TaskStatus::Waiting);
+    loop {
+        Timer::after(CHECK_INTERVAL).await;
+        task_registry::iteration(task);
+
+        let (i2c_bus0, frequency) = {
+            let state = app_state.lock().await;
+            (state.i2c_bus0, state.i2c_frequency.load(Ordering::Relaxed))
+        };
+
+        // A sensor stretching the clock forever leaves its transaction holding the lock.
+        // There's nothing to clock out then, so just report it.
+        let Ok(mut bus) = with_timeout(LOCK_TIMEOUT, i2c_bus0.lock()).await else {
+            warn!(
+                "i2c_health: Bus held for over {} ms",
+                LOCK_TIMEOUT.as_millis()
+            );
+            I2C_BUS_HEALTHY.store(false, Ordering::Relaxed);
+            task_registry::set_status(task, TaskStatus::Error);
+            continue;
+        };
+
+        if lines_idle() {
+            I2C_BUS_HEALTHY.store(true, Ordering::Relaxed);
+            task_registry::set_status(task, TaskStatus::Waiting);
+            continue;
+        }
+
+        warn!("i2c_health: Bus stuck low, clocking it free");
+        I2C_BUS_RESETS.fetch_add(1, Ordering::Relaxed);
+        let released = clock_out_bus().await;
+        reinit(&mut bus, frequency);
+        drop(bus);
+
+        I2C_BUS_HEALTHY.store(released, Ordering::Relaxed);
+        if released {
+            info!("i2c_health: Bus recovered");
+            task_registry::set_status(task, TaskStatus::Waiting);
+        } else {
+            warn!("i2c_health: Bus still held after recovery");
+            task_registry::set_status(task, TaskStatus::Error);
+        }
+    }
+}
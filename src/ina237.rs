@@ -1,16 +1,75 @@
+use core::cell::RefCell;
 use core::ops::Sub;
 
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_rp::gpio::Input;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
 use embedded_hal::i2c::ErrorType;
 
-use defmt::{error, info, Format};
+use defmt::{error, info, warn, Format};
+use heapless::Vec;
+
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+
+use crate::events::{self, SensorEvent};
+use crate::flash_log;
+use crate::flash_store::{FlashStore, Slot};
+use crate::i2c_health::{self, I2cOperation};
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{
+    counter, describe, gauge, HistogramSamples, MetricDescription, MetricSink, MetricWriter,
+};
+use crate::task_registry::{self, TaskStatus};
+use crate::{
+    log_error, record_sensor_error, AverageSet, DualI2cDevice, ErrorSource, Mutex, SampleSet,
+    SensorError,
+};
 
-use embassy_time::{Duration, Instant, Timer};
+const TICK_TIMEOUT: Duration = Duration::from_millis(1000);
 
-use crate::{AverageSet, I2c0, Mutex, SampleSet};
+/// ADC_CONFIG for `start_continuous`: shunt and bus for 4.12 ms each, die temperature for
+/// 1.05 ms, averaged 64 times.
+const CONTINUOUS_ADC_CONFIG: u16 = INA237_MODE_CONT_ALL
+    | INA237_VBUSCT_4120US
+    | INA237_VSHCT_4120US
+    | INA237_VTCT_1052US
+    | INA237_AVG_64;
+/// A new result is ready this often with `CONTINUOUS_ADC_CONFIG`.
+const CONTINUOUS_CONVERSION_TIME: Duration = Duration::from_micros((4120 + 4120 + 1052) * 64);
+/// Conversion times for one-shot reads, 4.12 ms each and no averaging.  `trigger` adds the
+/// mode that starts the conversion.
+const ONE_SHOT_ADC_CONFIG: u16 =
+    INA237_VBUSCT_4120US | INA237_VSHCT_4120US | INA237_VTCT_4120US | INA237_AVG_1;
+/// Time between one-shot reads with `INA237_LOW_POWER`, powered down in between.
+const LOW_POWER_INTERVAL: Duration = Duration::from_secs(10);
+/// Longest `wait_for_value` waits on the ALERT pin before polling DIAG_ALRT instead.
+const ALERT_TIMEOUT: Duration = Duration::from_millis(500);
+const ALERT_LATENCY_BUCKETS_MS: [f32; 8] = [5., 10., 15., 20., 50., 100., 250., f32::INFINITY];
+
+// Blocking and outside `SharedState` since it is sampled with the device locked, in
+// the middle of `tick`.
+static ALERT_LATENCY: BlockingMutex<
+    CriticalSectionRawMutex,
+    RefCell<HistogramSamples<'static, 0, 8>>,
+> = BlockingMutex::new(RefCell::new(HistogramSamples::new(
+    [],
+    ALERT_LATENCY_BUCKETS_MS,
+)));
+
+/// A copy of the time from triggering a one-shot conversion to the ALERT pin reporting it
+/// ready, `None` unless the pin is wired up and has fired.
+pub fn alert_latency_histogram() -> Option<HistogramSamples<'static, 0, 8>> {
+    ALERT_LATENCY.lock(|histogram| {
+        let histogram = histogram.borrow();
+        (histogram.observation_count() > 0).then(|| histogram.clone())
+    })
+}
 
-const TICK_TIMEOUT: Duration = Duration::from_millis(1000);
+/// TEMP_LIMIT for the 125 °C maximum operating temperature, in 125 m°C steps from bit 4.
+/// DIAG_ALRT.TMPOL is set while the die is hotter.
+const DIE_TEMP_LIMIT: u16 = ((125_000_u32 / 125) << 4) as u16;
+/// Well below the limit, but worth a warning.
+const DIE_TEMP_WARNING_C: f32 = 100.;
 
 // INA237 Register Addresses
 pub const INA237_REG_CONFIG: u8 = 0x00;
@@ -29,6 +88,26 @@ pub const INA237_REG_BUVL: u8 = 0x0F;
 pub const INA237_REG_TEMP_LIMIT: u8 = 0x10;
 pub const INA237_REG_PWR_LIMIT: u8 = 0x11;
 pub const INA237_REG_MANUFACTURER_ID: u8 = 0x3E;
+pub const INA237_REG_DEVICE_ID: u8 = 0x3F;
+
+/// "TI" in ASCII.
+pub const INA237_MANUFACTURER_ID: u16 = 0x5449;
+
+/// Every register `read_all_registers` dumps.  0x03, 0x09 and 0x0A only exist on the
+/// INA228 family.
+const DUMPED_REGISTERS: [u8; 11] = [
+    INA237_REG_CONFIG,
+    INA237_REG_ADC_CONFIG,
+    INA237_REG_SHUNT_CAL,
+    INA237_REG_SHUNT_VOLTAGE,
+    INA237_REG_BUS_VOLTAGE,
+    INA237_REG_DIE_TEMP,
+    INA237_REG_CURRENT,
+    INA237_REG_POWER,
+    INA237_REG_DIAG_ALRT,
+    INA237_REG_MANUFACTURER_ID,
+    INA237_REG_DEVICE_ID,
+];
 
 // CONFIG Register (0x00) Bit Definitions
 pub const INA237_CONFIG_RST: u16 = 1 << 15;
@@ -119,31 +198,125 @@ pub const INA237_DIAG_MEMSTAT: u16 = 1 << 0;
 pub const INA237_DEFAULT_ADDR: u8 = 0x40;
 
 const MAX_EXPECTED_CURRENT: f32 = 100.0;
-const CURRENT_LSB: f32 = MAX_EXPECTED_CURRENT / (1 << 15) as f32;
+pub const CURRENT_LSB: f32 = MAX_EXPECTED_CURRENT / (1 << 15) as f32;
+/// Shunt voltage LSB in mV with ADCRANGE = 0 (±163.84 mV full scale), 5 µV.
+const SHUNT_VOLTAGE_LSB_MV: f32 = 5e-3;
+/// Below this the shunt resistance worked out from the readings is mostly noise.
+pub const MIN_CURRENT_FOR_SHUNT_OHMS: f32 = 0.01;
+/// SHUNT_CAL is a 15 bit field, bit 15 is reserved.
+const MAX_SHUNT_CAL: f32 = 0x7FFF as f32;
+pub const DEFAULT_SHUNT_OHMS: f32 = 0.015;
 const POWER_LSB: f32 = 3.2 * CURRENT_LSB;
+/// Drift of the internal reference, and so of the current, from the datasheet.
+const CURRENT_TEMP_COEFFICIENT_PPM: f32 = 5.;
+/// Die temperature the current is trimmed at.
+const CURRENT_REFERENCE_TEMP_C: f32 = 25.;
+
+/// `raw_current` corrected linearly for the die being away from `reference_temp`.
+pub fn compensate_current(raw_current: f32, die_temp_celsius: f32, reference_temp: f32) -> f32 {
+    raw_current * (1. + CURRENT_TEMP_COEFFICIENT_PPM * 1e-6 * (die_temp_celsius - reference_temp))
+}
 
 /// Sensor output returned via channel (includes medians and counters)
 #[derive(Clone, Copy, Default)]
 pub struct Output {
     pub bus_voltage: f32,
-    pub shunt_voltage: f32,
+    pub shunt_voltage_mv: f32,
+    /// Temperature compensated, see `compensate_current`.
     pub current: f32,
+    pub current_raw: f32,
     pub successes: f32,
     pub timeouts: f32,
     pub zeros: f32,
     pub recoverable_errors: f32,
     pub resets: f32,
+    pub energy_watt_hours: f32,
+    pub continuous: bool,
+    pub die_temperature: f32,
+    pub overtemp_events: f32,
+    pub overtemp_active: bool,
+    /// Reads with DIAG_ALRT.MATHOF set, when the current can't be trusted.
+    pub overflow_events: f32,
+    pub power_down_cycles: f32,
+    pub powered_on_ms: f32,
+}
+
+/// How often `energy_persist_task` writes the accumulated energy to flash.
+const ENERGY_PERSIST_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// Integrates power over time into watt-hours, for estimating battery usage.
+pub struct EnergyAccumulator {
+    watt_hours: f32,
+    /// `None` until the first reading, so time before the sensor started isn't counted.
+    last_update: Option<Instant>,
+}
+
+impl EnergyAccumulator {
+    pub const fn new(watt_hours: f32) -> Self {
+        Self {
+            watt_hours,
+            last_update: None,
+        }
+    }
+
+    /// Add the energy used since the previous update, assuming `power_watts` held for the
+    /// whole interval.
+    pub fn update(&mut self, power_watts: f32) {
+        let now = Instant::now();
+        if let Some(last_update) = self.last_update {
+            let elapsed_hours = (now - last_update).as_millis() as f32 / 3_600_000.;
+            self.watt_hours += power_watts * elapsed_hours;
+        }
+        self.last_update = Some(now);
+    }
+
+    /// Start accumulating from zero again, e.g. after replacing a battery.
+    pub fn reset(&mut self) {
+        self.watt_hours = 0.;
+    }
+
+    pub fn watt_hours(&self) -> f32 {
+        self.watt_hours
+    }
+
+    /// Energy saved by `save`, or zero if nothing has been saved yet.
+    pub async fn load(store: &FlashStore) -> Self {
+        let watt_hours = flash_log::load(store, Slot::Ina237Energy)
+            .await
+            .map_or(0., |milli_watt_hours| milli_watt_hours as f32 / 1000.);
+        Self::new(watt_hours)
+    }
+
+    /// Persist the accumulated energy, rounded to the nearest milliwatt-hour.  Appended to
+    /// a log, so the sector is only erased every few hundred saves.
+    pub async fn save(&self, store: &FlashStore) -> Result<(), embassy_rp::flash::Error> {
+        let milli_watt_hours = libm::roundf(self.watt_hours * 1000.) as u32;
+        flash_log::append(store, Slot::Ina237Energy, milli_watt_hours).await
+    }
 }
 
 pub struct SharedState {
     bus_voltages: SampleSet<11>,
     shunt_voltages: SampleSet<11>,
     currents: AverageSet,
+    raw_currents: AverageSet,
+    energy: EnergyAccumulator,
     successes: f32,
     timeouts: f32,
     zeros: f32,
     recoverable_errors: f32,
     resets: f32,
+    last_success: Option<Instant>,
+    continuous: bool,
+    die_temperature: f32,
+    overtemp_events: f32,
+    overtemp_active: bool,
+    die_temp_warning: bool,
+    overflow_events: f32,
+    /// The last read had MATHOF set.
+    math_overflow: bool,
+    power_down_cycles: f32,
+    powered_on_ms: f32,
 }
 
 impl SharedState {
@@ -152,11 +325,23 @@ impl SharedState {
             bus_voltages: SampleSet::new(),
             shunt_voltages: SampleSet::new(),
             currents: AverageSet::new(),
+            raw_currents: AverageSet::new(),
+            energy: EnergyAccumulator::new(0.),
             successes: 0.,
             timeouts: 0.,
             zeros: 0.,
             recoverable_errors: 0.,
             resets: 0.,
+            last_success: None,
+            continuous: false,
+            die_temperature: 0.,
+            overtemp_events: 0.,
+            overtemp_active: false,
+            die_temp_warning: false,
+            overflow_events: 0.,
+            math_overflow: false,
+            power_down_cycles: 0.,
+            powered_on_ms: 0.,
         }
     }
 
@@ -173,7 +358,7 @@ impl SharedState {
         self.currents.record(v);
     }
 
-    pub fn record_shunt_voltage(&mut self, v: f32) {
+    pub fn record_shunt_voltage_mv(&mut self, v: f32) {
         self.shunt_voltages.record(v);
     }
 
@@ -181,11 +366,67 @@ impl SharedState {
         self.recoverable_errors = count as f32;
     }
 
+    /// See `Ina237::power_down_cycles` and `Ina237::powered_on_ms`.
+    pub fn set_power_stats(&mut self, power_down_cycles: u32, powered_on_ms: u64) {
+        self.power_down_cycles = power_down_cycles as f32;
+        self.powered_on_ms = powered_on_ms as f32;
+    }
+
     pub fn record_success(&mut self, tick: &TickOutput) {
         self.successes += 1.;
+        self.last_success = Some(Instant::now());
         self.record_bus_voltage(tick.bus_voltage);
+        self.record_shunt_voltage_mv(tick.shunt_voltage_mv);
+        self.record_die_temperature(tick.die_temperature, tick.over_temperature);
+
+        // An overflowed current is garbage, keep it out of the average and the energy
+        if tick.math_overflow {
+            if !self.math_overflow {
+                warn!("INA237 math overflow - check calibration");
+            }
+            self.overflow_events += 1.;
+            self.math_overflow = true;
+            return;
+        }
+        self.math_overflow = false;
         self.record_current(tick.current);
-        self.record_shunt_voltage(tick.shunt_voltage);
+        self.raw_currents.record(tick.current_raw);
+        self.energy.update(tick.bus_voltage * tick.current);
+    }
+
+    /// Log and count the over-temperature flag when it is raised, rather than on every
+    /// read while it stays set.
+    fn record_die_temperature(&mut self, temperature: f32, over_temperature: bool) {
+        self.die_temperature = temperature;
+
+        if over_temperature && !self.overtemp_active {
+            error!("INA237 over-temperature detected! Die at {} C", temperature);
+            self.overtemp_events += 1.;
+        }
+        self.overtemp_active = over_temperature;
+
+        let warning = temperature > DIE_TEMP_WARNING_C;
+        if warning && !self.die_temp_warning && !over_temperature {
+            warn!("ina237: Die temperature {} C", temperature);
+        }
+        self.die_temp_warning = warning;
+    }
+
+    pub fn last_success(&self) -> Option<Instant> {
+        self.last_success
+    }
+
+    pub fn energy(&self) -> &EnergyAccumulator {
+        &self.energy
+    }
+
+    pub fn energy_mut(&mut self) -> &mut EnergyAccumulator {
+        &mut self.energy
+    }
+
+    /// Whether the device is converting continuously, see `Ina237::start_continuous`.
+    pub fn set_continuous(&mut self, continuous: bool) {
+        self.continuous = continuous;
     }
 
     pub fn record_timeout(&mut self) {
@@ -197,16 +438,339 @@ impl SharedState {
     }
 
     pub fn snapshot(&mut self) -> Output {
+        let current = self.currents.avg();
+        let current_raw = self.raw_currents.avg();
+        self.output(current, current_raw)
+    }
+
+    /// Like `snapshot`, but leaves the current averages accumulating for the next scrape.
+    pub fn peek(&self) -> Output {
+        self.output(self.currents.peek(), self.raw_currents.peek())
+    }
+
+    /// The currents are NaN while the INA237 reports a math overflow.
+    fn output(&self, current: f32, current_raw: f32) -> Output {
+        let (current, current_raw) = if self.math_overflow {
+            (f32::NAN, f32::NAN)
+        } else {
+            (current, current_raw)
+        };
         Output {
             bus_voltage: self.bus_voltages.median(),
-            shunt_voltage: self.shunt_voltages.median(),
-            current: self.currents.avg(),
+            shunt_voltage_mv: self.shunt_voltages.median(),
+            current,
+            current_raw,
             successes: self.successes,
             timeouts: self.timeouts,
             zeros: self.zeros,
             recoverable_errors: self.recoverable_errors,
             resets: self.resets,
+            energy_watt_hours: self.energy.watt_hours(),
+            continuous: self.continuous,
+            die_temperature: self.die_temperature,
+            overtemp_events: self.overtemp_events,
+            overtemp_active: self.overtemp_active,
+            overflow_events: self.overflow_events,
+            power_down_cycles: self.power_down_cycles,
+            powered_on_ms: self.powered_on_ms,
+        }
+    }
+}
+
+/// The INA237 as seen by the metrics endpoint.  The device is only read by
+/// `continuous_reading` on core1, so polling snapshots the shared state without any I2C
+/// while `/metrics` holds the `State` lock.  The snapshot restarts the current average,
+/// so it covers the time since the last scrape, and a push only peeks at it.
+pub struct Ina237Sensor {
+    state: &'static Mutex<SharedState>,
+    last: Output,
+}
+
+impl Ina237Sensor {
+    pub fn new(state: &'static Mutex<SharedState>) -> Self {
+        Self {
+            state,
+            last: Output::default(),
+        }
+    }
+}
+
+impl crate::Sensor for Ina237Sensor {
+    type Reading = Output;
+
+    fn name(&self) -> &'static str {
+        "ina237"
+    }
+
+    async fn poll(&mut self) -> Result<(), crate::SensorError> {
+        self.last = self.state.lock().await.snapshot();
+        Ok(())
+    }
+
+    async fn peek(&mut self) -> Result<(), crate::SensorError> {
+        self.last = self.state.lock().await.peek();
+        Ok(())
+    }
+
+    fn reading(&self) -> Output {
+        self.last
+    }
+}
+
+pub(crate) const INA237_READING: MetricDescription = describe(
+    "ina237_reading",
+    "INA237 readings by register.  bus_voltage is volts (LSB 3.125 mV, 0 to 85 V), \
+     shunt_voltage_mv is millivolts (LSB 5 uV, -163.84 to 163.84 mV), current is amps \
+     (LSB from the shunt calibration) compensated for the die temperature, as is \
+     current_compensated, current_raw is amps as measured, power is watts from \
+     bus_voltage times current, die_temperature is degrees Celsius (LSB 0.125, -40 to 125)",
+    "",
+);
+
+impl crate::WriteMetrics for Output {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        let output = self;
+
+        // The current, and the power from it, are NaN after a math overflow
+        let valid = !output.current.is_nan();
+        let power = output.bus_voltage * output.current;
+        writer
+            .write(
+                INA237_READING.gauge(
+                    ["register"],
+                    [
+                        Sample::new(["bus_voltage"], output.bus_voltage),
+                        Sample::new(["shunt_voltage_mv"], output.shunt_voltage_mv),
+                        Sample::new(["current"], output.current),
+                        Sample::new(["current_raw"], output.current_raw),
+                        Sample::new(["current_compensated"], output.current),
+                        Sample::new(["power"], power),
+                        Sample::new(["die_temperature"], output.die_temperature),
+                    ]
+                    .iter()
+                    .filter(|sample| !sample.get().is_nan()),
+                ),
+            )
+            .await?;
+
+        if valid {
+            writer
+                .write(gauge(
+                    "ina237_temp_compensation_delta_ma",
+                    "Milliamps the die temperature compensation added to the measured current",
+                    [],
+                    [Sample::new(
+                        [],
+                        (output.current - output.current_raw) * 1000.,
+                    )]
+                    .iter(),
+                ))
+                .await?;
+        }
+
+        writer
+            .write(gauge(
+                "ina237_data_valid",
+                "0 while the INA237 reports a math overflow and the register isn't reported",
+                ["register"],
+                [
+                    Sample::new(["current"], valid as u8 as f32),
+                    Sample::new(["power"], valid as u8 as f32),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_overflow_total",
+                "Reads where the INA237 flagged a math overflow, check the calibration",
+                [],
+                [Sample::new([], output.overflow_events)].iter(),
+            ))
+            .await?;
+
+        // Should match the calibrated shunt, skipped when the current is too small to tell
+        if output.current.abs() >= MIN_CURRENT_FOR_SHUNT_OHMS {
+            writer
+                .write(
+                    gauge(
+                        "ina237_shunt_resistance_ohms",
+                        "Shunt resistance from the measured shunt voltage and current",
+                        [],
+                        [Sample::new(
+                            [],
+                            output.shunt_voltage_mv / (output.current * 1000.),
+                        )]
+                        .iter(),
+                    )
+                    .with_unit("ohms"),
+                )
+                .await?;
+        }
+
+        writer
+            .write(
+                counter(
+                    "ina237_energy_watt_hours_total",
+                    "Energy used since the last reset, from bus voltage times current",
+                    [],
+                    [Sample::new([], output.energy_watt_hours)].iter(),
+                )
+                .with_unit("watt_hours"),
+            )
+            .await?;
+
+        // Suggested alert rules:
+        //   increase(ina237_overtemp_events_total[1h]) > 0
+        //   ina237_reading{register="die_temperature"} > 100
+        writer
+            .write(counter(
+                "ina237_overtemp_events_total",
+                "Times the INA237 die went over 125 C, when its measurements are unreliable",
+                [],
+                [Sample::new([], output.overtemp_events)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "ina237_die_temp_alert_active",
+                "1 while the INA237 over-temperature flag is set",
+                [],
+                [Sample::new([], output.overtemp_active as u8 as f32)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "ina237_mode",
+                "ADC conversion mode, 0 for one-shot and 1 for continuous",
+                [],
+                [Sample::new([], output.continuous as u8 as f32)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_successes",
+                "Successful reads from ina237",
+                [],
+                [Sample::new([], output.successes)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_timeouts",
+                "Timeout events reading ina237",
+                [],
+                [Sample::new([], output.timeouts)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_zeros",
+                "Zeroes reading from ina237",
+                [],
+                [Sample::new([], output.zeros)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_recoverable_errors",
+                "Recoverable errors from ina237",
+                [],
+                [Sample::new([], output.recoverable_errors)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_power_down_cycles_total",
+                "Times the INA237 ADC was powered down between one-shot reads",
+                [],
+                [Sample::new([], output.power_down_cycles)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_powered_on_ms_total",
+                "Milliseconds the INA237 ADC has spent powered on since boot",
+                [],
+                [Sample::new([], output.powered_on_ms)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_errors",
+                "Errors reading from ina237",
+                [],
+                [Sample::new([], output.resets)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "ina237_resets",
+                "Resets of the ina237",
+                [],
+                [Sample::new([], output.resets)].iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// SHUNT_CAL register value for the given full scale current and shunt resistance,
+/// datasheet equation 2.
+pub fn compute_shunt_cal(max_current_a: f32, shunt_ohms: f32) -> u16 {
+    shunt_cal(max_current_a, shunt_ohms) as u16
+}
+
+fn shunt_cal(max_current_a: f32, shunt_ohms: f32) -> f32 {
+    let current_lsb = max_current_a / (1 << 15) as f32;
+    819.2e6 * current_lsb * shunt_ohms
+}
+
+/// Whether SHUNT_CAL can represent `shunt_ohms`, between 1 and the 15 bit maximum.
+pub fn shunt_ohms_in_range(shunt_ohms: f32) -> bool {
+    let value = shunt_cal(MAX_EXPECTED_CURRENT, shunt_ohms);
+    value.is_finite() && (1. ..=MAX_SHUNT_CAL).contains(&value)
+}
+
+/// Shunt resistance measured by `/sensor/ina237/calibrate`, persisted in flash.
+#[derive(Clone, Copy)]
+pub struct Calibration {
+    pub shunt_ohms: f32,
+    /// Unix time supplied by the client when calibrating, 0 if unknown.
+    pub timestamp: u32,
+}
+
+impl Calibration {
+    pub async fn load(store: &FlashStore) -> Option<Self> {
+        let mut buf = [0u8; 8];
+        if store.load(Slot::Ina237Calibration, &mut buf).await? != buf.len() {
+            return None;
         }
+        let shunt_ohms = f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+        let timestamp = u32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]);
+        Some(Self {
+            shunt_ohms,
+            timestamp,
+        })
+    }
+
+    pub async fn save(&self, store: &FlashStore) -> Result<(), embassy_rp::flash::Error> {
+        let mut buf = [0u8; 8];
+        buf[..4].copy_from_slice(&self.shunt_ohms.to_le_bytes());
+        buf[4..].copy_from_slice(&self.timestamp.to_le_bytes());
+        store.store(Slot::Ina237Calibration, &buf).await
     }
 }
 
@@ -217,67 +781,175 @@ where
 {
     I2cError(<I as ErrorType>::Error),
     InvalidDeviceId,
+    /// A calibration measured a shunt resistance SHUNT_CAL can't represent.
+    ShuntOutOfRange,
 }
 
+#[derive(Clone, Format)]
 pub struct TickOutput {
     pub bus_voltage: f32,
+    /// Temperature compensated, see `compensate_current`.
     pub current: f32,
-    pub shunt_voltage: f32,
+    pub current_raw: f32,
+    pub shunt_voltage_mv: f32,
+    pub die_temperature: f32,
+    /// DIAG_ALRT.TMPOL, the die is over `DIE_TEMP_LIMIT`.
+    pub over_temperature: bool,
+    /// DIAG_ALRT.MATHOF, usually a SHUNT_CAL that doesn't suit the shunt and current.
+    pub math_overflow: bool,
 }
 
+pub type Ina237Bus = DualI2cDevice;
+pub type Ina237Device = Ina237<Ina237Bus>;
+
 pub struct Ina237<I> {
     addr: u8,
     i2c: I,
+    shunt_ohms: f32,
     recoverable_errors: usize,
     last_reading: Instant,
     time_between_reading: Duration,
+    continuous: bool,
+    /// When the ADC was last woken, `None` while it is powered down.
+    powered_on_since: Option<Instant>,
+    /// Time the ADC spent on before `powered_on_since`.
+    powered_on: Duration,
+    power_down_cycles: u32,
+    /// ALERT, asserted low on conversion ready, if it is wired to a GPIO.
+    alert_pin: Option<Input<'static>>,
+    /// Die temperature `read_current` compensates to.
+    reference_temp: f32,
+    /// Last die temperature read, the reference until then.
+    die_temperature: f32,
 }
 
+/// Keep `SharedState` up to date from the readings published by `continuous_reading`.
+#[embassy_executor::task]
+pub async fn record_task(shared: &'static Mutex<SharedState>) -> ! {
+    let task = task_registry::INA237_STATE;
+    let mut subscriber = events::subscribe();
+    loop {
+        task_registry::set_status(task, TaskStatus::Waiting);
+        if let SensorEvent::Ina237(output) = events::next(&mut subscriber).await {
+            task_registry::iteration(task);
+            shared.lock().await.record_success(&output);
+        }
+    }
+}
+
+/// Write the accumulated energy to flash every 10 minutes so it survives a reboot.
+#[embassy_executor::task]
+pub async fn energy_persist_task(shared: &'static Mutex<SharedState>, store: &'static FlashStore) {
+    let mut last_saved = None;
+    let task = task_registry::INA237_ENERGY_PERSIST;
+    loop {
+        Timer::after(ENERGY_PERSIST_INTERVAL).await;
+        task_registry::iteration(task);
+        let energy = EnergyAccumulator::new(shared.lock().await.energy().watt_hours());
+        // Skip unchanged values to save flash erase cycles
+        if last_saved == Some(energy.watt_hours()) {
+            task_registry::set_status(task, TaskStatus::Waiting);
+            continue;
+        }
+        match energy.save(store).await {
+            Ok(()) => {
+                last_saved = Some(energy.watt_hours());
+                task_registry::set_status(task, TaskStatus::Waiting);
+            }
+            Err(e) => {
+                error!("Unable to save ina237 energy: {:?}", e);
+                task_registry::set_status(task, TaskStatus::Error);
+            }
+        }
+    }
+}
+
+/// With `low_power` the INA237 is left powered down between one-shot reads every
+/// `LOW_POWER_INTERVAL`, rather than converting continuously.
 #[embassy_executor::task]
 pub async fn continuous_reading(
-    device: &'static mut Ina237<I2cDevice<'static, CriticalSectionRawMutex, I2c0>>,
+    device: &'static Mutex<Ina237Device>,
     shared: &'static Mutex<SharedState>,
+    low_power: bool,
 ) {
+    let task = task_registry::INA237_READING;
     loop {
-        if let Err(e) = device.reset().await {
-            error!("Unable to reset ina237: {:?}", e);
-        }
-        if let Err(e) = device.init().await {
-            error!("Unable to init ina237: {:?}", e);
+        {
+            let mut device = device.lock().await;
+            if let Err(e) = device.reset().await {
+                error!("Unable to reset ina237: {:?}", e);
+            }
+            if let Err(e) = device.init().await {
+                error!("Unable to init ina237: {:?}", e);
+            }
+            if low_power {
+                if let Err(e) = device.power_down().await {
+                    error!("Unable to power down ina237: {:?}", e);
+                }
+            } else if let Err(e) = device.start_continuous().await {
+                error!("Unable to start ina237 continuous conversion: {:?}", e);
+            }
+            shared.lock().await.set_continuous(device.is_continuous());
         }
 
         Timer::after_secs(5).await;
 
         loop {
-            let result = embassy_time::with_timeout(TICK_TIMEOUT, device.tick()).await;
+            task_registry::iteration(task);
+            let (result, recoverable_errors, power_down_cycles, powered_on_ms) = {
+                let mut device = device.lock().await;
+                let result = embassy_time::with_timeout(TICK_TIMEOUT, device.tick()).await;
+                (
+                    result,
+                    device.recoverable_errors,
+                    device.power_down_cycles(),
+                    device.powered_on_ms(),
+                )
+            };
 
             let mut state = match embassy_time::with_timeout(TICK_TIMEOUT, shared.lock()).await {
                 Ok(v) => v,
                 Err(_) => {
                     error!("Timeout getting state lock");
+                    task_registry::set_status(task, TaskStatus::Error);
                     break;
                 }
             };
+            state.set_power_stats(power_down_cycles, powered_on_ms);
 
             match result {
                 Ok(Ok(output)) => {
-                    state.record_success(&output);
-                    state.set_recoverable_errors(device.recoverable_errors);
+                    // Recorded by `record_task`
+                    events::publish(SensorEvent::Ina237(output));
+                    state.set_recoverable_errors(recoverable_errors);
                 }
                 Ok(Err(e)) => {
-                    error!("Error reading ina237: {:?}", e);
-                    state.set_recoverable_errors(device.recoverable_errors);
+                    log_error!(ErrorSource::Ina237, "Error reading ina237: {:?}", e);
+                    record_sensor_error("ina237", &e.into());
+                    state.set_recoverable_errors(recoverable_errors);
                     state.record_reset();
+                    task_registry::set_status(task, TaskStatus::Error);
                     break;
                 }
                 Err(_) => {
-                    state.set_recoverable_errors(device.recoverable_errors);
+                    log_error!(ErrorSource::Ina237, "Timeout reading ina237");
+                    record_sensor_error("ina237", &SensorError::Timeout);
+                    state.set_recoverable_errors(recoverable_errors);
                     state.record_timeout();
                     state.record_reset();
+                    task_registry::set_status(task, TaskStatus::Error);
                     break;
                 }
             }
-            // Timer::after_millis(100).await;
+            drop(state);
+            task_registry::set_status(task, TaskStatus::Waiting);
+
+            if low_power {
+                Timer::after(LOW_POWER_INTERVAL).await;
+            } else {
+                // Let the HTTP handlers in for calibration before the next tick grabs the device.
+                Timer::after_millis(1).await;
+            }
         }
     }
 }
@@ -290,9 +962,18 @@ where
         let mut dev = Self {
             addr,
             i2c,
+            shunt_ohms: DEFAULT_SHUNT_OHMS,
             recoverable_errors: 0,
             last_reading: Instant::now(),
             time_between_reading: Duration::from_millis(500),
+            continuous: false,
+            // Converting continuously from power on until told otherwise
+            powered_on_since: Some(Instant::now()),
+            powered_on: Duration::from_ticks(0),
+            power_down_cycles: 0,
+            alert_pin: None,
+            reference_temp: CURRENT_REFERENCE_TEMP_C,
+            die_temperature: CURRENT_REFERENCE_TEMP_C,
         };
 
         // Check device ID with timeout
@@ -312,7 +993,7 @@ where
                 return Err(Ina237Error::InvalidDeviceId);
             }
         };
-        if manuf_id != 21577 {
+        if manuf_id != INA237_MANUFACTURER_ID {
             return Err(Ina237Error::InvalidDeviceId);
         }
 
@@ -324,50 +1005,200 @@ where
         // Reset device and accumulation registers
         self.write_register(INA237_REG_CONFIG, INA237_CONFIG_RST)
             .await?;
+        // ADC_CONFIG resets to continuous conversion of everything
+        self.powered_on_since.get_or_insert_with(Instant::now);
+        self.continuous = false;
         Timer::after_millis(100).await;
         Ok(())
     }
 
     pub async fn init(&mut self) -> Result<(), Ina237Error<I>> {
+        i2c_health::timed(I2cOperation::Ina237Init, self.init_untimed()).await
+    }
+
+    async fn init_untimed(&mut self) -> Result<(), Ina237Error<I>> {
         self.last_reading = Instant::now();
 
-        let config = INA237_MODE_CONT_SHUNT_BUS
-            | INA237_VBUSCT_4120US
-            | INA237_VSHCT_4120US
-            | INA237_VTCT_4120US
-            | INA237_AVG_64;
-        self.write_register(INA237_REG_ADC_CONFIG, config).await?;
+        self.write_register(
+            INA237_REG_SHUNT_CAL,
+            compute_shunt_cal(MAX_EXPECTED_CURRENT, self.shunt_ohms),
+        )
+        .await?;
+        self.write_register(INA237_REG_TEMP_LIMIT, DIE_TEMP_LIMIT)
+            .await?;
+        if self.alert_pin.is_some() {
+            // ALERT follows CNVRF, which reading DIAG_ALRT clears
+            self.write_register(INA237_REG_DIAG_ALRT, INA237_DIAG_CNVR)
+                .await?;
+        }
+        Timer::after_millis(100).await;
 
-        let calib = (819.2e6 * CURRENT_LSB * 0.015) as u16;
+        Ok(())
+    }
 
-        self.write_register(INA237_REG_SHUNT_CAL, calib).await?;
-        Timer::after_millis(100).await;
+    /// Convert shunt and bus voltage continuously, so `tick` reads the latest result
+    /// without triggering a conversion or polling for it.
+    pub async fn start_continuous(&mut self) -> Result<(), Ina237Error<I>> {
+        self.write_register(INA237_REG_ADC_CONFIG, CONTINUOUS_ADC_CONFIG)
+            .await?;
+        self.powered_on_since.get_or_insert_with(Instant::now);
+        self.continuous = true;
+        self.last_reading = Instant::now();
+        info!("ina237: Continuous conversion started");
+        Ok(())
+    }
 
+    /// Power down the ADC.  `tick` goes back to triggering one-shot conversions.
+    pub async fn stop_continuous(&mut self) -> Result<(), Ina237Error<I>> {
+        self.power_down().await?;
+        self.continuous = false;
+        info!("ina237: Continuous conversion stopped");
         Ok(())
     }
 
-    /// Perform one full read cycle: trigger conversion, wait for ready, read all registers.
+    /// Shut the ADC down, about 3 µA instead of 1 mA, until the next `wake_up`.  Clears the
+    /// conversion settings too.
+    pub async fn power_down(&mut self) -> Result<(), Ina237Error<I>> {
+        self.write_register(INA237_REG_ADC_CONFIG, 0x0000).await?;
+        if let Some(since) = self.powered_on_since.take() {
+            self.powered_on += since.elapsed();
+            self.power_down_cycles += 1;
+        }
+        Ok(())
+    }
+
+    /// Restore the one-shot conversion settings after `power_down`.  The ADC stays in
+    /// shutdown mode until `trigger` starts a conversion.
+    pub async fn wake_up(&mut self) -> Result<(), Ina237Error<I>> {
+        self.write_register(
+            INA237_REG_ADC_CONFIG,
+            ONE_SHOT_ADC_CONFIG | INA237_MODE_SHUTDOWN,
+        )
+        .await?;
+        self.powered_on_since.get_or_insert_with(Instant::now);
+        Ok(())
+    }
+
+    /// Times `power_down` has turned the ADC off.
+    pub fn power_down_cycles(&self) -> u32 {
+        self.power_down_cycles
+    }
+
+    /// Time the ADC has spent powered on since boot, including now if it is on.
+    pub fn powered_on_ms(&self) -> u64 {
+        let current = self
+            .powered_on_since
+            .map_or(Duration::from_ticks(0), |since| since.elapsed());
+        (self.powered_on + current).as_millis()
+    }
+
+    pub fn is_continuous(&self) -> bool {
+        self.continuous
+    }
+
+    pub fn shunt_ohms(&self) -> f32 {
+        self.shunt_ohms
+    }
+
+    /// The SHUNT_CAL value `init` and `calibrate` program for the current shunt resistance.
+    pub fn expected_shunt_cal(&self) -> u16 {
+        compute_shunt_cal(MAX_EXPECTED_CURRENT, self.shunt_ohms)
+    }
+
+    /// Read every INA237 register in 0x00-0x0B and 0x3E-0x3F as `(address, value)`.
+    pub async fn read_all_registers(&mut self) -> Result<Vec<(u8, u16), 16>, Ina237Error<I>> {
+        let mut registers = Vec::new();
+        for register in DUMPED_REGISTERS {
+            let value = self.read_register(register).await?;
+            // Can't fail, there are fewer registers than capacity
+            let _ = registers.push((register, value));
+        }
+        Ok(registers)
+    }
+
+    /// Wait on `pin` for one-shot conversions rather than polling DIAG_ALRT.  Takes effect
+    /// at the next `init`, which points ALERT at conversion ready.
+    pub fn set_alert_pin(&mut self, pin: Input<'static>) {
+        self.alert_pin = Some(pin);
+    }
+
+    /// Set the shunt resistance used by the next `init`, without touching the device.
+    pub fn set_shunt_ohms(&mut self, shunt_ohms: f32) {
+        self.shunt_ohms = shunt_ohms;
+    }
+
+    /// Measure the shunt resistance with a known current flowing through it and program
+    /// the matching SHUNT_CAL value.  Returns the measured resistance.  Nothing is changed
+    /// if the current is below `MIN_CURRENT_FOR_SHUNT_OHMS` or the resistance is one
+    /// SHUNT_CAL can't represent, `ShuntOutOfRange`.
+    pub async fn calibrate(&mut self, known_current_a: f32) -> Result<f32, Ina237Error<I>> {
+        if !known_current_a.is_finite() || known_current_a < MIN_CURRENT_FOR_SHUNT_OHMS {
+            return Err(Ina237Error::ShuntOutOfRange);
+        }
+        let shunt_voltage = self.read_shunt_voltage_mv().await? / 1000.;
+        let shunt_ohms = shunt_voltage / known_current_a;
+        if !shunt_ohms_in_range(shunt_ohms) {
+            error!(
+                "ina237: Shunt {} ohms from {} V at {} A is out of range",
+                shunt_ohms, shunt_voltage, known_current_a
+            );
+            return Err(Ina237Error::ShuntOutOfRange);
+        }
+        info!(
+            "ina237: Calibrated shunt {} ohms from {} V at {} A",
+            shunt_ohms, shunt_voltage, known_current_a
+        );
+
+        self.shunt_ohms = shunt_ohms;
+        self.write_register(
+            INA237_REG_SHUNT_CAL,
+            compute_shunt_cal(MAX_EXPECTED_CURRENT, shunt_ohms),
+        )
+        .await?;
+        Ok(shunt_ohms)
+    }
+
+    /// Perform one full read cycle and read all registers.  In continuous mode this waits
+    /// for the next result to be due, otherwise it triggers a conversion and waits for it.
     pub async fn tick(&mut self) -> Result<TickOutput, Ina237Error<I>> {
-        // self.trigger().await?;
+        if self.continuous {
+            Timer::at(self.last_reading + CONTINUOUS_CONVERSION_TIME).await;
+            self.last_reading = Instant::now();
+        } else {
+            self.wake_up().await?;
+            // Let the reference settle before converting
+            Timer::after_millis(1).await;
+            self.trigger().await?;
+            self.wait_for_value().await?;
+        }
 
-        self.wait_for_value().await?;
         let bus_voltage = self.read_bus_voltage().await?;
-        let current = self.read_current().await?;
-        let shunt_voltage = self.read_shunt_voltage().await?;
+        // First, so the current is compensated with this conversion's temperature
+        let die_temperature = self.read_die_temperature().await?;
+        let current_raw = self.read_current_raw().await?;
+        let current = compensate_current(current_raw, die_temperature, self.reference_temp);
+        let shunt_voltage_mv = self.read_shunt_voltage_mv().await?;
+        let diag_alrt = self.read_register(INA237_REG_DIAG_ALRT).await?;
+        if !self.continuous {
+            self.power_down().await?;
+        }
         Ok(TickOutput {
             bus_voltage,
             current,
-            shunt_voltage,
+            current_raw,
+            shunt_voltage_mv,
+            die_temperature,
+            over_temperature: diag_alrt & INA237_DIAG_TMPOL != 0,
+            math_overflow: diag_alrt & INA237_DIAG_MATHOF != 0,
         })
     }
 
     pub async fn trigger(&mut self) -> Result<(), Ina237Error<I>> {
-        let config = INA237_MODE_TRIG_SHUNT_BUS
-            | INA237_VBUSCT_4120US
-            | INA237_VSHCT_4120US
-            | INA237_VTCT_4120US
-            | INA237_AVG_1;
-        self.write_register(INA237_REG_ADC_CONFIG, config).await?;
+        self.write_register(
+            INA237_REG_ADC_CONFIG,
+            INA237_MODE_TRIG_ALL | ONE_SHOT_ADC_CONFIG,
+        )
+        .await?;
         Ok(())
     }
 
@@ -375,7 +1206,23 @@ where
         let start = Instant::now();
         let expected_reading_at = self.last_reading.saturating_add(self.time_between_reading);
         let expected_wait_time = expected_reading_at.saturating_duration_since(start);
-        Timer::after(expected_wait_time).await;
+
+        if let Some(pin) = self.alert_pin.as_mut() {
+            match with_timeout(ALERT_TIMEOUT, pin.wait_for_low()).await {
+                Ok(()) => {
+                    let latency_ms = start.elapsed().as_micros() as f32 / 1000.;
+                    ALERT_LATENCY.lock(|histogram| histogram.borrow_mut().sample(latency_ms));
+                    self.last_reading = Instant::now();
+                    return Ok(());
+                }
+                Err(_) => warn!(
+                    "ina237: No ALERT after {} ms, polling",
+                    ALERT_TIMEOUT.as_millis()
+                ),
+            }
+        } else {
+            Timer::after(expected_wait_time).await;
+        }
         loop {
             let diag_alrt = self.read_register(INA237_REG_DIAG_ALRT).await?;
 
@@ -402,17 +1249,28 @@ where
         let raw_temp = raw_temp >> 4;
         // Temperature LSB = : 125 m°C/LSB
         let temperature = (raw_temp as f32) * 125.0 / 1000.;
+        self.die_temperature = temperature;
         Ok(temperature)
     }
 
-    pub async fn read_shunt_voltage(&mut self) -> Result<f32, Ina237Error<I>> {
+    /// Shunt voltage in millivolts, assuming ADCRANGE is left at its reset value of 0.
+    pub async fn read_shunt_voltage_mv(&mut self) -> Result<f32, Ina237Error<I>> {
         let raw_voltage = self.read_register(INA237_REG_SHUNT_VOLTAGE).await? as i16;
-
-        // Return raw value as float
-        Ok(raw_voltage as f32)
+        Ok(raw_voltage as f32 * SHUNT_VOLTAGE_LSB_MV)
     }
 
+    /// Current in amps, compensated for the last die temperature read.
     pub async fn read_current(&mut self) -> Result<f32, Ina237Error<I>> {
+        let current = self.read_current_raw().await?;
+        Ok(compensate_current(
+            current,
+            self.die_temperature,
+            self.reference_temp,
+        ))
+    }
+
+    /// Current in amps as the INA237 measured it.
+    pub async fn read_current_raw(&mut self) -> Result<f32, Ina237Error<I>> {
         let raw_current = self.read_register(INA237_REG_CURRENT).await? as i16;
         // Current = raw_value × current_lsb
         let current = (raw_current as f32) * CURRENT_LSB;
@@ -431,11 +1289,12 @@ where
 
         let mut attempts = 1;
         loop {
-            match self
-                .i2c
-                .write_read(self.addr, &[register], &mut buffer)
-                .await
-                .map_err(Ina237Error::I2cError)
+            match i2c_health::timed(
+                I2cOperation::Ina237Read,
+                self.i2c.write_read(self.addr, &[register], &mut buffer),
+            )
+            .await
+            .map_err(Ina237Error::I2cError)
             {
                 Ok(_) => break,
                 Err(e) => {
@@ -460,11 +1319,12 @@ where
 
         let mut attempts = 1;
         loop {
-            match self
-                .i2c
-                .write_read(self.addr, &[register], &mut buffer)
-                .await
-                .map_err(Ina237Error::I2cError)
+            match i2c_health::timed(
+                I2cOperation::Ina237Read,
+                self.i2c.write_read(self.addr, &[register], &mut buffer),
+            )
+            .await
+            .map_err(Ina237Error::I2cError)
             {
                 Ok(_) => break,
                 Err(e) => {
@@ -0,0 +1,137 @@
+use core::future::Future;
+
+use picoserve::response::chunked::{ChunkWriter, Chunks, ChunksWritten};
+
+use crate::prometheus::{MetricSink, MetricsRender};
+
+/// Escape a tag key, tag value or field key for the InfluxDB line protocol.
+///
+/// Commas, spaces and equals signs are backslash escaped.  Returns `false` if the escaped
+/// value does not fit in `buf`.
+pub fn to_influx_tag_value(s: &str, buf: &mut heapless::String<64>) -> bool {
+    buf.clear();
+    for c in s.chars() {
+        if matches!(c, ',' | ' ' | '=') && buf.push('\\').is_err() {
+            return false;
+        }
+        if buf.push(c).is_err() {
+            return false;
+        }
+    }
+    true
+}
+
+/// A single line protocol point: `measurement,tag=value field=value [timestamp]`.
+pub struct InfluxPoint<'a, const FIELDS: usize> {
+    measurement: &'a str,
+    tags: &'a [(&'a str, &'a str)],
+    fields: [(&'a str, f32); FIELDS],
+    timestamp_ns: Option<u64>,
+}
+
+pub const fn point<'a, const FIELDS: usize>(
+    measurement: &'a str,
+    tags: &'a [(&'a str, &'a str)],
+    fields: [(&'a str, f32); FIELDS],
+) -> InfluxPoint<'a, FIELDS> {
+    InfluxPoint {
+        measurement,
+        tags,
+        fields,
+        timestamp_ns: None,
+    }
+}
+
+impl<'a, const FIELDS: usize> InfluxPoint<'a, FIELDS> {
+    /// Attach a timestamp in nanoseconds since the Unix epoch.  Without one, `None` here,
+    /// InfluxDB uses the time the point was received.
+    pub const fn with_timestamp(mut self, timestamp_ns: Option<u64>) -> Self {
+        self.timestamp_ns = timestamp_ns;
+        self
+    }
+}
+
+pub trait WriteInflux<'a> {
+    fn write_chunks<W>(self, chunk_writer: &'a mut W) -> impl Future<Output = Result<(), W::Error>>
+    where
+        W: MetricSink;
+}
+
+impl<'a, const FIELDS: usize> WriteInflux<'a> for InfluxPoint<'a, FIELDS> {
+    async fn write_chunks<W: MetricSink>(self, chunk_writer: &'a mut W) -> Result<(), W::Error> {
+        let mut escaped = heapless::String::<64>::new();
+
+        // Measurement names only need commas and spaces escaped, which is a subset of tags.
+        if !to_influx_tag_value(self.measurement, &mut escaped) {
+            return Ok(());
+        }
+        write!(chunk_writer, "{}", escaped).await?;
+
+        for (key, value) in self.tags {
+            if !to_influx_tag_value(key, &mut escaped) {
+                continue;
+            }
+            write!(chunk_writer, ",{}=", escaped).await?;
+            if !to_influx_tag_value(value, &mut escaped) {
+                escaped.clear();
+            }
+            write!(chunk_writer, "{}", escaped).await?;
+        }
+
+        // Line protocol has no NaN, leave out fields without a valid value
+        let mut separator = " ";
+        for (key, value) in self.fields.iter() {
+            if value.is_nan() || !to_influx_tag_value(key, &mut escaped) {
+                continue;
+            }
+            write!(chunk_writer, "{}{}={}", separator, escaped, value).await?;
+            separator = ",";
+        }
+
+        if let Some(timestamp_ns) = self.timestamp_ns {
+            write!(chunk_writer, " {}", timestamp_ns).await?;
+        }
+        writeln!(chunk_writer).await?;
+        Ok(())
+    }
+}
+
+pub trait InfluxWriter<E> {
+    fn write_point<'a>(
+        &'a mut self,
+        point: impl WriteInflux<'a>,
+    ) -> impl Future<Output = Result<(), E>>;
+}
+
+impl<W: MetricSink> InfluxWriter<W::Error> for W {
+    async fn write_point<'a>(&'a mut self, point: impl WriteInflux<'a>) -> Result<(), W::Error> {
+        point.write_chunks(self).await
+    }
+}
+
+pub struct InfluxResponse<T>
+where
+    T: MetricsRender,
+{
+    points: T,
+}
+
+impl<T: MetricsRender> InfluxResponse<T> {
+    pub fn new(points: T) -> Self {
+        InfluxResponse { points }
+    }
+}
+
+impl<T: MetricsRender> Chunks for InfluxResponse<T> {
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        self.points.write_chunks(&mut chunk_writer).await?;
+        chunk_writer.finalize().await
+    }
+}
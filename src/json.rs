@@ -0,0 +1,200 @@
+//! JSON written into a fixed buffer, for responses `serde` can't describe, such as objects
+//! whose keys are only known at runtime.
+//!
+//! The encoder puts in the commas and escapes strings.  Running out of room is remembered
+//! rather than returned from every call, so the builder methods can be chained, and is
+//! reported once by `JsonEncoder::into_response`.
+
+use core::fmt::Write;
+
+use heapless::String;
+use picoserve::response::chunked::{ChunkWriter, ChunkedResponse, Chunks, ChunksWritten};
+use picoserve::response::StatusCode;
+
+/// Anything that can be written as a JSON value.
+pub trait JsonValue {
+    fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result;
+}
+
+impl JsonValue for bool {
+    fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result {
+        out.write_str(if *self { "true" } else { "false" })
+    }
+}
+
+/// JSON has no NaN or infinity, so those are written as `null`.
+impl JsonValue for f32 {
+    fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result {
+        if self.is_finite() {
+            write!(out, "{}", self)
+        } else {
+            out.write_str("null")
+        }
+    }
+}
+
+impl JsonValue for u32 {
+    fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result {
+        write!(out, "{}", self)
+    }
+}
+
+impl JsonValue for u64 {
+    fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result {
+        write!(out, "{}", self)
+    }
+}
+
+impl JsonValue for i32 {
+    fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result {
+        write!(out, "{}", self)
+    }
+}
+
+impl JsonValue for &str {
+    fn write_json<W: Write>(&self, out: &mut W) -> core::fmt::Result {
+        out.write_char('"')?;
+        for c in self.chars() {
+            match c {
+                '"' => out.write_str("\\\"")?,
+                '\\' => out.write_str("\\\\")?,
+                '\n' => out.write_str("\\n")?,
+                '\r' => out.write_str("\\r")?,
+                '\t' => out.write_str("\\t")?,
+                c if (c as u32) < 0x20 => write!(out, "\\u{:04x}", c as u32)?,
+                c => out.write_char(c)?,
+            }
+        }
+        out.write_char('"')
+    }
+}
+
+pub struct JsonEncoder<const N: usize> {
+    buffer: String<N>,
+    /// Set after each value, so the next one in the same object or array gets a comma.
+    needs_comma: bool,
+    overflowed: bool,
+}
+
+impl<const N: usize> JsonEncoder<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: String::new(),
+            needs_comma: false,
+            overflowed: false,
+        }
+    }
+
+    /// False once anything didn't fit.
+    pub fn is_ok(&self) -> bool {
+        !self.overflowed
+    }
+
+    fn check(&mut self, result: core::fmt::Result) {
+        if result.is_err() {
+            self.overflowed = true;
+        }
+    }
+
+    fn raw(&mut self, text: &str) {
+        let result = self.buffer.push_str(text).map_err(|_| core::fmt::Error);
+        self.check(result);
+    }
+
+    fn separator(&mut self) {
+        if self.needs_comma {
+            self.raw(",");
+        }
+    }
+
+    fn value(&mut self, value: impl JsonValue) {
+        self.separator();
+        let result = value.write_json(&mut self.buffer);
+        self.check(result);
+        self.needs_comma = true;
+    }
+
+    /// `"key":`, for the value written next.
+    fn key(&mut self, key: &str) -> &mut Self {
+        self.value(key);
+        self.raw(":");
+        self.needs_comma = false;
+        self
+    }
+
+    /// An object, as the whole document or the value of a `field_object` or array entry.
+    pub fn object(&mut self, f: impl FnOnce(&mut Self)) -> &mut Self {
+        self.separator();
+        self.raw("{");
+        self.needs_comma = false;
+        f(self);
+        self.raw("}");
+        self.needs_comma = true;
+        self
+    }
+
+    /// A field of the object being written.
+    pub fn field(&mut self, key: &str, value: impl JsonValue) -> &mut Self {
+        self.key(key).value(value);
+        self
+    }
+
+    /// A field of the object being written whose value is itself an object.
+    pub fn field_object(&mut self, key: &str, f: impl FnOnce(&mut Self)) -> &mut Self {
+        self.key(key).object(f)
+    }
+
+    /// A field of the object being written whose value is an array.
+    pub fn array(&mut self, key: &str, f: impl FnOnce(&mut ArrayEncoder<'_, N>)) -> &mut Self {
+        self.key(key);
+        self.raw("[");
+        self.needs_comma = false;
+        f(&mut ArrayEncoder(self));
+        self.raw("]");
+        self.needs_comma = true;
+        self
+    }
+
+    /// The JSON as an `application/json` response, or a 500 if it didn't fit in `N` bytes.
+    pub fn into_response(self) -> Result<ChunkedResponse<Self>, (StatusCode, &'static str)> {
+        if self.overflowed {
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Response too large\n"));
+        }
+        Ok(ChunkedResponse::new(self))
+    }
+}
+
+impl<const N: usize> Default for JsonEncoder<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Entries of an array started by `JsonEncoder::array`.
+pub struct ArrayEncoder<'a, const N: usize>(&'a mut JsonEncoder<N>);
+
+impl<const N: usize> ArrayEncoder<'_, N> {
+    pub fn value(&mut self, value: impl JsonValue) -> &mut Self {
+        self.0.value(value);
+        self
+    }
+
+    pub fn object(&mut self, f: impl FnOnce(&mut JsonEncoder<N>)) -> &mut Self {
+        self.0.object(f);
+        self
+    }
+}
+
+impl<const N: usize> Chunks for JsonEncoder<N> {
+    fn content_type(&self) -> &'static str {
+        "application/json"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        writeln!(chunk_writer, "{}", self.buffer.as_str()).await?;
+        chunk_writer.finalize().await
+    }
+}
@@ -1,25 +1,717 @@
-#![no_std]
+#![cfg_attr(not(test), no_std)]
+#![recursion_limit = "512"]
 
+use core::cell::RefCell;
+use core::future::Future;
+
+use defmt::{error, info, Format};
+use embassy_embedded_hal::shared_bus::I2cDeviceError;
 use embassy_rp::i2c::Async;
-use embassy_rp::peripherals::I2C0;
+use embassy_rp::peripherals::{I2C0, I2C1};
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_sync::mutex::Mutex as EmbMutex;
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::mutex::{Mutex as EmbMutex, MutexGuard};
+use embassy_sync::signal::Signal;
+use embassy_time::TimeoutError;
+use embassy_time::{with_timeout, Duration, Instant, Ticker, Timer};
+use portable_atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
 
 pub mod adc_temp_sensor;
+pub mod alarm;
+pub mod base64;
+pub mod battery;
+pub mod bh1750;
+pub mod bmp280;
+pub mod climate_math;
+pub mod config;
+pub mod csv;
+pub mod dht22;
+pub mod events;
+pub mod flash_log;
+pub mod flash_sample_set;
+pub mod flash_store;
+pub mod heat_pump_controller;
+pub mod history;
 pub mod http;
+pub mod i2c_health;
 pub mod ina237;
+pub mod influx;
+pub mod json;
+pub mod mem_info;
+pub mod mqtt;
+pub mod panic_info;
 pub mod prometheus;
+pub mod push;
+pub mod remote_write;
 pub mod sht30;
-// pub mod tcp_logger;
+pub mod statsd;
+pub mod task_registry;
+#[cfg(feature = "tcp-logger")]
+pub mod tcp_logger;
+pub mod vpd;
+pub mod wifi;
+// Only one global logger can be linked, tcp_logger replaces RTT
+#[cfg(not(feature = "tcp-logger"))]
 use defmt_rtt as _;
-use heapless::Vec;
+use heapless::Deque;
+use serde::Serialize;
 use static_cell::StaticCell;
+use task_registry::TaskStatus;
+
+use crate::adc_temp_sensor::AdcError;
+use crate::bmp280::Bmp280Error;
+use crate::dht22::Dht22Error;
+use crate::ina237::{Ina237Bus, Ina237Error};
+use crate::prometheus::sample::Sample;
+use crate::prometheus::MetricSink;
 
 pub type Mutex<T> = EmbMutex<CriticalSectionRawMutex, T>;
 
+/// How long to wait for a lock that is only ever held briefly before assuming a task
+/// holding it is stuck.
+pub const LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub trait MutexExt<T> {
+    /// `lock`, giving up after `timeout` rather than waiting forever on a task that never
+    /// releases it.
+    fn lock_with_timeout<'a>(
+        &'a self,
+        timeout: Duration,
+    ) -> impl Future<Output = Result<MutexGuard<'a, CriticalSectionRawMutex, T>, TimeoutError>>
+    where
+        T: 'a;
+}
+
+impl<T> MutexExt<T> for Mutex<T> {
+    async fn lock_with_timeout<'a>(
+        &'a self,
+        timeout: Duration,
+    ) -> Result<MutexGuard<'a, CriticalSectionRawMutex, T>, TimeoutError>
+    where
+        T: 'a,
+    {
+        with_timeout(timeout, self.lock()).await
+    }
+}
+
+/// Size of the Pico W's external QSPI flash.
+pub const FLASH_SIZE: usize = 2 * 1024 * 1024;
+
 pub type I2c0 = embassy_rp::i2c::I2c<'static, I2C0, Async>;
 pub type I2c0Bus = Mutex<I2c0>;
 pub static I2C_BUS_0: StaticCell<I2c0Bus> = StaticCell::new();
+embassy_rp::bind_interrupts!(
+    /// Here rather than with main's interrupts so `i2c_health` can set I2C0 up again.
+    pub struct I2c0Irqs {
+        I2C0_IRQ => embassy_rp::i2c::InterruptHandler<I2C0>;
+    }
+);
+/// I2C0 clock at boot, can be changed at runtime with `POST /i2c/frequency`.
+pub const I2C0_DEFAULT_FREQUENCY: u32 = 10_000;
+
+/// The second controller, on GPIO 6 (SDA) and 7 (SCL), for the sensors that are read
+/// often, so they don't wait behind the slow ones on I2C0.
+pub type I2c1 = embassy_rp::i2c::I2c<'static, I2C1, Async>;
+pub type I2c1Bus = Mutex<I2c1>;
+pub static I2C_BUS_1: StaticCell<I2c1Bus> = StaticCell::new();
+pub const I2C1_DEFAULT_FREQUENCY: u32 = 400_000;
+
+/// Which controller a device on a `DualI2cBus` is wired to.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum BusSelector {
+    Bus0,
+    Bus1,
+}
+
+/// Both I2C controllers, so a device's type doesn't depend on which one it was found on.
+pub struct DualI2cBus {
+    bus0: &'static I2c0Bus,
+    bus1: &'static I2c1Bus,
+}
+
+impl DualI2cBus {
+    pub const fn new(bus0: &'static I2c0Bus, bus1: &'static I2c1Bus) -> Self {
+        Self { bus0, bus1 }
+    }
+
+    /// A device on `bus`, sharing it with the others like `I2cDevice`.
+    pub fn device(&'static self, bus: BusSelector) -> DualI2cDevice {
+        DualI2cDevice { buses: self, bus }
+    }
+}
+
+pub struct DualI2cDevice {
+    buses: &'static DualI2cBus,
+    bus: BusSelector,
+}
+
+impl DualI2cDevice {
+    pub fn bus(&self) -> BusSelector {
+        self.bus
+    }
+}
+
+impl embedded_hal::i2c::ErrorType for DualI2cDevice {
+    // The same as `I2cDevice`, so the sensors' errors convert the same way
+    type Error = I2cDeviceError<embassy_rp::i2c::Error>;
+}
+
+impl embedded_hal_async::i2c::I2c for DualI2cDevice {
+    async fn transaction(
+        &mut self,
+        address: u8,
+        operations: &mut [embedded_hal::i2c::Operation<'_>],
+    ) -> Result<(), Self::Error> {
+        let result = match self.bus {
+            BusSelector::Bus0 => {
+                self.buses
+                    .bus0
+                    .lock()
+                    .await
+                    .transaction(address, operations)
+                    .await
+            }
+            BusSelector::Bus1 => {
+                self.buses
+                    .bus1
+                    .lock()
+                    .await
+                    .transaction(address, operations)
+                    .await
+            }
+        };
+        result.map_err(I2cDeviceError::I2c)
+    }
+}
+
+/// Errors from any sensor, so they can be logged and counted in one place.
+#[derive(Format)]
+pub enum SensorError {
+    Adc(AdcError),
+    I2c(embassy_rp::i2c::Error),
+    Ina237(Ina237Error<Ina237Bus>),
+    Timeout,
+    Other,
+}
+
+impl SensorError {
+    /// Value of the `kind` label on `sensor_error_total`.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            SensorError::Adc(_) => "adc",
+            SensorError::I2c(_) => "i2c",
+            SensorError::Ina237(_) => "ina237",
+            SensorError::Timeout => "timeout",
+            SensorError::Other => "other",
+        }
+    }
+}
+
+impl From<AdcError> for SensorError {
+    fn from(value: AdcError) -> Self {
+        SensorError::Adc(value)
+    }
+}
+
+impl From<embassy_rp::i2c::Error> for SensorError {
+    fn from(value: embassy_rp::i2c::Error) -> Self {
+        SensorError::I2c(value)
+    }
+}
+
+impl From<I2cDeviceError<embassy_rp::i2c::Error>> for SensorError {
+    fn from(value: I2cDeviceError<embassy_rp::i2c::Error>) -> Self {
+        match value {
+            I2cDeviceError::I2c(e) => SensorError::I2c(e),
+            I2cDeviceError::Config => SensorError::Other,
+        }
+    }
+}
+
+impl From<Bmp280Error<I2cDeviceError<embassy_rp::i2c::Error>>> for SensorError {
+    fn from(value: Bmp280Error<I2cDeviceError<embassy_rp::i2c::Error>>) -> Self {
+        match value {
+            Bmp280Error::I2c(e) => e.into(),
+            Bmp280Error::InvalidChipId(_) => SensorError::Other,
+        }
+    }
+}
+
+impl From<Dht22Error> for SensorError {
+    fn from(value: Dht22Error) -> Self {
+        match value {
+            Dht22Error::Timeout => SensorError::Timeout,
+            Dht22Error::Checksum => SensorError::Other,
+        }
+    }
+}
+
+impl From<Ina237Error<Ina237Bus>> for SensorError {
+    fn from(value: Ina237Error<Ina237Bus>) -> Self {
+        SensorError::Ina237(value)
+    }
+}
+
+impl From<TimeoutError> for SensorError {
+    fn from(_: TimeoutError) -> Self {
+        SensorError::Timeout
+    }
+}
+
+const SENSOR_NAMES: [&str; 5] = ["adc_temp_sensor", "sht30", "ina237", "bmp280", "dht22"];
+const SENSOR_ERROR_KINDS: [&str; 5] = ["adc", "i2c", "ina237", "timeout", "other"];
+const SENSOR_ERROR_COUNT: usize = SENSOR_NAMES.len() * SENSOR_ERROR_KINDS.len();
+
+// Shared with the core1 readers, which don't have access to `AppState`.
+static SENSOR_ERRORS: [AtomicU32; SENSOR_ERROR_COUNT] =
+    [const { AtomicU32::new(0) }; SENSOR_ERROR_COUNT];
+
+/// Count an error against `sensor`, one of the names returned by `Sensor::name`.
+pub fn record_sensor_error(sensor: &str, error: &SensorError) {
+    let Some(s) = SENSOR_NAMES.iter().position(|name| *name == sensor) else {
+        return;
+    };
+    let k = SENSOR_ERROR_KINDS
+        .iter()
+        .position(|kind| *kind == error.kind())
+        .unwrap_or(SENSOR_ERROR_KINDS.len() - 1);
+    SENSOR_ERRORS[s * SENSOR_ERROR_KINDS.len() + k].fetch_add(1, Ordering::Relaxed);
+}
+
+/// Samples for `sensor_error_total{sensor, kind}`.
+pub fn sensor_error_samples() -> [Sample<'static, 2>; SENSOR_ERROR_COUNT] {
+    core::array::from_fn(|i| {
+        Sample::new(
+            [
+                SENSOR_NAMES[i / SENSOR_ERROR_KINDS.len()],
+                SENSOR_ERROR_KINDS[i % SENSOR_ERROR_KINDS.len()],
+            ],
+            SENSOR_ERRORS[i].load(Ordering::Relaxed) as f32,
+        )
+    })
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize)]
+pub enum ErrorSource {
+    #[serde(rename = "SHT30")]
+    Sht30,
+    #[serde(rename = "INA237")]
+    Ina237,
+    #[serde(rename = "WiFi")]
+    Wifi,
+    #[serde(rename = "ADC")]
+    Adc,
+    #[serde(rename = "HTTP")]
+    Http,
+}
+
+/// One error, or a run of identical ones in a row.
+#[derive(Clone, Copy, Serialize)]
+pub struct ErrorEvent {
+    /// Milliseconds since boot of the latest in the run.
+    #[serde(rename = "ts")]
+    pub timestamp_ms: u64,
+    pub source: ErrorSource,
+    /// CRC-32 of the defmt format string, to look up in the source rather than decode.
+    #[serde(rename = "hash")]
+    pub message_hash: u32,
+    pub count: u32,
+}
+
+/// The last `N` errors, oldest first, with repeats of the newest merged into it.
+pub struct ErrorLog<const N: usize> {
+    events: Deque<ErrorEvent, N>,
+}
+
+impl<const N: usize> ErrorLog<N> {
+    pub const fn new() -> Self {
+        Self {
+            events: Deque::new(),
+        }
+    }
+
+    pub fn record(&mut self, source: ErrorSource, message_hash: u32) {
+        let timestamp_ms = Instant::now().as_millis();
+        if let Some(last) = self.events.back_mut() {
+            if last.source == source && last.message_hash == message_hash {
+                last.timestamp_ms = timestamp_ms;
+                last.count = last.count.saturating_add(1);
+                return;
+            }
+        }
+        if self.events.is_full() {
+            self.events.pop_front();
+        }
+        // Can't fail, there is always room after the pop above.
+        let _ = self.events.push_back(ErrorEvent {
+            timestamp_ms,
+            source,
+            message_hash,
+            count: 1,
+        });
+    }
+
+    pub fn events(&self) -> impl Iterator<Item = &ErrorEvent> {
+        self.events.iter()
+    }
+}
+
+impl<const N: usize> Default for ErrorLog<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Entries in `ERROR_LOG`, served by `GET /log/last-errors`.
+pub const ERROR_LOG_LEN: usize = 16;
+
+// Blocking so errors can be recorded from anywhere, on either core.
+pub static ERROR_LOG: BlockingMutex<CriticalSectionRawMutex, RefCell<ErrorLog<ERROR_LOG_LEN>>> =
+    BlockingMutex::new(RefCell::new(ErrorLog::new()));
+
+/// CRC-32 (IEEE 802.3, reflected polynomial 0xEDB88320).
+pub(crate) fn crc32(data: &[u8]) -> u32 {
+    let mut crc: u32 = 0xFFFF_FFFF;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Add an error to `ERROR_LOG`.  `message` is the format string given to `error!`, use
+/// `log_error!` to log and record it in one go.
+pub fn record_error(source: ErrorSource, message: &str) {
+    let hash = crc32(message.as_bytes());
+    ERROR_LOG.lock(|log| log.borrow_mut().record(source, hash));
+}
+
+/// `error!` a message and `record_error` it under `source`, so the entry in `ERROR_LOG`
+/// is always the hash of the format string that was logged.
+#[macro_export]
+macro_rules! log_error {
+    ($source:expr, $fmt:literal $(, $arg:expr)* $(,)?) => {{
+        ::defmt::error!($fmt $(, $arg)*);
+        $crate::record_error($source, $fmt);
+    }};
+}
+
+/// Something that renders as Prometheus metrics, usually a reading copied out of a
+/// `Sensor`.
+pub trait WriteMetrics {
+    fn write_metrics<W: MetricSink>(
+        &self,
+        writer: &mut W,
+    ) -> impl Future<Output = Result<(), W::Error>>;
+}
+
+/// A missing reading (a failed poll, or hardware that wasn't detected at boot) renders
+/// nothing.
+impl<T: WriteMetrics> WriteMetrics for Option<T> {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        match self {
+            Some(value) => value.write_metrics(writer).await,
+            None => Ok(()),
+        }
+    }
+}
+
+impl WriteMetrics for () {
+    async fn write_metrics<W: MetricSink>(&self, _writer: &mut W) -> Result<(), W::Error> {
+        Ok(())
+    }
+}
+
+impl<A: WriteMetrics, B: WriteMetrics> WriteMetrics for (A, B) {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        self.0.write_metrics(writer).await?;
+        self.1.write_metrics(writer).await
+    }
+}
+
+/// A sensor that can be refreshed and rendered as Prometheus metrics.
+///
+/// `poll` is called once per scrape and caches the latest reading.  `reading` copies it
+/// out so it can be rendered after the lock on the sensors is released.
+pub trait Sensor {
+    type Reading: WriteMetrics;
+
+    fn name(&self) -> &'static str;
+
+    /// Where failed polls go in `ERROR_LOG`, `None` to leave them out.
+    fn error_source(&self) -> Option<ErrorSource> {
+        None
+    }
+
+    fn poll(&mut self) -> impl Future<Output = Result<(), SensorError>>;
+
+    /// Like `poll`, but leaves anything `poll` averages over since the last scrape
+    /// accumulating, for renders such as a push that aren't the scrape.
+    fn peek(&mut self) -> impl Future<Output = Result<(), SensorError>> {
+        self.poll()
+    }
+
+    fn reading(&self) -> Self::Reading;
+}
+
+/// Optional sensors (e.g. hardware that wasn't detected at boot) render nothing.
+impl<S: Sensor> Sensor for Option<S> {
+    type Reading = Option<S::Reading>;
+
+    fn name(&self) -> &'static str {
+        self.as_ref().map_or("none", |sensor| sensor.name())
+    }
+
+    fn error_source(&self) -> Option<ErrorSource> {
+        self.as_ref().and_then(|sensor| sensor.error_source())
+    }
+
+    async fn poll(&mut self) -> Result<(), SensorError> {
+        match self {
+            Some(sensor) => sensor.poll().await,
+            None => Ok(()),
+        }
+    }
+
+    async fn peek(&mut self) -> Result<(), SensorError> {
+        match self {
+            Some(sensor) => sensor.peek().await,
+            None => Ok(()),
+        }
+    }
+
+    fn reading(&self) -> Self::Reading {
+        self.as_ref().map(|sensor| sensor.reading())
+    }
+}
+
+/// A fixed list of sensors built from nested tuples, `(A, (B, (C, ())))`.
+///
+/// `async fn` in traits isn't object safe, so rather than a `Vec<&dyn Sensor>` the list is
+/// a cons list and the compiler generates the loop.
+pub trait SensorList {
+    /// The readings of every sensor, as a cons list of the same shape.
+    type Readings: WriteMetrics;
+
+    fn poll_all(&mut self) -> impl Future<Output = ()>;
+
+    /// `Sensor::peek` every sensor.
+    fn peek_all(&mut self) -> impl Future<Output = ()>;
+
+    fn readings(&self) -> Self::Readings;
+}
+
+impl SensorList for () {
+    type Readings = ();
+
+    async fn poll_all(&mut self) {}
+
+    async fn peek_all(&mut self) {}
+
+    fn readings(&self) {}
+}
+
+impl<S: Sensor, R: SensorList> SensorList for (S, R) {
+    type Readings = (S::Reading, R::Readings);
+
+    async fn poll_all(&mut self) {
+        if let Err(e) = self.0.poll().await {
+            poll_failed(&self.0, e);
+        }
+        self.1.poll_all().await;
+    }
+
+    async fn peek_all(&mut self) {
+        if let Err(e) = self.0.peek().await {
+            poll_failed(&self.0, e);
+        }
+        self.1.peek_all().await;
+    }
+
+    fn readings(&self) -> Self::Readings {
+        (self.0.reading(), self.1.readings())
+    }
+}
+
+fn poll_failed<S: Sensor>(sensor: &S, e: SensorError) {
+    match sensor.error_source() {
+        Some(source) => log_error!(source, "Error polling {}: {}", sensor.name(), e),
+        None => error!("Error polling {}: {}", sensor.name(), e),
+    }
+    record_sensor_error(sensor.name(), &e);
+}
+
+/// When the device last got something out over the network: any HTTP response, or a push
+/// or remote write that was accepted.  main's watchdog feeder lets the watchdog reset the
+/// device once this is 2 minutes old.
+pub static LAST_DELIVERY: Mutex<Instant> = Mutex::new(Instant::MIN);
+
+/// Mark the device as alive for the watchdog, see `LAST_DELIVERY`.
+pub async fn record_delivery() {
+    *LAST_DELIVERY.lock().await = Instant::now();
+}
+
+/// Seconds counted by `heartbeat_task`.  If an executor task stops yielding this falls
+/// behind the uptime.
+pub static HEARTBEAT: AtomicU64 = AtomicU64::new(0);
+
+/// How far the heartbeat may lag the uptime before it is counted as skipped.
+const HEARTBEAT_SLACK_SECS: u64 = 5;
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Count a heartbeat every second.  A `Ticker` keeps the period fixed however long the
+/// loop takes, so the count only falls behind the uptime when the executor stalls.
+#[embassy_executor::task]
+pub async fn heartbeat_task() -> ! {
+    let mut ticker = Ticker::every(HEARTBEAT_INTERVAL);
+    let mut last_beat = Instant::now();
+    loop {
+        task_registry::set_status(task_registry::HEARTBEAT, TaskStatus::Waiting);
+        ticker.next().await;
+        task_registry::iteration(task_registry::HEARTBEAT);
+        // After a stall the ticker would fire the missed beats back to back and hide it,
+        // start a fresh period instead so they stay missing
+        if last_beat.elapsed() > HEARTBEAT_INTERVAL * 2 {
+            ticker.reset();
+        }
+        last_beat = Instant::now();
+        HEARTBEAT.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Heartbeats missed since boot, ignoring the first few seconds of drift.
+pub fn heartbeat_skips() -> u64 {
+    let behind = Instant::now()
+        .as_secs()
+        .saturating_sub(HEARTBEAT.load(Ordering::Relaxed));
+    if behind > HEARTBEAT_SLACK_SECS {
+        behind
+    } else {
+        0
+    }
+}
+
+/// Signalled with a delay to reboot the device, so the request asking for it can still
+/// be answered.
+pub static REBOOT: Signal<CriticalSectionRawMutex, Duration> = Signal::new();
+
+#[embassy_executor::task]
+pub async fn reboot_task() -> ! {
+    task_registry::set_status(task_registry::REBOOT, TaskStatus::Waiting);
+    let delay = REBOOT.wait().await;
+    task_registry::iteration(task_registry::REBOOT);
+    let reboot_at = Instant::now() + delay;
+    push::push_stale_markers().await;
+    Timer::at(reboot_at).await;
+    cortex_m::peripheral::SCB::sys_reset()
+}
+
+/// SHT30 temperatures outside this range blink the onboard LED.
+pub const TEMP_HIGH_ALARM_C: f32 = 35.0;
+pub const TEMP_LOW_ALARM_C: f32 = 5.0;
+
+/// What the onboard LED shows.  Temperature alarms blink at 1 Hz and take priority over
+/// the WiFi status, which is steady on when connected.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum LedState {
+    WiFiConnected,
+    WiFiDisconnected,
+    AlarmHigh,
+    AlarmLow,
+}
+
+impl LedState {
+    pub fn is_alarm(self) -> bool {
+        matches!(self, LedState::AlarmHigh | LedState::AlarmLow)
+    }
+
+    /// The state to show when there is no alarm.
+    fn wifi() -> Self {
+        if WIFI_CONNECTED.load(Ordering::Relaxed) {
+            LedState::WiFiConnected
+        } else {
+            LedState::WiFiDisconnected
+        }
+    }
+}
+
+pub static LED_STATE: Mutex<LedState> = Mutex::new(LedState::WiFiDisconnected);
+static WIFI_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+pub async fn set_wifi_connected(connected: bool) {
+    WIFI_CONNECTED.store(connected, Ordering::Relaxed);
+    let mut led_state = LED_STATE.lock().await;
+    if !led_state.is_alarm() {
+        *led_state = LedState::wifi();
+    }
+}
+
+/// Raise or clear the temperature alarm for a new SHT30 temperature.
+pub async fn update_temperature_alarm(temperature_c: f32) {
+    let next = if temperature_c > TEMP_HIGH_ALARM_C {
+        LedState::AlarmHigh
+    } else if temperature_c < TEMP_LOW_ALARM_C {
+        LedState::AlarmLow
+    } else {
+        LedState::wifi()
+    };
+
+    let mut led_state = LED_STATE.lock().await;
+    if *led_state != next && (led_state.is_alarm() || next.is_alarm()) {
+        info!("led: {} -> {}", *led_state, next);
+    }
+    *led_state = next;
+}
+
+/// Drive the temperature alarm and the heat pump from the SHT30 readings, using the
+/// median of the last 11 so a single bad reading doesn't trip them.  Readings taken with
+/// the heater on are ignored, and the heat pump stops after
+/// `heat_pump_controller::STALE_AFTER` without any others.
+#[embassy_executor::task]
+pub async fn temperature_alert_task(sht30_state: &'static Mutex<sht30::SharedState>) -> ! {
+    let task = task_registry::TEMPERATURE_ALERT;
+    let mut subscriber = events::subscribe();
+    let mut temperatures = SampleSet::<11>::new();
+    let mut heat_pump_deadline = Instant::now() + heat_pump_controller::STALE_AFTER;
+    loop {
+        task_registry::set_status(task, TaskStatus::Waiting);
+        let event =
+            match embassy_time::with_deadline(heat_pump_deadline, events::next(&mut subscriber))
+                .await
+            {
+                Ok(event) => event,
+                Err(TimeoutError) => {
+                    heat_pump_controller::stop().await;
+                    heat_pump_deadline = Instant::now() + heat_pump_controller::STALE_AFTER;
+                    continue;
+                }
+            };
+        if let events::SensorEvent::Sht30(reading) = event {
+            task_registry::iteration(task);
+            let (dehumidifying, reading) = {
+                let state = sht30_state.lock().await;
+                let dehumidifying = state
+                    .dehumidification()
+                    .is_some_and(|session| session.is_active());
+                (dehumidifying, state.calibrated(&reading))
+            };
+            // The heater would raise the alarm and turn the heat pump to cooling
+            if dehumidifying {
+                continue;
+            }
+            temperatures.record(reading.temperature);
+            update_temperature_alarm(temperatures.median()).await;
+            heat_pump_controller::update(temperatures.median()).await;
+            heat_pump_deadline = Instant::now() + heat_pump_controller::STALE_AFTER;
+        }
+    }
+}
 
 pub struct AverageSet {
     sum: f32,
@@ -37,56 +729,182 @@ impl AverageSet {
     }
 
     pub fn avg(&mut self) -> f32 {
+        let avg = self.peek();
+        self.count = 0;
+        self.sum = 0.;
+        avg
+    }
+
+    /// Average of the samples recorded so far, without starting a new window.
+    pub fn peek(&self) -> f32 {
         if self.count == 0 {
             return 0.0;
         }
 
-        let avg = self.sum / self.count as f32;
-        self.count = 0;
-        self.sum = 0.;
-        avg
+        self.sum / self.count as f32
     }
 }
 
+/// The last `N` samples, kept sorted so the median is always at hand.
+///
+/// `raw` is a circular buffer in arrival order and `sorted` holds the same values in
+/// order.  Once full, each `record` evicts the oldest sample: O(log N) to find it in
+/// `sorted` plus O(N) to shift, and the same again to insert the new one.  `median` is
+/// O(1).
 pub struct SampleSet<const N: usize> {
-    samples: [f32; N],
-    count: usize,
+    raw: [f32; N],
+    sorted: [f32; N],
+    /// Index in `raw` the next sample is written to.
+    next: usize,
+    len: usize,
 }
 
 impl<const N: usize> SampleSet<N> {
     pub const fn new() -> Self {
         Self {
-            samples: [0.; N],
-            count: 0,
+            raw: [0.; N],
+            sorted: [0.; N],
+            next: 0,
+            len: 0,
         }
     }
 
     pub fn record(&mut self, sample: f32) {
-        self.samples[self.count % N] = sample;
-        self.count += 1;
+        if self.len == N {
+            let oldest = self.raw[self.next];
+            // `oldest` is always present, it was inserted when it arrived
+            if let Ok(i) = self.sorted.binary_search_by(|v| v.total_cmp(&oldest)) {
+                self.sorted.copy_within(i + 1.., i);
+                self.len -= 1;
+            }
+        }
+
+        let i = self.sorted[..self.len].partition_point(|v| v.total_cmp(&sample).is_lt());
+        self.sorted.copy_within(i..self.len, i + 1);
+        self.sorted[i] = sample;
+        self.len += 1;
+
+        self.raw[self.next] = sample;
+        self.next = (self.next + 1) % N;
     }
 
     pub fn median(&self) -> f32 {
-        let sample_count = self.sample_count();
-        if sample_count == 0 {
+        if self.len == 0 {
             return 0.;
         }
 
-        let mut samples = self
-            .samples
-            .iter()
-            .take(sample_count)
-            .collect::<Vec<&f32, N>>();
-        samples.sort_unstable_by(|a, b| a.total_cmp(b));
+        self.sorted[self.len / 2]
+    }
 
-        *samples[samples.len() / 2]
+    /// Nearest-rank percentile, `p` from 0 to 1.
+    pub fn percentile(&self, p: f32) -> f32 {
+        if self.len == 0 {
+            return 0.;
+        }
+
+        let i = libm::roundf(p.clamp(0., 1.) * (self.len - 1) as f32) as usize;
+        self.sorted[i]
     }
 
-    fn sample_count(&self) -> usize {
-        if self.count > N {
-            N
-        } else {
-            self.count
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Samples in the order they were recorded.
+    pub fn oldest_first(&self) -> impl Iterator<Item = f32> + '_ {
+        let start = if self.len == N { self.next } else { 0 };
+        (0..self.len).map(move |i| self.raw[(start + i) % N])
+    }
+}
+
+/// Fixed capacity FIFO that overwrites the oldest entries when full, so writers never block
+/// and the most recent data is always kept.
+pub struct RingBuffer<T, const N: usize> {
+    buffer: Deque<T, N>,
+}
+
+impl<T: Copy, const N: usize> RingBuffer<T, N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: Deque::new(),
+        }
+    }
+
+    pub fn write_bytes(&mut self, data: &[T]) {
+        for item in data {
+            if self.buffer.is_full() {
+                self.buffer.pop_front();
+            }
+            // Can't fail, there is always room after the pop above.
+            let _ = self.buffer.push_back(*item);
+        }
+    }
+
+    /// Move as many entries as fit into `buf`, oldest first.  Returns the number moved.
+    pub fn read_bytes(&mut self, buf: &mut [T]) -> usize {
+        let mut count = 0;
+        for slot in buf.iter_mut() {
+            match self.buffer.pop_front() {
+                Some(item) => *slot = item,
+                None => break,
+            }
+            count += 1;
+        }
+        count
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Entries that can be written before the oldest start being overwritten.
+    pub fn free(&self) -> usize {
+        N - self.buffer.len()
+    }
+}
+
+impl<T: Copy, const N: usize> Default for RingBuffer<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_set_evicts_oldest_after_n_plus_one() {
+        let mut set = SampleSet::<3>::new();
+        for sample in [1., 2., 3., 4.] {
+            set.record(sample);
+        }
+        assert_eq!(set.len(), 3);
+        assert!(!set.sorted.contains(&1.));
+        assert_eq!(set.sorted, [2., 3., 4.]);
+        assert_eq!(set.median(), 3.);
+    }
+
+    #[test]
+    fn sample_set_evicts_oldest_not_smallest() {
+        let mut set = SampleSet::<3>::new();
+        for sample in [5., 1., 3., 2.] {
+            set.record(sample);
+        }
+        assert_eq!(set.sorted, [1., 2., 3.]);
+        assert!(set.oldest_first().eq([1., 3., 2.]));
+    }
+
+    #[test]
+    fn sample_set_evicts_one_of_equal_values() {
+        let mut set = SampleSet::<3>::new();
+        for sample in [2., 2., 1., 3.] {
+            set.record(sample);
         }
+        assert_eq!(set.sorted, [1., 2., 3.]);
     }
 }
@@ -3,51 +3,72 @@
 use embassy_rp::multicore::spawn_core1;
 use pico_climate::ina237::INA237_DEFAULT_ADDR;
 
-use cyw43::{JoinOptions, ScanOptions};
+use cyw43::JoinOptions;
 use cyw43_pio::PioSpi;
+use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
 use embassy_executor::{Executor, Spawner};
 use embassy_rp::adc::{Adc, Channel};
 use embassy_rp::i2c::{self, I2c};
 use embassy_rp::multicore::Stack as MulticoreStack;
-use embassy_rp::peripherals::{DMA_CH0, I2C0, I2C1, PIO0};
+use embassy_rp::peripherals::{DMA_CH0, I2C1, PIO0, PIO1};
+use embassy_rp::pwm::{Config as PwmConfig, Pwm};
 use embassy_rp::watchdog::Watchdog;
 use embassy_rp::{
     bind_interrupts,
-    gpio::{Level, Output},
+    gpio::{Input, Level, Output, Pull},
     pio::{InterruptHandler, Pio},
 };
 use embassy_time::{Duration, Timer};
-use panic_probe as _;
-use pico_climate::http::{web_task, AppState, LAST_REQUEST_TIME};
-use pico_climate::ina237::{continuous_reading, Ina237};
+use pico_climate::battery::BatteryMonitor;
+use pico_climate::config::HTTP_TASK_COUNT;
+use pico_climate::flash_store::FlashStore;
+use pico_climate::heat_pump_controller::{self, Controller};
+use pico_climate::history::history_task;
+use pico_climate::http::{web_task, AppState};
+use pico_climate::i2c_health::i2c_health_task;
+use pico_climate::ina237::{
+    continuous_reading, energy_persist_task, Calibration, EnergyAccumulator, Ina237, Ina237Device,
+};
+use pico_climate::mqtt::{mqtt_task, MQTT_DEFAULT_PORT};
+use pico_climate::push::{pushgateway_task, request_stale_markers, PUSHGATEWAY_DEFAULT_PORT};
+use pico_climate::remote_write::{remote_write_task, REMOTE_WRITE_DEFAULT_PORT};
 use pico_climate::sht30::Sht30Device;
-use pico_climate::{adc_temp_sensor, sht30, Mutex, I2C_BUS_0};
-// use pico_climate::tcp_logger::tcp_logger_task;
-use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
-use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use pico_climate::statsd::{statsd_task, STATSD_DEFAULT_PORT};
+use pico_climate::task_registry::{self, TaskStatus};
+#[cfg(feature = "tcp-logger")]
+use pico_climate::tcp_logger::{tcp_logger_task, TCP_LOGGER_DEFAULT_PORT};
+use pico_climate::wifi::{histogram_reset_task, led_task, rssi_task};
+use pico_climate::{
+    adc_temp_sensor, alarm, battery, bh1750, bmp280, dht22, heartbeat_task, ina237, log_error,
+    mem_info, panic_info, reboot_task, set_wifi_connected, sht30, temperature_alert_task, wifi,
+    BusSelector, DualI2cBus, ErrorSource, I2c0Irqs, Mutex, FLASH_SIZE, I2C0_DEFAULT_FREQUENCY,
+    I2C1_DEFAULT_FREQUENCY, I2C_BUS_0, I2C_BUS_1, LAST_DELIVERY,
+};
 use static_cell::StaticCell;
 
 use core::fmt::Write;
 use embassy_net::{Config as NetConfig, DhcpConfig, Stack};
 use embassy_rp::clocks::RoscRng;
 
-use defmt::{self as _, debug, info};
+use defmt::{self as _, debug, error, info};
 
 bind_interrupts!(struct Irqs {
     PIO0_IRQ_0 => InterruptHandler<PIO0>;
+    PIO1_IRQ_0 => InterruptHandler<PIO1>;
     ADC_IRQ_FIFO => embassy_rp::adc::InterruptHandler;
-    I2C0_IRQ => i2c::InterruptHandler<I2C0>;
     I2C1_IRQ => i2c::InterruptHandler<I2C1>;
 });
 
-static INA237: StaticCell<Ina237<I2cDevice<'static, CriticalSectionRawMutex, pico_climate::I2c0>>> =
-    StaticCell::new();
-static SHT30: StaticCell<
-    sht30::Sht30Device<I2cDevice<'static, CriticalSectionRawMutex, pico_climate::I2c0>>,
-> = StaticCell::new();
+static INA237: StaticCell<Mutex<Ina237Device>> = StaticCell::new();
+static BMP280: StaticCell<Mutex<bmp280::Bmp280>> = StaticCell::new();
+static DHT22: StaticCell<Mutex<dht22::Dht22>> = StaticCell::new();
+static FLASH_STORE: StaticCell<FlashStore> = StaticCell::new();
+static SHT30: StaticCell<Mutex<sht30::Sht30>> = StaticCell::new();
 static SHT30_STATE: Mutex<sht30::SharedState> = Mutex::new(sht30::SharedState::new());
 static INA237_STATE: Mutex<pico_climate::ina237::SharedState> =
     Mutex::new(pico_climate::ina237::SharedState::new());
+static BH1750_STATE: Mutex<bh1750::SharedState> = Mutex::new(bh1750::SharedState::new());
+static I2C_BUSES: StaticCell<DualI2cBus> = StaticCell::new();
 
 defmt::timestamp!("{=u64:us}", embassy_time::Instant::now().as_micros());
 
@@ -76,14 +97,27 @@ fn create_unique_hostname(uid: [u8; 8]) -> heapless::String<32> {
 
 #[embassy_executor::task]
 async fn watchdog_feeder(mut watchdog: Watchdog) {
-    // Require a request in the last 2 minutes.
+    // Require a request, or a push where nothing scrapes the device, in the last 2 minutes.
+    let mut starving = false;
     loop {
-        let elapsed = LAST_REQUEST_TIME.lock().await.elapsed();
+        task_registry::iteration(task_registry::WATCHDOG_FEEDER);
+        let elapsed = LAST_DELIVERY.lock().await.elapsed();
         debug!("elapsed: {}", elapsed);
         if elapsed < Duration::from_secs(120) {
             debug!("Feeding the watchdog");
             watchdog.feed();
+            starving = false;
+        } else if !starving {
+            // The watchdog resets the device in 5 seconds
+            starving = true;
+            request_stale_markers();
         }
+        let status = if starving {
+            TaskStatus::Error
+        } else {
+            TaskStatus::Waiting
+        };
+        task_registry::set_status(task_registry::WATCHDOG_FEEDER, status);
         Timer::after(Duration::from_secs(1)).await;
     }
 }
@@ -95,11 +129,14 @@ async fn main(spawner: Spawner) {
     let p = embassy_rp::init(Default::default());
 
     info!("Booting!");
+    mem_info::init();
     {
         let mut watchdog = Watchdog::new(p.WATCHDOG);
         watchdog.start(Duration::from_secs(5));
         spawner.spawn(watchdog_feeder(watchdog)).unwrap();
     }
+    spawner.spawn(heartbeat_task()).unwrap();
+    spawner.spawn(reboot_task()).unwrap();
 
     //Onboard temp sensor
     let adc = Adc::new(p.ADC, Irqs, embassy_rp::adc::Config::default());
@@ -108,23 +145,149 @@ async fn main(spawner: Spawner) {
     let temp_sensor = TEMP_SENSOR.init(adc_temp_sensor::Sensor { temp_sensor, adc });
 
     let mut bus0_config = i2c::Config::default();
-    bus0_config.frequency = 10_000;
+    bus0_config.frequency = I2C0_DEFAULT_FREQUENCY;
 
     let i2c_bus0 = I2C_BUS_0.init(Mutex::new(I2c::new_async(
         p.I2C0,
         p.PIN_5,
         p.PIN_4,
-        Irqs,
+        I2c0Irqs,
         bus0_config,
     )));
 
-    let sht30_device = Sht30Device::new(I2cDevice::new(i2c_bus0), sht30::SHT30_ADDR);
+    let mut bus1_config = i2c::Config::default();
+    bus1_config.frequency = I2C1_DEFAULT_FREQUENCY;
+    let i2c_bus1 = I2C_BUS_1.init(Mutex::new(I2c::new_async(
+        p.I2C1,
+        p.PIN_7,
+        p.PIN_6,
+        Irqs,
+        bus1_config,
+    )));
+    let i2c_buses: &'static DualI2cBus = I2C_BUSES.init(DualI2cBus::new(i2c_bus0, i2c_bus1));
+    // The INA237 and BH1750 are read often, so they go on I2C1 when wired there.  Boards
+    // with everything on I2C0 still work, they're looked for there next.
+    let fast_buses = [BusSelector::Bus1, BusSelector::Bus0];
+
+    let mut sht30_i2c = I2cDevice::new(i2c_bus0);
+    let sht30_addr = sht30::probe_sht30_address(&mut sht30_i2c).await;
+    let has_sht30 = sht30_addr.is_some();
+    let sht30_device: &'static Mutex<sht30::Sht30> = SHT30.init(Mutex::new(Sht30Device::new(
+        sht30_i2c,
+        sht30_addr.unwrap_or(sht30::SHT30_ADDR),
+    )));
+    if has_sht30 {
+        spawner.must_spawn(sht30::dehumidify_task(sht30_device, &SHT30_STATE));
+    }
 
-    let ina237_device = Ina237::new(I2cDevice::new(i2c_bus0), INA237_DEFAULT_ADDR)
-        .await
-        .ok();
+    let mut flash = embassy_rp::flash::Flash::<_, embassy_rp::flash::Async, FLASH_SIZE>::new(
+        p.FLASH, p.DMA_CH1,
+    );
+    let mut uid = [0u8; 8];
+    flash.blocking_unique_id(&mut uid).unwrap();
+    let flash_store: &'static FlashStore = FLASH_STORE.init(FlashStore::new(flash));
+    panic_info::persist(flash_store).await;
+    if let Some(calibration) = sht30::Sht30Calibration::load(flash_store).await {
+        SHT30_STATE.lock().await.set_calibration(calibration);
+    }
+    spawner.must_spawn(history_task(&SHT30_STATE, flash_store));
+
+    let ina237_calibration = Calibration::load(flash_store).await;
+    // INA237 ALERT on GPIO 22, open drain so it needs the pull up
+    let ina237_alert_pin = option_env!("INA237_ALERT")
+        .is_some()
+        .then(|| Input::new(p.PIN_22, Pull::Up));
+    let mut found_ina237 = None;
+    for bus in fast_buses {
+        if let Ok(device) = Ina237::new(i2c_buses.device(bus), INA237_DEFAULT_ADDR).await {
+            info!("ina237: Found on {}", bus);
+            found_ina237 = Some(device);
+            break;
+        }
+    }
+    let ina237_device: Option<&'static Mutex<Ina237Device>> = found_ina237.map(|mut device| {
+        if let Some(calibration) = &ina237_calibration {
+            device.set_shunt_ohms(calibration.shunt_ohms);
+        }
+        if let Some(pin) = ina237_alert_pin {
+            device.set_alert_pin(pin);
+        }
+        &*INA237.init(Mutex::new(device))
+    });
 
     let has_ina237 = ina237_device.is_some();
+    if has_ina237 {
+        *INA237_STATE.lock().await.energy_mut() = EnergyAccumulator::load(flash_store).await;
+        spawner.must_spawn(energy_persist_task(&INA237_STATE, flash_store));
+    }
+
+    // Battery state of charge from the INA237, only tracked when the capacity is configured
+    let mut has_battery = false;
+    if let Some(capacity) = option_env!("BATTERY_CAPACITY_MAH") {
+        match capacity.parse::<f32>() {
+            Ok(_) if !has_ina237 => error!("battery: BATTERY_CAPACITY_MAH needs an INA237"),
+            Ok(capacity) if capacity > 0. => {
+                let charge = BatteryMonitor::load(flash_store).await;
+                *battery::MONITOR.lock().await = Some(BatteryMonitor::new(capacity, charge));
+                spawner.must_spawn(battery::persist_task(flash_store));
+                has_battery = true;
+                info!("battery: {} mAh, saved charge {} mAh", capacity, charge);
+            }
+            _ => error!("battery: Invalid BATTERY_CAPACITY_MAH: {}", capacity),
+        }
+    }
+
+    let mut bmp280_device = None;
+    for addr in [bmp280::BMP280_ADDR_SDO_LOW, bmp280::BMP280_ADDR_SDO_HIGH] {
+        let mut device = bmp280::Bmp280Device::new(I2cDevice::new(i2c_bus0), addr);
+        if device.init().await.is_ok() {
+            let device: &'static Mutex<bmp280::Bmp280> = BMP280.init(Mutex::new(device));
+            bmp280_device = Some(device);
+            break;
+        }
+    }
+
+    let mut has_bh1750 = false;
+    'bh1750: for bus in fast_buses {
+        for addr in [bh1750::BH1750_ADDR_LOW, bh1750::BH1750_ADDR_HIGH] {
+            let mut device = bh1750::Bh1750Device::new(i2c_buses.device(bus), addr);
+            if device.init().await.is_ok() {
+                info!("bh1750: Found on {}", bus);
+                spawner.must_spawn(bh1750::ambient_light_task(device, &BH1750_STATE));
+                has_bh1750 = true;
+                break 'bh1750;
+            }
+        }
+    }
+
+    // DHT22 on GPIO 15, read by PIO1 since PIO0 runs the WiFi SPI.  It ignores the start
+    // signal for the first second after power up.
+    let mut pio1 = Pio::new(p.PIO1, Irqs);
+    let mut dht22_device = dht22::Dht22Device::new(&mut pio1.common, pio1.sm0, p.PIN_15);
+    Timer::after_secs(1).await;
+    let dht22_device: Option<&'static Mutex<dht22::Dht22>> = match dht22_device.read().await {
+        Err(dht22::Dht22Error::Timeout) => None,
+        _ => {
+            info!("dht22: Found on GPIO 15");
+            Some(DHT22.init(Mutex::new(dht22_device)))
+        }
+    };
+
+    // Heat pump PWM on GPIO 16, only driven when a setpoint is configured
+    if let Some(setpoint) = option_env!("HEAT_PUMP_SETPOINT") {
+        match setpoint.parse::<f32>() {
+            Ok(setpoint) if heat_pump_controller::SETPOINT_RANGE.contains(&setpoint) => {
+                let pwm = Pwm::new_output_a(p.PWM_SLICE0, p.PIN_16, PwmConfig::default());
+                *heat_pump_controller::CONTROLLER.lock().await = Some(Controller::new(
+                    pwm,
+                    setpoint,
+                    heat_pump_controller::DEFAULT_HYSTERESIS_C,
+                ));
+                info!("heat_pump: Setpoint {} C on GPIO 16", setpoint);
+            }
+            _ => error!("heat_pump: Invalid HEAT_PUMP_SETPOINT: {}", setpoint),
+        }
+    }
 
     spawn_core1(
         p.CORE1,
@@ -132,24 +295,31 @@ async fn main(spawner: Spawner) {
         move || {
             let executor1 = EXECUTOR1.init(Executor::new());
             executor1.run(|spawner| {
-                spawner.must_spawn(sht30::continuous_reading(
-                    SHT30.init(sht30_device),
-                    &SHT30_STATE,
-                ));
+                // Subscribers go first so they don't miss the first readings
+                if has_sht30 {
+                    spawner.must_spawn(sht30::record_task(&SHT30_STATE));
+                    spawner.must_spawn(temperature_alert_task(&SHT30_STATE));
+                    spawner.must_spawn(sht30::continuous_reading(
+                        sht30_device,
+                        &SHT30_STATE,
+                        sht30::RetryConfig::default(),
+                    ));
+                }
                 if let Some(device) = ina237_device {
-                    spawner.must_spawn(continuous_reading(INA237.init(device), &INA237_STATE));
+                    spawner.must_spawn(ina237::record_task(&INA237_STATE));
+                    if has_battery {
+                        spawner.must_spawn(battery::record_task());
+                    }
+                    spawner.must_spawn(continuous_reading(
+                        device,
+                        &INA237_STATE,
+                        option_env!("INA237_LOW_POWER").is_some(),
+                    ));
                 }
             });
         },
     );
 
-    let mut flash =
-        embassy_rp::flash::Flash::<_, embassy_rp::flash::Async, { 2 * 1024 * 1024 }>::new(
-            p.FLASH, p.DMA_CH1,
-        );
-    let mut uid = [0u8; 8];
-    flash.blocking_unique_id(&mut uid).unwrap();
-
     let fw = include_bytes!("../cyw43-firmware/43439A0.bin");
     let clm = include_bytes!("../cyw43-firmware/43439A0_clm.bin");
 
@@ -186,8 +356,11 @@ async fn main(spawner: Spawner) {
     let wifi_password = env!("WIFI_PASSWORD");
     let seed: u64 = RoscRng.next_u64();
 
+    static HOSTNAME: StaticCell<heapless::String<32>> = StaticCell::new();
+    let hostname: &'static heapless::String<32> = HOSTNAME.init(create_unique_hostname(uid));
+
     let mut dhcp_config = DhcpConfig::default();
-    dhcp_config.hostname = Some(create_unique_hostname(uid));
+    dhcp_config.hostname = Some(hostname.clone());
     let net_config = NetConfig::dhcpv4(dhcp_config);
 
     static RESOURCES: StaticCell<embassy_net::StackResources<16>> = StaticCell::new();
@@ -210,63 +383,135 @@ async fn main(spawner: Spawner) {
     };
 
     let app_state = APP_STATE.init(
-        AppState::new(temp_sensor, ina237_state, &SHT30_STATE)
-            .await
-            .unwrap(),
+        AppState::new(
+            temp_sensor,
+            ina237_state,
+            ina237_device,
+            ina237_calibration,
+            sht30_device,
+            &SHT30_STATE,
+            has_sht30,
+            bmp280_device,
+            has_bh1750.then_some(&BH1750_STATE),
+            dht22_device,
+            flash_store,
+            i2c_bus0,
+            hostname.as_str(),
+        )
+        .await
+        .unwrap(),
     );
 
-    // spawner.must_spawn(tcp_logger_task(stack, "ryzen.lan", 9091));
-    for id in 0..4 {
+    spawner.must_spawn(i2c_health_task(app_state));
+    #[cfg(feature = "tcp-logger")]
+    match option_env!("TCP_LOGGER_HOST") {
+        Some(host) => spawner.must_spawn(tcp_logger_task(stack, host, TCP_LOGGER_DEFAULT_PORT)),
+        None => error!("TCP_LOGGER_HOST isn't set, logs from the tcp-logger build go nowhere"),
+    }
+    if let Some(broker) = option_env!("MQTT_BROKER") {
+        spawner.must_spawn(mqtt_task(stack, broker, MQTT_DEFAULT_PORT, app_state));
+    }
+    if let Some(host) = option_env!("STATSD_HOST") {
+        spawner.must_spawn(statsd_task(stack, host, STATSD_DEFAULT_PORT, app_state));
+    }
+    if let Some(host) = option_env!("PUSHGATEWAY_HOST") {
+        let job = option_env!("PUSHGATEWAY_JOB").unwrap_or("pico-climate");
+        spawner.must_spawn(pushgateway_task(
+            stack,
+            host,
+            PUSHGATEWAY_DEFAULT_PORT,
+            job,
+            app_state,
+        ));
+    }
+    if let Some(host) = option_env!("REMOTE_WRITE_HOST") {
+        let path = option_env!("REMOTE_WRITE_PATH").unwrap_or("/api/v1/push");
+        spawner.must_spawn(remote_write_task(
+            stack,
+            host,
+            REMOTE_WRITE_DEFAULT_PORT,
+            path,
+            app_state,
+        ));
+    }
+    // Only alarms on the bus voltage when there's somewhere to send them
+    if let Some(url) = option_env!("VOLTAGE_ALARM_WEBHOOK") {
+        let low = option_env!("VOLTAGE_ALARM_LOW_V")
+            .unwrap_or("4.5")
+            .parse::<f32>();
+        let high = option_env!("VOLTAGE_ALARM_HIGH_V")
+            .unwrap_or("5.5")
+            .parse::<f32>();
+        match (low, high) {
+            _ if !has_ina237 => error!("alarm: VOLTAGE_ALARM_WEBHOOK needs an INA237"),
+            _ if alarm::parse_url(url).is_none() => {
+                error!(
+                    "alarm: VOLTAGE_ALARM_WEBHOOK must be http://host[:port]/path: {}",
+                    url
+                )
+            }
+            (Ok(low), Ok(high)) if low < high => {
+                *alarm::VOLTAGE_ALARM.lock().await = Some(alarm::VoltageAlarm::new(
+                    low,
+                    high,
+                    alarm::DEFAULT_DEBOUNCE_COUNT,
+                ));
+                spawner.must_spawn(alarm::voltage_alarm_task(stack, url, app_state.hostname));
+                info!("alarm: Bus voltage {} to {} V, webhook {}", low, high, url);
+            }
+            _ => error!("alarm: Invalid VOLTAGE_ALARM_LOW_V or VOLTAGE_ALARM_HIGH_V"),
+        }
+    }
+    for id in 0..HTTP_TASK_COUNT {
         spawner.must_spawn(web_task(id, stack, app_state));
     }
 
+    static CONTROL: StaticCell<Mutex<cyw43::Control<'static>>> = StaticCell::new();
+    let control = CONTROL.init(Mutex::new(control));
+    spawner.must_spawn(led_task(control));
+    spawner.must_spawn(histogram_reset_task(app_state));
+    spawner.must_spawn(rssi_task(control, wifi_ssid, app_state));
+
+    let mac = control.lock().await.address().await;
     loop {
-        control.gpio_set(0, true).await;
+        set_wifi_connected(false).await;
         info!("Joining wifi {}", wifi_ssid);
-        while let Err(_) = control
-            .join(wifi_ssid, JoinOptions::new(wifi_password.as_bytes()))
-            .await
-        {
-            for _ in 0..5 {
-                control.gpio_set(0, false).await;
-                Timer::after(Duration::from_millis(100)).await;
-
-                control.gpio_set(0, true).await;
-                Timer::after(Duration::from_millis(100)).await;
+        loop {
+            let joined = control
+                .lock()
+                .await
+                .join(wifi_ssid, JoinOptions::new(wifi_password.as_bytes()))
+                .await;
+            if joined.is_ok() {
+                break;
             }
+            log_error!(ErrorSource::Wifi, "wifi: Join failed, retrying");
+            Timer::after(Duration::from_millis(1000)).await;
         }
 
         stack.wait_link_up().await;
+        // The histograms are empty on the first link up, only a reconnect needs a reset
+        if wifi::WIFI_STATS.lock().await.link_up() {
+            wifi::WIFI_RECONNECT.signal(wifi::WifiReconnectSignal);
+        }
         info!("Link up");
         stack.wait_config_up().await;
-        control.gpio_set(0, false).await;
+        set_wifi_connected(true).await;
+        if let Some(config) = stack.config_v4() {
+            app_state.lock().await.network_info =
+                Some(wifi::NetworkInfo::new(config.address.address(), mac));
+        }
 
         info!("Stack configured");
-        info!("Hostname: '{}'", create_unique_hostname(uid));
+        info!("Hostname: '{}'", hostname.as_str());
         info!("Network Config: {}", stack.config_v4());
 
-        embassy_futures::select::select(stack.wait_link_down(), async {
-            loop {
-                let mut scan_opts = ScanOptions::default();
-                scan_opts.ssid = Some(heapless::String::try_from(wifi_ssid).unwrap());
-
-                let mut scan = control.scan(scan_opts).await;
-                loop {
-                    let s = match scan.next().await {
-                        Some(s) => s,
-                        None => break,
-                    };
-                    let channel = s.chanspec & 0xff;
-
-                    app_state.lock().await.wifi_signal[(channel as usize - 1) + 14 * 0]
-                        .sample(-s.rssi as f32);
-                    app_state.lock().await.wifi_signal[(channel as usize - 1) + 14 * 1]
-                        .sample(-s.phy_noise as f32);
-                    app_state.lock().await.wifi_signal[(channel as usize - 1) + 14 * 2]
-                        .sample((s.rssi - s.phy_noise as i16) as f32);
-                }
-            }
-        })
+        embassy_futures::select::select(
+            stack.wait_link_down(),
+            wifi::wifi_monitor(control, wifi_ssid, app_state),
+        )
         .await;
+        wifi::WIFI_STATS.lock().await.link_down();
+        log_error!(ErrorSource::Wifi, "Link down");
     }
 }
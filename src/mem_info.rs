@@ -0,0 +1,80 @@
+use portable_atomic::{AtomicUsize, Ordering};
+
+/// RP2040 SRAM: four striped 64K banks plus the two 4K scratch banks.
+pub const SRAM_TOTAL_BYTES: usize = 264 * 1024;
+
+const RAM_ORIGIN: usize = 0x2000_0000;
+
+// Symbols provided by cortex-m-rt's link.x
+extern "C" {
+    static __sdata: u32;
+    static __edata: u32;
+    static __sbss: u32;
+    static __ebss: u32;
+    static __sheap: u32;
+    static _stack_start: u32;
+}
+
+static STATIC_USED_BYTES: AtomicUsize = AtomicUsize::new(0);
+static STACK_ALLOCATED_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// Lowest address the core 0 stack can grow down to.
+static STACK_LIMIT: AtomicUsize = AtomicUsize::new(0);
+static STACK_FREE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// Compute the memory layout from the linker symbols.  The layout is fixed at link time,
+/// so this only needs to run once at boot.
+pub fn init() {
+    // Taking the address of an extern static is safe, only reading it isn't
+    let (sdata, edata, sbss, ebss, sheap, stack_start) = (
+        core::ptr::addr_of!(__sdata) as usize,
+        core::ptr::addr_of!(__edata) as usize,
+        core::ptr::addr_of!(__sbss) as usize,
+        core::ptr::addr_of!(__ebss) as usize,
+        core::ptr::addr_of!(__sheap) as usize,
+        core::ptr::addr_of!(_stack_start) as usize,
+    );
+
+    STATIC_USED_BYTES.store((edata - sdata) + (ebss - sbss), Ordering::Relaxed);
+
+    // flip-link moves the statics to the top of RAM and puts the stack below them, growing
+    // down towards the start of RAM.  Without flip-link the stack grows down towards the
+    // statics instead.
+    let stack_limit = if stack_start <= sdata {
+        RAM_ORIGIN
+    } else {
+        sheap
+    };
+    STACK_ALLOCATED_BYTES.store(stack_start - stack_limit, Ordering::Relaxed);
+    STACK_LIMIT.store(stack_limit, Ordering::Relaxed);
+}
+
+/// Bytes left below the current stack pointer on core 0.  Only the depth at this moment,
+/// not the high-water mark.
+pub fn current_stack_free_bytes() -> usize {
+    let sp: usize;
+    // SAFETY: Copies SP into a register, no memory is touched.
+    unsafe {
+        core::arch::asm!("mov {}, sp", out(reg) sp, options(nomem, nostack, preserves_flags));
+    }
+    sp.saturating_sub(STACK_LIMIT.load(Ordering::Relaxed))
+}
+
+/// Measure the free stack and keep it for `stack_free_bytes`.
+pub fn sample_stack_free() {
+    STACK_FREE_BYTES.store(current_stack_free_bytes(), Ordering::Relaxed);
+}
+
+/// Free stack at the last `sample_stack_free`.
+pub fn stack_free_bytes() -> usize {
+    STACK_FREE_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes used by `.data` and `.bss`, which includes every `StaticCell` and static buffer.
+pub fn static_used_bytes() -> usize {
+    STATIC_USED_BYTES.load(Ordering::Relaxed)
+}
+
+/// Bytes reserved for the core 0 stack.
+pub fn stack_allocated_bytes() -> usize {
+    STACK_ALLOCATED_BYTES.load(Ordering::Relaxed)
+}
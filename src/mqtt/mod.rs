@@ -0,0 +1,344 @@
+use core::fmt::Write as _;
+
+use defmt::{error, info, Format};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::{Read, ReadExactError, Write};
+use heapless::{String, Vec};
+use portable_atomic::{AtomicBool, Ordering};
+
+use crate::http::AppState;
+use crate::task_registry::{self, TaskStatus};
+
+#[cfg(feature = "mqtt-tls")]
+pub mod tls;
+
+#[cfg(not(feature = "mqtt-tls"))]
+pub const MQTT_DEFAULT_PORT: u16 = 1883;
+#[cfg(feature = "mqtt-tls")]
+pub const MQTT_DEFAULT_PORT: u16 = 8883;
+
+/// Value of the `transport` label on `mqtt_connected`.
+#[cfg(not(feature = "mqtt-tls"))]
+pub const MQTT_TRANSPORT: &str = "tcp";
+#[cfg(feature = "mqtt-tls")]
+pub const MQTT_TRANSPORT: &str = "tls";
+
+/// Set while a session with the broker is established.
+pub static MQTT_CONNECTED: AtomicBool = AtomicBool::new(false);
+
+const PUBLISH_INTERVAL: Duration = Duration::from_secs(30);
+const RECONNECT_BACKOFF: Duration = Duration::from_secs(10);
+#[cfg(feature = "mqtt-tls")]
+const TLS_RETRY_BACKOFF: Duration = Duration::from_secs(30);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const KEEP_ALIVE_SECS: u16 = 60;
+const PACKET_SIZE: usize = 256;
+
+// MQTT 3.1.1 control packet types (upper nibble of the fixed header)
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PINGREQ: u8 = 0xC0;
+const PINGRESP: u8 = 0xD0;
+
+const PROTOCOL_LEVEL_3_1_1: u8 = 0x04;
+const CONNECT_FLAG_CLEAN_SESSION: u8 = 0x02;
+
+#[derive(Debug, Format)]
+pub enum MqttError {
+    Dns,
+    Connect(embassy_net::tcp::ConnectError),
+    Tcp(embassy_net::tcp::Error),
+    ConnectionClosed,
+    Refused(u8),
+    UnexpectedPacket(u8),
+    PacketTooLarge,
+    Timeout,
+    #[cfg(feature = "mqtt-tls")]
+    Tls(embedded_tls::TlsError),
+    #[cfg(feature = "mqtt-tls")]
+    TlsTimeout,
+}
+
+impl MqttError {
+    /// Failures setting up TLS, counted in `mqtt_tls_errors_total`.
+    pub fn is_tls(&self) -> bool {
+        #[cfg(feature = "mqtt-tls")]
+        if matches!(self, MqttError::Tls(_) | MqttError::TlsTimeout) {
+            return true;
+        }
+        false
+    }
+}
+
+impl From<embassy_net::tcp::Error> for MqttError {
+    fn from(value: embassy_net::tcp::Error) -> Self {
+        MqttError::Tcp(value)
+    }
+}
+
+#[cfg(feature = "mqtt-tls")]
+impl From<embedded_tls::TlsError> for MqttError {
+    fn from(value: embedded_tls::TlsError) -> Self {
+        MqttError::Tls(value)
+    }
+}
+
+/// Lets `MqttError` be the I/O error of `tls::TlsSocket`.
+#[cfg(feature = "mqtt-tls")]
+impl embedded_io_async::Error for MqttError {
+    fn kind(&self) -> embedded_io_async::ErrorKind {
+        embedded_io_async::ErrorKind::Other
+    }
+}
+
+impl<E> From<ReadExactError<E>> for MqttError
+where
+    MqttError: From<E>,
+{
+    fn from(value: ReadExactError<E>) -> Self {
+        match value {
+            ReadExactError::UnexpectedEof => MqttError::ConnectionClosed,
+            ReadExactError::Other(e) => e.into(),
+        }
+    }
+}
+
+type Packet = Vec<u8, PACKET_SIZE>;
+
+fn push(packet: &mut Packet, bytes: &[u8]) -> Result<(), MqttError> {
+    packet
+        .extend_from_slice(bytes)
+        .map_err(|_| MqttError::PacketTooLarge)
+}
+
+/// Push a length-prefixed UTF-8 string as used throughout the MQTT variable header and payload.
+fn push_str(packet: &mut Packet, value: &str) -> Result<(), MqttError> {
+    push(packet, &(value.len() as u16).to_be_bytes())?;
+    push(packet, value.as_bytes())
+}
+
+/// Prepend the fixed header (packet type + variable length "remaining length") to `body`.
+fn with_fixed_header(packet_type: u8, body: &[u8]) -> Result<Packet, MqttError> {
+    let mut packet = Packet::new();
+    push(&mut packet, &[packet_type])?;
+
+    let mut remaining = body.len();
+    loop {
+        let mut byte = (remaining % 128) as u8;
+        remaining /= 128;
+        if remaining > 0 {
+            byte |= 0x80;
+        }
+        push(&mut packet, &[byte])?;
+        if remaining == 0 {
+            break;
+        }
+    }
+
+    push(&mut packet, body)?;
+    Ok(packet)
+}
+
+fn connect_packet(client_id: &str) -> Result<Packet, MqttError> {
+    let mut body = Packet::new();
+    push_str(&mut body, "MQTT")?;
+    push(
+        &mut body,
+        &[PROTOCOL_LEVEL_3_1_1, CONNECT_FLAG_CLEAN_SESSION],
+    )?;
+    push(&mut body, &KEEP_ALIVE_SECS.to_be_bytes())?;
+    push_str(&mut body, client_id)?;
+    with_fixed_header(CONNECT, &body)
+}
+
+fn publish_packet(topic: &str, payload: &[u8]) -> Result<Packet, MqttError> {
+    let mut body = Packet::new();
+    push_str(&mut body, topic)?;
+    push(&mut body, payload)?;
+    with_fixed_header(PUBLISH, &body)
+}
+
+/// MQTT over any byte stream, a plain `TcpSocket` or a TLS connection on top of one.
+struct MqttClient<T> {
+    socket: T,
+}
+
+impl<T: Read + Write> MqttClient<T>
+where
+    MqttError: From<T::Error>,
+{
+    async fn connect(&mut self, client_id: &str) -> Result<(), MqttError> {
+        self.socket.write_all(&connect_packet(client_id)?).await?;
+        self.socket.flush().await?;
+
+        let mut connack = [0u8; 4];
+        with_timeout(RESPONSE_TIMEOUT, self.socket.read_exact(&mut connack))
+            .await
+            .map_err(|_| MqttError::Timeout)??;
+
+        if connack[0] != CONNACK {
+            return Err(MqttError::UnexpectedPacket(connack[0]));
+        }
+        if connack[3] != 0 {
+            return Err(MqttError::Refused(connack[3]));
+        }
+        Ok(())
+    }
+
+    async fn publish(&mut self, topic: &str, value: f32) -> Result<(), MqttError> {
+        let mut payload = String::<16>::new();
+        write!(&mut payload, "{}", value).map_err(|_| MqttError::PacketTooLarge)?;
+
+        self.socket
+            .write_all(&publish_packet(topic, payload.as_bytes())?)
+            .await?;
+        self.socket.flush().await?;
+        Ok(())
+    }
+
+    async fn ping(&mut self) -> Result<(), MqttError> {
+        self.socket.write_all(&[PINGREQ, 0x00]).await?;
+        self.socket.flush().await?;
+
+        let mut pingresp = [0u8; 2];
+        with_timeout(RESPONSE_TIMEOUT, self.socket.read_exact(&mut pingresp))
+            .await
+            .map_err(|_| MqttError::Timeout)??;
+
+        if pingresp[0] != PINGRESP {
+            return Err(MqttError::UnexpectedPacket(pingresp[0]));
+        }
+        Ok(())
+    }
+}
+
+async fn publish_readings<T: Read + Write>(
+    client: &mut MqttClient<T>,
+    topic_prefix: &str,
+    app_state: &AppState,
+) -> Result<(), MqttError>
+where
+    MqttError: From<T::Error>,
+{
+    let (has_sht30, sht30_state, ina237_state) = {
+        let state = app_state.lock().await;
+        (state.has_sht30, state.sht30_state, state.ina237_state)
+    };
+
+    let mut readings: Vec<(&str, f32), 4> = Vec::new();
+    if has_sht30 {
+        let sht30_output = sht30_state.lock().await.snapshot();
+        let _ = readings.push(("temperature", sht30_output.temperature));
+        let _ = readings.push(("humidity", sht30_output.humidity));
+    }
+
+    if let Some(ina237_state) = ina237_state {
+        let ina237_output = ina237_state.lock().await.peek();
+        let _ = readings.push(("bus_voltage", ina237_output.bus_voltage));
+        let _ = readings.push(("current", ina237_output.current));
+    }
+
+    // e.g. the INA237 current after a math overflow
+    for (name, value) in readings.into_iter().filter(|(_, value)| !value.is_nan()) {
+        let mut topic = String::<64>::new();
+        write!(
+            &mut topic,
+            "{}/{}/{}",
+            topic_prefix, app_state.hostname, name
+        )
+        .map_err(|_| MqttError::PacketTooLarge)?;
+        client.publish(&topic, value).await?;
+    }
+
+    Ok(())
+}
+
+async fn run_session(
+    stack: Stack<'static>,
+    broker: &'static str,
+    port: u16,
+    app_state: &'static AppState,
+    rx_buffer: &mut [u8],
+    tx_buffer: &mut [u8],
+    #[cfg(feature = "mqtt-tls")] tls_buffers: &mut tls::RecordBuffers,
+) -> Result<(), MqttError> {
+    let addr = match stack
+        .dns_query(broker, embassy_net::dns::DnsQueryType::A)
+        .await
+    {
+        Ok(addresses) => *addresses.first().ok_or(MqttError::Dns)?,
+        Err(_) => return Err(MqttError::Dns),
+    };
+
+    let mut socket = TcpSocket::new(stack, rx_buffer, tx_buffer);
+    socket.set_timeout(Some(RESPONSE_TIMEOUT));
+    socket
+        .connect(embassy_net::IpEndpoint::new(addr, port))
+        .await
+        .map_err(MqttError::Connect)?;
+
+    #[cfg(feature = "mqtt-tls")]
+    let socket = tls::connect(socket, broker, tls_buffers).await?;
+
+    let mut client = MqttClient { socket };
+    client.connect(app_state.hostname).await?;
+    info!("MQTT: Connected to {}:{}", broker, port);
+    MQTT_CONNECTED.store(true, Ordering::Relaxed);
+
+    let topic_prefix = option_env!("MQTT_TOPIC_PREFIX").unwrap_or("pico-climate");
+    loop {
+        publish_readings(&mut client, topic_prefix, app_state).await?;
+        client.ping().await?;
+        Timer::after(PUBLISH_INTERVAL).await;
+    }
+}
+
+/// Publish sensor readings to an MQTT 3.1.1 broker every 30 seconds.
+///
+/// Topics are `{MQTT_TOPIC_PREFIX}/{hostname}/{reading}`, payloads are UTF-8 floats, and
+/// everything is sent with QoS 0.  The client connects with a clean session and no
+/// credentials, so without the `mqtt-tls` feature this is intended for local brokers.
+#[embassy_executor::task]
+pub async fn mqtt_task(
+    stack: &'static Stack<'static>,
+    broker: &'static str,
+    port: u16,
+    app_state: &'static AppState,
+) -> ! {
+    let mut rx_buffer = [0; 256];
+    let mut tx_buffer = [0; 512];
+    #[cfg(feature = "mqtt-tls")]
+    let mut tls_buffers = tls::RecordBuffers::new();
+    info!("MQTT: Target broker {}:{}", broker, port);
+    loop {
+        stack.wait_config_up().await;
+        task_registry::iteration(task_registry::MQTT);
+
+        if let Err(e) = run_session(
+            *stack,
+            broker,
+            port,
+            app_state,
+            &mut rx_buffer,
+            &mut tx_buffer,
+            #[cfg(feature = "mqtt-tls")]
+            &mut tls_buffers,
+        )
+        .await
+        {
+            error!("MQTT: Session ended: {:?}", e);
+            MQTT_CONNECTED.store(false, Ordering::Relaxed);
+            task_registry::set_status(task_registry::MQTT, TaskStatus::Error);
+            #[cfg(feature = "mqtt-tls")]
+            if e.is_tls() {
+                tls::TLS_ERRORS.fetch_add(1, Ordering::Relaxed);
+                Timer::after(TLS_RETRY_BACKOFF).await;
+                continue;
+            }
+        }
+
+        Timer::after(RECONNECT_BACKOFF).await;
+    }
+}
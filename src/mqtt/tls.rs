@@ -0,0 +1,208 @@
+//! TLS with client certificate authentication for the MQTT connection.
+//!
+//! The certificates are compiled in from `certs/`.  The client key must be a P-256 key,
+//! either SEC1 (`EC PRIVATE KEY`) or PKCS#8 (`PRIVATE KEY`).
+//!
+//! The broker's chain is verified against `MQTT_CA_CERT`, and its certificate must name
+//! `MQTT_BROKER` as a DNS subject alternative name or the common name.
+
+use embassy_net::tcp::TcpSocket;
+use embassy_rp::clocks::RoscRng;
+use embassy_time::{with_timeout, Duration};
+use embedded_io_async::{ErrorType, Read, Write};
+use embedded_io_async_07 as io07;
+use embedded_tls::pki::CertVerifier;
+use embedded_tls::{
+    Aes128GcmSha256, Certificate, CryptoProvider, SignatureScheme, TlsClock, TlsConfig,
+    TlsConnection, TlsContext, TlsError, TlsVerifier,
+};
+use p256::ecdsa::{DerSignature, SigningKey};
+use p256::pkcs8::DecodePrivateKey;
+use portable_atomic::AtomicU32;
+
+use super::MqttError;
+
+const MQTT_CA_CERT: &[u8] = include_bytes!("../../certs/ca.pem");
+const MQTT_CLIENT_CERT: &[u8] = include_bytes!("../../certs/client.pem");
+const MQTT_CLIENT_KEY: &[u8] = include_bytes!("../../certs/client.key");
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Largest TLS record: 16K of plaintext plus header, padding and tag.
+const READ_RECORD_SIZE: usize = 16640;
+/// Outgoing records are MQTT packets and the client certificate, so they stay small.
+const WRITE_RECORD_SIZE: usize = 4096;
+/// Room for each certificate or key once decoded from PEM.
+const DER_SIZE: usize = 2048;
+
+/// Failed TLS handshakes, including timeouts.
+pub static TLS_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// A `TcpSocket` for embedded-tls, which is on embedded-io-async 0.7 while embassy-net
+/// is still on 0.6.
+pub struct TcpTransport<'a>(TcpSocket<'a>);
+
+impl io07::ErrorType for TcpTransport<'_> {
+    type Error = io07::ErrorKind;
+}
+
+impl io07::Read for TcpTransport<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, Self::Error> {
+        self.0
+            .read(buf)
+            .await
+            .map_err(|_| io07::ErrorKind::ConnectionReset)
+    }
+}
+
+impl io07::Write for TcpTransport<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, Self::Error> {
+        self.0
+            .write(buf)
+            .await
+            .map_err(|_| io07::ErrorKind::ConnectionReset)
+    }
+
+    async fn flush(&mut self) -> Result<(), Self::Error> {
+        self.0
+            .flush()
+            .await
+            .map_err(|_| io07::ErrorKind::ConnectionReset)
+    }
+}
+
+/// The TLS connection back on embedded-io-async 0.6 for `MqttClient`.
+pub struct TlsSocket<'a>(TlsConnection<'a, TcpTransport<'a>, Aes128GcmSha256>);
+
+impl ErrorType for TlsSocket<'_> {
+    type Error = MqttError;
+}
+
+impl Read for TlsSocket<'_> {
+    async fn read(&mut self, buf: &mut [u8]) -> Result<usize, MqttError> {
+        Ok(self.0.read(buf).await?)
+    }
+}
+
+impl Write for TlsSocket<'_> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, MqttError> {
+        Ok(self.0.write(buf).await?)
+    }
+
+    /// Closes the TLS record, nothing is sent before this.
+    async fn flush(&mut self) -> Result<(), MqttError> {
+        Ok(self.0.flush().await?)
+    }
+}
+
+pub struct RecordBuffers {
+    read: [u8; READ_RECORD_SIZE],
+    write: [u8; WRITE_RECORD_SIZE],
+}
+
+impl RecordBuffers {
+    pub const fn new() -> Self {
+        Self {
+            read: [0; READ_RECORD_SIZE],
+            write: [0; WRITE_RECORD_SIZE],
+        }
+    }
+}
+
+impl Default for RecordBuffers {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Decode the first PEM block in `pem` into `der`, returning the DER length.
+fn pem_to_der(pem: &[u8], der: &mut [u8]) -> Option<usize> {
+    let text = core::str::from_utf8(pem).ok()?;
+    let begin = &text[text.find("-----BEGIN")?..];
+    let body = &begin[begin.find('\n')? + 1..];
+    let body = &body[..body.find("-----END")?];
+    crate::base64::decode(body.as_bytes(), der)
+}
+
+/// Certificate validity is only checked once remote write has learned the time, there is
+/// no other wall clock.
+struct RemoteWriteClock;
+
+impl TlsClock for RemoteWriteClock {
+    fn now() -> Option<u64> {
+        crate::remote_write::unix_time_ms().map(|ms| ms / 1000)
+    }
+}
+
+/// Verifies the broker's certificate against the CA and signs the handshake with the
+/// client key.
+struct ClientCertProvider<'a> {
+    rng: RoscRng,
+    verifier: CertVerifier<'a, Aes128GcmSha256, RemoteWriteClock, DER_SIZE>,
+    cert_der: &'a [u8],
+    key_der: &'a [u8],
+}
+
+impl CryptoProvider for ClientCertProvider<'_> {
+    type CipherSuite = Aes128GcmSha256;
+    type Signature = DerSignature;
+
+    fn rng(&mut self) -> impl embedded_tls::CryptoRngCore {
+        &mut self.rng
+    }
+
+    fn verifier(&mut self) -> Result<&mut impl TlsVerifier<Self::CipherSuite>, TlsError> {
+        Ok(&mut self.verifier)
+    }
+
+    fn signer(
+        &mut self,
+    ) -> Result<
+        (
+            impl p256::ecdsa::signature::SignerMut<Self::Signature>,
+            SignatureScheme,
+        ),
+        TlsError,
+    > {
+        let key = SigningKey::from_pkcs8_der(self.key_der)
+            .or_else(|_| p256::SecretKey::from_sec1_der(self.key_der).map(SigningKey::from))
+            .map_err(|_| TlsError::InvalidPrivateKey)?;
+        Ok((key, SignatureScheme::EcdsaSecp256r1Sha256))
+    }
+
+    fn client_cert(&mut self) -> Option<Certificate<impl AsRef<[u8]>>> {
+        Some(Certificate::X509(self.cert_der))
+    }
+}
+
+/// Run the TLS handshake over an open TCP connection to `server_name`.
+pub async fn connect<'a>(
+    socket: TcpSocket<'a>,
+    server_name: &str,
+    buffers: &'a mut RecordBuffers,
+) -> Result<TlsSocket<'a>, MqttError> {
+    let mut ca = [0u8; DER_SIZE];
+    let mut cert = [0u8; DER_SIZE];
+    let mut key = [0u8; DER_SIZE];
+    let ca_len = pem_to_der(MQTT_CA_CERT, &mut ca).ok_or(TlsError::InvalidCertificate)?;
+    let cert_len = pem_to_der(MQTT_CLIENT_CERT, &mut cert).ok_or(TlsError::InvalidCertificate)?;
+    let key_len = pem_to_der(MQTT_CLIENT_KEY, &mut key).ok_or(TlsError::InvalidPrivateKey)?;
+
+    let config = TlsConfig::new().with_server_name(server_name);
+
+    let mut tls = TlsConnection::new(TcpTransport(socket), &mut buffers.read, &mut buffers.write);
+    let provider = ClientCertProvider {
+        rng: RoscRng,
+        verifier: CertVerifier::new(Certificate::X509(&ca[..ca_len])),
+        cert_der: &cert[..cert_len],
+        key_der: &key[..key_len],
+    };
+    with_timeout(
+        HANDSHAKE_TIMEOUT,
+        tls.open(TlsContext::new(&config, provider)),
+    )
+    .await
+    .map_err(|_| MqttError::TlsTimeout)??;
+
+    Ok(TlsSocket(tls))
+}
@@ -0,0 +1,172 @@
+//! Keep the last panic message across the reboot that follows it.
+//!
+//! The panic handler can't touch flash, so it leaves the message in a 256 byte block of
+//! SRAM that `memory.x` keeps out of RAM.  SRAM survives the watchdog reset, and on the
+//! next boot `persist` moves the message into flash.
+
+use core::fmt::Write as _;
+use core::panic::PanicInfo;
+
+use defmt::{error, info};
+use heapless::String;
+use picoserve::response::chunked::{ChunkWriter, Chunks, ChunksWritten};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::flash_store::{FlashStore, Slot, MAX_RECORD_LEN};
+
+/// The last 256 bytes of the striped SRAM banks, see `memory.x`.
+const PANIC_RAM: *mut u8 = 0x2003_FF00 as *mut u8;
+const PANIC_RAM_SIZE: usize = 256;
+const MAGIC: [u8; 4] = [0xDE, 0xAD, 0xBE, 0xEF];
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// The flash record is the panic count followed by the message.
+const COUNT_LEN: usize = 4;
+/// Limited by the flash record rather than the SRAM block.
+pub const MAX_MESSAGE_LEN: usize = MAX_RECORD_LEN - COUNT_LEN;
+const _: () = assert!(HEADER_LEN + MAX_MESSAGE_LEN <= PANIC_RAM_SIZE);
+
+/// Panics recorded in flash, loaded by `persist` at boot.
+pub static PANIC_COUNT: AtomicU32 = AtomicU32::new(0);
+
+pub struct PanicRecord {
+    pub count: u32,
+    pub message: String<MAX_MESSAGE_LEN>,
+}
+
+/// Formats into the SRAM block, dropping whatever doesn't fit.
+struct PanicRamWriter {
+    len: usize,
+}
+
+impl core::fmt::Write for PanicRamWriter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for byte in s.bytes() {
+            if self.len == MAX_MESSAGE_LEN {
+                break;
+            }
+            unsafe { PANIC_RAM.add(HEADER_LEN + self.len).write_volatile(byte) };
+            self.len += 1;
+        }
+        Ok(())
+    }
+}
+
+// Host test builds link std, which has its own
+#[cfg_attr(not(test), panic_handler)]
+#[cfg_attr(test, allow(dead_code))]
+fn panic(info: &PanicInfo) -> ! {
+    static PANICKED: AtomicBool = AtomicBool::new(false);
+
+    cortex_m::interrupt::disable();
+    // Formatting the message could panic again, only record the first one
+    if !PANICKED.swap(true, Ordering::Relaxed) {
+        error!("{}", defmt::Display2Format(info));
+
+        let mut writer = PanicRamWriter { len: 0 };
+        let _ = write!(writer, "{}", info);
+        unsafe {
+            PANIC_RAM.add(MAGIC.len()).write_volatile(writer.len as u8);
+            for (i, byte) in MAGIC.iter().enumerate() {
+                PANIC_RAM.add(i).write_volatile(*byte);
+            }
+        }
+    }
+
+    // Stops a debug probe here, otherwise the watchdog reboots the device
+    cortex_m::asm::udf()
+}
+
+/// Take the message left in SRAM by the panic handler, clearing it.
+fn take_from_ram() -> Option<String<MAX_MESSAGE_LEN>> {
+    let mut magic = [0u8; MAGIC.len()];
+    for (i, byte) in magic.iter_mut().enumerate() {
+        *byte = unsafe { PANIC_RAM.add(i).read_volatile() };
+    }
+    if magic != MAGIC {
+        return None;
+    }
+
+    let len = (unsafe { PANIC_RAM.add(MAGIC.len()).read_volatile() } as usize).min(MAX_MESSAGE_LEN);
+    let mut bytes = [0u8; MAX_MESSAGE_LEN];
+    for (i, byte) in bytes[..len].iter_mut().enumerate() {
+        *byte = unsafe { PANIC_RAM.add(HEADER_LEN + i).read_volatile() };
+    }
+    for i in 0..PANIC_RAM_SIZE {
+        unsafe { PANIC_RAM.add(i).write_volatile(0) };
+    }
+
+    // Truncating may have split a character
+    let message = match core::str::from_utf8(&bytes[..len]) {
+        Ok(message) => message,
+        Err(e) => core::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    String::try_from(message).ok()
+}
+
+/// The last panic recorded in flash.
+pub async fn load(store: &FlashStore) -> Option<PanicRecord> {
+    let mut buf = [0u8; COUNT_LEN + MAX_MESSAGE_LEN];
+    let len = store.load(Slot::PanicInfo, &mut buf).await?;
+    if len < COUNT_LEN {
+        return None;
+    }
+
+    let count = u32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]);
+    let message = core::str::from_utf8(&buf[COUNT_LEN..len]).ok()?;
+    Some(PanicRecord {
+        count,
+        message: String::try_from(message).ok()?,
+    })
+}
+
+/// Move a panic left in SRAM before the last reset into flash, and load the panic count.
+/// Call once at boot.
+pub async fn persist(store: &FlashStore) {
+    let previous = load(store).await;
+    let mut count = previous.as_ref().map_or(0, |record| record.count);
+
+    if let Some(message) = take_from_ram() {
+        count += 1;
+        error!("Rebooted after panic: {}", message.as_str());
+
+        let mut record = [0u8; COUNT_LEN + MAX_MESSAGE_LEN];
+        record[..COUNT_LEN].copy_from_slice(&count.to_le_bytes());
+        record[COUNT_LEN..COUNT_LEN + message.len()].copy_from_slice(message.as_bytes());
+        if let Err(e) = store
+            .store(Slot::PanicInfo, &record[..COUNT_LEN + message.len()])
+            .await
+        {
+            error!("Error saving panic message: {:?}", e);
+        }
+    } else if count > 0 {
+        info!("{} panics recorded in flash", count);
+    }
+
+    PANIC_COUNT.store(count, Ordering::Relaxed);
+}
+
+/// The panic message as plain text.
+pub struct PanicMessage {
+    message: String<MAX_MESSAGE_LEN>,
+}
+
+impl PanicMessage {
+    pub fn new(message: String<MAX_MESSAGE_LEN>) -> Self {
+        PanicMessage { message }
+    }
+}
+
+impl Chunks for PanicMessage {
+    fn content_type(&self) -> &'static str {
+        "text/plain; charset=utf-8"
+    }
+
+    async fn write_chunks<W: picoserve::io::Write>(
+        self,
+        mut chunk_writer: ChunkWriter<W>,
+    ) -> Result<ChunksWritten, W::Error> {
+        writeln!(chunk_writer, "{}", self.message).await?;
+        chunk_writer.finalize().await
+    }
+}
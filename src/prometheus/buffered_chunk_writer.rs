@@ -0,0 +1,129 @@
+use core::fmt;
+
+use picoserve::io::{ErrorType, Write};
+use picoserve::response::chunked::{ChunkWriter, ChunksWritten};
+use portable_atomic::{AtomicU32, Ordering};
+
+use crate::prometheus::MetricSink;
+
+/// The usual TCP MSS on Ethernet and WiFi.
+pub const TCP_MSS: usize = 1460;
+/// The chunk size line for up to 0xFFF bytes, `5ad\r\n`, and the CRLF after the data.
+const CHUNK_OVERHEAD: usize = 7;
+/// Chunk data that, with its framing, fills exactly one segment.
+pub const CHUNK_SIZE: usize = TCP_MSS - CHUNK_OVERHEAD;
+
+/// Chunks sent by every `BufferedChunkWriter` since boot.
+static CHUNKS_WRITTEN: AtomicU32 = AtomicU32::new(0);
+
+pub fn chunks_written() -> u32 {
+    CHUNKS_WRITTEN.load(Ordering::Relaxed)
+}
+
+/// Collects writes into chunks of `N` bytes.  `ChunkWriter` sends every piece of a
+/// `write!` as its own chunk, so a metric line would otherwise be a dozen chunks, each
+/// with its own length header and likely its own TCP segment.
+pub struct BufferedChunkWriter<W: Write, const N: usize> {
+    inner: ChunkWriter<W>,
+    buffer: [u8; N],
+    len: usize,
+}
+
+impl<W: Write, const N: usize> BufferedChunkWriter<W, N> {
+    pub fn new(inner: ChunkWriter<W>) -> Self {
+        Self {
+            inner,
+            buffer: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Send what's buffered as one chunk.
+    async fn write_buffer(&mut self) -> Result<(), W::Error> {
+        if self.len > 0 {
+            self.inner.write_chunk(&self.buffer[..self.len]).await?;
+            CHUNKS_WRITTEN.fetch_add(1, Ordering::Relaxed);
+            self.len = 0;
+        }
+        Ok(())
+    }
+
+    /// Send what's left and end the response.
+    pub async fn finalize(mut self) -> Result<ChunksWritten, W::Error> {
+        self.write_buffer().await?;
+        self.inner.finalize().await
+    }
+}
+
+impl<W: Write, const N: usize> ErrorType for BufferedChunkWriter<W, N> {
+    type Error = W::Error;
+}
+
+impl<W: Write, const N: usize> Write for BufferedChunkWriter<W, N> {
+    async fn write(&mut self, buf: &[u8]) -> Result<usize, W::Error> {
+        if self.len == N {
+            self.write_buffer().await?;
+        }
+        let n = buf.len().min(N - self.len);
+        self.buffer[self.len..self.len + n].copy_from_slice(&buf[..n]);
+        self.len += n;
+        Ok(n)
+    }
+
+    async fn flush(&mut self) -> Result<(), W::Error> {
+        self.write_buffer().await?;
+        self.inner.flush().await
+    }
+}
+
+/// Formats into the free end of the buffer, skipping the first `skip` bytes of the output,
+/// which an earlier pass already buffered.  Stops with an error once the buffer is full.
+struct Window<'a> {
+    buffer: &'a mut [u8],
+    len: &'a mut usize,
+    skip: usize,
+    /// Bytes of output seen, skipped or buffered.
+    seen: usize,
+}
+
+impl fmt::Write for Window<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let mut bytes = s.as_bytes();
+        let skipped = bytes.len().min(self.skip.saturating_sub(self.seen));
+        bytes = &bytes[skipped..];
+        self.seen += skipped;
+
+        let n = bytes.len().min(self.buffer.len() - *self.len);
+        self.buffer[*self.len..*self.len + n].copy_from_slice(&bytes[..n]);
+        *self.len += n;
+        self.seen += n;
+        if n < bytes.len() {
+            return Err(fmt::Error);
+        }
+        Ok(())
+    }
+}
+
+impl<W: Write, const N: usize> MetricSink for BufferedChunkWriter<W, N> {
+    type Error = W::Error;
+
+    async fn write_fmt(&mut self, args: fmt::Arguments<'_>) -> Result<(), W::Error> {
+        // Nearly every write fits in one pass.  Longer ones are formatted again after
+        // each full buffer is sent, skipping what was already buffered.
+        let mut done = 0;
+        loop {
+            let mut window = Window {
+                buffer: &mut self.buffer,
+                len: &mut self.len,
+                skip: done,
+                seen: 0,
+            };
+            let finished = fmt::write(&mut window, args).is_ok();
+            done = window.seen;
+            if finished {
+                return Ok(());
+            }
+            self.write_buffer().await?;
+        }
+    }
+}
@@ -1,12 +1,12 @@
 use core::fmt::Write;
 
-use picoserve::response::chunked::ChunkWriter;
+use embassy_time::Instant;
 
 use crate::prometheus::{
     metric_comments::MetricComments,
     metric_samples::{LabelsIter, MetricLineWriter, MetricSamples},
     sample::Sample,
-    Bucket, HistogramSamples, MetricType, MetricWriter, WriteMetric,
+    Bucket, HistogramSamples, MetricSink, MetricType, MetricWriter, WriteMetric,
 };
 pub struct HistogramFamily<'a, const LABELS: usize, const SIZE: usize, I>
 where
@@ -36,20 +36,24 @@ where
             samples,
         }
     }
+
+    /// Base unit for the `# UNIT` line, e.g. `seconds`.
+    pub fn with_unit(mut self, unit: &'a str) -> Self {
+        self.comments.unit = Some(unit);
+        self
+    }
 }
 
 impl<'a, const LABELS: usize, const SIZE: usize, I> WriteMetric<'a>
     for HistogramFamily<'a, LABELS, SIZE, I>
 where
-    I: Iterator<Item = &'a HistogramSamples<'a, LABELS, SIZE>>,
+    I: Iterator<Item = &'a HistogramSamples<'a, LABELS, SIZE>> + Clone,
 {
-    async fn write_chunks<W: picoserve::io::Write>(
-        self,
-        chunk_writer: &'a mut ChunkWriter<W>,
-    ) -> Result<(), W::Error> {
+    async fn write_chunks<W: MetricSink>(self, chunk_writer: &'a mut W) -> Result<(), W::Error> {
         self.comments.write_chunks(self.name, chunk_writer).await?;
-        for sample in self.samples {
-            if sample.count == 0 {
+        // Nothing until the first sample, rather than a row of zero buckets
+        for sample in self.samples.clone() {
+            if sample.last_sampled == Instant::MIN {
                 continue;
             }
             {
@@ -84,18 +88,53 @@ where
                 }
             }
         }
+
+        // A family of its own, OpenMetrics only allows the histogram suffixes above
+        if self.samples.clone().all(|s| s.last_sampled == Instant::MIN) {
+            return Ok(());
+        }
+        let mut age_name = heapless::String::<64>::new();
+        if write!(age_name, "{}_last_sampled_seconds", self.name).is_err() {
+            return Ok(());
+        }
+        let mut age_comments = MetricComments::new(
+            "Time since the histogram of the same name was last sampled",
+            MetricType::Gauge,
+        );
+        age_comments.unit = Some("seconds");
+        age_comments
+            .write_chunks(age_name.as_str(), chunk_writer)
+            .await?;
+        let now = Instant::now();
+        for sample in self.samples {
+            if sample.last_sampled == Instant::MIN {
+                continue;
+            }
+            let age = now.saturating_duration_since(sample.last_sampled);
+            let age_samples = [Sample::new(
+                sample.label_values,
+                age.as_micros() as f32 / 1_000_000.,
+            )];
+            MetricSamples::new(self.labels, age_samples.iter())
+                .write_chunks(SummaryMetricLineWriter::new(
+                    age_name.as_str(),
+                    "",
+                    chunk_writer,
+                ))
+                .await?;
+        }
         Ok(())
     }
 }
 
-pub struct BucketMetricLineWriter<'a, W: picoserve::io::Write> {
+pub struct BucketMetricLineWriter<'a, W: MetricSink> {
     pub name: &'a str,
-    pub chunk_writer: &'a mut ChunkWriter<W>,
+    pub chunk_writer: &'a mut W,
     pub bucket: Bucket,
 }
 
-impl<'a, W: picoserve::io::Write> BucketMetricLineWriter<'a, W> {
-    pub fn new(name: &'a str, chunk_writer: &'a mut ChunkWriter<W>, bucket: Bucket) -> Self {
+impl<'a, W: MetricSink> BucketMetricLineWriter<'a, W> {
+    pub fn new(name: &'a str, chunk_writer: &'a mut W, bucket: Bucket) -> Self {
         BucketMetricLineWriter::<'a, W> {
             name,
             chunk_writer,
@@ -104,7 +143,7 @@ impl<'a, W: picoserve::io::Write> BucketMetricLineWriter<'a, W> {
     }
 }
 
-impl<'a, W: picoserve::io::Write> MetricLineWriter for BucketMetricLineWriter<'a, W> {
+impl<'a, W: MetricSink> MetricLineWriter for BucketMetricLineWriter<'a, W> {
     type Error = W::Error;
 
     async fn write_metric_line<'b, const LABELS: usize>(
@@ -119,7 +158,7 @@ impl<'a, W: picoserve::io::Write> MetricLineWriter for BucketMetricLineWriter<'a
             write!(&mut le_label, "{}", self.bucket.le).unwrap();
         }
 
-        self.chunk_writer.write_str(self.name).await?;
+        self.chunk_writer.write_name(self.name).await?;
         self.chunk_writer.write_str("_bucket").await?;
         self.chunk_writer
             .write_labels(labels_iter.chain([("le", le_label.as_str())]))
@@ -129,14 +168,14 @@ impl<'a, W: picoserve::io::Write> MetricLineWriter for BucketMetricLineWriter<'a
     }
 }
 
-pub struct SummaryMetricLineWriter<'a, W: picoserve::io::Write> {
+pub struct SummaryMetricLineWriter<'a, W: MetricSink> {
     pub name: &'a str,
     pub name_suffix: &'a str,
-    pub chunk_writer: &'a mut ChunkWriter<W>,
+    pub chunk_writer: &'a mut W,
 }
 
-impl<'a, W: picoserve::io::Write> SummaryMetricLineWriter<'a, W> {
-    pub fn new(name: &'a str, name_suffix: &'a str, chunk_writer: &'a mut ChunkWriter<W>) -> Self {
+impl<'a, W: MetricSink> SummaryMetricLineWriter<'a, W> {
+    pub fn new(name: &'a str, name_suffix: &'a str, chunk_writer: &'a mut W) -> Self {
         SummaryMetricLineWriter::<'a, W> {
             name,
             name_suffix,
@@ -145,7 +184,7 @@ impl<'a, W: picoserve::io::Write> SummaryMetricLineWriter<'a, W> {
     }
 }
 
-impl<'a, W: picoserve::io::Write> MetricLineWriter for SummaryMetricLineWriter<'a, W> {
+impl<'a, W: MetricSink> MetricLineWriter for SummaryMetricLineWriter<'a, W> {
     type Error = W::Error;
 
     async fn write_metric_line<'b, const LABELS: usize>(
@@ -153,7 +192,7 @@ impl<'a, W: picoserve::io::Write> MetricLineWriter for SummaryMetricLineWriter<'
         value: f32,
         labels_iter: LabelsIter<'b, LABELS>,
     ) -> Result<(), Self::Error> {
-        self.chunk_writer.write_str(self.name).await?;
+        self.chunk_writer.write_name(self.name).await?;
         self.chunk_writer.write_str(self.name_suffix).await?;
         self.chunk_writer.write_labels(labels_iter).await?;
         self.chunk_writer.write_value(value).await?;
@@ -1,30 +1,101 @@
-use picoserve::response::chunked::ChunkWriter;
-
-use crate::prometheus::MetricType;
+use crate::prometheus::{MetricSink, MetricType, METRIC_PREFIX};
 
 pub(super) struct MetricComments<'a> {
     help: &'a str,
     metric_type: MetricType,
+    pub(super) unit: Option<&'a str>,
 }
 
 impl<'a> MetricComments<'a> {
     pub(super) const fn new(help: &'a str, metric_type: MetricType) -> Self {
-        Self { help, metric_type }
+        Self {
+            help,
+            metric_type,
+            unit: None,
+        }
     }
 
-    pub(super) async fn write_chunks<W: picoserve::io::Write>(
+    pub(super) async fn write_chunks<W: MetricSink>(
         &self,
         name: &'a str,
-        chunk_writer: &mut ChunkWriter<W>,
+        chunk_writer: &mut W,
     ) -> Result<(), W::Error> {
-        write!(chunk_writer, "# HELP {} {}\n", name, self.help).await?;
-        write!(
+        // An OpenMetrics counter family is named without the `_total` on its samples, and
+        // a `# UNIT` that isn't a suffix of the family name fails the whole scrape
+        let name = match self.metric_type {
+            MetricType::Counter if chunk_writer.is_open_metrics() => {
+                name.strip_suffix("_total").unwrap_or(name)
+            }
+            _ => name,
+        };
+        writeln!(
+            chunk_writer,
+            "# HELP {}{} {}",
+            METRIC_PREFIX, name, self.help
+        )
+        .await?;
+        writeln!(
             chunk_writer,
-            "# TYPE {} {}\n",
+            "# TYPE {}{} {}",
+            METRIC_PREFIX,
             name,
             self.metric_type.to_str()
         )
         .await?;
+        // OpenMetrics metadata, not part of the Prometheus text format
+        if let Some(unit) = self.unit.filter(|_| chunk_writer.is_open_metrics()) {
+            writeln!(chunk_writer, "# UNIT {}{} {}", METRIC_PREFIX, name, unit).await?;
+        }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    use crate::prometheus::{counter, BufferSink, MetricWriter, OpenMetricsSink, Sample};
+
+    /// Nothing here waits on anything, so the first poll completes.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future didn't complete"),
+        }
+    }
+
+    fn render(open_metrics: bool) -> String {
+        let mut sink = BufferSink::<512>::new();
+        let samples = [Sample::new([], 1.5)];
+        let family = counter("energy_watt_hours_total", "Energy", [], samples.iter())
+            .with_unit("watt_hours");
+        let result = if open_metrics {
+            block_on(OpenMetricsSink::new(&mut sink).write(family))
+        } else {
+            block_on(sink.write(family))
+        };
+        assert!(result.is_ok());
+        String::from_utf8(sink.as_bytes().to_vec()).unwrap()
+    }
+
+    #[test]
+    fn open_metrics_counter_unit_is_on_the_family_name() {
+        let output = render(true);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "# HELP energy_watt_hours Energy");
+        assert_eq!(lines[1], "# TYPE energy_watt_hours counter");
+        assert_eq!(lines[2], "# UNIT energy_watt_hours watt_hours");
+        assert_eq!(lines[3], "energy_watt_hours_total{} 1.5");
+    }
+
+    #[test]
+    fn text_format_counter_keeps_total() {
+        let output = render(false);
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines[0], "# HELP energy_watt_hours_total Energy");
+        assert_eq!(lines[1], "# TYPE energy_watt_hours_total counter");
+        assert_eq!(lines[2], "energy_watt_hours_total{} 1.5");
+    }
+}
@@ -1,9 +1,7 @@
-use picoserve::response::chunked::ChunkWriter;
-
 use crate::prometheus::{
     metric_comments::MetricComments,
     metric_samples::{LabelsIter, MetricLineWriter, MetricSamples},
-    MetricType, MetricWriter, Sample, WriteMetric,
+    MetricSink, MetricType, MetricWriter, Sample, WriteMetric,
 };
 
 pub struct MetricFamily<'a, const LABELS: usize, I>
@@ -32,20 +30,27 @@ where
             samples: MetricSamples::new(labels, samples),
         }
     }
+
+    /// Base unit for the `# UNIT` line, e.g. `seconds`.  Every sample in the family must
+    /// share it, and by convention the name ends with it.
+    pub fn with_unit(mut self, unit: &'a str) -> Self {
+        self.comments.unit = Some(unit);
+        self
+    }
 }
 
-pub struct SimpleMetricLineWriter<'a, W: picoserve::io::Write> {
+pub struct SimpleMetricLineWriter<'a, W: MetricSink> {
     pub name: &'a str,
-    pub chunk_writer: &'a mut ChunkWriter<W>,
+    pub chunk_writer: &'a mut W,
 }
 
-impl<'a, W: picoserve::io::Write> SimpleMetricLineWriter<'a, W> {
-    pub fn new(name: &'a str, chunk_writer: &'a mut ChunkWriter<W>) -> Self {
+impl<'a, W: MetricSink> SimpleMetricLineWriter<'a, W> {
+    pub fn new(name: &'a str, chunk_writer: &'a mut W) -> Self {
         SimpleMetricLineWriter::<'a, W> { name, chunk_writer }
     }
 }
 
-impl<'a, W: picoserve::io::Write> MetricLineWriter for SimpleMetricLineWriter<'a, W> {
+impl<'a, W: MetricSink> MetricLineWriter for SimpleMetricLineWriter<'a, W> {
     type Error = W::Error;
 
     async fn write_metric_line<'b, const LABELS: usize>(
@@ -53,7 +58,7 @@ impl<'a, W: picoserve::io::Write> MetricLineWriter for SimpleMetricLineWriter<'a
         value: f32,
         labels_iter: LabelsIter<'b, LABELS>,
     ) -> Result<(), Self::Error> {
-        self.chunk_writer.write_str(self.name).await?;
+        self.chunk_writer.write_name(self.name).await?;
         self.chunk_writer.write_labels(labels_iter).await?;
         self.chunk_writer.write_value(value).await?;
         Ok(())
@@ -64,10 +69,7 @@ impl<'a, const LABELS: usize, I> WriteMetric<'a> for MetricFamily<'a, LABELS, I>
 where
     I: Iterator<Item = &'a Sample<'a, LABELS>> + 'a,
 {
-    async fn write_chunks<W: picoserve::io::Write>(
-        self,
-        chunk_writer: &'a mut ChunkWriter<W>,
-    ) -> Result<(), W::Error> {
+    async fn write_chunks<W: MetricSink>(self, chunk_writer: &'a mut W) -> Result<(), W::Error> {
         self.comments.write_chunks(self.name, chunk_writer).await?;
         self.samples
             .write_chunks(SimpleMetricLineWriter::new(self.name, chunk_writer))
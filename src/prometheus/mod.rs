@@ -1,51 +1,240 @@
+mod buffered_chunk_writer;
 mod histogram_family;
 mod metric_comments;
 mod metric_family;
 mod metric_samples;
 pub mod sample;
+pub mod summary;
 
 use core::future::Future;
 
+use defmt::Format;
+use embassy_time::{Duration, Instant};
+use heapless::String;
+use serde::Deserialize;
+
 use picoserve::response::chunked::{ChunkWriter, Chunks, ChunksWritten};
 
+use crate::csv::CsvSink;
+pub use crate::prometheus::buffered_chunk_writer::{
+    chunks_written, BufferedChunkWriter, CHUNK_SIZE,
+};
+pub use crate::prometheus::summary::{ReservoirSample, Summary};
 use crate::prometheus::{
     histogram_family::HistogramFamily, metric_family::MetricFamily, sample::Sample,
+    summary::SummaryFamily,
 };
 
+/// Prepended to every metric name, e.g. `pico_climate_` turns `sht30_reading` into
+/// `pico_climate_sht30_reading`.  Set with the `METRIC_PREFIX` environment variable at
+/// build time.
+pub const METRIC_PREFIX: &str = match option_env!("METRIC_PREFIX") {
+    Some(prefix) => prefix,
+    None => "",
+};
+const _: () = assert!(
+    verify_metric_prefix(METRIC_PREFIX),
+    "METRIC_PREFIX must match [a-zA-Z_:][a-zA-Z0-9_:]*"
+);
+
+/// Check that `prefix` followed by a valid metric name is still a valid metric name.
+pub const fn verify_metric_prefix(prefix: &str) -> bool {
+    let bytes = prefix.as_bytes();
+    let mut i = 0;
+    while i < bytes.len() {
+        let valid = match bytes[i] {
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' | b':' => true,
+            b'0'..=b'9' => i > 0,
+            _ => false,
+        };
+        if !valid {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+/// Where rendered metrics go: a chunked HTTP response, or a `BufferSink` when the metrics
+/// are pushed somewhere.  `write!` works on anything implementing it.
+pub trait MetricSink {
+    type Error;
+
+    fn write_fmt(
+        &mut self,
+        args: core::fmt::Arguments<'_>,
+    ) -> impl Future<Output = Result<(), Self::Error>>;
+
+    /// Whether the output is OpenMetrics, which has metadata the Prometheus text format
+    /// doesn't, like `# UNIT`.
+    fn is_open_metrics(&self) -> bool {
+        false
+    }
+}
+
+/// Passes writes through to `inner`, marking them as OpenMetrics.
+pub struct OpenMetricsSink<'a, W: MetricSink> {
+    inner: &'a mut W,
+}
+
+impl<'a, W: MetricSink> OpenMetricsSink<'a, W> {
+    pub fn new(inner: &'a mut W) -> Self {
+        Self { inner }
+    }
+}
+
+impl<W: MetricSink> MetricSink for OpenMetricsSink<'_, W> {
+    type Error = W::Error;
+
+    async fn write_fmt(&mut self, args: core::fmt::Arguments<'_>) -> Result<(), W::Error> {
+        self.inner.write_fmt(args).await
+    }
+
+    fn is_open_metrics(&self) -> bool {
+        true
+    }
+}
+
+impl<W: picoserve::io::Write> MetricSink for ChunkWriter<W> {
+    type Error = W::Error;
+
+    async fn write_fmt(&mut self, args: core::fmt::Arguments<'_>) -> Result<(), W::Error> {
+        ChunkWriter::write_fmt(self, args).await
+    }
+}
+
+#[derive(Debug, Format)]
+pub struct BufferFull;
+
+/// Renders metrics into memory, for senders that need the length up front.
+pub struct BufferSink<const N: usize> {
+    buffer: String<N>,
+}
+
+impl<const N: usize> BufferSink<N> {
+    pub const fn new() -> Self {
+        Self {
+            buffer: String::new(),
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        self.buffer.as_bytes()
+    }
+}
+
+impl<const N: usize> Default for BufferSink<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> MetricSink for BufferSink<N> {
+    type Error = BufferFull;
+
+    async fn write_fmt(&mut self, args: core::fmt::Arguments<'_>) -> Result<(), BufferFull> {
+        core::fmt::Write::write_fmt(&mut self.buffer, args).map_err(|_| BufferFull)
+    }
+}
+
 pub trait MetricsRender {
-    fn write_chunks<W>(
-        &self,
-        chunk_writer: &mut ChunkWriter<W>,
-    ) -> impl Future<Output = Result<(), W::Error>>
+    fn write_chunks<W>(&self, chunk_writer: &mut W) -> impl Future<Output = Result<(), W::Error>>
     where
-        W: picoserve::io::Write;
+        W: MetricSink;
 }
+
+/// Exposition format of a `MetricsResponse`.  The metrics are the same either way,
+/// OpenMetrics only adds `# UNIT` lines and the `# EOF` terminator.  `Csv` is for spreadsheets rather than
+/// Prometheus, see `csv::CsvSink`.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Format, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum MetricsFormat {
+    #[default]
+    Prometheus,
+    OpenMetrics,
+    Csv,
+}
+
 pub struct MetricsResponse<T>
 where
     T: MetricsRender,
 {
     metrics: T,
+    format: MetricsFormat,
 }
 
 // Implement Chunks for ResponseList to enable streaming
 impl<T: MetricsRender> Chunks for MetricsResponse<T> {
     fn content_type(&self) -> &'static str {
-        "text/plain; version=0.0.4; charset=utf-8; escaping=underscores"
+        match self.format {
+            MetricsFormat::Prometheus => {
+                "text/plain; version=0.0.4; charset=utf-8; escaping=underscores"
+            }
+            MetricsFormat::OpenMetrics => {
+                "application/openmetrics-text; version=1.0.0; charset=utf-8"
+            }
+            MetricsFormat::Csv => "text/csv; charset=utf-8",
+        }
     }
 
     async fn write_chunks<W: picoserve::io::Write>(
         self,
-        mut chunk_writer: ChunkWriter<W>,
+        chunk_writer: ChunkWriter<W>,
     ) -> Result<ChunksWritten, W::Error> {
-        self.metrics.write_chunks(&mut chunk_writer).await?;
+        let mut chunk_writer = BufferedChunkWriter::<_, CHUNK_SIZE>::new(chunk_writer);
+        match self.format {
+            MetricsFormat::Prometheus => self.metrics.write_chunks(&mut chunk_writer).await?,
+            MetricsFormat::OpenMetrics => {
+                let mut open_metrics = OpenMetricsSink::new(&mut chunk_writer);
+                self.metrics.write_chunks(&mut open_metrics).await?;
+                write_eof(&mut open_metrics).await?;
+            }
+            MetricsFormat::Csv => {
+                let mut csv = CsvSink::new(&mut chunk_writer);
+                self.metrics.write_chunks(&mut csv).await?;
+            }
+        }
         chunk_writer.finalize().await
     }
 }
 
 impl<T: MetricsRender> MetricsResponse<T> {
     pub fn new(metrics: T) -> Self {
-        MetricsResponse { metrics }
+        MetricsResponse {
+            metrics,
+            format: MetricsFormat::Prometheus,
+        }
+    }
+
+    pub fn with_format(mut self, format: MetricsFormat) -> Self {
+        self.format = format;
+        self
+    }
+}
+
+/// The line OpenMetrics requires at the end of every exposition.
+pub async fn write_eof<W: MetricSink>(writer: &mut W) -> Result<(), W::Error> {
+    writeln!(writer, "# EOF").await
+}
+
+/// Write `name NaN` for each metric, with the same HELP and TYPE as a normal render, so
+/// whatever holds the last value stops reporting it.  The text formats can't carry
+/// Prometheus' own staleness marker, which is a NaN with a particular payload, so a plain
+/// NaN is the closest they get.
+pub async fn write_stale_markers<W: MetricSink>(
+    writer: &mut W,
+    metrics: &[MetricDescription],
+) -> Result<(), W::Error> {
+    for metric in metrics {
+        writer
+            .write(metric.gauge([], [Sample::new([], f32::NAN)].iter()))
+            .await?;
     }
+    Ok(())
 }
 
 #[derive(Default, Clone, Copy)]
@@ -54,15 +243,47 @@ pub struct Bucket {
     pub(crate) count: usize,
 }
 
+#[derive(Clone)]
 pub struct HistogramSamples<'a, const LABELS: usize, const SIZE: usize> {
     label_values: [&'a str; LABELS],
     buckets: [Bucket; SIZE],
     sum: f32,
     count: usize,
+    /// When `sample` was last called, `Instant::MIN` before the first.
+    pub last_sampled: Instant,
+}
+
+/// Check that histogram limits are strictly increasing and end with `+Inf`, which
+/// `HistogramSamples::sample` relies on to count each bucket correctly.
+///
+/// Use it on a `const` limits array to catch mistakes at compile time:
+/// `const _: () = assert!(verify_buckets(&LIMITS));`
+pub const fn verify_buckets(limits: &[f32]) -> bool {
+    if limits.is_empty() || limits[limits.len() - 1] != f32::INFINITY {
+        return false;
+    }
+
+    let mut i = 1;
+    while i < limits.len() {
+        // Written this way round so NaN, which compares false against everything, fails.
+        if limits[i - 1] < limits[i] {
+            i += 1;
+        } else {
+            return false;
+        }
+    }
+    true
 }
 
 impl<'a, const LABELS: usize, const SIZE: usize> HistogramSamples<'a, LABELS, SIZE> {
+    /// Panics if `limits` fails `verify_buckets`.  In a const context that panic is a
+    /// compile error.
     pub const fn new(label_values: [&'a str; LABELS], limits: [f32; SIZE]) -> Self {
+        assert!(
+            verify_buckets(&limits),
+            "histogram buckets must be strictly increasing"
+        );
+
         let mut buckets = [Bucket { le: 0.0, count: 0 }; SIZE];
         let mut i = 0;
         loop {
@@ -78,10 +299,27 @@ impl<'a, const LABELS: usize, const SIZE: usize> HistogramSamples<'a, LABELS, SI
             buckets,
             sum: 0.,
             count: 0,
+            last_sampled: Instant::MIN,
         }
     }
 
+    /// Observations since boot or the last `reset`, the histogram's `_count`.
+    pub fn observation_count(&self) -> usize {
+        self.count
+    }
+
+    /// Forget every observation, keeping the bucket limits.  `last_sampled` is kept so the
+    /// series stays on `/metrics`, where Prometheus sees the drop as a counter reset.
+    pub fn reset(&mut self) {
+        for bucket in &mut self.buckets {
+            bucket.count = 0;
+        }
+        self.sum = 0.;
+        self.count = 0;
+    }
+
     pub fn sample(&mut self, value: f32) {
+        self.last_sampled = Instant::now();
         self.count += 1;
         self.sum += value;
 
@@ -91,12 +329,119 @@ impl<'a, const LABELS: usize, const SIZE: usize> HistogramSamples<'a, LABELS, SI
             }
         }
     }
+
+    /// Estimate the `p` quantile by interpolating linearly within the bucket it falls in,
+    /// the way PromQL's `histogram_quantile()` does.  0 with no samples.  Quantiles in
+    /// the `+Inf` bucket, and `p >= 1`, give the largest finite bucket limit, a lower
+    /// bound, or `f32::MAX` if there are no finite buckets.
+    pub fn quantile_estimate(&self, p: f32) -> f32 {
+        if self.count == 0 {
+            return 0.;
+        }
+        let last_finite = if SIZE >= 2 {
+            self.buckets[SIZE - 2].le
+        } else {
+            f32::MAX
+        };
+        if p >= 1. {
+            return last_finite;
+        }
+
+        let rank = p.max(0.) * self.count as f32;
+        let mut lower_le = 0f32;
+        let mut lower_count = 0;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            if bucket.count as f32 >= rank {
+                if bucket.le == f32::INFINITY {
+                    return last_finite;
+                }
+                // Like Prometheus, the first bucket starts at 0 unless its limit is below
+                if i == 0 && bucket.le <= 0. {
+                    return bucket.le;
+                }
+                let in_bucket = (bucket.count - lower_count) as f32;
+                if in_bucket == 0. {
+                    return bucket.le;
+                }
+                return lower_le + (bucket.le - lower_le) * (rank - lower_count as f32) / in_bucket;
+            }
+            lower_le = bucket.le;
+            lower_count = bucket.count;
+        }
+        last_finite
+    }
+}
+
+/// A gauge that also keeps its minimum and maximum since the window started.  The window
+/// restarts at the first `set` after `WINDOW_SECS`, so the extremes cover up to one window
+/// of history, and only the latest value just after a restart.
+#[derive(Clone, Copy)]
+pub struct GaugeWithHistory<const WINDOW_SECS: u64> {
+    current: f32,
+    min: f32,
+    max: f32,
+    window_start: Instant,
+}
+
+impl<const WINDOW_SECS: u64> GaugeWithHistory<WINDOW_SECS> {
+    pub const fn new() -> Self {
+        Self {
+            current: 0.,
+            min: f32::INFINITY,
+            max: f32::NEG_INFINITY,
+            window_start: Instant::MIN,
+        }
+    }
+
+    pub fn set(&mut self, value: f32) {
+        if self.window_start.elapsed() > Duration::from_secs(WINDOW_SECS) {
+            self.min = value;
+            self.max = value;
+            self.window_start = Instant::now();
+        }
+        self.current = value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    /// False until the first `set`.
+    pub fn has_value(&self) -> bool {
+        self.min <= self.max
+    }
+
+    pub fn current(&self) -> f32 {
+        self.current
+    }
+
+    pub fn min(&self) -> f32 {
+        self.min
+    }
+
+    pub fn max(&self) -> f32 {
+        self.max
+    }
+
+    /// Samples labelled `stat="current"`, `stat="window_min"` and `stat="window_max"`.
+    pub fn samples(&self) -> [Sample<'static, 1>; 3] {
+        [
+            Sample::new(["current"], self.current),
+            Sample::new(["window_min"], self.min),
+            Sample::new(["window_max"], self.max),
+        ]
+    }
+}
+
+impl<const WINDOW_SECS: u64> Default for GaugeWithHistory<WINDOW_SECS> {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 pub enum MetricType {
     Counter,
     Gauge,
     Histogram,
+    Summary,
 }
 
 impl MetricType {
@@ -105,6 +450,7 @@ impl MetricType {
             Self::Counter => "counter",
             Self::Gauge => "gauge",
             Self::Histogram => "histogram",
+            Self::Summary => "summary",
         }
     }
 }
@@ -112,25 +458,36 @@ impl MetricType {
 pub trait MetricWriter<E> {
     fn write<'a>(&'a mut self, metric: impl WriteMetric<'a>)
         -> impl Future<Output = Result<(), E>>;
-    fn write_str<'s>(&mut self, value: &'s str) -> impl Future<Output = Result<(), E>>;
+    fn write_str(&mut self, value: &str) -> impl Future<Output = Result<(), E>>;
+    /// A metric name, with `METRIC_PREFIX` in front.
+    fn write_name(&mut self, name: &str) -> impl Future<Output = Result<(), E>>;
     fn write_labels<'s>(
         &mut self,
         labels: impl Iterator<Item = (&'s str, &'s str)>,
     ) -> impl Future<Output = Result<(), E>>;
     fn write_value(&mut self, value: f32) -> impl Future<Output = Result<(), E>>;
+    /// For running totals, which lose precision as `f32`.
+    fn write_value_f64(&mut self, value: f64) -> impl Future<Output = Result<(), E>>;
+    fn write_value_u64(&mut self, value: u64) -> impl Future<Output = Result<(), E>>;
 }
 
-impl<W: picoserve::io::Write> MetricWriter<W::Error> for ChunkWriter<W> {
+impl<W: MetricSink> MetricWriter<W::Error> for W {
     async fn write<'a>(&'a mut self, metric: impl WriteMetric<'a>) -> Result<(), W::Error> {
         metric.write_chunks(self).await?;
         Ok(())
     }
 
-    async fn write_str<'s>(&mut self, value: &'s str) -> Result<(), W::Error> {
+    async fn write_str(&mut self, value: &str) -> Result<(), W::Error> {
         write!(self, "{}", value).await?;
 
         Ok(())
     }
+
+    async fn write_name(&mut self, name: &str) -> Result<(), W::Error> {
+        write!(self, "{}{}", METRIC_PREFIX, name).await?;
+
+        Ok(())
+    }
     async fn write_labels<'s>(
         &mut self,
         labels_iter: impl Iterator<Item = (&'s str, &'s str)>,
@@ -140,24 +497,43 @@ impl<W: picoserve::io::Write> MetricWriter<W::Error> for ChunkWriter<W> {
             if i > 0 {
                 write!(self, ",").await?;
             }
-            write!(self, "{}=\"{}\"", label_name, label_value).await?;
+            // Backslashes, quotes and newlines escaped, as the format requires
+            write!(self, "{}=\"", label_name).await?;
+            let mut rest = label_value;
+            while let Some(i) = rest.find(['\\', '"', '\n']) {
+                let escape = match rest.as_bytes()[i] {
+                    b'\\' => "\\\\",
+                    b'"' => "\\\"",
+                    _ => "\\n",
+                };
+                write!(self, "{}{}", &rest[..i], escape).await?;
+                rest = &rest[i + 1..];
+            }
+            write!(self, "{}\"", rest).await?;
         }
         write!(self, "{}", "}").await?;
         Ok(())
     }
 
     async fn write_value(&mut self, value: f32) -> Result<(), W::Error> {
-        write!(self, " {}\n", value).await?;
+        writeln!(self, " {}", value).await?;
+        Ok(())
+    }
+
+    async fn write_value_f64(&mut self, value: f64) -> Result<(), W::Error> {
+        writeln!(self, " {}", value).await?;
+        Ok(())
+    }
+
+    async fn write_value_u64(&mut self, value: u64) -> Result<(), W::Error> {
+        writeln!(self, " {}", value).await?;
         Ok(())
     }
 }
 pub trait WriteMetric<'a> {
-    fn write_chunks<W>(
-        self,
-        chunk_writer: &'a mut ChunkWriter<W>,
-    ) -> impl Future<Output = Result<(), W::Error>>
+    fn write_chunks<W>(self, chunk_writer: &'a mut W) -> impl Future<Output = Result<(), W::Error>>
     where
-        W: picoserve::io::Write;
+        W: MetricSink;
 }
 
 pub const fn gauge<'a, const LABELS: usize, I>(
@@ -197,3 +573,71 @@ pub const fn histogram<
 ) -> HistogramFamily<'a, LABELS, COUNT, I> {
     HistogramFamily::new(name, help, MetricType::Histogram, labels, samples)
 }
+
+pub const fn summary<'a, const Q: usize>(
+    name: &'a str,
+    help: &'a str,
+    summary: &'a Summary<Q>,
+) -> SummaryFamily<'a, Q> {
+    SummaryFamily::new(name, help, summary)
+}
+
+/// Name, HELP and unit of a metric family, for those whose HELP spells out units and how
+/// the value is worked out.  Build it with `describe` in a `const` so mistakes fail the
+/// build.
+#[derive(Clone, Copy)]
+pub struct MetricDescription {
+    pub name: &'static str,
+    pub help: &'static str,
+    /// Base unit for the `# UNIT` line, or empty when the samples mix units.
+    pub unit: &'static str,
+}
+
+/// Panics on an empty HELP, which in a `const` is a compile error:
+/// `const TEMPERATURE: MetricDescription = describe("name", "help", "celsius");`
+pub const fn describe(
+    name: &'static str,
+    help: &'static str,
+    unit: &'static str,
+) -> MetricDescription {
+    assert!(!help.is_empty(), "metric HELP must not be empty");
+    MetricDescription { name, help, unit }
+}
+
+impl MetricDescription {
+    pub fn gauge<'a, const LABELS: usize, I>(
+        &self,
+        labels: [&'static str; LABELS],
+        samples: I,
+    ) -> MetricFamily<'a, LABELS, I>
+    where
+        I: Iterator<Item = &'a Sample<'a, LABELS>> + 'a,
+    {
+        self.with_unit(gauge(self.name, self.help, labels, samples))
+    }
+
+    pub fn counter<'a, const LABELS: usize, I>(
+        &self,
+        labels: [&'static str; LABELS],
+        samples: I,
+    ) -> MetricFamily<'a, LABELS, I>
+    where
+        I: Iterator<Item = &'a Sample<'a, LABELS>> + 'a,
+    {
+        self.with_unit(counter(self.name, self.help, labels, samples))
+    }
+
+    fn with_unit<'a, const LABELS: usize, I>(
+        &self,
+        family: MetricFamily<'a, LABELS, I>,
+    ) -> MetricFamily<'a, LABELS, I>
+    where
+        I: Iterator<Item = &'a Sample<'a, LABELS>> + 'a,
+    {
+        if self.unit.is_empty() {
+            family
+        } else {
+            family.with_unit(self.unit)
+        }
+    }
+}
@@ -1,3 +1,7 @@
+use core::fmt::{Display, Write};
+
+use heapless::String;
+
 pub struct Sample<'a, const LABELS: usize> {
     label_values: [&'a str; LABELS],
     value: f32,
@@ -31,3 +35,65 @@ impl<'a, const LABELS: usize> Sample<'a, LABELS> {
         f
     }
 }
+
+/// A label value formatted at runtime, e.g. an I2C address, for when there's no table of
+/// every possible string to borrow from.  `Sample` borrows it through `as_str`, so it must
+/// outlive the sample.
+pub struct LabelValue<const N: usize>(String<N>);
+
+impl<const N: usize> LabelValue<N> {
+    /// Empty if `value` doesn't fit in `N` bytes.
+    fn format(value: impl Display) -> Self {
+        let mut label = String::new();
+        if write!(label, "{}", value).is_err() {
+            label.clear();
+        }
+        Self(label)
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl<const N: usize> From<u8> for LabelValue<N> {
+    fn from(value: u8) -> Self {
+        Self::format(value)
+    }
+}
+
+impl<const N: usize> From<u16> for LabelValue<N> {
+    fn from(value: u16) -> Self {
+        Self::format(value)
+    }
+}
+
+impl<const N: usize> From<u32> for LabelValue<N> {
+    fn from(value: u32) -> Self {
+        Self::format(value)
+    }
+}
+
+impl<const N: usize> From<i8> for LabelValue<N> {
+    fn from(value: i8) -> Self {
+        Self::format(value)
+    }
+}
+
+impl<const N: usize> From<i16> for LabelValue<N> {
+    fn from(value: i16) -> Self {
+        Self::format(value)
+    }
+}
+
+impl<const N: usize> From<f32> for LabelValue<N> {
+    fn from(value: f32) -> Self {
+        Self::format(value)
+    }
+}
+
+impl<'a, const N: usize> From<&'a LabelValue<N>> for &'a str {
+    fn from(value: &'a LabelValue<N>) -> Self {
+        value.as_str()
+    }
+}
@@ -0,0 +1,227 @@
+use crate::prometheus::{
+    metric_comments::MetricComments, sample::LabelValue, MetricSink, MetricType, MetricWriter,
+    WriteMetric,
+};
+
+/// A fixed size, uniformly random sample of every value recorded, for quantiles over a
+/// stream too long to keep.  Vitter's Algorithm R: once full, the `n`th value replaces a
+/// random entry with probability `N / n`, so each value seen is equally likely to be in
+/// the sample.  `O(1)` per value, the sorting happens in `quantile`.
+///
+/// The sum and count run for as long as the device is up, so they are kept as `f64` and
+/// `u64`, which an `f32` sum would stop adding to within days of readings.
+pub struct ReservoirSample<const N: usize> {
+    buf: [f32; N],
+    count: u64,
+    /// LCG state, no need for anything better to pick entries.
+    rng: u32,
+    sum: f64,
+}
+
+impl<const N: usize> ReservoirSample<N> {
+    pub const fn new() -> Self {
+        Self {
+            buf: [0.; N],
+            count: 0,
+            rng: 0x2545_F491,
+            sum: 0.,
+        }
+    }
+
+    /// Numerical Recipes' LCG constants.
+    fn next_random(&mut self) -> u32 {
+        self.rng = self.rng.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+        self.rng
+    }
+
+    pub fn record(&mut self, value: f32) {
+        self.count += 1;
+        self.sum += value as f64;
+        if self.count <= N as u64 {
+            self.buf[self.count as usize - 1] = value;
+            return;
+        }
+
+        // Uniform in 0..count, kept if it lands in the buffer
+        let slot = (self.next_random() as u128 * self.count as u128) >> 32;
+        if slot < N as u128 {
+            self.buf[slot as usize] = value;
+        }
+    }
+
+    /// Values recorded since boot, not just those kept.
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Estimate the `p` quantile from the values kept.  NaN before the first value.
+    pub fn quantile(&self, p: f32) -> f32 {
+        let len = (self.count as usize).min(N);
+        if len == 0 {
+            return f32::NAN;
+        }
+        let mut sorted = self.buf;
+        let sorted = &mut sorted[..len];
+        sorted.sort_unstable_by(|a, b| a.total_cmp(b));
+        let index = libm::roundf(p.clamp(0., 1.) * (len - 1) as f32) as usize;
+        sorted[index]
+    }
+
+    /// The `quantiles` and totals, small enough to copy into a snapshot.  `None` before the
+    /// first value.
+    pub fn summary<const Q: usize>(&self, quantiles: [f32; Q]) -> Option<Summary<Q>> {
+        if self.count == 0 {
+            return None;
+        }
+        Some(Summary {
+            quantiles: quantiles.map(|p| (p, self.quantile(p))),
+            sum: self.sum,
+            count: self.count,
+        })
+    }
+}
+
+impl<const N: usize> Default for ReservoirSample<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Quantiles of a `ReservoirSample`, written by `summary` as a Prometheus summary.
+#[derive(Clone, Copy)]
+pub struct Summary<const Q: usize> {
+    /// `(p, value)` pairs.
+    pub quantiles: [(f32, f32); Q],
+    pub sum: f64,
+    pub count: u64,
+}
+
+pub struct SummaryFamily<'a, const Q: usize> {
+    name: &'a str,
+    comments: MetricComments<'a>,
+    summary: &'a Summary<Q>,
+}
+
+impl<'a, const Q: usize> SummaryFamily<'a, Q> {
+    pub(super) const fn new(name: &'a str, help: &'a str, summary: &'a Summary<Q>) -> Self {
+        SummaryFamily {
+            name,
+            comments: MetricComments::new(help, MetricType::Summary),
+            summary,
+        }
+    }
+
+    /// Base unit for the `# UNIT` line, e.g. `seconds`.
+    pub fn with_unit(mut self, unit: &'a str) -> Self {
+        self.comments.unit = Some(unit);
+        self
+    }
+}
+
+impl<'a, const Q: usize> WriteMetric<'a> for SummaryFamily<'a, Q> {
+    async fn write_chunks<W: MetricSink>(self, chunk_writer: &'a mut W) -> Result<(), W::Error> {
+        self.comments.write_chunks(self.name, chunk_writer).await?;
+        for (p, value) in self.summary.quantiles {
+            let quantile = LabelValue::<16>::from(p);
+
+            chunk_writer.write_name(self.name).await?;
+            chunk_writer
+                .write_labels([("quantile", quantile.as_str())].into_iter())
+                .await?;
+            chunk_writer.write_value(value).await?;
+        }
+
+        chunk_writer.write_name(self.name).await?;
+        chunk_writer.write_str("_sum").await?;
+        chunk_writer.write_labels(core::iter::empty()).await?;
+        chunk_writer.write_value_f64(self.summary.sum).await?;
+
+        chunk_writer.write_name(self.name).await?;
+        chunk_writer.write_str("_count").await?;
+        chunk_writer.write_labels(core::iter::empty()).await?;
+        chunk_writer.write_value_u64(self.summary.count).await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use core::future::Future;
+    use core::pin::pin;
+    use core::task::{Context, Poll, Waker};
+
+    use super::*;
+    use crate::prometheus::BufferSink;
+
+    /// Nothing here waits on anything, so the first poll completes.
+    fn block_on<F: Future>(future: F) -> F::Output {
+        match pin!(future).poll(&mut Context::from_waker(Waker::noop())) {
+            Poll::Ready(output) => output,
+            Poll::Pending => panic!("future didn't complete"),
+        }
+    }
+
+    /// xorshift32, unrelated to the reservoir's LCG so the two don't correlate.
+    fn uniform(state: &mut u32) -> f32 {
+        *state ^= *state << 13;
+        *state ^= *state >> 17;
+        *state ^= *state << 5;
+        *state as f32 / u32::MAX as f32
+    }
+
+    #[test]
+    fn median_of_uniform_values() {
+        // Large enough that the median's standard error, 0.5 / sqrt(N), is well under 0.01.
+        // The shipped size is checked against its own error below.
+        let mut reservoir = ReservoirSample::<16384>::new();
+        let mut state = 0x1234_5678;
+        for _ in 0..1_000_000 {
+            reservoir.record(uniform(&mut state));
+        }
+        assert_eq!(reservoir.count(), 1_000_000);
+        let median = reservoir.quantile(0.5);
+        assert!((median - 0.5).abs() <= 0.01, "median {}", median);
+    }
+
+    #[test]
+    fn median_of_uniform_values_at_shipped_size() {
+        // 0.5 / sqrt(128) is about 0.044, so 0.01 would fail most seeds.  0.15 is about
+        // 3.4 standard errors, wider than the worst of 200 seeds tried (0.11).
+        const N: usize = crate::sht30::TEMPERATURE_RESERVOIR_SIZE;
+        let mut reservoir = ReservoirSample::<N>::new();
+        let mut state = 0x1234_5678;
+        for _ in 0..1_000_000 {
+            reservoir.record(uniform(&mut state));
+        }
+        let median = reservoir.quantile(0.5);
+        assert!((median - 0.5).abs() <= 0.15, "median {}", median);
+    }
+
+    #[test]
+    fn sum_and_count_written_at_full_precision() {
+        // 2^24 + 1 readings of 1.0, one more than f32 counts exactly
+        let summary = Summary {
+            quantiles: [(0.5, 1.)],
+            sum: 16_777_217.,
+            count: 16_777_217,
+        };
+        let mut sink = BufferSink::<256>::new();
+        let result = block_on(sink.write(crate::prometheus::summary("t", "T", &summary)));
+        assert!(result.is_ok());
+        let output = core::str::from_utf8(sink.as_bytes()).unwrap();
+        assert!(output.contains("t_sum{} 16777217\n"), "{}", output);
+        assert!(output.contains("t_count{} 16777217\n"), "{}", output);
+    }
+
+    #[test]
+    fn sum_and_count_cover_every_value() {
+        let mut reservoir = ReservoirSample::<4>::new();
+        for _ in 0..10 {
+            reservoir.record(1.5);
+        }
+        let summary = reservoir.summary([0.5]).unwrap();
+        assert_eq!(summary.count, 10);
+        assert_eq!(summary.sum, 15.);
+        assert_eq!(summary.quantiles, [(0.5, 1.5)]);
+    }
+}
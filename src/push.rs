@@ -0,0 +1,220 @@
+use core::fmt::Write as _;
+
+use defmt::{error, info, Format};
+use embassy_futures::select::{select, Either};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::signal::Signal;
+use embassy_time::{with_timeout, Duration, Timer};
+use embedded_io_async::Write;
+use heapless::String;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::http::{AppState, PicoClimateMetrics};
+use crate::prometheus::{
+    write_stale_markers, BufferFull, BufferSink, MetricDescription, MetricsRender,
+};
+use crate::record_delivery;
+use crate::task_registry::{self, TaskStatus};
+use crate::{adc_temp_sensor, bmp280, dht22, ina237, sht30};
+
+pub const PUSHGATEWAY_DEFAULT_PORT: u16 = 9091;
+
+const PUSH_INTERVAL: Duration = Duration::from_secs(60);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The HELP and TYPE lines alone are over 4 KiB, so even without the WiFi histograms the
+/// body needs more room than that.
+const BODY_SIZE: usize = 16 * 1024;
+
+pub static PUSH_COUNT: AtomicU32 = AtomicU32::new(0);
+pub static PUSH_ERRORS: AtomicU32 = AtomicU32::new(0);
+
+/// The sensor readings, which shouldn't outlive the device on the Pushgateway.  POST
+/// replaces every series of a pushed name, so one unlabelled NaN clears them all.  Each
+/// goes with the HELP and TYPE of its normal render, as the Pushgateway rejects a family
+/// whose type differs from another group's.
+const STALE_METRICS: [MetricDescription; 5] = [
+    adc_temp_sensor::ADC_TEMP_SENSOR,
+    sht30::SHT30_READING,
+    ina237::INA237_READING,
+    bmp280::BMP280_READING,
+    dht22::DHT22_READING,
+];
+/// How long a reboot waits for the stale markers to go out.
+const STALE_PUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Set once `pushgateway_task` is running, otherwise nothing answers `STALE_REQUEST`.
+static PUSH_ENABLED: AtomicBool = AtomicBool::new(false);
+static STALE_REQUEST: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+static STALE_DONE: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+
+#[derive(Debug, Format)]
+pub enum PushError {
+    Dns,
+    Connect(embassy_net::tcp::ConnectError),
+    Tcp(embassy_net::tcp::Error),
+    BufferFull,
+    /// First digit of an HTTP status other than 2xx, or 0 if the response wasn't HTTP.
+    Status(u8),
+    Timeout,
+}
+
+impl From<embassy_net::tcp::Error> for PushError {
+    fn from(value: embassy_net::tcp::Error) -> Self {
+        PushError::Tcp(value)
+    }
+}
+
+impl From<BufferFull> for PushError {
+    fn from(_: BufferFull) -> Self {
+        PushError::BufferFull
+    }
+}
+
+/// Send `body` in one HTTP request and check for a 2xx response.
+async fn post(
+    stack: Stack<'static>,
+    host: &str,
+    port: u16,
+    path: &str,
+    body: &[u8],
+) -> Result<(), PushError> {
+    let addr = match stack
+        .dns_query(host, embassy_net::dns::DnsQueryType::A)
+        .await
+    {
+        Ok(addresses) => *addresses.first().ok_or(PushError::Dns)?,
+        Err(_) => return Err(PushError::Dns),
+    };
+
+    let mut rx_buffer = [0; 256];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(RESPONSE_TIMEOUT));
+    socket
+        .connect(embassy_net::IpEndpoint::new(addr, port))
+        .await
+        .map_err(PushError::Connect)?;
+
+    let mut head = String::<256>::new();
+    write!(
+        &mut head,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        port,
+        body.len()
+    )
+    .map_err(|_| PushError::BufferFull)?;
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+    socket.flush().await?;
+
+    // Only the status line matters: "HTTP/1.1 200 OK"
+    let mut status = [0u8; 12];
+    let mut len = 0;
+    while len < status.len() {
+        let n = with_timeout(RESPONSE_TIMEOUT, socket.read(&mut status[len..]))
+            .await
+            .map_err(|_| PushError::Timeout)??;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    socket.close();
+
+    match status.get(9) {
+        Some(b'2') if status.starts_with(b"HTTP/") => Ok(()),
+        Some(digit) if status.starts_with(b"HTTP/") => Err(PushError::Status(digit - b'0')),
+        _ => Err(PushError::Status(0)),
+    }
+}
+
+/// Ask `pushgateway_task` to replace the sensor readings with NaN, for when the device
+/// is about to go down and the last values would otherwise stay on the Pushgateway.
+pub fn request_stale_markers() {
+    if PUSH_ENABLED.load(Ordering::Relaxed) {
+        STALE_REQUEST.signal(());
+    }
+}
+
+/// `request_stale_markers`, then wait up to 2 seconds for the push to finish.
+pub async fn push_stale_markers() {
+    if PUSH_ENABLED.load(Ordering::Relaxed) {
+        STALE_DONE.reset();
+        STALE_REQUEST.signal(());
+        let _ = with_timeout(STALE_PUSH_TIMEOUT, STALE_DONE.wait()).await;
+    }
+}
+
+/// POST all metrics to a Prometheus Pushgateway every 60 seconds, for networks where
+/// Prometheus can't scrape the device.
+///
+/// Metrics go to `/metrics/job/{job}/instance/{hostname}`.  The body is rendered in full
+/// before sending so `Content-Length` can be set.
+#[embassy_executor::task]
+pub async fn pushgateway_task(
+    stack: &'static Stack<'static>,
+    host: &'static str,
+    port: u16,
+    job: &'static str,
+    app_state: &'static AppState,
+) -> ! {
+    let mut path = String::<128>::new();
+    if write!(
+        &mut path,
+        "/metrics/job/{}/instance/{}",
+        job, app_state.hostname
+    )
+    .is_err()
+    {
+        error!("pushgateway: Job name too long: {}", job);
+    }
+
+    let metrics = PicoClimateMetrics::for_push(*app_state);
+    let mut body = BufferSink::<BODY_SIZE>::new();
+    info!("pushgateway: Target {}:{}{}", host, port, path.as_str());
+    PUSH_ENABLED.store(true, Ordering::Relaxed);
+    let task = task_registry::PUSHGATEWAY;
+    loop {
+        let wake = select(Timer::after(PUSH_INTERVAL), STALE_REQUEST.wait()).await;
+        task_registry::iteration(task);
+        if let Either::Second(()) = wake {
+            info!("pushgateway: Pushing stale markers");
+            body.clear();
+            let result = match write_stale_markers(&mut body, &STALE_METRICS).await {
+                Ok(()) => post(*stack, host, port, &path, body.as_bytes()).await,
+                Err(e) => Err(e.into()),
+            };
+            if let Err(e) = result {
+                error!("pushgateway: Stale marker push failed: {:?}", e);
+            }
+            task_registry::set_status(task, TaskStatus::Waiting);
+            STALE_DONE.signal(());
+            continue;
+        }
+        stack.wait_config_up().await;
+
+        body.clear();
+        let result = match metrics.write_chunks(&mut body).await {
+            Ok(()) => post(*stack, host, port, &path, body.as_bytes()).await,
+            Err(e) => Err(e.into()),
+        };
+
+        PUSH_COUNT.fetch_add(1, Ordering::Relaxed);
+        match result {
+            Ok(()) => {
+                // Where nothing scrapes the device this is what keeps the watchdog fed
+                record_delivery().await;
+                task_registry::set_status(task, TaskStatus::Waiting);
+            }
+            Err(e) => {
+                error!("pushgateway: Push failed: {:?}", e);
+                PUSH_ERRORS.fetch_add(1, Ordering::Relaxed);
+                task_registry::set_status(task, TaskStatus::Error);
+            }
+        }
+    }
+}
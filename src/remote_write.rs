@@ -0,0 +1,428 @@
+//! Prometheus remote-write, for pushing straight into Prometheus, Mimir, Thanos or
+//! Grafana Cloud without a scrape target.
+//!
+//! The `WriteRequest` protobuf is encoded by hand into a fixed buffer, and "compressed"
+//! as a snappy block made only of literals, which every snappy decoder accepts.  The
+//! body is small enough that real compression isn't worth the RAM.
+
+use core::fmt::Write as _;
+
+use defmt::{error, info};
+use embassy_net::{tcp::TcpSocket, Stack};
+use embassy_time::{with_timeout, Duration, Instant, Timer};
+use embedded_io_async::Write;
+use heapless::{String, Vec};
+use portable_atomic::{AtomicU32, AtomicU64, Ordering};
+
+use crate::http::AppState;
+use crate::prometheus::METRIC_PREFIX;
+use crate::push::PushError;
+use crate::record_delivery;
+use crate::task_registry::{self, TaskStatus};
+
+/// Prometheus itself, which needs `--web.enable-remote-write-receiver`.
+pub const REMOTE_WRITE_DEFAULT_PORT: u16 = 9090;
+
+const PUSH_INTERVAL: Duration = Duration::from_secs(15);
+const RESPONSE_TIMEOUT: Duration = Duration::from_secs(10);
+const JOB: &str = "pico-climate";
+const MAX_SERIES: usize = 8;
+const BODY_SIZE: usize = 1024;
+/// The snappy header and literal tags add at most 5 bytes.
+const COMPRESSED_SIZE: usize = BODY_SIZE + 8;
+
+/// Samples accepted by the remote-write endpoint.
+pub static SAMPLES: AtomicU32 = AtomicU32::new(0);
+/// Compressed body bytes accepted by the remote-write endpoint.
+pub static BYTES: AtomicU32 = AtomicU32::new(0);
+
+// Protobuf wire types
+const WIRE_VARINT: u8 = 0;
+const WIRE_FIXED64: u8 = 1;
+const WIRE_LEN: u8 = 2;
+
+/// One series with a single sample, `name{label}` plus the `instance` and `job` labels.
+struct Series {
+    name: &'static str,
+    label: Option<(&'static str, &'static str)>,
+    value: f32,
+}
+
+impl Series {
+    fn new(name: &'static str, label: Option<(&'static str, &'static str)>, value: f32) -> Self {
+        Series { name, label, value }
+    }
+}
+
+fn varint_len(mut value: u64) -> usize {
+    let mut len = 1;
+    while value >= 0x80 {
+        value >>= 7;
+        len += 1;
+    }
+    len
+}
+
+/// Encoded size of a length-delimited field holding `len` bytes.
+fn len_field_len(len: usize) -> usize {
+    1 + varint_len(len as u64) + len
+}
+
+/// A protobuf message being written into a fixed buffer.
+struct Encoder<'a, const N: usize> {
+    buf: &'a mut Vec<u8, N>,
+}
+
+impl<const N: usize> Encoder<'_, N> {
+    fn varint(&mut self, mut value: u64) -> Result<(), PushError> {
+        while value >= 0x80 {
+            self.byte((value as u8) | 0x80)?;
+            value >>= 7;
+        }
+        self.byte(value as u8)
+    }
+
+    fn byte(&mut self, value: u8) -> Result<(), PushError> {
+        self.buf.push(value).map_err(|_| PushError::BufferFull)
+    }
+
+    fn tag(&mut self, field: u8, wire_type: u8) -> Result<(), PushError> {
+        self.byte((field << 3) | wire_type)
+    }
+
+    /// The header of a length-delimited field, the caller writes the `len` bytes.
+    fn len_header(&mut self, field: u8, len: usize) -> Result<(), PushError> {
+        self.tag(field, WIRE_LEN)?;
+        self.varint(len as u64)
+    }
+
+    fn string(&mut self, field: u8, value: &str) -> Result<(), PushError> {
+        self.len_header(field, value.len())?;
+        self.buf
+            .extend_from_slice(value.as_bytes())
+            .map_err(|_| PushError::BufferFull)
+    }
+
+    fn label(&mut self, name: &str, value: &str) -> Result<(), PushError> {
+        // TimeSeries.labels = 1, Label { name = 1, value = 2 }
+        self.len_header(1, len_field_len(name.len()) + len_field_len(value.len()))?;
+        self.string(1, name)?;
+        self.string(2, value)
+    }
+
+    /// One `TimeSeries`.  Labels must be sorted by name, and `__name__` sorts first.
+    fn series(
+        &mut self,
+        series: &Series,
+        instance: &str,
+        timestamp_ms: u64,
+    ) -> Result<(), PushError> {
+        let mut name = String::<64>::new();
+        name.push_str(METRIC_PREFIX)
+            .and_then(|_| name.push_str(series.name))
+            .map_err(|_| PushError::BufferFull)?;
+        let mut labels = [
+            ("__name__", name.as_str()),
+            ("instance", instance),
+            ("job", JOB),
+            ("", ""),
+        ];
+        let mut label_count = 3;
+        if let Some(label) = series.label {
+            labels[3] = label;
+            label_count = 4;
+        }
+        let labels = &mut labels[..label_count];
+        labels.sort_unstable_by_key(|(name, _)| *name);
+
+        let label_len = |(name, value): &(&str, &str)| {
+            len_field_len(len_field_len(name.len()) + len_field_len(value.len()))
+        };
+        // Sample { double value = 1; int64 timestamp = 2; }
+        let sample_len = 1 + 8 + 1 + varint_len(timestamp_ms);
+        let len = labels.iter().map(label_len).sum::<usize>() + len_field_len(sample_len);
+
+        // WriteRequest.timeseries = 1
+        self.len_header(1, len)?;
+        for (name, value) in labels.iter() {
+            self.label(name, value)?;
+        }
+        // TimeSeries.samples = 2
+        self.len_header(2, sample_len)?;
+        self.tag(1, WIRE_FIXED64)?;
+        self.buf
+            .extend_from_slice(&(series.value as f64).to_le_bytes())
+            .map_err(|_| PushError::BufferFull)?;
+        self.tag(2, WIRE_VARINT)?;
+        self.varint(timestamp_ms)
+    }
+}
+
+/// Wrap `src` as a snappy block of literals.
+fn snappy_literals<const N: usize>(src: &[u8], dst: &mut Vec<u8, N>) -> Result<(), PushError> {
+    dst.clear();
+    let mut encoder = Encoder { buf: dst };
+    encoder.varint(src.len() as u64)?;
+    // A literal tag holds at most 65536 bytes with a 2 byte length
+    for chunk in src.chunks(1 << 16) {
+        let n = chunk.len() - 1;
+        if n < 60 {
+            encoder.byte((n as u8) << 2)?;
+        } else if n < 1 << 8 {
+            encoder.byte(60 << 2)?;
+            encoder.byte(n as u8)?;
+        } else {
+            encoder.byte(61 << 2)?;
+            encoder.byte(n as u8)?;
+            encoder.byte((n >> 8) as u8)?;
+        }
+        encoder
+            .buf
+            .extend_from_slice(chunk)
+            .map_err(|_| PushError::BufferFull)?;
+    }
+    Ok(())
+}
+
+/// The current sensor values, mostly the same ones the statsd push sends.
+async fn collect(app_state: &AppState) -> Vec<Series, MAX_SERIES> {
+    let (sht30_state, ina237_state) = {
+        let state = app_state.lock().await;
+        (state.sht30_state, state.ina237_state)
+    };
+
+    let mut series = Vec::new();
+    let sht30_output = sht30_state.lock().await.snapshot();
+    if sht30_output.last_read_age_seconds.is_some() {
+        let _ = series.push(Series::new(
+            "sht30_reading",
+            Some(("sensor", "temperature")),
+            sht30_output.temperature,
+        ));
+        let _ = series.push(Series::new(
+            "sht30_reading",
+            Some(("sensor", "humidity")),
+            sht30_output.humidity,
+        ));
+    }
+
+    if let Some(ina237_state) = ina237_state {
+        let ina237_output = ina237_state.lock().await.peek();
+        let _ = series.push(Series::new(
+            "ina237_reading",
+            Some(("register", "bus_voltage")),
+            ina237_output.bus_voltage,
+        ));
+        let _ = series.push(Series::new(
+            "ina237_reading",
+            Some(("register", "shunt_voltage_mv")),
+            ina237_output.shunt_voltage_mv,
+        ));
+        let _ = series.push(Series::new(
+            "ina237_reading",
+            Some(("register", "current")),
+            ina237_output.current,
+        ));
+        let _ = series.push(Series::new(
+            "ina237_energy_watt_hours_total",
+            None,
+            ina237_output.energy_watt_hours,
+        ));
+    }
+
+    series
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let month = month as i64;
+    let day_of_year =
+        (153 * (if month > 2 { month - 3 } else { month + 9 }) + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// Parse an HTTP `Date` value, `Sun, 06 Nov 1994 08:49:37 GMT`, into Unix seconds.
+fn parse_http_date(value: &str) -> Option<u64> {
+    let mut parts = value.split_ascii_whitespace().skip(1);
+    let day: u32 = parts.next()?.parse().ok()?;
+    let month = match parts.next()? {
+        "Jan" => 1,
+        "Feb" => 2,
+        "Mar" => 3,
+        "Apr" => 4,
+        "May" => 5,
+        "Jun" => 6,
+        "Jul" => 7,
+        "Aug" => 8,
+        "Sep" => 9,
+        "Oct" => 10,
+        "Nov" => 11,
+        "Dec" => 12,
+        _ => return None,
+    };
+    let year: i64 = parts.next()?.parse().ok()?;
+    let mut time = parts.next()?.split(':').map(|t| t.parse::<u64>().ok());
+    let (hour, minute, second) = (time.next()??, time.next()??, time.next()??);
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    Some(days * 86400 + hour * 3600 + minute * 60 + second)
+}
+
+/// POST one snappy compressed `WriteRequest`, returning the `Date` of a 2xx response in
+/// Unix seconds if the server sent one.
+async fn post(
+    stack: Stack<'static>,
+    host: &str,
+    port: u16,
+    path: &str,
+    body: &[u8],
+) -> Result<Option<u64>, PushError> {
+    let addr = match stack
+        .dns_query(host, embassy_net::dns::DnsQueryType::A)
+        .await
+    {
+        Ok(addresses) => *addresses.first().ok_or(PushError::Dns)?,
+        Err(_) => return Err(PushError::Dns),
+    };
+
+    let mut rx_buffer = [0; 512];
+    let mut tx_buffer = [0; 1024];
+    let mut socket = TcpSocket::new(stack, &mut rx_buffer, &mut tx_buffer);
+    socket.set_timeout(Some(RESPONSE_TIMEOUT));
+    socket
+        .connect(embassy_net::IpEndpoint::new(addr, port))
+        .await
+        .map_err(PushError::Connect)?;
+
+    let mut head = String::<320>::new();
+    write!(
+        &mut head,
+        "POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/x-protobuf\r\nContent-Encoding: snappy\r\nX-Prometheus-Remote-Write-Version: 0.1.0\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        path,
+        host,
+        port,
+        body.len()
+    )
+    .map_err(|_| PushError::BufferFull)?;
+
+    socket.write_all(head.as_bytes()).await?;
+    socket.write_all(body).await?;
+    socket.flush().await?;
+
+    // Read until the end of the headers, or as much of them as fits
+    let mut response = [0u8; 512];
+    let mut len = 0;
+    while len < response.len() && !response[..len].windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = with_timeout(RESPONSE_TIMEOUT, socket.read(&mut response[len..]))
+            .await
+            .map_err(|_| PushError::Timeout)??;
+        if n == 0 {
+            break;
+        }
+        len += n;
+    }
+    socket.close();
+
+    let response = &response[..len];
+    match response.get(9) {
+        Some(b'2') if response.starts_with(b"HTTP/") => {}
+        Some(digit) if response.starts_with(b"HTTP/") => {
+            return Err(PushError::Status(digit - b'0'))
+        }
+        _ => return Err(PushError::Status(0)),
+    }
+
+    let head = match core::str::from_utf8(response) {
+        Ok(head) => head,
+        // Cut off mid character, the Date header is ASCII so the valid prefix will do
+        Err(e) => core::str::from_utf8(&response[..e.valid_up_to()]).unwrap_or_default(),
+    };
+    Ok(head.split("\r\n").find_map(|line| {
+        let (name, value) = line.split_once(':')?;
+        if name.eq_ignore_ascii_case("date") {
+            parse_http_date(value.trim())
+        } else {
+            None
+        }
+    }))
+}
+
+/// Unix time in ms at boot, learned from the server, 0 until the first response.
+static BOOT_UNIX_MS: AtomicU64 = AtomicU64::new(0);
+
+/// The time now in Unix milliseconds, if remote-write has heard it from its server.
+pub fn unix_time_ms() -> Option<u64> {
+    match BOOT_UNIX_MS.load(Ordering::Relaxed) {
+        0 => None,
+        boot_unix_ms => Some(boot_unix_ms + Instant::now().as_millis()),
+    }
+}
+
+/// Push the current sensor values with Prometheus remote-write every 15 seconds.
+///
+/// Samples need a timestamp and the device has no clock, so the time is taken from the
+/// `Date` header of the endpoint's responses.  Until the first response arrives an empty
+/// `WriteRequest` is sent to get one.
+#[embassy_executor::task]
+pub async fn remote_write_task(
+    stack: &'static Stack<'static>,
+    host: &'static str,
+    port: u16,
+    path: &'static str,
+    app_state: &'static AppState,
+) -> ! {
+    let mut body = Vec::<u8, BODY_SIZE>::new();
+    let mut compressed = Vec::<u8, COMPRESSED_SIZE>::new();
+    let mut boot_unix_ms: Option<u64> = None;
+    info!("remote_write: Target {}:{}{}", host, port, path);
+    let task = task_registry::REMOTE_WRITE;
+    loop {
+        Timer::after(PUSH_INTERVAL).await;
+        stack.wait_config_up().await;
+        task_registry::iteration(task);
+
+        body.clear();
+        let mut sample_count = 0;
+        if let Some(boot_unix_ms) = boot_unix_ms {
+            let timestamp_ms = boot_unix_ms + Instant::now().as_millis();
+            let mut encoder = Encoder { buf: &mut body };
+            for series in collect(app_state).await.iter() {
+                let mark = encoder.buf.len();
+                if let Err(e) = encoder.series(series, app_state.hostname, timestamp_ms) {
+                    // Drop the partly written series so the rest still decodes
+                    error!("remote_write: Dropping {}: {:?}", series.name, e);
+                    encoder.buf.truncate(mark);
+                    continue;
+                }
+                sample_count += 1;
+            }
+        }
+
+        let result = match snappy_literals(&body, &mut compressed) {
+            Ok(()) => post(*stack, host, port, path, &compressed).await,
+            Err(e) => Err(e),
+        };
+        match result {
+            Ok(date) => {
+                SAMPLES.fetch_add(sample_count, Ordering::Relaxed);
+                BYTES.fetch_add(compressed.len() as u32, Ordering::Relaxed);
+                if let Some(unix_seconds) = date {
+                    let ms = (unix_seconds * 1000).saturating_sub(Instant::now().as_millis());
+                    boot_unix_ms = Some(ms);
+                    BOOT_UNIX_MS.store(ms, Ordering::Relaxed);
+                }
+                // A device that is only ever written from is never scraped
+                record_delivery().await;
+                task_registry::set_status(task, TaskStatus::Waiting);
+            }
+            Err(e) => {
+                error!("remote_write: Push failed: {:?}", e);
+                task_registry::set_status(task, TaskStatus::Error);
+            }
+        }
+    }
+}
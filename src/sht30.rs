@@ -1,12 +1,168 @@
-use defmt::{error, info};
+use defmt::{debug, error, info, warn, Format, Formatter};
 use embassy_embedded_hal::shared_bus::asynch::i2c::I2cDevice;
+use embassy_embedded_hal::shared_bus::I2cDeviceError;
 use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
-use embassy_time::{Duration, Timer};
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Timer};
 use embedded_hal::i2c::ErrorType;
+use serde::{Deserialize, Serialize};
 
-use crate::{I2c0, Mutex, SampleSet};
+use crate::climate_math::ComfortCategory;
+use crate::events::{self, SensorEvent};
+use crate::flash_store::{FlashStore, Slot};
+use crate::i2c_health::{self, I2cOperation};
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{
+    counter, describe, gauge, summary, GaugeWithHistory, MetricDescription, MetricSink,
+    MetricWriter, ReservoirSample, Summary,
+};
+use crate::task_registry::{self, TaskStatus};
+use crate::{
+    climate_math, log_error, record_sensor_error, ErrorSource, I2c0, Mutex, MutexExt, SampleSet,
+    SensorError, LOCK_TIMEOUT,
+};
 
 const TICK_TIMEOUT: Duration = Duration::from_millis(1000);
+/// Identical readings in a row taken to mean the sensor's output has frozen.
+const STUCK_READINGS: u8 = 5;
+
+/// Poll interval of `continuous_reading` while reads succeed, and the most it backs off to
+/// while they fail.
+const POLL_INTERVAL_MS: u32 = 100;
+const MAX_POLL_INTERVAL_MS: u32 = 30_000;
+/// Wait after the soft reset at startup, and after one for a frozen output.
+const RESET_DELAY: Duration = Duration::from_secs(5);
+
+/// How often `continuous_reading` takes the extra readings that compare high and medium
+/// repeatability.
+const HIGH_REP_INTERVAL: Duration = Duration::from_secs(10);
+const MEDIUM_REP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Longest heater run `POST /sht30/dehumidify` allows, to avoid damaging the sensor.
+pub const MAX_DEHUMIDIFY: Duration = Duration::from_secs(60);
+/// Wait before retrying a failed heater off, doubling up to `MAX_HEATER_OFF_RETRY`.
+const HEATER_OFF_RETRY: Duration = Duration::from_millis(500);
+const MAX_HEATER_OFF_RETRY: Duration = Duration::from_secs(5);
+
+/// Readings kept for `sht30_temperature_distribution_celsius`, 4 bytes each.
+pub(crate) const TEMPERATURE_RESERVOIR_SIZE: usize = 128;
+const TEMPERATURE_QUANTILES: [f32; 3] = [0.5, 0.9, 0.99];
+
+/// Starts `dehumidify_task` timing a session whose heater is already on.
+pub static DEHUMIDIFY: Signal<CriticalSectionRawMutex, Duration> = Signal::new();
+
+/// How `continuous_reading` retries a read that failed on the bus before falling back on
+/// a soft reset.  Retry `n` (from 0) waits `base_delay_ms * 2^n`.
+#[derive(Clone, Copy)]
+pub struct RetryConfig {
+    pub max_retries: u8,
+    pub base_delay_ms: u32,
+}
+
+impl Default for RetryConfig {
+    fn default() -> Self {
+        Self {
+            max_retries: 3,
+            base_delay_ms: 10,
+        }
+    }
+}
+
+impl RetryConfig {
+    fn delay(&self, attempt: u8) -> Duration {
+        Duration::from_millis((self.base_delay_ms as u64) << attempt)
+    }
+}
+
+/// Poll interval that doubles on each failed read, up to `max_ms`, so a disconnected
+/// sensor doesn't flood the bus with reads.  Back to `base_ms` on the next success.
+pub struct AdaptiveInterval {
+    base_ms: u32,
+    current_ms: u32,
+    max_ms: u32,
+    consecutive_errors: u32,
+}
+
+impl AdaptiveInterval {
+    pub const fn new(base_ms: u32, max_ms: u32) -> Self {
+        Self {
+            base_ms,
+            current_ms: base_ms,
+            max_ms,
+            consecutive_errors: 0,
+        }
+    }
+
+    pub fn success(&mut self) {
+        self.current_ms = self.base_ms;
+        self.consecutive_errors = 0;
+    }
+
+    /// Back off, returning how long to wait before the next read.
+    pub fn error(&mut self) -> Duration {
+        self.consecutive_errors += 1;
+        self.current_ms = self.current_ms.saturating_mul(2).min(self.max_ms);
+        self.current()
+    }
+
+    pub fn current(&self) -> Duration {
+        Duration::from_millis(self.current_ms as u64)
+    }
+
+    pub fn current_ms(&self) -> u32 {
+        self.current_ms
+    }
+
+    pub fn consecutive_errors(&self) -> u32 {
+        self.consecutive_errors
+    }
+}
+
+/// A run of the heater to drive off condensation.
+#[derive(Clone, Copy)]
+pub struct DehumidificationSession {
+    pub start: Instant,
+    pub duration: Duration,
+    pub heater_on: bool,
+}
+
+impl DehumidificationSession {
+    pub fn is_active(&self) -> bool {
+        self.heater_on
+    }
+}
+
+/// Offsets added to every reading, set by `POST /sht30/calibrate` against a reference
+/// instrument and persisted in flash.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct Sht30Calibration {
+    pub temp_offset_celsius: f32,
+    pub humidity_offset_percent: f32,
+    /// Unix time of the calibration, 0 if the device didn't know it.
+    pub calibrated_at_unix: u32,
+}
+
+impl Sht30Calibration {
+    pub async fn load(store: &FlashStore) -> Option<Self> {
+        let mut buf = [0u8; 12];
+        if store.load(Slot::Sht30Calibration, &mut buf).await? != buf.len() {
+            return None;
+        }
+        Some(Self {
+            temp_offset_celsius: f32::from_le_bytes([buf[0], buf[1], buf[2], buf[3]]),
+            humidity_offset_percent: f32::from_le_bytes([buf[4], buf[5], buf[6], buf[7]]),
+            calibrated_at_unix: u32::from_le_bytes([buf[8], buf[9], buf[10], buf[11]]),
+        })
+    }
+
+    pub async fn save(&self, store: &FlashStore) -> Result<(), embassy_rp::flash::Error> {
+        let mut buf = [0u8; 12];
+        buf[..4].copy_from_slice(&self.temp_offset_celsius.to_le_bytes());
+        buf[4..8].copy_from_slice(&self.humidity_offset_percent.to_le_bytes());
+        buf[8..].copy_from_slice(&self.calibrated_at_unix.to_le_bytes());
+        store.store(Slot::Sht30Calibration, &buf).await
+    }
+}
 
 /// Sensor output returned via channel (includes medians and counters)
 #[derive(Clone, Copy, Default)]
@@ -18,11 +174,49 @@ pub struct Output {
     pub zeros: f32,
     pub recoverable_errors: f32,
     pub resets: f32,
+    pub retry_successes: f32,
+    pub retry_failures: f32,
+    /// Times the output froze and the sensor was reset, see `SharedState::check_stuck`.
+    pub stuck_readings: f32,
+    /// Current interval between reads, longer than `POLL_INTERVAL_MS` while backing off.
+    pub poll_interval_ms: f32,
     pub heater_status_count: f32,
     pub humidity_tracking_alert_count: f32,
     pub temperature_tracking_alert_count: f32,
     pub command_status_success_count: f32,
     pub write_data_checksum_status_count: f32,
+    /// The heater is on, so temperature reads high and humidity low.
+    pub dehumidifying: bool,
+    pub dehumidification_seconds_total: f32,
+    /// Status register flags from the last successful read.
+    pub alert_pending: bool,
+    pub reset_detected: bool,
+    /// Seconds since the last successful read, `None` before the first one.
+    pub last_read_age_seconds: Option<f32>,
+    /// Latest temperature with the extremes over the last hour.
+    pub temperature_window: GaugeWithHistory<3600>,
+    /// Temperature quantiles since boot, `None` before the first reading.
+    pub temperature_distribution: Option<Summary<3>>,
+    /// Median temperatures of the repeatability comparison, `None` until the first reading.
+    pub temperature_high_rep: Option<f32>,
+    pub temperature_medium_rep: Option<f32>,
+    pub calibration: Sht30Calibration,
+}
+
+impl Format for Output {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(
+            fmt,
+            "Output {{ temp: {=f32}, hum: {=f32}, successes: {=f32}, timeouts: {=f32}, zeros: {=f32}, recoverable_errors: {=f32}, resets: {=f32} }}",
+            self.temperature,
+            self.humidity,
+            self.successes,
+            self.timeouts,
+            self.zeros,
+            self.recoverable_errors,
+            self.resets,
+        )
+    }
 }
 
 pub struct SharedState {
@@ -31,13 +225,40 @@ pub struct SharedState {
     successes: f32,
     timeouts: f32,
     zeros: f32,
+    /// The last reading had a zero, so the next ones aren't logged until it clears.
+    zero_reading: bool,
     recoverable_errors: f32,
     resets: f32,
+    /// Reads that failed on the bus but succeeded on a retry.
+    retry_successes: f32,
+    /// Reads that still failed after every retry, and so led to a reset.
+    retry_failures: f32,
+    stuck_readings: f32,
+    poll_interval_ms: f32,
+    /// The previous reading, to spot the output freezing.
+    last_temperature: f32,
+    last_humidity: f32,
+    /// Readings in a row identical to the one before.
+    consecutive_identical: u8,
     heater_status_count: f32,
     humidity_tracking_alert_count: f32,
     temperature_tracking_alert_count: f32,
     command_status_success_count: f32,
     write_data_checksum_status_count: f32,
+    alert_pending: bool,
+    reset_detected: bool,
+    dehumidification: Option<DehumidificationSession>,
+    dehumidification_seconds: f32,
+    last_success: Option<Instant>,
+    temperature_window: GaugeWithHistory<3600>,
+    /// Every temperature since boot outside dehumidification, where `temperatures` only
+    /// keeps the last 11 for the median.
+    temperature_distribution: ReservoirSample<TEMPERATURE_RESERVOIR_SIZE>,
+    /// Readings taken every 10 seconds at high repeatability and every second at medium,
+    /// whatever the configured repeatability, to compare the two.
+    temperatures_high_rep: SampleSet<11>,
+    temperatures_medium_rep: SampleSet<61>,
+    calibration: Sht30Calibration,
 }
 
 impl SharedState {
@@ -48,24 +269,77 @@ impl SharedState {
             successes: 0.,
             timeouts: 0.,
             zeros: 0.,
+            zero_reading: false,
             recoverable_errors: 0.,
             resets: 0.,
+            retry_successes: 0.,
+            retry_failures: 0.,
+            stuck_readings: 0.,
+            poll_interval_ms: POLL_INTERVAL_MS as f32,
+            last_temperature: f32::NAN,
+            last_humidity: f32::NAN,
+            consecutive_identical: 0,
             heater_status_count: 0.,
             humidity_tracking_alert_count: 0.,
             temperature_tracking_alert_count: 0.,
             command_status_success_count: 0.,
             write_data_checksum_status_count: 0.,
+            alert_pending: false,
+            reset_detected: false,
+            dehumidification: None,
+            dehumidification_seconds: 0.,
+            last_success: None,
+            temperature_window: GaugeWithHistory::new(),
+            temperature_distribution: ReservoirSample::new(),
+            temperatures_high_rep: SampleSet::new(),
+            temperatures_medium_rep: SampleSet::new(),
+            calibration: Sht30Calibration {
+                temp_offset_celsius: 0.,
+                humidity_offset_percent: 0.,
+                calibrated_at_unix: 0,
+            },
+        }
+    }
+
+    /// `reading` with the calibration offsets added, the one place they are applied.
+    /// Humidity stays within 0 to 100 %RH.
+    pub fn calibrated(&self, reading: &Reading) -> Reading {
+        Reading {
+            temperature: reading.temperature + self.calibration.temp_offset_celsius,
+            humidity: (reading.humidity + self.calibration.humidity_offset_percent).clamp(0., 100.),
+            ..reading.clone()
         }
     }
 
+    /// Record `reading` with the calibration offsets applied.
     pub fn record(&mut self, reading: &Reading) {
         self.successes += 1.;
-        self.humidities.record(reading.humidity);
-        self.temperatures.record(reading.temperature);
+        self.last_success = Some(Instant::now());
+        let calibrated = self.calibrated(reading);
+        let (temperature, humidity) = (calibrated.temperature, calibrated.humidity);
+        self.humidities.record(humidity);
+        self.temperatures.record(temperature);
+        // The heater skews the temperature, keep it out of the extremes
+        if !self
+            .dehumidification
+            .is_some_and(|session| session.is_active())
+        {
+            self.temperature_window.set(temperature);
+            self.temperature_distribution.record(temperature);
+        }
 
-        if reading.humidity == 0. || reading.temperature == 0. {
+        // Logged once when the zeros start and once when they stop, a sensor stuck at 0
+        // would otherwise fill the log
+        let zero_reading = reading.humidity == 0. || reading.temperature == 0.;
+        if zero_reading {
             self.zeros += 1.;
         }
+        if zero_reading && !self.zero_reading {
+            error!("sht30: Zero reading {}", reading);
+        } else if !zero_reading && self.zero_reading {
+            info!("sht30: Readings no longer zero");
+        }
+        self.zero_reading = zero_reading;
         if reading.heater_status {
             self.heater_status_count += 1.;
         }
@@ -81,6 +355,93 @@ impl SharedState {
         if reading.write_data_checksum_status {
             self.write_data_checksum_status_count += 1.;
         }
+        self.alert_pending = reading.alert_pending;
+        self.reset_detected = reading.reset_detected;
+    }
+
+    /// Record a reading taken for the repeatability comparison.  Only the temperature is
+    /// compared, and nothing is recorded while the heater skews it.
+    pub fn record_comparison(&mut self, repeatability: Repeatability, reading: &Reading) {
+        if self
+            .dehumidification
+            .is_some_and(|session| session.is_active())
+        {
+            return;
+        }
+        let temperature = self.calibrated(reading).temperature;
+        match repeatability {
+            Repeatability::High => self.temperatures_high_rep.record(temperature),
+            Repeatability::Medium => self.temperatures_medium_rep.record(temperature),
+            Repeatability::Low => {}
+        }
+    }
+
+    pub fn last_success(&self) -> Option<Instant> {
+        self.last_success
+    }
+
+    pub fn calibration(&self) -> Sht30Calibration {
+        self.calibration
+    }
+
+    /// Offsets that would make the current medians read `reference_temp_c` and
+    /// `reference_humidity_rh`.  The medians already include the offsets in use, so the
+    /// difference is added to them.  `None` before the first reading.
+    pub fn calibrate(
+        &self,
+        reference_temp_c: f32,
+        reference_humidity_rh: f32,
+        calibrated_at_unix: u32,
+    ) -> Option<Sht30Calibration> {
+        if self.temperatures.is_empty() {
+            return None;
+        }
+        Some(Sht30Calibration {
+            temp_offset_celsius: self.calibration.temp_offset_celsius + reference_temp_c
+                - self.temperatures.median(),
+            humidity_offset_percent: self.calibration.humidity_offset_percent
+                + reference_humidity_rh
+                - self.humidities.median(),
+            calibrated_at_unix,
+        })
+    }
+
+    /// Applies to readings from now on.  The median windows and the hourly extremes are
+    /// cleared rather than mix readings with the old and new offsets, the distribution
+    /// since boot keeps them.
+    pub fn set_calibration(&mut self, calibration: Sht30Calibration) {
+        info!(
+            "sht30: Applying calibration offsets {} C, {} %RH",
+            calibration.temp_offset_celsius, calibration.humidity_offset_percent
+        );
+        self.calibration = calibration;
+        self.temperatures = SampleSet::new();
+        self.humidities = SampleSet::new();
+        self.temperatures_high_rep = SampleSet::new();
+        self.temperatures_medium_rep = SampleSet::new();
+        self.temperature_window = GaugeWithHistory::new();
+    }
+
+    pub fn dehumidification(&self) -> Option<DehumidificationSession> {
+        self.dehumidification
+    }
+
+    pub fn start_dehumidification(&mut self, duration: Duration) {
+        self.dehumidification = Some(DehumidificationSession {
+            start: Instant::now(),
+            duration,
+            heater_on: true,
+        });
+    }
+
+    /// Record the end of the session and add its length to the total.
+    pub fn finish_dehumidification(&mut self) {
+        if let Some(session) = self.dehumidification.as_mut() {
+            if session.heater_on {
+                session.heater_on = false;
+                self.dehumidification_seconds += session.start.elapsed().as_millis() as f32 / 1000.;
+            }
+        }
     }
 
     pub fn record_error(&mut self) {
@@ -95,6 +456,41 @@ impl SharedState {
         self.resets += 1.;
     }
 
+    pub fn record_retry_success(&mut self) {
+        self.retry_successes += 1.;
+    }
+
+    pub fn record_retry_failure(&mut self) {
+        self.retry_failures += 1.;
+    }
+
+    pub fn set_poll_interval(&mut self, interval: &AdaptiveInterval) {
+        self.poll_interval_ms = interval.current_ms() as f32;
+    }
+
+    /// Whether `reading` makes `STUCK_READINGS` in a row with exactly the same temperature
+    /// and humidity.  Some SHT30 lots freeze like this after a power glitch, without any
+    /// bus error, until they are soft reset.  Counted, and the count starts again.
+    pub fn check_stuck(&mut self, reading: &Reading) -> bool {
+        if reading.temperature == self.last_temperature && reading.humidity == self.last_humidity {
+            self.consecutive_identical += 1;
+        } else {
+            self.consecutive_identical = 0;
+        }
+        self.last_temperature = reading.temperature;
+        self.last_humidity = reading.humidity;
+
+        // The first of the run isn't identical to anything
+        if self.consecutive_identical + 1 < STUCK_READINGS {
+            return false;
+        }
+        self.consecutive_identical = 0;
+        self.last_temperature = f32::NAN;
+        self.last_humidity = f32::NAN;
+        self.stuck_readings += 1.;
+        true
+    }
+
     pub fn snapshot(&self) -> Output {
         Output {
             temperature: self.temperatures.median(),
@@ -104,27 +500,560 @@ impl SharedState {
             zeros: self.zeros,
             recoverable_errors: self.recoverable_errors,
             resets: self.resets,
+            retry_successes: self.retry_successes,
+            retry_failures: self.retry_failures,
+            stuck_readings: self.stuck_readings,
+            poll_interval_ms: self.poll_interval_ms,
             heater_status_count: self.heater_status_count,
             humidity_tracking_alert_count: self.humidity_tracking_alert_count,
             temperature_tracking_alert_count: self.temperature_tracking_alert_count,
             command_status_success_count: self.command_status_success_count,
             write_data_checksum_status_count: self.write_data_checksum_status_count,
+            dehumidifying: self
+                .dehumidification
+                .is_some_and(|session| session.is_active()),
+            dehumidification_seconds_total: self.dehumidification_seconds,
+            alert_pending: self.alert_pending,
+            reset_detected: self.reset_detected,
+            last_read_age_seconds: self
+                .last_success
+                .map(|at| at.elapsed().as_millis() as f32 / 1000.),
+            temperature_window: self.temperature_window,
+            temperature_distribution: self.temperature_distribution.summary(TEMPERATURE_QUANTILES),
+            temperature_high_rep: (!self.temperatures_high_rep.is_empty())
+                .then(|| self.temperatures_high_rep.median()),
+            temperature_medium_rep: (!self.temperatures_medium_rep.is_empty())
+                .then(|| self.temperatures_medium_rep.median()),
+            calibration: self.calibration,
         }
     }
 }
 
-// SHT30 I2C Address
+/// The SHT30 as seen by the metrics endpoint.  The device itself is read by
+/// `continuous_reading` on core1, so polling only snapshots the shared state.
+pub struct Sht30Sensor {
+    state: &'static Mutex<SharedState>,
+    last: Output,
+}
+
+impl Sht30Sensor {
+    pub fn new(state: &'static Mutex<SharedState>) -> Self {
+        Self {
+            state,
+            last: Output::default(),
+        }
+    }
+
+    pub fn last(&self) -> &Output {
+        &self.last
+    }
+}
+
+impl crate::Sensor for Sht30Sensor {
+    type Reading = Output;
+
+    fn name(&self) -> &'static str {
+        "sht30"
+    }
+
+    async fn poll(&mut self) -> Result<(), crate::SensorError> {
+        self.last = self.state.lock().await.snapshot();
+        debug!("sht30: {}", self.last);
+        Ok(())
+    }
+
+    fn reading(&self) -> Output {
+        self.last
+    }
+}
+
+pub(crate) const SHT30_READING: MetricDescription = describe(
+    "sht30_reading",
+    "SHT30 readings from the raw 16 bit value S.  temperature is degrees Celsius, -40 to \
+     125, from T = -45 + 175 * S / 65535.  humidity is percent relative humidity, 0 to 100, \
+     from RH = 100 * S / 65535",
+    "",
+);
+
+impl crate::WriteMetrics for Output {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        let output = self;
+
+        if output.dehumidifying {
+            // Flag readings taken with the heater on so consumers can ignore them
+            writer
+                .write(
+                    SHT30_READING.gauge(
+                        ["sensor", "dehumidify"],
+                        [
+                            Sample::new(["temperature", "true"], output.temperature),
+                            Sample::new(["humidity", "true"], output.humidity),
+                        ]
+                        .iter(),
+                    ),
+                )
+                .await?;
+        } else {
+            writer
+                .write(
+                    SHT30_READING.gauge(
+                        ["sensor"],
+                        [
+                            Sample::new(["temperature"], output.temperature),
+                            Sample::new(["humidity"], output.humidity),
+                        ]
+                        .iter(),
+                    ),
+                )
+                .await?;
+        }
+
+        // More series of `sht30_reading`, so no HELP and TYPE of their own
+        for (repeatability, temperature) in [
+            ("high", output.temperature_high_rep),
+            ("medium", output.temperature_medium_rep),
+        ] {
+            if let Some(temperature) = temperature {
+                writer.write_name("sht30_reading").await?;
+                writer
+                    .write_labels(
+                        [("sensor", "temperature"), ("repeatability", repeatability)].into_iter(),
+                    )
+                    .await?;
+                writer.write_value(temperature).await?;
+            }
+        }
+
+        if let (Some(high), Some(medium)) =
+            (output.temperature_high_rep, output.temperature_medium_rep)
+        {
+            writer
+                .write(gauge(
+                    "sht30_repeatability_diff_celsius",
+                    "Difference between the SHT30 temperature at high and medium repeatability",
+                    [],
+                    [Sample::new([], libm::fabsf(high - medium))].iter(),
+                ))
+                .await?;
+        }
+
+        // `sht30_reading{sensor="temperature"}` stays alongside this, remote write, push,
+        // statsd and CSV export it by that name and dashboards and alerts already use it
+        if output.temperature_window.has_value() {
+            writer
+                .write(gauge(
+                    "sht30_temperature_celsius",
+                    "Latest SHT30 temperature and its extremes over the last hour",
+                    ["stat"],
+                    output.temperature_window.samples().iter(),
+                ))
+                .await?;
+        }
+
+        if let Some(distribution) = &output.temperature_distribution {
+            writer
+                .write(
+                    summary(
+                        "sht30_temperature_distribution_celsius",
+                        "SHT30 temperature quantiles since boot, from a random sample of \
+                         the readings",
+                        distribution,
+                    )
+                    .with_unit("celsius"),
+                )
+                .await?;
+        }
+
+        writer
+            .write(gauge(
+                "sht30_calibration_offset",
+                "Offsets added to the SHT30 readings by POST /sht30/calibrate",
+                ["dimension"],
+                [
+                    Sample::new(
+                        ["temperature_celsius"],
+                        output.calibration.temp_offset_celsius,
+                    ),
+                    Sample::new(
+                        ["humidity_percent"],
+                        output.calibration.humidity_offset_percent,
+                    ),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "sht30_dehumidification_active",
+                "1 while the SHT30 heater is on for dehumidification",
+                [],
+                [Sample::new([], output.dehumidifying as u8 as f32)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(
+                counter(
+                    "sht30_dehumidification_seconds_total",
+                    "Time the SHT30 heater has spent on for dehumidification",
+                    [],
+                    [Sample::new([], output.dehumidification_seconds_total)].iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        writer
+            .write(gauge(
+                "sht30_derived",
+                "Values derived from the SHT30 temperature and humidity",
+                ["sensor"],
+                [
+                    Sample::new(
+                        ["absolute_humidity_g_m3"],
+                        climate_math::absolute_humidity_g_m3(output.temperature, output.humidity),
+                    ),
+                    Sample::new(
+                        ["vapor_pressure_deficit_kpa"],
+                        climate_math::vapor_pressure_deficit_kpa(
+                            output.temperature,
+                            output.humidity,
+                        ),
+                    ),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        // Meaningless before the first reading and while the heater is on
+        if output.last_read_age_seconds.is_some() && !output.dehumidifying {
+            let comfort = climate_math::comfort_index(output.temperature, output.humidity);
+            // One series per category so a state timeline can show which one is active
+            let samples =
+                ComfortCategory::ALL.map(|c| Sample::new([c.label()], (c == comfort) as u8 as f32));
+            writer
+                .write(gauge(
+                    "sht30_comfort_index",
+                    "1 for the comfort category of the SHT30 temperature and humidity, 0 for the others",
+                    ["category"],
+                    samples.iter(),
+                ))
+                .await?;
+
+            writer
+                .write(gauge(
+                    "sht30_comfort_score",
+                    "Comfort category as a number, 1 for cold through 6 for very hot",
+                    [],
+                    [Sample::new([], comfort.score() as f32)].iter(),
+                ))
+                .await?;
+        }
+
+        // Alert on e.g. `sht30_last_read_age_seconds > 60` to catch a stuck sensor or bus,
+        // before the first read it is `f32::MAX` so the alert fires
+        writer
+            .write(
+                gauge(
+                    "sht30_last_read_age_seconds",
+                    "Time since the background reader last read the SHT30 successfully",
+                    [],
+                    [Sample::new(
+                        [],
+                        output.last_read_age_seconds.unwrap_or(f32::MAX),
+                    )]
+                    .iter(),
+                )
+                .with_unit("seconds"),
+            )
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_status_count",
+                "Number of times SHT30 Status Registers have been true",
+                ["feature"],
+                [
+                    Sample::new(["heater_status"], output.heater_status_count),
+                    Sample::new(
+                        ["command_status_success"],
+                        output.command_status_success_count,
+                    ),
+                    Sample::new(
+                        ["write_data_checksum_status"],
+                        output.write_data_checksum_status_count,
+                    ),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_alert_count",
+                "Reads where the SHT30 had raised a tracking alert since the previous read",
+                ["type"],
+                [
+                    Sample::new(["humidity"], output.humidity_tracking_alert_count),
+                    Sample::new(["temperature"], output.temperature_tracking_alert_count),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "sht30_status",
+                "SHT30 Status Register flags from the last read",
+                ["flag"],
+                [
+                    Sample::new(["alert_pending"], output.alert_pending as u8 as f32),
+                    Sample::new(["reset_detected"], output.reset_detected as u8 as f32),
+                ]
+                .iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_zeros",
+                "Zero readings from SHT30 Sensor",
+                [],
+                [Sample::new([], output.zeros)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_successes",
+                "Successful reads from SHT30 Sensor",
+                [],
+                [Sample::new([], output.successes)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_timeouts",
+                "Timeout events reading SHT30 Sensor",
+                [],
+                [Sample::new([], output.timeouts)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_recoverable_errors",
+                "Recoverable erors from SHT30 Sensor",
+                [],
+                [Sample::new([], output.recoverable_errors)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_resets",
+                "Resets of the SHT30 Sensor",
+                [],
+                [Sample::new([], output.resets)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_stuck_readings_total",
+                "Times the SHT30 repeated the same reading and was reset",
+                [],
+                [Sample::new([], output.stuck_readings)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(gauge(
+                "sht30_poll_interval_ms",
+                "Interval between SHT30 reads, above 100 while backing off after errors",
+                [],
+                [Sample::new([], output.poll_interval_ms)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_retry_successes",
+                "SHT30 reads that failed on the bus and succeeded on a retry",
+                [],
+                [Sample::new([], output.retry_successes)].iter(),
+            ))
+            .await?;
+
+        writer
+            .write(counter(
+                "sht30_retry_failures",
+                "SHT30 reads that failed every retry and led to a reset",
+                [],
+                [Sample::new([], output.retry_failures)].iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
+
+// SHT30 I2C Addresses, selected by the ADDR pin
 pub const SHT30_ADDR: u8 = 0x44;
+pub const SHT30_ADDR_ALT: u8 = 0x45;
 
 // SHT30 Commands (no clock stretching)
 const SHT30_HIG_REP_NO_STRETCH: [u8; 2] = [0x24, 0x00];
+const SHT30_MED_REP_NO_STRETCH: [u8; 2] = [0x24, 0x0B];
+const SHT30_LOW_REP_NO_STRETCH: [u8; 2] = [0x24, 0x16];
+
+// SHT30 Commands (clock stretching)
+const SHT30_HIG_REP_CLOCK_STRETCH_READ: [u8; 2] = [0x2C, 0x06];
+const SHT30_MED_REP_CLOCK_STRETCH_READ: [u8; 2] = [0x2C, 0x0D];
+const SHT30_LOW_REP_CLOCK_STRETCH_READ: [u8; 2] = [0x2C, 0x10];
 const SHT30_READ_STATUS: [u8; 2] = [0xF3, 0x2D];
 const SHT30_CLEAR_STATUS: [u8; 2] = [0x30, 0x41];
 const SHT30_SOFT_RESET: [u8; 2] = [0x30, 0xA2];
+const SHT30_HEATER_ON: [u8; 2] = [0x30, 0x6D];
+const SHT30_HEATER_OFF: [u8; 2] = [0x30, 0x66];
+
+// SHT30 Periodic Mode Commands
+const SHT30_FETCH_DATA: [u8; 2] = [0xE0, 0x00];
+const SHT30_BREAK: [u8; 2] = [0x30, 0x93];
+/// Returns the 32 bit serial number as two words, each followed by its CRC.
+const SHT30_READ_SERIAL: [u8; 2] = [0x37, 0x80];
 
 // Max measurement duration for high repeatability (per datasheet: 15.5ms)
 const MEASUREMENT_DELAY: Duration = Duration::from_millis(20);
+// Max measurement duration for medium repeatability (per datasheet: 6ms)
+const MEASUREMENT_DELAY_MEDIUM: Duration = Duration::from_millis(8);
+// Max measurement duration for low repeatability (per datasheet: 4ms)
+const MEASUREMENT_DELAY_LOW: Duration = Duration::from_millis(6);
+
+/// Measurement repeatability.  Lower repeatability measures faster and draws less current
+/// while measuring, at the cost of noisier readings (±0.2 °C more uncertainty at `Low`).
+#[derive(Clone, Copy, PartialEq, Eq, Format, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[repr(u8)]
+pub enum Repeatability {
+    High = 0,
+    Medium = 1,
+    Low = 2,
+}
+
+impl Repeatability {
+    pub const fn from_u8(value: u8) -> Self {
+        match value {
+            1 => Repeatability::Medium,
+            2 => Repeatability::Low,
+            _ => Repeatability::High,
+        }
+    }
+
+    /// Single shot measurement command, without clock stretching.
+    const fn single_shot_command(self) -> [u8; 2] {
+        match self {
+            Repeatability::High => SHT30_HIG_REP_NO_STRETCH,
+            Repeatability::Medium => SHT30_MED_REP_NO_STRETCH,
+            Repeatability::Low => SHT30_LOW_REP_NO_STRETCH,
+        }
+    }
+
+    /// Single shot measurement command with clock stretching.
+    const fn clock_stretch_command(self) -> [u8; 2] {
+        match self {
+            Repeatability::High => SHT30_HIG_REP_CLOCK_STRETCH_READ,
+            Repeatability::Medium => SHT30_MED_REP_CLOCK_STRETCH_READ,
+            Repeatability::Low => SHT30_LOW_REP_CLOCK_STRETCH_READ,
+        }
+    }
+
+    const fn measurement_delay(self) -> Duration {
+        match self {
+            Repeatability::High => MEASUREMENT_DELAY,
+            Repeatability::Medium => MEASUREMENT_DELAY_MEDIUM,
+            Repeatability::Low => MEASUREMENT_DELAY_LOW,
+        }
+    }
+
+    /// Start periodic acquisition command (datasheet table 10).
+    const fn periodic_command(self, rate: PeriodicRate) -> [u8; 2] {
+        match (rate, self) {
+            (PeriodicRate::HalfPerSecond, Repeatability::High) => [0x20, 0x32],
+            (PeriodicRate::HalfPerSecond, Repeatability::Medium) => [0x20, 0x24],
+            (PeriodicRate::HalfPerSecond, Repeatability::Low) => [0x20, 0x2F],
+            (PeriodicRate::OnePerSecond, Repeatability::High) => [0x21, 0x30],
+            (PeriodicRate::OnePerSecond, Repeatability::Medium) => [0x21, 0x26],
+            (PeriodicRate::OnePerSecond, Repeatability::Low) => [0x21, 0x2D],
+            (PeriodicRate::TwoPerSecond, Repeatability::High) => [0x22, 0x36],
+            (PeriodicRate::TwoPerSecond, Repeatability::Medium) => [0x22, 0x20],
+            (PeriodicRate::TwoPerSecond, Repeatability::Low) => [0x22, 0x2B],
+            (PeriodicRate::FourPerSecond, Repeatability::High) => [0x23, 0x34],
+            (PeriodicRate::FourPerSecond, Repeatability::Medium) => [0x23, 0x22],
+            (PeriodicRate::FourPerSecond, Repeatability::Low) => [0x23, 0x29],
+            (PeriodicRate::TenPerSecond, Repeatability::High) => [0x27, 0x37],
+            (PeriodicRate::TenPerSecond, Repeatability::Medium) => [0x27, 0x21],
+            (PeriodicRate::TenPerSecond, Repeatability::Low) => [0x27, 0x2A],
+        }
+    }
+}
+
+/// How a single shot measurement waits for the result.
+#[derive(Clone, Copy, PartialEq, Eq, Format, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AcquisitionMode {
+    /// Send the command, sleep for the worst case measurement time, then read.  The sensor
+    /// NACKs a read that comes too early, so the sleep has to cover the slowest case.
+    Poll,
+    /// Send the clock stretching command and read with a repeated start.  The sensor holds
+    /// SCL low until the measurement is done (up to 15.5 ms), so there is no sleep, but the
+    /// bus is blocked for other devices while it waits.
+    ClockStretch,
+}
+
+/// Measurements per second in periodic acquisition mode.
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum PeriodicRate {
+    HalfPerSecond,
+    OnePerSecond,
+    TwoPerSecond,
+    FourPerSecond,
+    TenPerSecond,
+}
+
+/// The status register, datasheet table 17.  The remaining bits are reserved.
+#[derive(Clone, Copy)]
+pub struct Status {
+    /// At least one alert is pending.
+    pub alert_pending: bool,
+    pub heater_status: bool,
+    pub humidity_tracking_alert: bool,
+    pub temperature_tracking_alert: bool,
+    /// A power on, soft or hard reset happened since the register was last cleared.
+    pub reset_detected: bool,
+    /// Set if the last command was not processed.
+    pub command_status: bool,
+    /// Set if the checksum of the last write transfer failed.
+    pub write_data_checksum_status: bool,
+}
+
+impl Status {
+    pub fn from_bits(bits: u16) -> Self {
+        Status {
+            alert_pending: bits & 0x8000 != 0,
+            heater_status: bits & 0x2000 != 0,
+            humidity_tracking_alert: bits & 0x0800 != 0,
+            temperature_tracking_alert: bits & 0x0400 != 0,
+            reset_detected: bits & 0x0010 != 0,
+            command_status: bits & 0x0002 != 0,
+            write_data_checksum_status: bits & 0x0001 != 0,
+        }
+    }
+}
 
+#[derive(Clone)]
 pub struct Reading {
     pub temperature: f32,
     pub humidity: f32,
@@ -133,120 +1062,432 @@ pub struct Reading {
     pub temperature_tracking_alert: bool,
     pub command_status_success: bool,
     pub write_data_checksum_status: bool,
+    pub alert_pending: bool,
+    pub reset_detected: bool,
+}
+
+impl Format for Reading {
+    fn format(&self, fmt: Formatter) {
+        defmt::write!(
+            fmt,
+            "Reading {{ temp: {=f32}, hum: {=f32}, heater: {=bool} }}",
+            self.temperature,
+            self.humidity,
+            self.heater_status,
+        )
+    }
+}
+
+#[derive(Debug, Format)]
+pub enum Sht30Error<E> {
+    I2c(E),
+    /// A word didn't match its CRC.
+    Crc,
+    /// All ones, what a floating bus or a device that isn't an SHT30 reads back.
+    UnexpectedSerial,
+}
+
+/// CRC-8 used by the SHT30 (polynomial 0x31, init 0xFF), per datasheet section 4.12.
+pub fn crc8(data: &[u8]) -> u8 {
+    let mut crc: u8 = 0xFF;
+    for byte in data {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 {
+                (crc << 1) ^ 0x31
+            } else {
+                crc << 1
+            };
+        }
+    }
+    crc
 }
 
 pub struct Sht30Device<I> {
     addr: u8,
     i2c: I,
+    repeatability: Repeatability,
+    acquisition_mode: AcquisitionMode,
 }
 
+/// Find the SHT30 by sending a soft reset to 0x44 then 0x45, returning the first address
+/// that ACKs.  `None` means no SHT30 is fitted.
+pub async fn probe_sht30_address<I: embedded_hal_async::i2c::I2c>(i2c: &mut I) -> Option<u8> {
+    for (i, addr) in [SHT30_ADDR, SHT30_ADDR_ALT].into_iter().enumerate() {
+        if i > 0 {
+            Timer::after(Duration::from_millis(100)).await;
+        }
+        if i2c.write(addr, &SHT30_SOFT_RESET).await.is_ok() {
+            info!("sht30: Found at {:x}", addr);
+            return Some(addr);
+        }
+    }
+    info!(
+        "sht30: Not found at {:x} or {:x}",
+        SHT30_ADDR, SHT30_ADDR_ALT
+    );
+    None
+}
+
+/// The SHT30 as wired on the shared I2C0 bus.
+pub type Sht30 = Sht30Device<I2cDevice<'static, CriticalSectionRawMutex, I2c0>>;
+
 impl<I: embedded_hal_async::i2c::I2c> Sht30Device<I> {
     pub fn new(i2c: I, addr: u8) -> Self {
-        Self { addr, i2c }
+        Self {
+            addr,
+            i2c,
+            repeatability: Repeatability::High,
+            acquisition_mode: AcquisitionMode::Poll,
+        }
+    }
+
+    pub fn repeatability(&self) -> Repeatability {
+        self.repeatability
+    }
+
+    pub fn set_repeatability(&mut self, repeatability: Repeatability) {
+        self.repeatability = repeatability;
+    }
+
+    pub fn acquisition_mode(&self) -> AcquisitionMode {
+        self.acquisition_mode
+    }
+
+    pub fn set_acquisition_mode(&mut self, acquisition_mode: AcquisitionMode) {
+        self.acquisition_mode = acquisition_mode;
     }
 
     pub async fn soft_reset(&mut self) -> Result<(), <I as ErrorType>::Error> {
-        self.i2c.write(self.addr, &SHT30_SOFT_RESET).await
+        i2c_health::timed(
+            I2cOperation::Sht30Reset,
+            self.i2c.write(self.addr, &SHT30_SOFT_RESET),
+        )
+        .await
+    }
+
+    pub async fn heater_on(&mut self) -> Result<(), <I as ErrorType>::Error> {
+        self.i2c.write(self.addr, &SHT30_HEATER_ON).await
+    }
+
+    pub async fn heater_off(&mut self) -> Result<(), <I as ErrorType>::Error> {
+        self.i2c.write(self.addr, &SHT30_HEATER_OFF).await
     }
 
     /// Read temperature, humidity, and status from the SHT30 sensor
     pub async fn read(&mut self) -> Result<Reading, <I as ErrorType>::Error> {
-        // Clear status register
-        self.i2c.write(self.addr, &SHT30_CLEAR_STATUS).await?;
-        Timer::after_millis(1).await;
+        let raw = self.read_raw().await?;
+        Ok(Reading::from_raw(&raw))
+    }
+
+    /// `read` at `repeatability`, leaving the configured repeatability as it was.  The
+    /// status register is left for the next `read` rather than read and cleared, so its
+    /// flags aren't lost, and the status fields of the reading are all false.
+    pub async fn read_with(
+        &mut self,
+        repeatability: Repeatability,
+    ) -> Result<Reading, <I as ErrorType>::Error> {
+        let configured = self.repeatability;
+        self.repeatability = repeatability;
+        let result = self.read_raw_timed(false).await;
+        self.repeatability = configured;
+        Ok(Reading::from_raw(&result?))
+    }
+
+    /// Perform a measurement and return the unparsed bytes: the 6 byte measurement buffer
+    /// (temperature MSB, LSB, CRC, humidity MSB, LSB, CRC) followed by the 2 status bytes.
+    ///
+    /// The status is read before it is cleared, so its flags cover everything since the
+    /// previous read rather than just this measurement.
+    pub async fn read_raw(&mut self) -> Result<[u8; 8], <I as ErrorType>::Error> {
+        self.read_raw_timed(true).await
+    }
+
+    /// The status bytes are left zero without `read_status`.  Only the transfers count
+    /// towards the `sht30_read` timing, not the waits between them.
+    async fn read_raw_timed(
+        &mut self,
+        read_status: bool,
+    ) -> Result<[u8; 8], <I as ErrorType>::Error> {
+        let mut transaction = i2c_health::Transaction::start(I2cOperation::Sht30Read);
+        let mut raw = [0u8; 8];
+
+        // Read status register, then clear it for the next read
+        if read_status {
+            self.i2c
+                .write_read(self.addr, &SHT30_READ_STATUS, &mut raw[6..])
+                .await?;
+            self.i2c.write(self.addr, &SHT30_CLEAR_STATUS).await?;
+            transaction.pause();
+            Timer::after_millis(1).await;
+            transaction.resume();
+        }
+
+        match self.acquisition_mode {
+            AcquisitionMode::Poll => {
+                // Trigger measurement (no clock stretching)
+                self.i2c
+                    .write(self.addr, &self.repeatability.single_shot_command())
+                    .await?;
 
-        // Trigger measurement (high repeatability, no clock stretching)
-        self.i2c.write(self.addr, &SHT30_HIG_REP_NO_STRETCH).await?;
+                // Wait for measurement to complete
+                transaction.pause();
+                Timer::after(self.repeatability.measurement_delay()).await;
+                transaction.resume();
 
-        // Wait for measurement to complete
-        Timer::after(MEASUREMENT_DELAY).await;
+                // Read 6 bytes of measurement data
+                self.i2c.read(self.addr, &mut raw[..6]).await?;
+            }
+            AcquisitionMode::ClockStretch => {
+                // The sensor stretches the clock on the read until the measurement is done
+                self.i2c
+                    .write_read(
+                        self.addr,
+                        &self.repeatability.clock_stretch_command(),
+                        &mut raw[..6],
+                    )
+                    .await?;
+            }
+        }
+
+        transaction.finish();
+        Ok(raw)
+    }
+
+    /// Read the status register on its own, without triggering a measurement.
+    pub async fn read_status(&mut self) -> Result<Status, <I as ErrorType>::Error> {
+        let mut raw = [0u8; 2];
+        self.i2c
+            .write_read(self.addr, &SHT30_READ_STATUS, &mut raw)
+            .await?;
+        Ok(Status::from_bits(u16::from_be_bytes(raw)))
+    }
 
-        // Read 6 bytes of measurement data
+    /// Read the sensor's serial number, checking both words against their CRCs.  Anything
+    /// else that ACKs at the SHT30's address is unlikely to pass.
+    pub async fn read_serial_number(&mut self) -> Result<u32, Sht30Error<<I as ErrorType>::Error>> {
+        let mut raw = [0u8; 6];
+        self.i2c
+            .write_read(self.addr, &SHT30_READ_SERIAL, &mut raw)
+            .await
+            .map_err(Sht30Error::I2c)?;
+        if crc8(&raw[0..2]) != raw[2] || crc8(&raw[3..5]) != raw[5] {
+            return Err(Sht30Error::Crc);
+        }
+
+        let serial = u32::from_be_bytes([raw[0], raw[1], raw[3], raw[4]]);
+        if serial == u32::MAX {
+            return Err(Sht30Error::UnexpectedSerial);
+        }
+        Ok(serial)
+    }
+
+    /// Switch the sensor into periodic acquisition mode at the configured repeatability.
+    /// Results are collected with [`Sht30Device::fetch_periodic`].
+    pub async fn start_periodic(
+        &mut self,
+        rate: PeriodicRate,
+    ) -> Result<(), <I as ErrorType>::Error> {
+        self.i2c
+            .write(self.addr, &self.repeatability.periodic_command(rate))
+            .await
+    }
+
+    /// Fetch the latest periodic measurement.  The sensor NACKs the read if no new
+    /// measurement is available yet.
+    pub async fn fetch_periodic(&mut self) -> Result<[u8; 6], <I as ErrorType>::Error> {
         let mut buffer = [0u8; 6];
-        self.i2c.read(self.addr, &mut buffer).await?;
+        self.i2c
+            .write_read(self.addr, &SHT30_FETCH_DATA, &mut buffer)
+            .await?;
+        Ok(buffer)
+    }
 
+    /// Stop periodic acquisition and return to single shot mode.
+    pub async fn stop_periodic(&mut self) -> Result<(), <I as ErrorType>::Error> {
+        self.i2c.write(self.addr, &SHT30_BREAK).await?;
+        Timer::after_millis(1).await;
+        Ok(())
+    }
+}
+
+impl Reading {
+    /// Parse the buffer returned by [`Sht30Device::read_raw`].
+    pub fn from_raw(raw: &[u8; 8]) -> Self {
         // Parse temperature data (first 3 bytes)
-        let temp_raw = ((buffer[0] as u16) << 8) | (buffer[1] as u16);
-        // Note: buffer[2] is CRC - skipped for simplicity
+        let temp_raw = ((raw[0] as u16) << 8) | (raw[1] as u16);
+        // Note: raw[2] is CRC - skipped for simplicity
 
         // Parse humidity data (next 3 bytes)
-        let hum_raw = ((buffer[3] as u16) << 8) | (buffer[4] as u16);
-        // Note: buffer[5] is CRC - skipped for simplicity
+        let hum_raw = ((raw[3] as u16) << 8) | (raw[4] as u16);
+        // Note: raw[5] is CRC - skipped for simplicity
 
         // Convert to actual values using SHT30 formulas
         let temperature = -45.0 + 175.0 * (temp_raw as f32) / 65535.0;
         let humidity = 100.0 * (hum_raw as f32) / 65535.0;
 
-        // Read status register
-        let mut buffer = [0u8; 2];
-        self.i2c
-            .write_read(self.addr, &SHT30_READ_STATUS, &mut buffer)
-            .await?;
-        Timer::after_millis(1).await;
-
-        let status: u16 = ((buffer[0] as u16) << 8) | (buffer[1] as u16);
-
-        // Parse status bits
-        let heater_status = status & 0b0100_0000_0000_0000 != 0;
-        let humidity_tracking_alert = status & 0b0001_0000_0000_0000 != 0;
-        let temperature_tracking_alert = status & 0b0000_1000_0000_0000 != 0;
-        let command_status_success = status & 0b0000_0000_0000_0010 != 0;
-        let write_data_checksum_status = status & 0b0000_0000_0000_0001 != 0;
+        let status = Status::from_bits(((raw[6] as u16) << 8) | (raw[7] as u16));
 
-        Ok(Reading {
+        Reading {
             temperature,
             humidity,
-            heater_status,
-            humidity_tracking_alert,
-            temperature_tracking_alert,
-            command_status_success,
-            write_data_checksum_status,
-        })
+            heater_status: status.heater_status,
+            humidity_tracking_alert: status.humidity_tracking_alert,
+            temperature_tracking_alert: status.temperature_tracking_alert,
+            command_status_success: status.command_status,
+            write_data_checksum_status: status.write_data_checksum_status,
+            alert_pending: status.alert_pending,
+            reset_detected: status.reset_detected,
+        }
     }
 }
 
 #[embassy_executor::task]
 pub async fn continuous_reading(
-    device: &'static mut Sht30Device<I2cDevice<'static, CriticalSectionRawMutex, I2c0>>,
+    device: &'static Mutex<Sht30>,
     shared: &'static Mutex<SharedState>,
+    retry: RetryConfig,
 ) {
     // return;
     info!("sht30 continuous_reading");
+    let task = task_registry::SHT30_READING;
+    // Set when the loop below gave up on a frozen output
+    let mut stuck = false;
+    let mut interval = AdaptiveInterval::new(POLL_INTERVAL_MS, MAX_POLL_INTERVAL_MS);
+    // The backoff after a failed read, waited once after the reset in place of the
+    // reset delay and the poll interval
+    let mut backoff = None;
     loop {
         info!("sht30: reset");
-        if let Err(e) = embassy_time::with_timeout(TICK_TIMEOUT, device.soft_reset()).await {
+        if let Err(e) = embassy_time::with_timeout(TICK_TIMEOUT, async {
+            device.lock().await.soft_reset().await
+        })
+        .await
+        {
             error!("Timeout resetting sht30: {:?}", e);
         }
 
-        Timer::after(Duration::from_secs(5)).await;
+        if interval.consecutive_errors() > 0 {
+            info!(
+                "sht30: {} errors in a row, next read in {} ms",
+                interval.consecutive_errors(),
+                interval.current_ms()
+            );
+        }
+        let backed_off = backoff.is_some();
+        Timer::after(backoff.take().unwrap_or(RESET_DELAY)).await;
 
+        // After a reset for a frozen output the first reading may still be stale
+        let mut skip_next = stuck;
+        stuck = false;
+        let mut next_high_rep = Instant::now();
+        let mut next_medium_rep = Instant::now();
+        let mut first = true;
         loop {
             // info!("sht30: reading");
-            Timer::after(Duration::from_millis(100)).await;
-            let result = embassy_time::with_timeout(TICK_TIMEOUT, device.read()).await;
+            if !(first && backed_off) {
+                Timer::after(interval.current()).await;
+            }
+            first = false;
+            task_registry::iteration(task);
 
-            let mut state = match embassy_time::with_timeout(TICK_TIMEOUT, shared.lock()).await {
+            // Failures here are left to the main reading below to notice
+            let now = Instant::now();
+            for (repeatability, next, interval) in [
+                (Repeatability::High, &mut next_high_rep, HIGH_REP_INTERVAL),
+                (
+                    Repeatability::Medium,
+                    &mut next_medium_rep,
+                    MEDIUM_REP_INTERVAL,
+                ),
+            ] {
+                if now < *next {
+                    continue;
+                }
+                *next = now + interval;
+                let result = embassy_time::with_timeout(TICK_TIMEOUT, async {
+                    device.lock().await.read_with(repeatability).await
+                })
+                .await;
+                if let Ok(Ok(reading)) = result {
+                    match shared.lock_with_timeout(LOCK_TIMEOUT).await {
+                        Ok(mut state) => state.record_comparison(repeatability, &reading),
+                        Err(_) => error!("sht30: Timeout getting state lock for the comparison"),
+                    }
+                }
+            }
+
+            let read = || {
+                embassy_time::with_timeout(TICK_TIMEOUT, async { device.lock().await.read().await })
+            };
+            let mut result = read().await;
+            // Bus errors are often a one-off glitch, retry before resetting.  Timeouts
+            // aren't retried, the sensor is unlikely to come back without a reset.
+            let mut retries = 0;
+            while retries < retry.max_retries {
+                let Ok(Err(e)) = &result else {
+                    break;
+                };
+                error!("Error reading sht30, retrying: {}", e);
+                Timer::after(retry.delay(retries)).await;
+                retries += 1;
+                result = read().await;
+            }
+
+            let mut state = match shared.lock_with_timeout(LOCK_TIMEOUT).await {
                 Ok(v) => v,
                 Err(_) => {
                     error!("Timeout getting state lock");
+                    task_registry::set_status(task, TaskStatus::Error);
                     break;
                 }
             };
 
             match result {
                 Ok(Ok(reading)) => {
-                    state.record(&reading);
+                    if retries > 0 {
+                        state.record_retry_success();
+                    }
+                    interval.success();
+                    state.set_poll_interval(&interval);
+                    if skip_next {
+                        skip_next = false;
+                    } else if state.check_stuck(&reading) {
+                        warn!("sht30: {} identical readings, resetting", STUCK_READINGS);
+                        state.record_reset();
+                        stuck = true;
+                        task_registry::set_status(task, TaskStatus::Error);
+                        break;
+                    } else {
+                        // Recorded by `record_task`
+                        events::publish(SensorEvent::Sht30(reading));
+                    }
+                    task_registry::set_status(task, TaskStatus::Waiting);
                 }
                 Ok(Err(e)) => {
-                    error!("Error reading sht30: {}", e);
+                    log_error!(ErrorSource::Sht30, "Error reading sht30: {}", e);
+                    record_sensor_error("sht30", &e.into());
+                    if retries > 0 {
+                        state.record_retry_failure();
+                    }
                     state.record_error();
                     state.record_reset();
+                    backoff = Some(interval.error());
+                    state.set_poll_interval(&interval);
+                    task_registry::set_status(task, TaskStatus::Error);
                     break;
                 }
                 Err(_) => {
-                    error!("Timeout reading sht30, attempting soft reset");
+                    log_error!(
+                        ErrorSource::Sht30,
+                        "Timeout reading sht30, attempting soft reset"
+                    );
+                    record_sensor_error("sht30", &SensorError::Timeout);
                     state.record_timeout();
                     state.record_reset();
+                    backoff = Some(interval.error());
+                    state.set_poll_interval(&interval);
+                    task_registry::set_status(task, TaskStatus::Error);
                     break;
                 }
             }
@@ -254,3 +1495,59 @@ pub async fn continuous_reading(
         }
     }
 }
+
+/// Keep `SharedState` up to date from the readings published by `continuous_reading`.
+#[embassy_executor::task]
+pub async fn record_task(shared: &'static Mutex<SharedState>) -> ! {
+    let task = task_registry::SHT30_STATE;
+    let mut subscriber = events::subscribe();
+    loop {
+        task_registry::set_status(task, TaskStatus::Waiting);
+        if let SensorEvent::Sht30(reading) = events::next(&mut subscriber).await {
+            task_registry::iteration(task);
+            shared.lock().await.record(&reading);
+        }
+    }
+}
+
+/// Turn the heater off when a dehumidification session started by
+/// `POST /sht30/dehumidify` runs out.
+#[embassy_executor::task]
+pub async fn dehumidify_task(device: &'static Mutex<Sht30>, shared: &'static Mutex<SharedState>) {
+    let task = task_registry::SHT30_DEHUMIDIFY;
+    task_registry::set_status(task, TaskStatus::Waiting);
+    loop {
+        let duration = DEHUMIDIFY.wait().await.min(MAX_DEHUMIDIFY);
+        task_registry::iteration(task);
+        info!("sht30: Heater on for {} seconds", duration.as_secs());
+        Timer::after(duration).await;
+
+        // A soft reset also turns the heater off, fall back on it rather than leave it on.
+        // The session stays active, keeping readings out, until the heater is known off.
+        let mut retry = HEATER_OFF_RETRY;
+        loop {
+            let result = embassy_time::with_timeout(TICK_TIMEOUT, async {
+                let mut device = device.lock().await;
+                if let Err(e) = device.heater_off().await {
+                    error!("sht30: Error turning heater off, resetting: {:?}", e);
+                    device.soft_reset().await?;
+                }
+                Ok::<_, I2cDeviceError<embassy_rp::i2c::Error>>(())
+            })
+            .await;
+            match result {
+                Ok(Ok(())) => {
+                    info!("sht30: Heater off");
+                    break;
+                }
+                Ok(Err(e)) => error!("sht30: Error resetting after dehumidification: {:?}", e),
+                Err(_) => error!("sht30: Timeout turning heater off"),
+            }
+            task_registry::set_status(task, TaskStatus::Error);
+            Timer::after(retry).await;
+            retry = (retry * 2).min(MAX_HEATER_OFF_RETRY);
+        }
+        shared.lock().await.finish_dehumidification();
+        task_registry::set_status(task, TaskStatus::Waiting);
+    }
+}
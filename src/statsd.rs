@@ -0,0 +1,214 @@
+use core::fmt::Write as _;
+
+use defmt::{error, info};
+use embassy_net::udp::{PacketMetadata, UdpSocket};
+use embassy_net::Stack;
+use embassy_time::{Duration, Timer};
+use heapless::String;
+
+use crate::http::AppState;
+use crate::task_registry::{self, TaskStatus};
+
+pub const STATSD_DEFAULT_PORT: u16 = 8125;
+
+const PUSH_INTERVAL: Duration = Duration::from_secs(10);
+const METRIC_PREFIX: &str = "pico_climate";
+const DATAGRAM_SIZE: usize = 512;
+
+/// Flatten a Prometheus metric name and its labels into a dotted statsd name, so
+/// `sht30_reading{sensor="temperature"}` becomes `sht30_reading.temperature`.
+///
+/// Characters statsd treats specially are replaced with `_`.  Returns `false` if the
+/// result does not fit in `buf`.
+pub fn prometheus_to_statsd_name(
+    name: &str,
+    labels: &[(&str, &str)],
+    buf: &mut String<64>,
+) -> bool {
+    buf.clear();
+    let parts = core::iter::once(name).chain(labels.iter().map(|(_, value)| *value));
+    for (i, part) in parts.enumerate() {
+        if i > 0 && buf.push('.').is_err() {
+            return false;
+        }
+        for c in part.chars() {
+            let c = if matches!(c, ':' | '|' | '@' | '.' | ' ' | '\n') {
+                '_'
+            } else {
+                c
+            };
+            if buf.push(c).is_err() {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+/// Append one `prefix.hostname.name:value|type` line to `datagram`.  Lines that don't fit
+/// are dropped.
+fn push_metric(
+    datagram: &mut String<DATAGRAM_SIZE>,
+    hostname: &str,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: f32,
+    metric_type: &str,
+) {
+    let mut statsd_name = String::<64>::new();
+    // e.g. the INA237 current after a math overflow
+    if value.is_nan() || !prometheus_to_statsd_name(name, labels, &mut statsd_name) {
+        return;
+    }
+
+    let mark = datagram.len();
+    if writeln!(
+        datagram,
+        "{}.{}.{}:{}|{}",
+        METRIC_PREFIX, hostname, statsd_name, value, metric_type
+    )
+    .is_err()
+    {
+        datagram.truncate(mark);
+    }
+}
+
+/// Build the datagram for one push.  `last_request_count` carries the counter value from
+/// the previous push so only the delta is sent.
+async fn build_datagram(
+    app_state: &AppState,
+    last_request_count: &mut f32,
+    datagram: &mut String<DATAGRAM_SIZE>,
+) {
+    datagram.clear();
+    let hostname = app_state.hostname;
+
+    let (request_count, sht30_state, ina237_state) = {
+        let state = app_state.lock().await;
+        (
+            state.request_count(),
+            state.has_sht30.then_some(state.sht30_state),
+            state.ina237_state,
+        )
+    };
+
+    push_metric(
+        datagram,
+        hostname,
+        "http_request_count",
+        &[],
+        request_count - *last_request_count,
+        "c",
+    );
+    *last_request_count = request_count;
+
+    if let Some(sht30_state) = sht30_state {
+        let sht30_output = sht30_state.lock().await.snapshot();
+        push_metric(
+            datagram,
+            hostname,
+            "sht30",
+            &[("sensor", "temperature")],
+            sht30_output.temperature,
+            "g",
+        );
+        push_metric(
+            datagram,
+            hostname,
+            "sht30",
+            &[("sensor", "humidity")],
+            sht30_output.humidity,
+            "g",
+        );
+    }
+
+    if let Some(ina237_state) = ina237_state {
+        let ina237_output = ina237_state.lock().await.peek();
+        push_metric(
+            datagram,
+            hostname,
+            "ina237",
+            &[("register", "bus_voltage")],
+            ina237_output.bus_voltage,
+            "g",
+        );
+        push_metric(
+            datagram,
+            hostname,
+            "ina237",
+            &[("register", "current")],
+            ina237_output.current,
+            "g",
+        );
+    }
+}
+
+/// Push readings to a statsd server over UDP every 10 seconds.
+///
+/// Names are `pico_climate.{hostname}.{metric}`, e.g.
+/// `pico_climate.pico-climate-0123.sht30.temperature:23.5|g`.  UDP is fire and forget, so
+/// readings sent while the server is down are lost.
+#[embassy_executor::task]
+pub async fn statsd_task(
+    stack: &'static Stack<'static>,
+    host: &'static str,
+    port: u16,
+    app_state: &'static AppState,
+) -> ! {
+    let mut rx_meta = [PacketMetadata::EMPTY; 1];
+    let mut rx_buffer = [0; 64];
+    let mut tx_meta = [PacketMetadata::EMPTY; 2];
+    let mut tx_buffer = [0; DATAGRAM_SIZE];
+    let mut socket = UdpSocket::new(
+        *stack,
+        &mut rx_meta,
+        &mut rx_buffer,
+        &mut tx_meta,
+        &mut tx_buffer,
+    );
+    let task = task_registry::STATSD;
+    // An ephemeral port on a fresh socket can't fail to bind for a reason that would
+    // pass, and every send from an unbound socket would fail, so give up for good
+    if let Err(e) = socket.bind(0) {
+        error!("statsd: Unable to bind socket: {:?}", e);
+        task_registry::set_status(task, TaskStatus::Error);
+        core::future::pending::<()>().await;
+    }
+
+    info!("statsd: Target server {}:{}", host, port);
+    let mut last_request_count = 0.;
+    let mut datagram = String::<DATAGRAM_SIZE>::new();
+    loop {
+        Timer::after(PUSH_INTERVAL).await;
+        stack.wait_config_up().await;
+        task_registry::iteration(task);
+
+        let addr = match stack
+            .dns_query(host, embassy_net::dns::DnsQueryType::A)
+            .await
+        {
+            Ok(addresses) if !addresses.is_empty() => addresses[0],
+            _ => {
+                error!("statsd: Failed to lookup address: {}", host);
+                task_registry::set_status(task, TaskStatus::Error);
+                continue;
+            }
+        };
+
+        build_datagram(app_state, &mut last_request_count, &mut datagram).await;
+        let status = match socket
+            .send_to(
+                datagram.as_bytes(),
+                embassy_net::IpEndpoint::new(addr, port),
+            )
+            .await
+        {
+            Ok(()) => TaskStatus::Waiting,
+            Err(e) => {
+                error!("statsd: Send failed: {:?}", e);
+                TaskStatus::Error
+            }
+        };
+        task_registry::set_status(task, status);
+    }
+}
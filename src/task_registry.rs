@@ -0,0 +1,126 @@
+//! What each embassy task was last doing, for `GET /debug/tasks`.
+//!
+//! Tasks report into fixed slots so a stuck task shows up as one that has stopped
+//! counting iterations, or is stuck `running`.
+
+use defmt::Format;
+use portable_atomic::{AtomicU32, AtomicU8, Ordering};
+use serde::Serialize;
+
+use crate::config::HTTP_TASK_COUNT;
+
+pub const WATCHDOG_FEEDER: usize = 0;
+pub const HEARTBEAT: usize = 1;
+pub const REBOOT: usize = 2;
+pub const LED: usize = 3;
+pub const SHT30_READING: usize = 4;
+pub const SHT30_DEHUMIDIFY: usize = 5;
+pub const INA237_READING: usize = 6;
+pub const INA237_ENERGY_PERSIST: usize = 7;
+pub const HISTORY: usize = 8;
+pub const MQTT: usize = 9;
+pub const PUSHGATEWAY: usize = 10;
+pub const REMOTE_WRITE: usize = 11;
+pub const STATSD: usize = 12;
+pub const SHT30_STATE: usize = 13;
+pub const INA237_STATE: usize = 14;
+pub const TEMPERATURE_ALERT: usize = 15;
+pub const I2C_HEALTH: usize = 16;
+pub const BATTERY: usize = 17;
+pub const BATTERY_PERSIST: usize = 18;
+pub const AMBIENT_LIGHT: usize = 19;
+pub const VOLTAGE_ALARM: usize = 20;
+pub const WIFI_HISTOGRAM_RESET: usize = 21;
+pub const WIFI_RSSI: usize = 22;
+/// One slot per `web_task`, indexed by its id.
+pub const WEB: usize = 23;
+pub const TASK_COUNT: usize = WEB + HTTP_TASK_COUNT;
+/// Every fixed slot plus the most `web_task`s `HTTP_TASK_COUNT` allows.
+pub const MAX_TASKS: usize = WEB + WEB_TASK_NAMES.len();
+const _: () = assert!(TASK_COUNT <= MAX_TASKS);
+
+const TASK_NAMES: [&str; WEB] = [
+    "watchdog_feeder",
+    "heartbeat",
+    "reboot",
+    "led",
+    "sht30_reading",
+    "sht30_dehumidify",
+    "ina237_reading",
+    "ina237_energy_persist",
+    "history",
+    "mqtt",
+    "pushgateway",
+    "remote_write",
+    "statsd",
+    "sht30_state",
+    "ina237_state",
+    "temperature_alert",
+    "i2c_health",
+    "battery",
+    "battery_persist",
+    "ambient_light",
+    "voltage_alarm",
+    "wifi_histogram_reset",
+    "wifi_rssi",
+];
+/// Up to the 16 tasks `HTTP_TASK_COUNT` allows.
+const WEB_TASK_NAMES: [&str; 16] = [
+    "web_0", "web_1", "web_2", "web_3", "web_4", "web_5", "web_6", "web_7", "web_8", "web_9",
+    "web_10", "web_11", "web_12", "web_13", "web_14", "web_15",
+];
+
+#[repr(u8)]
+#[derive(Clone, Copy, PartialEq, Eq, Format, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskStatus {
+    /// Not spawned in this build, or hasn't reached its loop yet.
+    NotStarted = 0,
+    Running = 1,
+    Waiting = 2,
+    /// Last iteration failed, until the next one starts.
+    Error = 3,
+}
+
+impl TaskStatus {
+    fn from_u8(value: u8) -> Self {
+        match value {
+            1 => TaskStatus::Running,
+            2 => TaskStatus::Waiting,
+            3 => TaskStatus::Error,
+            _ => TaskStatus::NotStarted,
+        }
+    }
+}
+
+pub static TASK_STATUS: [AtomicU8; MAX_TASKS] = [const { AtomicU8::new(0) }; MAX_TASKS];
+pub static TASK_ITERATIONS: [AtomicU32; MAX_TASKS] = [const { AtomicU32::new(0) }; MAX_TASKS];
+
+pub fn set_status(task: usize, status: TaskStatus) {
+    TASK_STATUS[task].store(status as u8, Ordering::Relaxed);
+}
+
+/// Count an iteration of the task's main loop and mark it running.
+pub fn iteration(task: usize) {
+    TASK_ITERATIONS[task].fetch_add(1, Ordering::Relaxed);
+    set_status(task, TaskStatus::Running);
+}
+
+#[derive(Serialize)]
+pub struct TaskInfo {
+    pub name: &'static str,
+    pub status: TaskStatus,
+    pub iterations: u32,
+}
+
+pub fn task_info(task: usize) -> TaskInfo {
+    TaskInfo {
+        name: if task < WEB {
+            TASK_NAMES[task]
+        } else {
+            WEB_TASK_NAMES[task - WEB]
+        },
+        status: TaskStatus::from_u8(TASK_STATUS[task].load(Ordering::Relaxed)),
+        iterations: TASK_ITERATIONS[task].load(Ordering::Relaxed),
+    }
+}
@@ -1,63 +1,134 @@
+//! defmt logs sent to a TCP server, for when there's no debug probe attached.
+//!
+//! Each defmt frame goes out behind its length as a 4 byte big endian prefix, so the
+//! receiver can find frame boundaries however TCP splits the stream.  Strip the prefixes
+//! before handing the frames to `defmt-print`.
+
+use core::cell::RefCell;
+
 use defmt::{error, info};
 use embassy_futures::block_on;
 use embassy_net::{tcp::TcpSocket, Stack};
-use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, channel::Channel, mutex::Mutex};
+use embassy_sync::blocking_mutex::Mutex as BlockingMutex;
+use embassy_sync::signal::Signal;
+use embassy_sync::{blocking_mutex::raw::CriticalSectionRawMutex, mutex::Mutex};
 use embassy_time::{Duration, Timer};
+use embedded_io_async::Write;
+use heapless::Vec;
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::RingBuffer;
+
+pub const TCP_LOGGER_DEFAULT_PORT: u16 = 9091;
 
 #[defmt::global_logger]
 struct Logger;
 
-static SHARED_CHANNEL: Channel<CriticalSectionRawMutex, u8, 1024> = Channel::new();
+/// Longest frame sent, longer ones are dropped.
+const MAX_FRAME_LEN: usize = 256;
+const HEADER_LEN: usize = 4;
+
+// Lossy: when the connection is down new frames are dropped once this fills up.  Only
+// whole frames are written, so the stream never loses its place.
+static SEND_BUFFER: BlockingMutex<CriticalSectionRawMutex, RefCell<RingBuffer<u8, 1024>>> =
+    BlockingMutex::new(RefCell::new(RingBuffer::new()));
+static DATA_READY: Signal<CriticalSectionRawMutex, ()> = Signal::new();
+/// The frame being logged, moved to `SEND_BUFFER` by `release`.
+static FRAME: BlockingMutex<CriticalSectionRawMutex, RefCell<Vec<u8, MAX_FRAME_LEN>>> =
+    BlockingMutex::new(RefCell::new(Vec::new()));
+/// `FRAME` ran out of room, so the frame is incomplete.
+static FRAME_TRUNCATED: AtomicBool = AtomicBool::new(false);
+/// Frames dropped for being too long or finding `SEND_BUFFER` full.
+pub static DROPPED_FRAMES: AtomicU32 = AtomicU32::new(0);
 static SHARED_LOCK: Mutex<CriticalSectionRawMutex, bool> = Mutex::new(false);
 static RTT_ENCODER: Mutex<CriticalSectionRawMutex, defmt::Encoder> =
     Mutex::new(defmt::Encoder::new());
 
+fn enqueue(bytes: &[u8]) {
+    FRAME.lock(|frame| {
+        if frame.borrow_mut().extend_from_slice(bytes).is_err() {
+            FRAME_TRUNCATED.store(true, Ordering::Relaxed);
+        }
+    });
+}
+
+/// Move the finished frame to `SEND_BUFFER` behind its length, if it fits.
+fn commit_frame() {
+    let committed = FRAME.lock(|frame| {
+        let mut frame = frame.borrow_mut();
+        let truncated = FRAME_TRUNCATED.swap(false, Ordering::Relaxed);
+        let committed = !truncated
+            && SEND_BUFFER.lock(|buffer| {
+                let mut buffer = buffer.borrow_mut();
+                if buffer.free() < HEADER_LEN + frame.len() {
+                    return false;
+                }
+                buffer.write_bytes(&(frame.len() as u32).to_be_bytes());
+                buffer.write_bytes(&frame);
+                true
+            });
+        frame.clear();
+        committed
+    });
+
+    if committed {
+        DATA_READY.signal(());
+    } else {
+        DROPPED_FRAMES.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// Take the next length prefixed frame from `SEND_BUFFER` into `out`, returning its length
+/// including the prefix, or 0 if there isn't one.
+fn next_frame(out: &mut [u8; HEADER_LEN + MAX_FRAME_LEN]) -> usize {
+    SEND_BUFFER.lock(|buffer| {
+        let mut buffer = buffer.borrow_mut();
+        // Frames are written whole, so a header is always followed by its payload
+        if buffer.read_bytes(&mut out[..HEADER_LEN]) < HEADER_LEN {
+            return 0;
+        }
+        let len = u32::from_be_bytes([out[0], out[1], out[2], out[3]]) as usize;
+        HEADER_LEN + buffer.read_bytes(&mut out[HEADER_LEN..HEADER_LEN + len])
+    })
+}
+
 unsafe impl defmt::Logger for Logger {
     fn acquire() {
         loop {
             if let Ok(mut lock) = SHARED_LOCK.try_lock() {
-                if *lock == false {
+                if !*lock {
                     *lock = true;
                     break;
                 }
             }
         }
-        block_on(RTT_ENCODER.lock()).start_frame(|bytes| {
-            for b in bytes {
-                SHARED_CHANNEL.sender().try_send(*b).unwrap();
-            }
-        });
+        block_on(RTT_ENCODER.lock()).start_frame(enqueue);
     }
 
     unsafe fn flush() {}
 
     unsafe fn release() {
+        // Before unlocking, so the next frame can't start in `FRAME` until this one is out
+        block_on(RTT_ENCODER.lock()).end_frame(enqueue);
+        commit_frame();
+
         loop {
             if let Ok(mut lock) = SHARED_LOCK.try_lock() {
-                if *lock == true {
+                if *lock {
                     *lock = false;
                     break;
                 }
             }
         }
-
-        block_on(RTT_ENCODER.lock()).end_frame(|bytes| {
-            for byte in bytes {
-                block_on(SHARED_CHANNEL.sender().send(*byte));
-            }
-        });
     }
 
     unsafe fn write(bytes: &[u8]) {
-        block_on(RTT_ENCODER.lock()).write(bytes, |bytes| {
-            for byte in bytes {
-                block_on(SHARED_CHANNEL.sender().send(*byte));
-            }
-        });
+        block_on(RTT_ENCODER.lock()).write(bytes, enqueue);
     }
 }
 
-/// Task that connects to a TCP server and sends canned defmt messages
+/// Connect to `server_addr` and send it the buffered defmt frames, reconnecting 5 seconds
+/// after a failure.
 #[embassy_executor::task]
 pub async fn tcp_logger_task(
     stack: &'static Stack<'static>,
@@ -88,24 +159,25 @@ pub async fn tcp_logger_task(
         socket.set_timeout(Some(Duration::from_secs(10)));
         socket.set_keep_alive(Some(Duration::from_secs(1)));
 
-        let remote_endpoint = embassy_net::IpEndpoint::new(addr.into(), server_port);
+        let remote_endpoint = embassy_net::IpEndpoint::new(addr, server_port);
 
         // Attempt to connect
         match socket.connect(remote_endpoint).await {
             Ok(()) => {
                 info!("TCP Logger: Connected to {}:{}", server_addr, server_port);
 
+                let mut frame = [0u8; HEADER_LEN + MAX_FRAME_LEN];
                 loop {
-                    let receiver = SHARED_CHANNEL.receiver();
-                    receiver.ready_to_receive().await;
-
-                    let byte = receiver.try_peek().unwrap();
+                    let len = next_frame(&mut frame);
+                    if len == 0 {
+                        DATA_READY.wait().await;
+                        continue;
+                    }
 
-                    match socket.write(&[byte]).await {
-                        Ok(_) => {
-                            receiver.try_receive().unwrap();
-                        }
-                        Err(_) => break,
+                    // A frame cut short by a dropped connection is lost, the next
+                    // connection starts on a frame boundary
+                    if socket.write_all(&frame[..len]).await.is_err() {
+                        break;
                     }
                 }
 
@@ -0,0 +1,135 @@
+//! Vapour pressure deficit from the SHT30, for growers.
+//!
+//! `sht30_derived` already carries the VPD, this adds it under a name and label set that
+//! doesn't depend on which sensor measured it, and buckets it for Grafana state timelines.
+
+use defmt::{debug, Format};
+
+use crate::prometheus::sample::Sample;
+use crate::prometheus::{gauge, MetricSink, MetricWriter};
+use crate::{climate_math, sht30, Mutex, SensorError};
+
+/// `location` label, there's only one SHT30.
+const LOCATION: &str = "sensor_0";
+
+#[derive(Clone, Copy, PartialEq, Eq, Format)]
+pub enum VpdCategory {
+    /// Below 0.4 kPa: too humid, risk of mould.
+    Low,
+    /// 0.4 to 1.2 kPa: ideal, from seedlings at the low end to vegetative growth.
+    Ideal,
+    /// 1.2 to 1.6 kPa: plants start closing their stomata.
+    High,
+    /// Above 1.6 kPa.
+    VeryHigh,
+}
+
+impl VpdCategory {
+    const ALL: [VpdCategory; 4] = [
+        VpdCategory::Low,
+        VpdCategory::Ideal,
+        VpdCategory::High,
+        VpdCategory::VeryHigh,
+    ];
+
+    pub fn from_kpa(vpd_kpa: f32) -> Self {
+        if vpd_kpa < 0.4 {
+            VpdCategory::Low
+        } else if vpd_kpa < 1.2 {
+            VpdCategory::Ideal
+        } else if vpd_kpa < 1.6 {
+            VpdCategory::High
+        } else {
+            VpdCategory::VeryHigh
+        }
+    }
+
+    fn label(self) -> &'static str {
+        match self {
+            VpdCategory::Low => "low",
+            VpdCategory::Ideal => "ideal",
+            VpdCategory::High => "high",
+            VpdCategory::VeryHigh => "very_high",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Format)]
+pub struct VpdReading {
+    pub vpd_kpa: f32,
+}
+
+impl VpdReading {
+    pub fn category(&self) -> VpdCategory {
+        VpdCategory::from_kpa(self.vpd_kpa)
+    }
+}
+
+/// VPD computed from the latest SHT30 snapshot.  Nothing is reported before the first
+/// SHT30 reading, or while the heater is on and the temperature is meaningless.
+pub struct VpdSensor {
+    sht30: &'static Mutex<sht30::SharedState>,
+    last: Option<VpdReading>,
+}
+
+impl VpdSensor {
+    pub fn new(sht30: &'static Mutex<sht30::SharedState>) -> Self {
+        Self { sht30, last: None }
+    }
+}
+
+impl crate::Sensor for VpdSensor {
+    type Reading = Option<VpdReading>;
+
+    fn name(&self) -> &'static str {
+        "vpd"
+    }
+
+    async fn poll(&mut self) -> Result<(), SensorError> {
+        let output = self.sht30.lock().await.snapshot();
+        self.last =
+            (output.last_read_age_seconds.is_some() && !output.dehumidifying).then(|| VpdReading {
+                vpd_kpa: climate_math::vapor_pressure_deficit_kpa(
+                    output.temperature,
+                    output.humidity,
+                ),
+            });
+        debug!("vpd: {}", self.last);
+        Ok(())
+    }
+
+    fn reading(&self) -> Option<VpdReading> {
+        self.last
+    }
+}
+
+impl crate::WriteMetrics for VpdReading {
+    async fn write_metrics<W: MetricSink>(&self, writer: &mut W) -> Result<(), W::Error> {
+        writer
+            .write(
+                gauge(
+                    "climate_vpd_kpa",
+                    "Vapour pressure deficit",
+                    ["location"],
+                    [Sample::new([LOCATION], self.vpd_kpa)].iter(),
+                )
+                .with_unit("kpa"),
+            )
+            .await?;
+
+        // One series per category so a state timeline can show which one is active
+        let category = self.category();
+        let samples =
+            VpdCategory::ALL.map(|c| Sample::new([c.label()], (c == category) as u8 as f32));
+        writer
+            .write(gauge(
+                "climate_vpd_category",
+                "1 for the band the vapour pressure deficit is in, 0 for the others",
+                ["category"],
+                samples.iter(),
+            ))
+            .await?;
+
+        Ok(())
+    }
+}
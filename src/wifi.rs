@@ -0,0 +1,401 @@
+use core::fmt::Write;
+
+use cyw43::{Control, ScanOptions};
+use defmt::{info, warn};
+use embassy_sync::blocking_mutex::raw::CriticalSectionRawMutex;
+use embassy_sync::channel::Channel;
+use embassy_sync::signal::Signal;
+use embassy_time::{Duration, Instant, Ticker, Timer};
+use heapless::{String, Vec};
+use portable_atomic::{AtomicBool, AtomicU32, Ordering};
+use serde::Serialize;
+
+use crate::config::WIFI_CHANNELS;
+use crate::http::{wifi_signal_indices, AppState, State};
+use crate::task_registry::{self, TaskStatus};
+use crate::{LedState, Mutex, LED_STATE};
+
+pub const MAX_SCAN_RESULTS: usize = 32;
+
+/// One access point seen by `POST /wifi/scan`.
+#[derive(Clone, Serialize)]
+pub struct ScanResult {
+    pub ssid: String<32>,
+    pub channel: u8,
+    pub rssi: i8,
+    pub bssid: [u8; 6],
+}
+
+/// Asks `wifi_monitor`, which owns the scanning, for a scan of every SSID.
+pub struct ScanRequest;
+
+pub struct ScanResponse {
+    pub results: Vec<ScanResult, MAX_SCAN_RESULTS>,
+}
+
+pub static SCAN_REQUESTS: Channel<CriticalSectionRawMutex, ScanRequest, 1> = Channel::new();
+pub static SCAN_RESPONSES: Channel<CriticalSectionRawMutex, ScanResponse, 1> = Channel::new();
+/// Set by the HTTP handler for the duration of a requested scan.
+pub static SCAN_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+pub static SCAN_COUNT: AtomicU32 = AtomicU32::new(0);
+pub static SCAN_DURATION_MS: AtomicU32 = AtomicU32::new(0);
+
+/// Set while 95% of the scans on some channel saw the SSID weaker than -80 dBm.  The
+/// RSSI histograms hold `-rssi`, so that is their 5th percentile being above 80.
+pub static WIFI_SIGNAL_WEAK: AtomicBool = AtomicBool::new(false);
+const WEAK_SIGNAL_QUANTILE: f32 = 0.05;
+const WEAK_SIGNAL_NEG_RSSI: f32 = 80.;
+
+/// The device's own addresses, set by the join loop in `main` each time DHCP completes.
+#[derive(Clone)]
+pub struct NetworkInfo {
+    /// Dotted decimal.
+    pub ip: String<16>,
+    /// Colon separated hex.
+    pub mac: String<17>,
+}
+
+impl NetworkInfo {
+    pub fn new(ip: embassy_net::Ipv4Address, mac: [u8; 6]) -> Self {
+        let mut info = Self {
+            ip: String::new(),
+            mac: String::new(),
+        };
+        // Can't fail, both fit at their longest
+        let _ = write!(info.ip, "{}", ip);
+        for (i, byte) in mac.iter().enumerate() {
+            let separator = if i == 0 { "" } else { ":" };
+            let _ = write!(info.mac, "{}{:02x}", separator, byte);
+        }
+        info
+    }
+}
+
+/// Link up and down times, updated by the join loop in `main`.
+pub static WIFI_STATS: Mutex<WifiStats> = Mutex::new(WifiStats::new());
+
+/// Sent by the join loop in `main` when the link comes back up, for
+/// `histogram_reset_task` to clear the signal histograms.
+pub struct WifiReconnectSignal;
+
+pub static WIFI_RECONNECT: Signal<CriticalSectionRawMutex, WifiReconnectSignal> = Signal::new();
+pub static HISTOGRAM_RESETS: AtomicU32 = AtomicU32::new(0);
+
+/// Cumulative WiFi link time.  Time before the first link up counts as disconnected.
+#[derive(Clone, Copy)]
+pub struct WifiStats {
+    /// Link ups after a link down, the first connection after boot isn't counted.
+    pub reconnects: u32,
+    pub connected_since: Option<Instant>,
+    pub total_connected_ms: u64,
+    pub total_disconnected_ms: u64,
+    pub last_disconnect: Option<Instant>,
+}
+
+impl WifiStats {
+    const fn new() -> Self {
+        WifiStats {
+            reconnects: 0,
+            connected_since: None,
+            total_connected_ms: 0,
+            total_disconnected_ms: 0,
+            last_disconnect: None,
+        }
+    }
+
+    /// True if this is a reconnect, rather than the first link up or a repeat.
+    pub fn link_up(&mut self) -> bool {
+        if self.connected_since.is_some() {
+            return false;
+        }
+        let (disconnected_at, reconnected) = match self.last_disconnect {
+            Some(at) => {
+                self.reconnects += 1;
+                (at, true)
+            }
+            None => (Instant::MIN, false),
+        };
+        self.total_disconnected_ms += disconnected_at.elapsed().as_millis();
+        self.connected_since = Some(Instant::now());
+        reconnected
+    }
+
+    pub fn link_down(&mut self) {
+        if let Some(since) = self.connected_since.take() {
+            self.total_connected_ms += since.elapsed().as_millis();
+            self.last_disconnect = Some(Instant::now());
+        }
+    }
+
+    /// Connected time including the current connection.
+    pub fn connected_seconds(&self) -> f32 {
+        let current = self
+            .connected_since
+            .map_or(0, |at| at.elapsed().as_millis());
+        (self.total_connected_ms + current) as f32 / 1000.
+    }
+
+    /// Time since the link last came up, `None` while it is down.
+    pub fn associated_seconds(&self) -> Option<f32> {
+        self.connected_since
+            .map(|at| at.elapsed().as_millis() as f32 / 1000.)
+    }
+
+    /// Disconnected time including the current outage.
+    pub fn disconnected_seconds(&self) -> f32 {
+        let current = match self.connected_since {
+            Some(_) => 0,
+            None => self
+                .last_disconnect
+                .unwrap_or(Instant::MIN)
+                .elapsed()
+                .as_millis(),
+        };
+        (self.total_disconnected_ms + current) as f32 / 1000.
+    }
+
+    /// Fraction of the uptime spent connected.
+    pub fn uptime_ratio(&self) -> f32 {
+        let connected = self.connected_seconds();
+        let total = connected + self.disconnected_seconds();
+        if total > 0. {
+            connected / total
+        } else {
+            0.
+        }
+    }
+}
+
+/// Half of the 1 Hz alarm blink period.
+const LED_TOGGLE_INTERVAL: Duration = Duration::from_millis(500);
+
+const RSSI_INTERVAL: Duration = Duration::from_secs(30);
+const RSSI_EWMA_ALPHA: f32 = 0.2;
+const RSSI_TREND_WINDOW: Duration = Duration::from_secs(300);
+
+/// Map RSSI in dBm to a 0-100 signal quality score: -100 dBm and below is 0%, -50 dBm and
+/// above is 100%.
+pub fn rssi_quality_percent(rssi_dbm: i32) -> f32 {
+    (2 * (rssi_dbm + 100)).clamp(0, 100) as f32
+}
+
+/// Fold a new RSSI reading into the moving average, and snapshot the average every five
+/// minutes so the trend can be reported.
+fn update_rssi_ewma(state: &mut State, rssi_dbm: f32) {
+    // wifi_rssi is still 0 before the first scan, seed the average instead of decaying from 0.
+    if state.rssi_ewma == 0. {
+        state.rssi_ewma = rssi_dbm;
+        state.rssi_ewma_prev = rssi_dbm;
+        state.rssi_ewma_prev_at = Instant::now();
+        return;
+    }
+
+    state.rssi_ewma += RSSI_EWMA_ALPHA * (rssi_dbm - state.rssi_ewma);
+    if state.rssi_ewma_prev_at.elapsed() >= RSSI_TREND_WINDOW {
+        state.rssi_ewma_prev = state.rssi_ewma;
+        state.rssi_ewma_prev_at = Instant::now();
+    }
+}
+
+/// Drive the onboard LED, which is CYW43 GPIO 0, from `LED_STATE`.
+///
+/// The LED shares `control` with the WiFi scans, so a blink can be held back until the
+/// scan in progress finishes.
+#[embassy_executor::task]
+pub async fn led_task(control: &'static Mutex<Control<'static>>) -> ! {
+    // main turns the LED on during init
+    let mut on = true;
+    loop {
+        task_registry::iteration(task_registry::LED);
+        let next = match *LED_STATE.lock().await {
+            LedState::WiFiConnected => true,
+            LedState::WiFiDisconnected => false,
+            LedState::AlarmHigh | LedState::AlarmLow => !on,
+        };
+        if next != on {
+            control.lock().await.gpio_set(0, next).await;
+            on = next;
+        }
+        task_registry::set_status(task_registry::LED, TaskStatus::Waiting);
+        Timer::after(LED_TOGGLE_INTERVAL).await;
+    }
+}
+
+/// Read the RSSI of the current association every 30 seconds into `State::wifi_rssi`, for
+/// the `wifi_rssi_dbm` gauge.  Nothing is read while the link is down.
+///
+/// cyw43 has no call for the RSSI of the association, so this scans for just the access
+/// point `wifi_monitor` last saw as the strongest, which is the one joined.
+#[embassy_executor::task]
+pub async fn rssi_task(
+    control: &'static Mutex<Control<'static>>,
+    ssid: &'static str,
+    app_state: &'static AppState,
+) -> ! {
+    let task = task_registry::WIFI_RSSI;
+    let mut ticker = Ticker::every(RSSI_INTERVAL);
+    loop {
+        task_registry::set_status(task, TaskStatus::Waiting);
+        ticker.next().await;
+        task_registry::iteration(task);
+        if WIFI_STATS.lock().await.connected_since.is_none() {
+            continue;
+        }
+
+        let Some(bssid) = app_state.lock().await.wifi_bssid else {
+            continue;
+        };
+        let mut scan_opts = ScanOptions::default();
+        scan_opts.ssid = Some(heapless::String::try_from(ssid).unwrap());
+        scan_opts.bssid = Some(bssid);
+
+        let mut rssi = None;
+        {
+            let mut control = control.lock().await;
+            let mut scan = control.scan(scan_opts).await;
+            while let Some(s) = scan.next().await {
+                rssi = Some(rssi.map_or(s.rssi, |rssi: i16| rssi.max(s.rssi)));
+            }
+        }
+        let Some(rssi) = rssi else {
+            continue;
+        };
+
+        let mut state = app_state.lock().await;
+        state.wifi_rssi.store(rssi as i32, Ordering::Relaxed);
+        update_rssi_ewma(&mut state, rssi as f32);
+    }
+}
+
+/// Scan every SSID on every channel for `POST /wifi/scan`, keeping the first
+/// `MAX_SCAN_RESULTS` access points.
+async fn scan_all(control: &Mutex<Control<'static>>) -> ScanResponse {
+    let start = Instant::now();
+    let mut results = Vec::new();
+    {
+        let mut control = control.lock().await;
+        let mut scan = control.scan(ScanOptions::default()).await;
+        while let Some(s) = scan.next().await {
+            let ssid_len = (s.ssid_len as usize).min(s.ssid.len());
+            let ssid = core::str::from_utf8(&s.ssid[..ssid_len])
+                .ok()
+                .and_then(|ssid| String::try_from(ssid).ok())
+                .unwrap_or_default();
+            let result = ScanResult {
+                ssid,
+                channel: (s.chanspec & 0xff) as u8,
+                rssi: s.rssi.clamp(i8::MIN as i16, i8::MAX as i16) as i8,
+                bssid: s.bssid,
+            };
+            // Keep draining the scanner so it finishes cleanly
+            let _ = results.push(result);
+        }
+    }
+
+    let elapsed = start.elapsed();
+    SCAN_COUNT.fetch_add(1, Ordering::Relaxed);
+    SCAN_DURATION_MS.store(elapsed.as_millis() as u32, Ordering::Relaxed);
+    info!(
+        "wifi: Scan found {} access points in {} ms",
+        results.len(),
+        elapsed.as_millis()
+    );
+    ScanResponse { results }
+}
+
+/// Record the strongest access point from a scan, counting a change of BSSID as roaming.
+fn update_association(state: &mut State, channel: u8, bssid: [u8; 6]) {
+    state.wifi_channel = channel;
+    if state.wifi_bssid != Some(bssid) {
+        info!("wifi: Access point {:02x} on channel {}", bssid, channel);
+        state.wifi_bssid = Some(bssid);
+        state.wifi_associations += 1;
+    }
+}
+
+/// Update `WIFI_SIGNAL_WEAK` from the RSSI histograms.
+fn update_weak_signal(state: &State) {
+    let weak = WIFI_CHANNELS
+        .iter()
+        .filter_map(|channel| wifi_signal_indices(*channel))
+        .any(|[rssi, _, _]| {
+            state.wifi_signal[rssi].quantile_estimate(WEAK_SIGNAL_QUANTILE) > WEAK_SIGNAL_NEG_RSSI
+        });
+    if WIFI_SIGNAL_WEAK.swap(weak, Ordering::Relaxed) != weak {
+        if weak {
+            warn!("wifi: Signal mostly weaker than -80 dBm");
+        } else {
+            info!("wifi: Signal no longer weak");
+        }
+    }
+}
+
+/// Clear the `wifi_signal_strength` histograms on each `WIFI_RECONNECT`, so they describe
+/// the current association rather than mixing in the access point or channel before it.
+#[embassy_executor::task]
+pub async fn histogram_reset_task(app_state: &'static AppState) -> ! {
+    let task = task_registry::WIFI_HISTOGRAM_RESET;
+    loop {
+        task_registry::set_status(task, TaskStatus::Waiting);
+        WIFI_RECONNECT.wait().await;
+        task_registry::iteration(task);
+
+        let mut state = app_state.lock().await;
+        for histogram in &mut state.wifi_signal {
+            histogram.reset();
+        }
+        update_weak_signal(&state);
+        drop(state);
+
+        HISTOGRAM_RESETS.fetch_add(1, Ordering::Relaxed);
+        info!("wifi: Reconnected, signal histograms reset");
+    }
+}
+
+/// Continuously scan for the configured SSID while the link is up, sampling the per
+/// channel signal histograms and noting the strongest access point seen in each scan.
+/// Requests on `SCAN_REQUESTS` are served between scans.
+pub async fn wifi_monitor(
+    control: &Mutex<Control<'static>>,
+    ssid: &str,
+    app_state: &AppState,
+) -> ! {
+    loop {
+        if SCAN_REQUESTS.try_receive().is_ok() {
+            // Nobody is waiting if the handler timed out, so don't block on a full channel
+            let _ = SCAN_RESPONSES.try_send(scan_all(control).await);
+        }
+
+        let mut scan_opts = ScanOptions::default();
+        scan_opts.ssid = Some(heapless::String::try_from(ssid).unwrap());
+
+        // RSSI, channel and BSSID of the strongest access point
+        let mut best: Option<(i16, u8, [u8; 6])> = None;
+        {
+            let mut control = control.lock().await;
+            let mut scan = control.scan(scan_opts).await;
+            while let Some(s) = scan.next().await {
+                let channel = (s.chanspec & 0xff) as u8;
+                if let Some([rssi, phy_noise, snr]) = wifi_signal_indices(channel) {
+                    let mut state = app_state.lock().await;
+                    state.wifi_signal[rssi].sample(-s.rssi as f32);
+                    state.wifi_signal[phy_noise].sample(-s.phy_noise as f32);
+                    state.wifi_signal[snr].sample((s.rssi - s.phy_noise as i16) as f32);
+                }
+
+                if best.is_none_or(|(rssi, _, _)| s.rssi > rssi) {
+                    best = Some((s.rssi, channel, s.bssid));
+                }
+            }
+        }
+
+        // Give led_task a chance at `control` between scans
+        embassy_futures::yield_now().await;
+
+        if let Some((_, channel, bssid)) = best {
+            let mut state = app_state.lock().await;
+            update_association(&mut state, channel, bssid);
+            update_weak_signal(&state);
+        }
+    }
+}